@@ -0,0 +1,254 @@
+use log::info;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use wal::common::WalPosition;
+use wal::wal::Wal;
+
+const NUM_PRODUCERS: u32 = 4;
+const NUM_PER_PRODUCER: u32 = 200;
+
+// Producers block once more than this fraction of the ring holds unacked messages so the head never
+// overwrites them.
+const HIGH_WATER: f64 = 0.75;
+
+/// A persistent multi-producer queue with at-least-once delivery. Every message is an entry in the
+/// wal. push returns once the message is durable. The consumer reads durable messages from its
+/// cursor, and acking a message moves the cursor past it and truncates the wal. After a restart,
+/// or if a consumer goes away without acking, delivery resumes from the cursor so unacked messages
+/// are delivered again.
+struct Queue {
+    wal: Mutex<Wal>,
+    // Signalled when an ack frees up space in the ring.
+    space: Condvar,
+    durability: Mutex<Durability>,
+    // Signalled when completions arrive.
+    completed: Condvar,
+    // Name of the consumer cursor stored in the wal.
+    name: String,
+}
+
+/// Tracks which messages are durable. The device can complete writes out of order, so a message
+/// is only durable once its own completion arrives, and the consumer only reads up to the first
+/// message that is not durable yet.
+#[derive(Default)]
+struct Durability {
+    // Messages in the order they were appended, up to and including the first one that hasn't
+    // completed.
+    pending: VecDeque<WalPosition>,
+    // Completions for messages in pending.
+    completions: HashSet<WalPosition>,
+    // The last message that is durable along with every message before it.
+    contiguous: Option<WalPosition>,
+}
+
+impl Durability {
+    fn complete(&mut self, pos: WalPosition) {
+        self.completions.insert(pos);
+        while let Some(front) = self.pending.front() {
+            if !self.completions.remove(front) {
+                break;
+            }
+            self.contiguous = self.pending.pop_front();
+        }
+    }
+
+    fn is_durable(&self, pos: WalPosition) -> bool {
+        self.completions.contains(&pos) || self.contiguous.is_some_and(|c| c >= pos)
+    }
+}
+
+/// A message handed to the consumer. It must be acked once processed.
+struct Delivery {
+    position: WalPosition,
+    // The position after this message, which becomes the cursor when it is acked.
+    next: WalPosition,
+    data: Vec<u8>,
+}
+
+struct Consumer<'a> {
+    queue: &'a Queue,
+    next: WalPosition,
+}
+
+impl Queue {
+    fn open(url: url::Url, name: &str) -> std::io::Result<Self> {
+        let wal = Wal::open(url)?;
+        Ok(Queue {
+            wal: Mutex::new(wal),
+            space: Condvar::new(),
+            durability: Mutex::new(Durability::default()),
+            completed: Condvar::new(),
+            name: name.to_string(),
+        })
+    }
+
+    // Append a message and wait until it is durable.
+    fn push(&self, data: &[u8]) -> std::io::Result<WalPosition> {
+        let mut wal = self.wal.lock().unwrap();
+        while wal.utilization() > HIGH_WATER {
+            wal = self.space.wait(wal).unwrap();
+        }
+        let pos = wal.append(data)?;
+        // Register the message while holding the wal so pending stays in append order.
+        self.durability.lock().unwrap().pending.push_back(pos);
+        drop(wal);
+
+        let mut durability = self.durability.lock().unwrap();
+        while !durability.is_durable(pos) {
+            durability = self.completed.wait(durability).unwrap();
+        }
+        Ok(pos)
+    }
+
+    // Collect completions so producers and the consumer see what is durable. In a real application
+    // this runs on its own thread.
+    fn process_completions(&self) {
+        let completions: Vec<WalPosition> =
+            self.wal.lock().unwrap().process_completions().collect();
+        if completions.is_empty() {
+            return;
+        }
+        let mut durability = self.durability.lock().unwrap();
+        for pos in completions {
+            durability.complete(pos);
+        }
+        drop(durability);
+        self.completed.notify_all();
+    }
+
+    // Create a consumer that starts from the persisted cursor, or the tail if nothing was acked.
+    fn consumer(&self) -> Consumer<'_> {
+        let wal = self.wal.lock().unwrap();
+        let next = wal.cursor(&self.name).unwrap_or(wal.tail());
+        Consumer { queue: self, next }
+    }
+}
+
+impl Consumer<'_> {
+    // Return the next durable message, if any.
+    fn poll(&mut self) -> std::io::Result<Option<Delivery>> {
+        let durable = match self.queue.durability.lock().unwrap().contiguous {
+            Some(durable) => durable,
+            None => return Ok(None),
+        };
+        let wal = self.queue.wal.lock().unwrap();
+        let mut iter = wal.iterate_from(self.next);
+        match iter.next() {
+            Some(Ok((position, data))) if position <= durable => {
+                let next = iter.position();
+                self.next = next;
+                Ok(Some(Delivery {
+                    position,
+                    next,
+                    data,
+                }))
+            }
+            Some(Err(e)) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    // Mark the message, and every message delivered before it, as processed.
+    fn ack(&mut self, delivery: &Delivery) -> std::io::Result<()> {
+        let mut wal = self.queue.wal.lock().unwrap();
+        wal.ack(&self.queue.name, delivery.next)?;
+        wal.truncate(delivery.next)?;
+        drop(wal);
+        self.queue.space.notify_all();
+        Ok(())
+    }
+}
+
+fn encode(producer: u32, seq: u32) -> Vec<u8> {
+    let mut data = producer.to_le_bytes().to_vec();
+    data.extend_from_slice(&seq.to_le_bytes());
+    data
+}
+
+fn decode(data: &[u8]) -> (u32, u32) {
+    (
+        u32::from_le_bytes(data[..4].try_into().unwrap()),
+        u32::from_le_bytes(data[4..8].try_into().unwrap()),
+    )
+}
+
+// Runs several producers against a single consumer. Partway through, the consumer is dropped without
+// acking what it received and a new one picks up from the cursor, so some messages are delivered
+// twice. Every message must be received at least once.
+fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let args: Vec<String> = env::args().collect();
+    let url = match args.get(1) {
+        Some(url) => url.parse().unwrap(),
+        None => "mem:256".parse().unwrap(),
+    };
+    let queue = Queue::open(url, "consumer")?;
+    let done = AtomicBool::new(false);
+    let total = (NUM_PRODUCERS * NUM_PER_PRODUCER) as usize;
+
+    let (received, deliveries) = thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                queue.process_completions();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        for producer in 0..NUM_PRODUCERS {
+            let queue = &queue;
+            s.spawn(move || {
+                for seq in 0..NUM_PER_PRODUCER {
+                    queue.push(&encode(producer, seq)).unwrap();
+                }
+            });
+        }
+
+        let consumer = s.spawn(|| -> std::io::Result<(HashSet<(u32, u32)>, usize)> {
+            let mut received = HashSet::new();
+            let mut deliveries = 0;
+            let mut consumer = queue.consumer();
+            let mut crashed = false;
+            while received.len() < total {
+                let delivery = match consumer.poll()? {
+                    Some(delivery) => delivery,
+                    None => {
+                        thread::sleep(Duration::from_millis(1));
+                        continue;
+                    }
+                };
+                deliveries += 1;
+                received.insert(decode(&delivery.data));
+
+                if !crashed && received.len() == total / 2 {
+                    // Lose the consumer without acking. The next one starts over from the cursor.
+                    info!("Restarting consumer after {:?}", delivery.position);
+                    crashed = true;
+                    consumer = queue.consumer();
+                    continue;
+                }
+                if deliveries % 10 == 0 || received.len() == total {
+                    consumer.ack(&delivery)?;
+                }
+            }
+            Ok((received, deliveries))
+        });
+
+        let result = consumer.join().unwrap();
+        done.store(true, Ordering::Relaxed);
+        result
+    })?;
+
+    assert_eq!(received.len(), total);
+    assert!(deliveries > total, "unacked messages were not redelivered");
+    println!(
+        "Received {} messages in {} deliveries",
+        received.len(),
+        deliveries
+    );
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::path::Path;
+use std::process::exit;
+
+use wal::rollover::{restore, restore_into, RestorePoint};
+use wal::wal::Wal;
+
+// Restore the history of a wal from the passes of the ring archived by set_rollover_archive and
+// the live wal, if there is one, up to a point in time.
+//
+//   restore <archive dir> <live wal url | -> [lsn=N | time=MICROS] [<target wal url>]
+//
+// Without a target the restored entries are printed rather than written to a fresh wal.
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!(
+            "usage: {} <archive dir> <live wal url | -> [lsn=N | time=MICROS] [<target wal url>]",
+            args[0]
+        );
+        exit(2);
+    }
+
+    let dir = Path::new(&args[1]);
+    let live = match args[2].as_str() {
+        "-" => None,
+        uri => Some(Wal::open(uri.parse().unwrap()).unwrap()),
+    };
+    let mut rest = &args[3..];
+    let until = match rest.first().and_then(|arg| arg.split_once('=')) {
+        Some((kind, value)) => {
+            rest = &rest[1..];
+            let value = value.parse().unwrap();
+            match kind {
+                "lsn" => RestorePoint::Lsn(value),
+                "time" => RestorePoint::Timestamp(value),
+                _ => panic!("unknown restore point {kind}"),
+            }
+        }
+        None => RestorePoint::End,
+    };
+
+    let count = match rest.first() {
+        Some(uri) => {
+            let mut target = Wal::open(uri.parse().unwrap()).unwrap();
+            restore_into(dir, live.as_ref(), until, &mut target).unwrap()
+        }
+        None => restore(dir, live.as_ref(), until, &mut |entry| {
+            println!(
+                "{:?} lsn {} stream {} appended at {}us: {} bytes",
+                entry.position,
+                entry.lsn,
+                entry.stream.0,
+                entry.timestamp,
+                entry.payload.len()
+            );
+            Ok(())
+        })
+        .unwrap(),
+    };
+    println!("Restored {count} entries");
+}
@@ -0,0 +1,77 @@
+#![no_main]
+
+// Interprets the fuzzer input as a script of appends, truncates, completions, crashes and reopens
+// against a simulated device and checks the wal against a model after every step. Run with
+// `cargo +nightly fuzz run lifecycle` from the repository root.
+
+#[path = "../../tests/common/mod.rs"]
+mod common;
+
+use common::{recover, Model, CAPACITY};
+use libfuzzer_sys::fuzz_target;
+use wal::common::*;
+use wal::sim::SimDevice;
+
+#[derive(Debug)]
+enum Op {
+    Append(usize),
+    /// Truncate to the nth live entry, or to the head.
+    Truncate(usize),
+    Complete,
+    /// Lose power with writes still in flight. The device persists writes in the order they were
+    /// submitted, so a crash loses a suffix of them.
+    Crash,
+    /// Sync everything, drop the wal and open it again.
+    Reopen,
+}
+
+// Each op is an opcode byte, appends take two more bytes for the size of the entry.
+fn parse(data: &[u8]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    let mut bytes = data.iter().copied();
+    while let Some(op) = bytes.next() {
+        ops.push(match op % 8 {
+            0..=2 => {
+                let size =
+                    u16::from_le_bytes([bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)]);
+                Op::Append(size as usize % (3 * BLOCK_SIZE as usize))
+            }
+            3 | 4 => Op::Truncate(bytes.next().unwrap_or(0) as usize),
+            5 => Op::Complete,
+            6 => Op::Crash,
+            _ => Op::Reopen,
+        });
+    }
+    ops
+}
+
+fn run(ops: Vec<Op>) -> std::io::Result<()> {
+    let mut sim = SimDevice::new(0, CAPACITY);
+    let mut wal = common::open(&sim)?;
+    let mut model = Model::default();
+
+    for (seq, op) in ops.into_iter().enumerate() {
+        match op {
+            Op::Append(len) => model.append(&mut wal, seq as u32, len)?,
+            Op::Truncate(n) => model.truncate(&mut wal, n)?,
+            Op::Complete => model.complete(wal.process_completions()),
+            Op::Crash => {
+                sim = sim.crash();
+                drop(wal);
+                wal = recover(&sim, &mut model)?;
+            }
+            Op::Reopen => {
+                model.complete(wal.process_completions());
+                assert_eq!(sim.pending(), 0);
+                assert!(model.incomplete.is_empty());
+                sim = sim.crash();
+                drop(wal);
+                wal = recover(&sim, &mut model)?;
+            }
+        }
+        model.check(&mut wal)?;
+    }
+    Ok(())
+}
+
+fuzz_target!(|data: &[u8]| run(parse(data)).unwrap());
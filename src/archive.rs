@@ -0,0 +1,326 @@
+use crate::common::WalPosition;
+use crate::wal::{StreamId, Wal};
+use crc32fast::Hasher;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "MANIFEST";
+
+// first lsn (8) + last lsn (8)
+const MANIFEST_RECORD_SIZE: usize = 16;
+
+// crc (4) + lsn (8) + timestamp (8) + stream (4) + len (8)
+const RECORD_HEADER_SIZE: usize = 32;
+
+/// A segment file of the archive, holding the entries from first_lsn to last_lsn. There can be gaps
+/// between segments if entries were overwritten in the ring before they were archived.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub first_lsn: u64,
+    pub last_lsn: u64,
+}
+
+impl Segment {
+    fn file_name(&self) -> String {
+        format!("{:020}.seg", self.first_lsn)
+    }
+}
+
+/// An entry read back from the archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedEntry {
+    pub lsn: u64,
+    /// When the entry was appended, in microseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub stream: StreamId,
+    pub payload: Vec<u8>,
+}
+
+/// Archiver copies durable entries out of the ring into segment files in a directory, so history
+/// is kept after the ring wraps over it. Each call to archive writes the entries that became
+/// durable since the last call to a new segment and then lists it in the manifest, so a segment is
+/// only ever read once it is complete. The archiver keeps a named cursor in the wal at the first
+/// entry it hasn't archived, see Wal::ack, so archive must be called well before the ring fills.
+pub struct Archiver {
+    dir: PathBuf,
+    name: String,
+    manifest: File,
+    segments: Vec<Segment>,
+}
+
+impl Archiver {
+    /// Open or create the archive in dir. The name is used for the archiver's cursor in the wal. A
+    /// torn record at the end of the manifest is ignored.
+    pub fn open(dir: &Path, name: &str) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut manifest = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(MANIFEST))?;
+
+        let mut buffer = Vec::new();
+        manifest.read_to_end(&mut buffer)?;
+        let segments: Vec<Segment> = buffer
+            .chunks_exact(MANIFEST_RECORD_SIZE)
+            .map(|record| Segment {
+                first_lsn: u64::from_le_bytes(record[..8].try_into().unwrap()),
+                last_lsn: u64::from_le_bytes(record[8..].try_into().unwrap()),
+            })
+            .collect();
+        let valid = (segments.len() * MANIFEST_RECORD_SIZE) as u64;
+        if valid != buffer.len() as u64 {
+            manifest.set_len(valid)?;
+        }
+        manifest.seek(std::io::SeekFrom::Start(valid))?;
+        Ok(Archiver {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            manifest,
+            segments,
+        })
+    }
+
+    /// The segments in the archive, oldest first.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Write the entries that are durable but not yet archived to a new segment and return how
+    /// many there were.
+    pub fn archive(&mut self, wal: &mut Wal) -> std::io::Result<usize> {
+        let last_lsn = self.segments.last().map(|s| s.last_lsn);
+        let pending = collect_durable(wal, &self.name, last_lsn)?;
+        let count = match pending.segment {
+            Some(segment) => {
+                self.write_segment(segment, &pending.records)?;
+                (segment.last_lsn - segment.first_lsn + 1) as usize
+            }
+            None => 0,
+        };
+        pending.commit(wal, &self.name)?;
+        Ok(count)
+    }
+
+    /// Read back the entries of a segment.
+    pub fn read_segment(&self, segment: &Segment) -> std::io::Result<Vec<ArchivedEntry>> {
+        let buffer = std::fs::read(self.dir.join(segment.file_name()))?;
+        decode_records(&buffer, &segment.file_name())
+    }
+
+    // The segment is synced and in place before it is added to the manifest, so the manifest
+    // never lists a segment that is missing or incomplete.
+    fn write_segment(&mut self, segment: Segment, buffer: &[u8]) -> std::io::Result<()> {
+        let mut temp = tempfile::Builder::new()
+            .prefix(".seg")
+            .tempfile_in(&self.dir)?;
+        temp.write_all(buffer)?;
+        temp.as_file().sync_all()?;
+        temp.persist(self.dir.join(segment.file_name()))
+            .map_err(|e| e.error)?;
+        File::open(&self.dir)?.sync_all()?;
+
+        let mut record = [0; MANIFEST_RECORD_SIZE];
+        record[..8].copy_from_slice(&segment.first_lsn.to_le_bytes());
+        record[8..].copy_from_slice(&segment.last_lsn.to_le_bytes());
+        self.manifest.write_all(&record)?;
+        self.manifest.sync_data()?;
+        self.segments.push(segment);
+        Ok(())
+    }
+}
+
+// The durable entries a consumer with a named cursor hasn't copied out of the wal yet.
+pub(crate) struct Pending {
+    // The entries encoded as records.
+    pub records: Vec<u8>,
+    // The LSNs of the entries, or None if there are none.
+    pub segment: Option<Segment>,
+    // The last timestamp of the entries.
+    pub timestamp: u64,
+    start: WalPosition,
+    next: WalPosition,
+}
+
+impl Pending {
+    // Moves the cursor past the entries once they are safely stored.
+    pub fn commit(&self, wal: &mut Wal, name: &str) -> std::io::Result<()> {
+        if self.next > self.start {
+            wal.ack(name, self.next)?;
+        }
+        Ok(())
+    }
+}
+
+// Collects the durable entries from the cursor called name on. Entries up to last_lsn were already
+// stored before a crash left the cursor behind, and are skipped.
+pub(crate) fn collect_durable(
+    wal: &mut Wal,
+    name: &str,
+    last_lsn: Option<u64>,
+) -> std::io::Result<Pending> {
+    let mut start = wal.cursor(name).unwrap_or(wal.tail());
+    if start < wal.tail() {
+        warn!(
+            "Entries from {:?} to {:?} were overwritten before {name} copied them",
+            start,
+            wal.tail()
+        );
+        start = wal.tail();
+    }
+    let mut iter = wal.iterate_durable(start);
+    let mut pending = Pending {
+        records: Vec::new(),
+        segment: None,
+        timestamp: 0,
+        start,
+        next: start,
+    };
+    while let Some(entry) = iter.next() {
+        let entry = entry?;
+        if last_lsn.is_some_and(|last| entry.lsn <= last) {
+            pending.next = iter.position();
+            continue;
+        }
+        let lsn = entry.lsn;
+        let archived = ArchivedEntry {
+            lsn,
+            timestamp: entry.timestamp,
+            stream: entry.stream,
+            payload: entry.payload,
+        };
+        encode_record(&archived, &mut pending.records);
+
+        let segment = pending.segment.get_or_insert(Segment {
+            first_lsn: lsn,
+            last_lsn: lsn,
+        });
+        segment.last_lsn = lsn;
+        pending.timestamp = archived.timestamp;
+        pending.next = iter.position();
+    }
+    Ok(pending)
+}
+
+// Appends the record of entry to buffer. Segments and replication shipments are both made of
+// these records.
+pub(crate) fn encode_record(entry: &ArchivedEntry, buffer: &mut Vec<u8>) {
+    let start = buffer.len();
+    buffer.resize(start + RECORD_HEADER_SIZE, 0);
+    let record = &mut buffer[start..];
+    record[4..12].copy_from_slice(&entry.lsn.to_le_bytes());
+    record[12..20].copy_from_slice(&entry.timestamp.to_le_bytes());
+    record[20..24].copy_from_slice(&entry.stream.0.to_le_bytes());
+    record[24..32].copy_from_slice(&(entry.payload.len() as u64).to_le_bytes());
+    buffer.extend_from_slice(&entry.payload);
+    let mut hasher = Hasher::new();
+    hasher.update(&buffer[start + 4..]);
+    buffer[start..start + 4].copy_from_slice(&hasher.finalize().to_le_bytes());
+}
+
+// Decodes the records in buffer, which was read from source.
+pub(crate) fn decode_records(buffer: &[u8], source: &str) -> std::io::Result<Vec<ArchivedEntry>> {
+    let truncated = || {
+        std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("{source} ends inside a record"),
+        )
+    };
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < buffer.len() {
+        let header = buffer
+            .get(offset..offset + RECORD_HEADER_SIZE)
+            .ok_or_else(truncated)?;
+        let len = u64::from_le_bytes(header[24..32].try_into().unwrap()) as usize;
+        let end = (offset + RECORD_HEADER_SIZE)
+            .checked_add(len)
+            .ok_or_else(truncated)?;
+        let record = buffer.get(offset..end).ok_or_else(truncated)?;
+        let mut hasher = Hasher::new();
+        hasher.update(&record[4..]);
+        if hasher.finalize() != u32::from_le_bytes(record[..4].try_into().unwrap()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("damaged record in {source}"),
+            ));
+        }
+        entries.push(ArchivedEntry {
+            lsn: u64::from_le_bytes(record[4..12].try_into().unwrap()),
+            timestamp: u64::from_le_bytes(record[12..20].try_into().unwrap()),
+            stream: StreamId(u32::from_le_bytes(record[20..24].try_into().unwrap())),
+            payload: record[RECORD_HEADER_SIZE..].to_vec(),
+        });
+        offset = end;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimDevice;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_archive_past_wrap() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sim = SimDevice::eager(0, 16);
+        let mut wal = Wal::open_device(Box::new(sim), 16)?;
+        let mut archiver = Archiver::open(dir.path(), "archiver")?;
+
+        // The ring holds far fewer than 100 entries, but archiving as it goes keeps them all.
+        for i in 0..100u32 {
+            wal.append(&i.to_le_bytes())?;
+            wal.process_completions().for_each(drop);
+            assert_eq!(archiver.archive(&mut wal)?, 1);
+        }
+        assert_eq!(archiver.archive(&mut wal)?, 0);
+        assert_eq!(wal.cursor("archiver"), Some(wal.head()));
+
+        // The manifest is read back when the archive is reopened.
+        let archiver = Archiver::open(dir.path(), "archiver")?;
+        assert_eq!(archiver.segments().len(), 100);
+        let mut lsn = 0;
+        for segment in archiver.segments() {
+            for entry in archiver.read_segment(segment)? {
+                assert_eq!(entry.lsn, lsn);
+                assert_eq!(entry.payload, (lsn as u32).to_le_bytes());
+                lsn += 1;
+            }
+        }
+        assert_eq!(lsn, 100);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_only_durable_entries() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sim = SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let mut archiver = Archiver::open(dir.path(), "archiver")?;
+
+        wal.append(b"one")?;
+        wal.process_completions().for_each(drop);
+        sim.set_complete_probability(0.0);
+        wal.append(b"two")?;
+        wal.process_completions().for_each(drop);
+        assert_eq!(archiver.archive(&mut wal)?, 1);
+
+        sim.set_complete_probability(1.0);
+        wal.process_completions().for_each(drop);
+        assert_eq!(archiver.archive(&mut wal)?, 1);
+        let payloads: Vec<Vec<u8>> = archiver
+            .segments()
+            .iter()
+            .flat_map(|s| archiver.read_segment(s).unwrap())
+            .map(|e| e.payload)
+            .collect();
+        assert_eq!(payloads, vec![b"one".to_vec(), b"two".to_vec()]);
+        Ok(())
+    }
+}
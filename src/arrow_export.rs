@@ -0,0 +1,170 @@
+use crate::wal::{Entry, StreamId, Wal};
+
+use arrow::array::{
+    ArrayRef, BinaryBuilder, StringBuilder, TimestampMicrosecondBuilder, UInt32Builder,
+    UInt64Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Default number of entries in each RecordBatch produced by the exporter.
+pub const DEFAULT_BATCH_SIZE: usize = 8192;
+
+/// The schema of the exported entries. There is one row per entry in the live region of the wal.
+/// The tag is the name of the stream the entry was appended to, and is null for the default
+/// stream. The timestamp is the time the entry was appended.
+pub fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("offset", DataType::UInt64, false),
+        Field::new("rollover", DataType::UInt32, false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Microsecond, None),
+            false,
+        ),
+        Field::new("tag", DataType::Utf8, true),
+        Field::new("payload", DataType::Binary, false),
+    ]))
+}
+
+// Accumulates the columns for a single batch. The arrow builders reset themselves on finish so
+// this can be reused for every batch.
+struct BatchBuilder {
+    schema: SchemaRef,
+    offsets: UInt64Builder,
+    rollovers: UInt32Builder,
+    timestamps: TimestampMicrosecondBuilder,
+    tags: StringBuilder,
+    payloads: BinaryBuilder,
+    rows: usize,
+}
+
+impl BatchBuilder {
+    fn new(schema: SchemaRef) -> Self {
+        BatchBuilder {
+            schema,
+            offsets: UInt64Builder::new(),
+            rollovers: UInt32Builder::new(),
+            timestamps: TimestampMicrosecondBuilder::new(),
+            tags: StringBuilder::new(),
+            payloads: BinaryBuilder::new(),
+            rows: 0,
+        }
+    }
+
+    fn push(&mut self, entry: &Entry, tag: Option<&str>) {
+        self.offsets.append_value(entry.position.offset);
+        self.rollovers.append_value(entry.position.rollover);
+        self.timestamps.append_value(entry.timestamp as i64);
+        self.tags.append_option(tag);
+        self.payloads.append_value(&entry.payload);
+        self.rows += 1;
+    }
+
+    fn finish(&mut self) -> std::io::Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.offsets.finish()),
+            Arc::new(self.rollovers.finish()),
+            Arc::new(self.timestamps.finish()),
+            Arc::new(self.tags.finish()),
+            Arc::new(self.payloads.finish()),
+        ];
+        self.rows = 0;
+        RecordBatch::try_new(self.schema.clone(), columns).map_err(std::io::Error::other)
+    }
+}
+
+/// Walk the live region of the wal from tail to head and call the given function with each batch
+/// of at most batch_size entries. Entries are never all held in memory at once.
+pub fn for_each_batch<F>(wal: &mut Wal, batch_size: usize, mut f: F) -> std::io::Result<()>
+where
+    F: FnMut(RecordBatch) -> std::io::Result<()>,
+{
+    assert!(batch_size > 0, "batch_size must be positive");
+    let tags: HashMap<StreamId, String> = wal
+        .streams()
+        .map(|(name, id)| (id, name.to_string()))
+        .collect();
+    let mut builder = BatchBuilder::new(schema());
+    for entry in wal.iterate().filter_meta(|_| true) {
+        let entry = entry?;
+        builder.push(&entry, tags.get(&entry.stream).map(String::as_str));
+        if builder.rows == batch_size {
+            f(builder.finish()?)?;
+        }
+    }
+    if builder.rows > 0 {
+        f(builder.finish()?)?;
+    }
+    Ok(())
+}
+
+/// Materialize the live region of the wal as a list of RecordBatches.
+pub fn to_record_batches(wal: &mut Wal, batch_size: usize) -> std::io::Result<Vec<RecordBatch>> {
+    let mut batches = Vec::new();
+    for_each_batch(wal, batch_size, |batch| {
+        batches.push(batch);
+        Ok(())
+    })?;
+    Ok(batches)
+}
+
+/// Write the live region of the wal to a new parquet file at the given path.
+pub fn write_parquet(wal: &mut Wal, path: &Path) -> std::io::Result<()> {
+    let file = File::create(path)?;
+    let mut writer = ArrowWriter::try_new(file, schema(), None).map_err(std::io::Error::other)?;
+    for_each_batch(wal, DEFAULT_BATCH_SIZE, |batch| {
+        writer.write(&batch).map_err(std::io::Error::other)
+    })?;
+    writer.close().map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Array, BinaryArray, StringArray, TimestampMicrosecondArray};
+
+    #[test]
+    fn test_record_batches() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let audit = wal.stream("audit")?;
+        wal.append(b"one")?;
+        wal.append_to(audit, b"two")?;
+        wal.append(b"three")?;
+
+        let batches = to_record_batches(&mut wal, 2)?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].num_rows(), 2);
+        assert_eq!(batches[1].num_rows(), 1);
+
+        let first = &batches[0];
+        let timestamps = first
+            .column(2)
+            .as_any()
+            .downcast_ref::<TimestampMicrosecondArray>()
+            .unwrap();
+        assert_eq!(timestamps.null_count(), 0);
+        assert!(timestamps.value(0) > 0);
+        let tags = first
+            .column(3)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert!(tags.is_null(0));
+        assert_eq!(tags.value(1), "audit");
+        let payloads = first
+            .column(4)
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .unwrap();
+        assert_eq!(payloads.value(0), b"one");
+        assert_eq!(payloads.value(1), b"two");
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use crate::common::WalPosition;
+use crate::wal::{Entry, Wal};
+use log::warn;
+use std::io::Write;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Sink receives the entries a Capture reads from the wal, in LSN order. Delivery is at least
+/// once: a batch whose write failed, or that was written just before a crash, is written again, so
+/// a sink that can't tolerate duplicates should skip entries with an LSN it has already seen.
+pub trait Sink {
+    fn write(&mut self, entries: &[Entry]) -> std::io::Result<()>;
+}
+
+/// JsonSink writes each entry as a line of JSON with its LSN, timestamp, stream id and payload.
+/// Payloads that are UTF-8 are written as a string in payload, and any others as hex in
+/// payload_hex.
+pub struct JsonSink<W: Write> {
+    out: W,
+}
+
+impl JsonSink<std::io::Stdout> {
+    pub fn stdout() -> Self {
+        JsonSink {
+            out: std::io::stdout(),
+        }
+    }
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(out: W) -> Self {
+        JsonSink { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> Sink for JsonSink<W> {
+    fn write(&mut self, entries: &[Entry]) -> std::io::Result<()> {
+        let mut line = String::new();
+        for entry in entries {
+            line.clear();
+            line.push_str(&format!(
+                "{{\"lsn\":{},\"timestamp\":{},\"stream\":{},",
+                entry.lsn, entry.timestamp, entry.stream.0
+            ));
+            match std::str::from_utf8(&entry.payload) {
+                Ok(text) => {
+                    line.push_str("\"payload\":");
+                    push_json_string(&mut line, text);
+                }
+                Err(_) => {
+                    line.push_str("\"payload_hex\":\"");
+                    for byte in &entry.payload {
+                        line.push_str(&format!("{byte:02x}"));
+                    }
+                    line.push('"');
+                }
+            }
+            line.push_str("}\n");
+            self.out.write_all(line.as_bytes())?;
+        }
+        self.out.flush()
+    }
+}
+
+fn push_json_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Capture tails a wal and hands its durable entries to a Sink in batches. Progress is kept in a
+/// named cursor in the wal at the first entry the sink hasn't taken, which is moved after every
+/// batch, so capture picks up where it left off after a restart. If the ring overwrites entries the
+/// cursor is still waiting for, see Wal::ack, capture skips ahead to the tail with a warning.
+///
+/// A batch the sink fails to write is retried after a backoff that doubles each time. Once the
+/// retries run out the error is returned and the batch is tried again by the next call to poll.
+pub struct Capture<S: Sink> {
+    sink: S,
+    name: String,
+    batch_size: usize,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl<S: Sink> Capture<S> {
+    /// Create a capture delivering to sink, keeping its progress in the cursor called name. By
+    /// default batches hold up to 100 entries and a failed batch is retried 3 times, starting
+    /// after 10ms.
+    pub fn new(sink: S, name: &str) -> Self {
+        Capture {
+            sink,
+            name: name.to_string(),
+            batch_size: 100,
+            retries: 3,
+            backoff: Duration::from_millis(10),
+        }
+    }
+
+    /// Set the most entries handed to the sink at once.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    /// Set how many times a failed batch is retried, and the wait before the first retry.
+    pub fn set_retries(&mut self, retries: u32, backoff: Duration) {
+        self.retries = retries;
+        self.backoff = backoff;
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+
+    /// Deliver every durable entry the sink hasn't taken yet and return how many there were.
+    pub fn poll(&mut self, wal: &mut Wal) -> std::io::Result<usize> {
+        let mut next = wal.cursor(&self.name).unwrap_or(wal.tail());
+        if next < wal.tail() {
+            warn!(
+                "Entries from {:?} to {:?} were overwritten before {} captured them",
+                next,
+                wal.tail(),
+                self.name
+            );
+            next = wal.tail();
+        }
+        let mut count = 0;
+        loop {
+            let (batch, after) = self.read_batch(wal, next)?;
+            if batch.is_empty() {
+                return Ok(count);
+            }
+            self.deliver(&batch)?;
+            wal.ack(&self.name, after)?;
+            count += batch.len();
+            next = after;
+        }
+    }
+
+    // Reads the next batch of durable entries from start, and the position after them.
+    fn read_batch(
+        &self,
+        wal: &Wal,
+        start: WalPosition,
+    ) -> std::io::Result<(Vec<Entry>, WalPosition)> {
+        let mut iter = wal.iterate_durable(start);
+        let batch = iter
+            .by_ref()
+            .take(self.batch_size)
+            .collect::<std::io::Result<_>>()?;
+        Ok((batch, iter.position()))
+    }
+
+    fn deliver(&mut self, batch: &[Entry]) -> std::io::Result<()> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.sink.write(batch) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    warn!("{} failed to write a batch, retrying: {e}", self.name);
+                    sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails the next `failures` writes, and records the LSNs of the batches it takes.
+    #[derive(Default)]
+    struct FlakySink {
+        failures: u32,
+        batches: Vec<Vec<u64>>,
+    }
+
+    impl Sink for FlakySink {
+        fn write(&mut self, entries: &[Entry]) -> std::io::Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(std::io::Error::other("unavailable"));
+            }
+            self.batches.push(entries.iter().map(|e| e.lsn).collect());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_batches_and_retries() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        for i in 0..5u32 {
+            wal.append(&i.to_le_bytes())?;
+        }
+        wal.process_completions().for_each(drop);
+        let mut capture = Capture::new(FlakySink::default(), "cdc");
+        capture.set_batch_size(2);
+        capture.set_retries(1, Duration::ZERO);
+
+        capture.sink.failures = 1;
+        assert_eq!(capture.poll(&mut wal)?, 5);
+        assert_eq!(
+            capture.sink().batches,
+            vec![vec![0, 1], vec![2, 3], vec![4]]
+        );
+        assert_eq!(wal.cursor("cdc"), Some(wal.head()));
+
+        // Once the retries run out the batch is left for the next poll.
+        wal.append(b"five")?;
+        wal.process_completions().for_each(drop);
+        capture.sink.failures = 2;
+        assert!(capture.poll(&mut wal).is_err());
+        assert_eq!(capture.poll(&mut wal)?, 1);
+        assert_eq!(capture.sink().batches.last(), Some(&vec![5]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_sink() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        wal.append(b"say \"hi\"\n")?;
+        wal.append(&[0xff, 0x00])?;
+        wal.process_completions().for_each(drop);
+        let mut capture = Capture::new(JsonSink::new(Vec::new()), "cdc");
+        assert_eq!(capture.poll(&mut wal)?, 2);
+        let out = String::from_utf8(capture.into_inner().into_inner()).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("{\"lsn\":0,\"timestamp\":"));
+        assert!(lines[0].ends_with(",\"stream\":0,\"payload\":\"say \\\"hi\\\"\\n\"}"));
+        assert!(lines[1].ends_with(",\"stream\":0,\"payload_hex\":\"ff00\"}"));
+        Ok(())
+    }
+}
@@ -1,9 +1,40 @@
-use std::alloc::{alloc_zeroed, dealloc, Layout};
-use std::cmp::Ordering::{Equal, Greater, Less};
+use crate::wal::RING_START;
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::cmp::Ordering;
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex};
 
 /// Use a 4K block size to align to the underlying hardware requirements.
 pub const BLOCK_SIZE: u32 = 4096;
 
+/// What a device supports, so the wal can adapt how it uses it. The default describes a device
+/// with none of the optional features.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Reads are submitted without blocking the caller.
+    pub async_reads: bool,
+    /// Writes are durable when they complete, so sync doesn't need to flush the drive's cache.
+    pub dsync_writes: bool,
+    /// Blocks that are no longer needed can be discarded.
+    pub trim: bool,
+    /// The device can order a write after the writes before it without waiting for them.
+    pub barriers: bool,
+    /// The most writes that can be outstanding before write fails with WouldBlock, or None if
+    /// there is no limit.
+    pub max_queue_depth: Option<usize>,
+    /// Writes of up to this many bytes, aligned to their size, are never torn by a crash. None if
+    /// any write can be torn.
+    pub atomic_write_unit: Option<u32>,
+}
+
+impl Capabilities {
+    /// Whether a write of a single block is never torn.
+    pub fn atomic_blocks(&self) -> bool {
+        self.atomic_write_unit
+            .is_some_and(|unit| unit >= BLOCK_SIZE)
+    }
+}
+
 /// A PersistentDevice allows writing aligned slices to it and should return immediately.
 pub trait PersistentDevice: Send {
     /// Write this aligned slice at the given position and return immediately. An error is returned
@@ -14,78 +45,408 @@ pub trait PersistentDevice: Send {
     /// written data has been synced to disk. It will write any completed data to the given channel.
     fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition>;
 
+    /// Like process_completions, but coalesce the completions into a Watermark rather than
+    /// returning each of them. Devices that can count their completions without collecting them
+    /// override this.
+    fn process_watermark(&mut self) -> Option<Watermark> {
+        Watermark::of(self.process_completions())
+    }
+
+    /// Block until every write submitted so far is durable. The completions of those writes are
+    /// still returned from process_completions.
+    fn sync(&mut self) -> std::io::Result<()>;
+
+    /// Return the hard error, such as EIO, that a write the device accepted failed with, so its
+    /// completion will never be returned. Each error is returned once. Devices whose writes can't
+    /// fail after they are accepted don't need to override this.
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        None
+    }
+
     /// Read data from the device at the given position and length
     fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>>;
+
+    /// Return a reader for the device. It reads what has been written so far through a shared
+    /// reference, so it can be used from other threads while the device keeps being written.
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>>;
+
+    /// What the device supports.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Write data at byte_offset of the sidecar file paired with the device. The write reaches the
+    /// sidecar once every write submitted before it has completed, so the sidecar never refers to
+    /// data that may not survive a crash. Devices without a sidecar fail with Unsupported.
+    fn write_sidecar(&mut self, _byte_offset: u64, _data: &[u8]) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "device has no sidecar",
+        ))
+    }
+
+    /// Read up to len bytes at byte_offset of the sidecar, less if the sidecar ends first.
+    fn read_sidecar(&mut self, _byte_offset: u64, _len: usize) -> std::io::Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "device has no sidecar",
+        ))
+    }
+}
+
+/// DeviceReader reads from a device independently of the handle that writes to it.
+pub trait DeviceReader: Send + Sync {
+    /// Read data from the device at the given position and length
+    fn read(&self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>>;
+
+    /// Make each read of len bytes at byte_offset in reads, calling done with the index of each
+    /// read and its result as it completes, which can be in any order. Devices that can have many
+    /// reads outstanding at once override this, by default they are made one at a time.
+    fn read_many(
+        &self,
+        reads: &[(u64, usize)],
+        done: &mut dyn FnMut(usize, std::io::Result<Vec<u8>>),
+    ) {
+        for (i, (byte_offset, len)) in reads.iter().enumerate() {
+            done(i, self.read(*byte_offset, *len));
+        }
+    }
+
+    /// Start loading len bytes at byte_offset into memory without waiting for them, because they
+    /// will be read soon. Devices that don't read through a cache don't need to do anything.
+    fn prefetch(&self, _byte_offset: u64, _len: usize) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// The whole device as a slice if it is memory mapped, so entries can be parsed in place
+    /// rather than read. Writes that land while the slice is held show up in it.
+    fn mapped(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// A position in the ring. Positions are ordered by rollover, the number of passes over the ring,
+/// and then by offset. The ring starts at RING_START, so advance, distance and is_overwritten treat
+/// a position with an offset before it, such as the default position, as the start of the ring on
+/// its pass. They take the capacity of a wal, which is always more than RING_START.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct WalPosition {
     // block offset into the file
-    pub offset: u32,
+    pub offset: u64,
     pub rollover: u32,
 }
 
 impl WalPosition {
     pub fn byte_offset(&self) -> u64 {
-        self.offset as u64 * BLOCK_SIZE as u64
+        self.offset * BLOCK_SIZE as u64
+    }
+
+    /// The start of the ring on the pass after this one.
+    pub fn next_pass(&self) -> WalPosition {
+        WalPosition {
+            offset: RING_START,
+            rollover: self.rollover + 1,
+        }
+    }
+
+    /// The position blocks after this one in a ring of capacity blocks, continuing at the start of
+    /// the ring on the next pass when it reaches the end.
+    pub fn advance(&self, blocks: u64, capacity: u64) -> WalPosition {
+        let ring = capacity - RING_START;
+        let index = self.index(capacity) + blocks;
+        WalPosition {
+            offset: RING_START + index % ring,
+            rollover: (index / ring) as u32,
+        }
+    }
+
+    /// The number of blocks from this position forward to other in a ring of capacity blocks, or 0
+    /// if other is not after this position.
+    pub fn distance(&self, other: WalPosition, capacity: u64) -> u64 {
+        other.index(capacity).saturating_sub(self.index(capacity))
+    }
+
+    /// Whether the block at this position has been overwritten once the ring of capacity blocks
+    /// has been written up to head.
+    pub fn is_overwritten(&self, head: WalPosition, capacity: u64) -> bool {
+        self.distance(head, capacity) > capacity - RING_START
+    }
+
+    // The number of ring blocks before this position, counting every earlier pass.
+    fn index(&self, capacity: u64) -> u64 {
+        self.rollover as u64 * (capacity - RING_START) + self.offset.saturating_sub(RING_START)
+    }
+}
+
+impl Ord for WalPosition {
+    fn cmp(&self, other: &WalPosition) -> Ordering {
+        self.rollover
+            .cmp(&other.rollover)
+            .then(self.offset.cmp(&other.offset))
     }
 }
 
 impl PartialOrd for WalPosition {
-    fn partial_cmp(&self, other: &WalPosition) -> Option<std::cmp::Ordering> {
-        if self.rollover > other.rollover {
-            Some(Greater)
-        } else if self.rollover < other.rollover {
-            Some(Less)
-        } else if self.offset > other.offset {
-            Some(Greater)
-        } else if self.offset < other.offset {
-            Some(Less)
-        } else {
-            Some(Equal)
-        }
+    fn partial_cmp(&self, other: &WalPosition) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The completions of a call to process_completions coalesced into the highest position that
+/// completed and the number of writes that did, for callers that only track how far the wal is
+/// durable. Unless completions are ordered, writes before the position can still be outstanding.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Watermark {
+    pub position: WalPosition,
+    pub count: usize,
+}
+
+impl Watermark {
+    /// Coalesce completions, or None if there are none.
+    pub fn of(completions: impl IntoIterator<Item = WalPosition>) -> Option<Watermark> {
+        completions.into_iter().fold(None, |watermark, pos| {
+            Some(Watermark {
+                position: watermark.map_or(pos, |w| w.position.max(pos)),
+                count: watermark.map_or(0, |w| w.count) + 1,
+            })
+        })
     }
 }
 
 /// AlignedSlice takes an unaligned size and creates an underlying buffer that is aligned to the
 /// BLOCK_SIZE of the underlying device. It will free the memory when the AlignedSlice is dropped.
-/// Alignment of the slice means that we always write at block boundaries.
+/// Alignment of the slice means that we always write at block boundaries. The unsafe code here and
+/// in the devices is checked with `cargo +nightly miri test --lib`, which skips the tests that
+/// open real files.
 pub struct AlignedSlice {
-    pub buffer_ptr: *mut u8,
-    pub blocks: u32,
+    ptr: NonNull<u8>,
+    blocks: u64,
+    // The pool the buffer goes back to when it is dropped, if it came from one.
+    pool: Option<Arc<PoolShared>>,
 }
 
+// The buffer is uniquely owned, so it can be moved to the thread or kernel doing the write.
 unsafe impl Send for AlignedSlice {}
 
 impl AlignedSlice {
     pub fn new(raw_size: usize) -> Self {
-        let blocks = (raw_size).div_ceil(BLOCK_SIZE as usize) as u32;
-        let layout = AlignedSlice::get_layout(blocks);
-        let buffer_ptr = unsafe { alloc_zeroed(layout) };
-        AlignedSlice { buffer_ptr, blocks }
+        let blocks = (raw_size).div_ceil(BLOCK_SIZE as usize) as u64;
+        let ptr = if blocks == 0 {
+            // Zero sized allocations are not allowed, use an aligned pointer that is never
+            // dereferenced or freed.
+            NonNull::new(std::ptr::without_provenance_mut(BLOCK_SIZE as usize)).unwrap()
+        } else {
+            let layout = AlignedSlice::get_layout(blocks);
+            let ptr = unsafe { alloc_zeroed(layout) };
+            NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout))
+        };
+        AlignedSlice {
+            ptr,
+            blocks,
+            pool: None,
+        }
     }
 
     pub fn as_slice(&mut self) -> &mut [u8] {
-        unsafe {
-            std::slice::from_raw_parts_mut(self.buffer_ptr, (self.blocks * BLOCK_SIZE) as usize)
-        }
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len()) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len()) }
     }
 
-    fn get_layout(blocks: u32) -> Layout {
-        Layout::from_size_align((blocks * BLOCK_SIZE) as usize, BLOCK_SIZE as usize)
+    /// A pointer to the start of the buffer, for handing it to the kernel. It is valid for
+    /// size() bytes until the AlignedSlice is dropped.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    fn get_layout(blocks: u64) -> Layout {
+        Layout::from_size_align(blocks as usize * BLOCK_SIZE as usize, BLOCK_SIZE as usize)
             .expect("invalid layout")
     }
 
-    pub fn size(&self) -> u32 {
-        self.blocks * BLOCK_SIZE
+    fn len(&self) -> usize {
+        self.blocks as usize * BLOCK_SIZE as usize
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn size(&self) -> u64 {
+        self.blocks * BLOCK_SIZE as u64
     }
 }
 
 impl Drop for AlignedSlice {
     fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.free.lock().unwrap().push(self.ptr);
+            return;
+        }
+        if self.blocks == 0 {
+            return;
+        }
         let layout = AlignedSlice::get_layout(self.blocks);
         unsafe {
-            dealloc(self.buffer_ptr, layout);
+            dealloc(self.ptr.as_ptr(), layout);
+        }
+    }
+}
+
+struct PoolShared {
+    // Has room for every buffer, so returning one never allocates.
+    free: Mutex<Vec<NonNull<u8>>>,
+    blocks: u64,
+    buffers: usize,
+}
+
+// The buffers are only handed out as uniquely owned AlignedSlices.
+unsafe impl Send for PoolShared {}
+unsafe impl Sync for PoolShared {}
+
+impl Drop for PoolShared {
+    fn drop(&mut self) {
+        // Every buffer taken holds a reference to the pool, so they are all back by now.
+        let layout = AlignedSlice::get_layout(self.blocks);
+        for ptr in self.free.get_mut().unwrap().drain(..) {
+            unsafe { dealloc(ptr.as_ptr(), layout) };
         }
     }
 }
+
+/// BufferPool holds a fixed number of buffers of up to a fixed number of blocks, all allocated
+/// when the pool is created. A buffer taken from the pool goes back to it when its AlignedSlice is
+/// dropped, so taking and returning buffers never allocates.
+#[derive(Clone)]
+pub struct BufferPool {
+    shared: Arc<PoolShared>,
+}
+
+impl BufferPool {
+    pub fn new(buffers: usize, blocks: u64) -> Self {
+        assert!(blocks > 0, "buffers must hold at least one block");
+        let layout = AlignedSlice::get_layout(blocks);
+        let free = (0..buffers)
+            .map(|_| {
+                let ptr = unsafe { alloc_zeroed(layout) };
+                NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout))
+            })
+            .collect();
+        BufferPool {
+            shared: Arc::new(PoolShared {
+                free: Mutex::new(free),
+                blocks,
+                buffers,
+            }),
+        }
+    }
+
+    /// Take a zeroed buffer for raw_size bytes, or None if raw_size needs more blocks than a buffer
+    /// holds or every buffer is taken.
+    pub fn take(&self, raw_size: usize) -> Option<AlignedSlice> {
+        let blocks = raw_size.div_ceil(BLOCK_SIZE as usize) as u64;
+        if blocks > self.shared.blocks {
+            return None;
+        }
+        let ptr = self.shared.free.lock().unwrap().pop()?;
+        // Buffers are returned holding whatever was written to them.
+        unsafe { std::ptr::write_bytes(ptr.as_ptr(), 0, blocks as usize * BLOCK_SIZE as usize) };
+        Some(AlignedSlice {
+            ptr,
+            blocks,
+            pool: Some(self.shared.clone()),
+        })
+    }
+
+    /// The number of buffers that aren't taken.
+    pub fn available(&self) -> usize {
+        self.shared.free.lock().unwrap().len()
+    }
+
+    /// The number of buffers in the pool.
+    pub fn buffers(&self) -> usize {
+        self.shared.buffers
+    }
+
+    /// The most blocks a buffer holds.
+    pub fn buffer_blocks(&self) -> u64 {
+        self.shared.blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_slice() {
+        let mut slice = AlignedSlice::new(BLOCK_SIZE as usize + 1);
+        assert_eq!(slice.blocks(), 2);
+        assert_eq!(slice.as_ptr() as usize % BLOCK_SIZE as usize, 0);
+        assert!(slice.as_bytes().iter().all(|b| *b == 0));
+        slice.as_slice()[BLOCK_SIZE as usize] = 7;
+        assert_eq!(slice.as_bytes()[BLOCK_SIZE as usize], 7);
+        assert_eq!(slice.as_bytes().len(), slice.size() as usize);
+    }
+
+    #[test]
+    fn test_position_arithmetic() {
+        let capacity = RING_START + 4;
+        let pos = |offset, rollover| WalPosition {
+            offset: RING_START + offset,
+            rollover,
+        };
+        assert!(pos(3, 0) < pos(0, 1));
+        assert_eq!(pos(1, 0).advance(2, capacity), pos(3, 0));
+        // Reaching the end of the ring starts the next pass.
+        assert_eq!(pos(1, 0).advance(3, capacity), pos(0, 1));
+        assert_eq!(pos(3, 0).advance(6, capacity), pos(1, 2));
+        assert_eq!(pos(3, 0).next_pass(), pos(0, 1));
+
+        assert_eq!(pos(3, 0).distance(pos(1, 1), capacity), 2);
+        assert_eq!(pos(1, 1).distance(pos(3, 0), capacity), 0);
+        assert!(!pos(2, 0).is_overwritten(pos(2, 1), capacity));
+        assert!(pos(2, 0).is_overwritten(pos(3, 1), capacity));
+
+        // Rings larger than 2^32 blocks.
+        let capacity = RING_START + (1 << 40);
+        assert_eq!(pos(0, 0).advance(1 << 36, capacity), pos(1 << 36, 0));
+        assert_eq!(pos(0, 0).advance(3 << 40, capacity), pos(0, 3));
+        assert_eq!(pos(1, 0).distance(pos(0, 1), capacity), (1 << 40) - 1);
+
+        // The default position is before the ring, and counts as its start.
+        let capacity = RING_START + 4;
+        assert_eq!(WalPosition::default().advance(1, capacity), pos(1, 0));
+        assert_eq!(WalPosition::default().distance(pos(2, 0), capacity), 2);
+        assert!(!WalPosition::default().is_overwritten(pos(2, 0), capacity));
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(2, 2);
+        assert!(pool.take(3 * BLOCK_SIZE as usize).is_none());
+        let mut first = pool.take(BLOCK_SIZE as usize).unwrap();
+        assert_eq!(first.blocks(), 1);
+        first.as_slice()[0] = 7;
+        let second = pool.take(2 * BLOCK_SIZE as usize).unwrap();
+        assert!(pool.take(1).is_none());
+
+        // A returned buffer is zeroed again when it is taken.
+        drop(first);
+        assert_eq!(pool.available(), 1);
+        let again = pool.take(1).unwrap();
+        assert!(again.as_bytes().iter().all(|b| *b == 0));
+        drop((again, second));
+        assert_eq!(pool.available(), pool.buffers());
+    }
+
+    #[test]
+    fn test_empty_aligned_slice() {
+        let mut slice = AlignedSlice::new(0);
+        assert_eq!(slice.size(), 0);
+        assert!(slice.as_slice().is_empty());
+        assert!(slice.as_bytes().is_empty());
+    }
+}
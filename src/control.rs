@@ -0,0 +1,483 @@
+use crate::common::*;
+use crate::wal::{NotAWal, UnsupportedVersion};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc32fast::Hasher;
+use log::warn;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher as _};
+use std::io::{Cursor, Read, Write};
+
+/// Number of blocks at the start of the device reserved for the control region. The ring of
+/// entries starts immediately after it. Each block holds a copy of the control block, and the
+/// copies are overwritten in turn so a torn write never loses both.
+pub const CONTROL_BLOCKS: u64 = 2;
+
+/// The version of the on-disk format, covering the control region and the entry headers. Devices
+/// written with another version are rejected at open.
+pub const FORMAT_VERSION: u32 = 13;
+
+// Marks a block as a control block of a wal.
+const MAGIC: [u8; 4] = *b"WALC";
+
+// magic (4) + version (4) + crc (4) + length of the encoded body (4) + sequence (8). The magic comes
+// first and, like the rest of the header, is assumed to be written atomically with the sector it is
+// in.
+const CONTROL_HEADER_SIZE: usize = 24;
+
+// Set in the flags of a control block when atomic_writes is.
+const FLAG_ATOMIC_WRITES: u32 = 1;
+
+// Set in the flags of a control block when block_crcs is.
+const FLAG_BLOCK_CRCS: u32 = 2;
+
+// Set in the flags of a control block when hash_chain is.
+const FLAG_HASH_CHAIN: u32 = 4;
+
+// Cursor and stream names are stored with a u16 length.
+const MAX_NAME: usize = u16::MAX as usize;
+
+/// The persisted state of a named stream.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StreamState {
+    /// The id stored in the header of each entry of the stream.
+    pub id: u32,
+    /// Entries of the stream before this position have been truncated.
+    pub low_water: WalPosition,
+}
+
+/// Where the wal stood when it was last closed with every write durable.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub head: WalPosition,
+    pub tail: WalPosition,
+    /// The LSN of the next entry to append.
+    pub next_lsn: u64,
+}
+
+/// ControlBlock holds the wal metadata that is not part of the ring of entries. It is encoded into
+/// a single block at the start of the device and rewritten whenever it changes, alternating
+/// between the blocks of the control region.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ControlBlock {
+    /// Identifies the wal, chosen at random when it is formatted. 0 if it was never set.
+    pub id: u64,
+    /// Entries of the default stream before this position have been truncated.
+    pub low_water: WalPosition,
+    /// Every write of a single block since the wal was formatted was made atomically, so an entry
+    /// that fits in one block is either entirely on the device or not at all. Cleared for good once
+    /// the wal is opened on a device that can tear a block.
+    pub atomic_writes: bool,
+    /// Every block of the ring ends with a CRC of the rest of it. Chosen when the wal is formatted
+    /// and never changed after, since it decides how entries are laid out.
+    pub block_crcs: bool,
+    /// Every entry holds the digest of the entry before it. Chosen when the wal is formatted.
+    pub hash_chain: bool,
+    /// The capacity of the ring in blocks when the device is a file that is extended as the ring
+    /// fills rather than allocated up front, see GrowableDevice. 0 if the capacity is the size of
+    /// the device.
+    pub capacity: u64,
+    /// Named consumer cursors. Each cursor is the position of the next entry the consumer needs.
+    pub cursors: BTreeMap<String, WalPosition>,
+    /// Named streams.
+    pub streams: BTreeMap<String, StreamState>,
+    /// Set when the wal is closed cleanly and cleared when it is opened again, so it is only
+    /// present while it matches what is on the device.
+    pub checkpoint: Option<Checkpoint>,
+}
+
+/// A random id for a newly formatted wal, never 0.
+pub fn new_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    hasher.finish().max(1)
+}
+
+fn write_name(body: &mut Vec<u8>, name: &str) -> std::io::Result<()> {
+    if name.len() > MAX_NAME {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("name is longer than {MAX_NAME} bytes"),
+        ));
+    }
+    body.write_u16::<LittleEndian>(name.len() as u16)?;
+    body.write_all(name.as_bytes())
+}
+
+fn read_name(body: &mut Cursor<&[u8]>) -> std::io::Result<String> {
+    let name_len = body.read_u16::<LittleEndian>()? as usize;
+    let mut name = vec![0; name_len];
+    body.read_exact(&mut name)?;
+    String::from_utf8(name).map_err(|_| invalid_data("name is not utf8"))
+}
+
+fn write_position(body: &mut Vec<u8>, pos: WalPosition) -> std::io::Result<()> {
+    body.write_u64::<LittleEndian>(pos.offset)?;
+    body.write_u32::<LittleEndian>(pos.rollover)
+}
+
+fn read_position(body: &mut Cursor<&[u8]>) -> std::io::Result<WalPosition> {
+    let offset = body.read_u64::<LittleEndian>()?;
+    let rollover = body.read_u32::<LittleEndian>()?;
+    Ok(WalPosition { offset, rollover })
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// What was found in one block of the control region.
+enum Slot {
+    // Zeroed, never written.
+    Empty,
+    // A valid control block with its sequence number.
+    Valid(u64, ControlBlock),
+    // The magic is there but the rest doesn't check out, typically a torn write.
+    Corrupt(String),
+    // Not a control block at all.
+    Foreign,
+}
+
+impl ControlBlock {
+    /// Encode into an aligned block ready to be written to the device. The sequence number is
+    /// incremented on every write so the newest copy can be found. An error is returned if the
+    /// contents don't fit in a block.
+    pub fn encode(&self, sequence: u64) -> std::io::Result<AlignedSlice> {
+        let mut body = Vec::new();
+        body.write_u64::<LittleEndian>(self.id)?;
+        write_position(&mut body, self.low_water)?;
+        let mut flags = 0;
+        if self.atomic_writes {
+            flags |= FLAG_ATOMIC_WRITES;
+        }
+        if self.block_crcs {
+            flags |= FLAG_BLOCK_CRCS;
+        }
+        if self.hash_chain {
+            flags |= FLAG_HASH_CHAIN;
+        }
+        body.write_u32::<LittleEndian>(flags)?;
+        body.write_u64::<LittleEndian>(self.capacity)?;
+        body.write_u32::<LittleEndian>(self.cursors.len() as u32)?;
+        for (name, pos) in &self.cursors {
+            write_name(&mut body, name)?;
+            write_position(&mut body, *pos)?;
+        }
+        body.write_u32::<LittleEndian>(self.streams.len() as u32)?;
+        for (name, state) in &self.streams {
+            write_name(&mut body, name)?;
+            body.write_u32::<LittleEndian>(state.id)?;
+            write_position(&mut body, state.low_water)?;
+        }
+        // The checkpoint is last and only written when present, so blocks written before it
+        // existed still decode.
+        if let Some(checkpoint) = &self.checkpoint {
+            write_position(&mut body, checkpoint.head)?;
+            write_position(&mut body, checkpoint.tail)?;
+            body.write_u64::<LittleEndian>(checkpoint.next_lsn)?;
+        }
+
+        let size = BLOCK_SIZE as usize;
+        if CONTROL_HEADER_SIZE + body.len() > size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "control block needs {} bytes",
+                    CONTROL_HEADER_SIZE + body.len()
+                ),
+            ));
+        }
+
+        let mut aligned = AlignedSlice::new(size);
+        let buffer = aligned.as_slice();
+        buffer[..4].copy_from_slice(&MAGIC);
+        buffer[4..8].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buffer[12..16].copy_from_slice(&(body.len() as u32).to_le_bytes());
+        buffer[16..24].copy_from_slice(&sequence.to_le_bytes());
+        buffer[CONTROL_HEADER_SIZE..][..body.len()].copy_from_slice(&body);
+        let crc = compute_crc(&buffer[12..CONTROL_HEADER_SIZE + body.len()]);
+        buffer[8..12].copy_from_slice(&crc.to_le_bytes());
+        Ok(aligned)
+    }
+
+    /// Decode the control region read from the device and return the newest valid copy along with
+    /// its sequence number. A zeroed region has never been written and decodes to an empty
+    /// ControlBlock. If no copy is valid the metadata is lost, which is logged and also results in
+    /// an empty ControlBlock rather than making the wal unusable. An error is returned if the
+    /// region was not written by this version of the wal.
+    pub fn decode(region: &[u8]) -> std::io::Result<(ControlBlock, u64)> {
+        let mut newest: Option<(u64, ControlBlock)> = None;
+        let mut corrupt = Vec::new();
+        let mut foreign = false;
+        for block in region.chunks(BLOCK_SIZE as usize) {
+            match decode_copy(block)? {
+                Slot::Empty => {}
+                Slot::Valid(sequence, control) => {
+                    if newest.as_ref().is_none_or(|(s, _)| sequence > *s) {
+                        newest = Some((sequence, control));
+                    }
+                }
+                Slot::Corrupt(reason) => corrupt.push(reason),
+                Slot::Foreign => foreign = true,
+            }
+        }
+        for reason in &corrupt {
+            warn!("Ignoring damaged copy of the control block: {reason}");
+        }
+        match newest {
+            Some((sequence, control)) => Ok((control, sequence)),
+            None if foreign && corrupt.is_empty() => Err(NotAWal::Foreign.error()),
+            None => {
+                if !corrupt.is_empty() {
+                    warn!("No valid copy of the control block, cursors and streams are lost");
+                }
+                Ok((ControlBlock::default(), 0))
+            }
+        }
+    }
+}
+
+/// Whether no copy of the control block has ever been written to the region, which means the
+/// device has not been formatted.
+pub fn is_blank(region: &[u8]) -> bool {
+    region
+        .chunks(BLOCK_SIZE as usize)
+        .all(|block| block[..CONTROL_HEADER_SIZE].iter().all(|b| *b == 0))
+}
+
+fn decode_copy(buffer: &[u8]) -> std::io::Result<Slot> {
+    if buffer.len() < CONTROL_HEADER_SIZE {
+        return Err(invalid_data("control block is too short"));
+    }
+    if buffer[..CONTROL_HEADER_SIZE].iter().all(|b| *b == 0) {
+        return Ok(Slot::Empty);
+    }
+    if buffer[..4] != MAGIC {
+        return Ok(Slot::Foreign);
+    }
+    let version = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            UnsupportedVersion {
+                version,
+                supported: FORMAT_VERSION,
+            },
+        ));
+    }
+    let crc = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+    let len = u32::from_le_bytes(buffer[12..16].try_into().unwrap()) as usize;
+    let sequence = u64::from_le_bytes(buffer[16..24].try_into().unwrap());
+    if CONTROL_HEADER_SIZE + len > buffer.len() {
+        return Ok(Slot::Corrupt("length is out of range".to_string()));
+    }
+    if compute_crc(&buffer[12..CONTROL_HEADER_SIZE + len]) != crc {
+        return Ok(Slot::Corrupt(format!("CRC mismatch in copy {sequence}")));
+    }
+
+    let mut body = Cursor::new(&buffer[CONTROL_HEADER_SIZE..][..len]);
+    let mut control = ControlBlock {
+        id: body.read_u64::<LittleEndian>()?,
+        low_water: read_position(&mut body)?,
+        ..Default::default()
+    };
+    let flags = body.read_u32::<LittleEndian>()?;
+    control.atomic_writes = flags & FLAG_ATOMIC_WRITES != 0;
+    control.block_crcs = flags & FLAG_BLOCK_CRCS != 0;
+    control.hash_chain = flags & FLAG_HASH_CHAIN != 0;
+    control.capacity = body.read_u64::<LittleEndian>()?;
+    let count = body.read_u32::<LittleEndian>()?;
+    for _ in 0..count {
+        let name = read_name(&mut body)?;
+        control.cursors.insert(name, read_position(&mut body)?);
+    }
+    let count = body.read_u32::<LittleEndian>()?;
+    for _ in 0..count {
+        let name = read_name(&mut body)?;
+        let id = body.read_u32::<LittleEndian>()?;
+        let low_water = read_position(&mut body)?;
+        control.streams.insert(name, StreamState { id, low_water });
+    }
+    if body.position() < len as u64 {
+        control.checkpoint = Some(Checkpoint {
+            head: read_position(&mut body)?,
+            tail: read_position(&mut body)?,
+            next_lsn: body.read_u64::<LittleEndian>()?,
+        });
+    }
+    Ok(Slot::Valid(sequence, control))
+}
+
+fn compute_crc(buffer: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(buffer);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_block_round_trip() -> std::io::Result<()> {
+        let mut control = ControlBlock {
+            id: new_id(),
+            low_water: WalPosition {
+                offset: 5,
+                rollover: 2,
+            },
+            atomic_writes: true,
+            block_crcs: true,
+            hash_chain: true,
+            capacity: 1 << 20,
+            ..Default::default()
+        };
+        control.cursors.insert(
+            "indexer".to_string(),
+            WalPosition {
+                offset: 12,
+                rollover: 3,
+            },
+        );
+        control.cursors.insert(
+            "replicator".to_string(),
+            WalPosition {
+                offset: 1,
+                rollover: 4,
+            },
+        );
+        control.streams.insert(
+            "audit".to_string(),
+            StreamState {
+                id: 1,
+                low_water: WalPosition {
+                    offset: 7,
+                    rollover: 2,
+                },
+            },
+        );
+
+        let mut aligned = control.encode(1)?;
+        assert_eq!(
+            ControlBlock::decode(aligned.as_slice())?,
+            (control.clone(), 1)
+        );
+
+        let mut checkpointed = control.clone();
+        checkpointed.checkpoint = Some(Checkpoint {
+            head: WalPosition {
+                offset: 9,
+                rollover: 2,
+            },
+            tail: WalPosition {
+                offset: 7,
+                rollover: 2,
+            },
+            next_lsn: 40,
+        });
+        assert_eq!(
+            ControlBlock::decode(checkpointed.encode(2)?.as_slice())?,
+            (checkpointed, 2)
+        );
+
+        // A zeroed region was never written.
+        assert!(is_blank(&[0; 2 * BLOCK_SIZE as usize]));
+        assert!(!is_blank(&region(&[&aligned, &aligned])));
+        assert_eq!(
+            ControlBlock::decode(&[0; 2 * BLOCK_SIZE as usize])?,
+            (ControlBlock::default(), 0)
+        );
+
+        // Corruption is detected.
+        aligned.as_slice()[CONTROL_HEADER_SIZE] ^= 1;
+        assert!(matches!(decode_copy(aligned.as_slice())?, Slot::Corrupt(_)));
+        Ok(())
+    }
+
+    fn region(copies: &[&AlignedSlice]) -> Vec<u8> {
+        copies.iter().flat_map(|c| c.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_newest_valid_copy() -> std::io::Result<()> {
+        let mut older = ControlBlock::default();
+        older.cursors.insert(
+            "indexer".to_string(),
+            WalPosition {
+                offset: 5,
+                rollover: 0,
+            },
+        );
+        let mut newer = older.clone();
+        newer.cursors.insert(
+            "indexer".to_string(),
+            WalPosition {
+                offset: 9,
+                rollover: 0,
+            },
+        );
+
+        let first = older.encode(4)?;
+        let mut second = newer.encode(5)?;
+        assert_eq!(
+            ControlBlock::decode(&region(&[&first, &second]))?,
+            (newer.clone(), 5)
+        );
+        assert_eq!(
+            ControlBlock::decode(&region(&[&second, &first]))?,
+            (newer, 5)
+        );
+
+        // A torn write of the newest copy falls back to the previous one.
+        second.as_slice()[CONTROL_HEADER_SIZE + 1] ^= 1;
+        assert_eq!(
+            ControlBlock::decode(&region(&[&first, &second]))?,
+            (older.clone(), 4)
+        );
+
+        // Losing both copies loses the metadata but not the wal.
+        let mut first = older.encode(4)?;
+        first.as_slice()[CONTROL_HEADER_SIZE + 1] ^= 1;
+        assert_eq!(
+            ControlBlock::decode(&region(&[&first, &second]))?,
+            (ControlBlock::default(), 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_foreign_region() -> std::io::Result<()> {
+        let mut region = vec![0; 2 * BLOCK_SIZE as usize];
+        region[..5].copy_from_slice(b"hello");
+        let err = ControlBlock::decode(&region).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut newer = ControlBlock::default().encode(1)?;
+        newer.as_slice()[4] = FORMAT_VERSION as u8 + 1;
+        let err = ControlBlock::decode(newer.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        let unsupported = err.get_ref().unwrap().downcast_ref::<UnsupportedVersion>();
+        assert!(unsupported.unwrap().is_newer());
+        assert_eq!(unsupported.unwrap().version, FORMAT_VERSION + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_block_overflow() {
+        let mut control = ControlBlock::default();
+        for i in 0..1000 {
+            control.cursors.insert(
+                format!("consumer-{i}"),
+                WalPosition {
+                    offset: 0,
+                    rollover: 0,
+                },
+            );
+        }
+        assert!(control.encode(1).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use crate::common::*;
+
+// offset (8) + rollover (4) + lsn (8)
+const CURSOR_SIZE: usize = 20;
+
+/// WalCursor is a read position that can be saved and used again after the wal is reopened. Along
+/// with the position of the next entry to read it holds the LSN that entry is expected to have, so
+/// Wal::check_cursor can tell when the entry was truncated or overwritten in the meantime.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WalCursor {
+    pub(crate) position: WalPosition,
+    pub(crate) lsn: u64,
+}
+
+impl WalCursor {
+    /// The position of the next entry to read.
+    pub fn position(&self) -> WalPosition {
+        self.position
+    }
+
+    /// The LSN of the next entry to read.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    pub fn encode(&self) -> [u8; CURSOR_SIZE] {
+        let mut buffer = [0; CURSOR_SIZE];
+        buffer[..8].copy_from_slice(&self.position.offset.to_le_bytes());
+        buffer[8..12].copy_from_slice(&self.position.rollover.to_le_bytes());
+        buffer[12..].copy_from_slice(&self.lsn.to_le_bytes());
+        buffer
+    }
+
+    pub fn decode(buffer: &[u8]) -> std::io::Result<WalCursor> {
+        if buffer.len() != CURSOR_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("cursor of {} bytes, expected {CURSOR_SIZE}", buffer.len()),
+            ));
+        }
+        Ok(WalCursor {
+            position: WalPosition {
+                offset: u64::from_le_bytes(buffer[..8].try_into().unwrap()),
+                rollover: u32::from_le_bytes(buffer[8..12].try_into().unwrap()),
+            },
+            lsn: u64::from_le_bytes(buffer[12..].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::Wal;
+
+    #[test]
+    fn test_round_trip() {
+        let cursor = WalCursor {
+            position: WalPosition {
+                offset: 7 << 33,
+                rollover: 3,
+            },
+            lsn: 42,
+        };
+        assert_eq!(WalCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(WalCursor::decode(&[0; 3]).is_err());
+    }
+
+    #[test]
+    fn test_resume() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let mut cursor = wal.tail_cursor();
+        wal.append(b"one")?;
+        wal.append(b"two")?;
+
+        assert_eq!(wal.next_from(&mut cursor)?.unwrap().1, b"one");
+        let saved = WalCursor::decode(&cursor.encode())?;
+        let mut resumed = saved;
+        assert_eq!(wal.next_from(&mut resumed)?.unwrap().1, b"two");
+        assert_eq!(wal.next_from(&mut resumed)?, None);
+
+        // At the head the cursor picks up entries appended later.
+        wal.append(b"three")?;
+        assert_eq!(wal.next_from(&mut resumed)?.unwrap().1, b"three");
+
+        // Once the entry it points to is truncated the saved cursor is no longer valid.
+        wal.check_cursor(&saved)?;
+        wal.truncate(resumed.position())?;
+        let err = wal.check_cursor(&saved).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwritten() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:6".parse().unwrap())?;
+        wal.append(b"one")?;
+        let mut cursor = wal.tail_cursor();
+        wal.next_from(&mut cursor)?;
+        wal.append(b"two")?;
+
+        // An entry with another LSN now sits where the cursor points.
+        let mut lost = cursor;
+        lost.lsn += 1;
+        let err = wal.check_cursor(&lost).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // A cursor past the head doesn't belong to this wal.
+        lost.position.offset += 2;
+        assert!(wal.next_from(&mut lost).is_err());
+        Ok(())
+    }
+}
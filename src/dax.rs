@@ -0,0 +1,224 @@
+use crate::common::*;
+use crate::sync::lock_exclusive;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+// From linux/mman.h. They are the same on every architecture, but libc doesn't define them for all
+// of them.
+const MAP_SHARED_VALIDATE: libc::c_int = 0x03;
+const MAP_SYNC: libc::c_int = 0x80000;
+
+/// DaxDevice writes to a file on a DAX file system, such as ext4 or xfs mounted with -o dax on
+/// persistent memory, by copying entries straight into a mapping of the file. On x86_64 the copy
+/// is made with non-temporal stores followed by a store fence, so an entry is durable as soon as
+/// write returns and its completion is reported by the next process_completions, without a system
+/// call. Select it with file:///path/to/file?device=dax.
+///
+/// The mapping is made with MAP_SYNC, which the kernel only allows when stores reach the media
+/// without going through the page cache. Files that aren't on DAX, and other architectures, fall
+/// back to an msync of the pages each write touched, which is correct but no faster than a file.
+pub struct DaxDevice {
+    // Held open so the lock lasts as long as the device.
+    _file: File,
+    map: Arc<DaxMapping>,
+    // Whether the mapping is MAP_SYNC, so flushing the CPU caches makes a write durable.
+    dax: bool,
+    ready: Vec<WalPosition>,
+}
+
+impl DaxDevice {
+    /// Map the file at path, which must already have its full size.
+    pub fn new(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        lock_exclusive(&file, path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is empty and can't be mapped", path.display()),
+            ));
+        }
+        let map = |flags| unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        let mut dax = true;
+        let mut ptr = map(MAP_SHARED_VALIDATE | MAP_SYNC);
+        if ptr == libc::MAP_FAILED {
+            // EOPNOTSUPP if the file isn't on DAX, EINVAL on kernels before 4.15.
+            dax = false;
+            ptr = map(libc::MAP_SHARED);
+        }
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(DaxDevice {
+            _file: file,
+            map: Arc::new(DaxMapping {
+                ptr: NonNull::new(ptr as *mut u8).unwrap(),
+                len,
+            }),
+            dax,
+            ready: Vec::new(),
+        })
+    }
+
+    /// Whether the file is mapped with MAP_SYNC, so writes don't need an msync.
+    pub fn is_dax(&self) -> bool {
+        self.dax
+    }
+
+    // Makes the bytes at start durable once they have been copied into the mapping.
+    fn persist(&self, start: usize, len: usize) -> std::io::Result<()> {
+        if self.dax && cfg!(target_arch = "x86_64") {
+            // The non-temporal stores bypassed the caches, so fencing them is enough.
+            #[cfg(target_arch = "x86_64")]
+            unsafe {
+                std::arch::x86_64::_mm_sfence()
+            };
+            return Ok(());
+        }
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let from = start / page * page;
+        let res = unsafe {
+            libc::msync(
+                self.map.ptr.as_ptr().add(from) as *mut libc::c_void,
+                start + len - from,
+                libc::MS_SYNC,
+            )
+        };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl PersistentDevice for DaxDevice {
+    fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
+        let start = pos.byte_offset() as usize;
+        let bytes = data.as_bytes();
+        if start + bytes.len() > self.map.len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Write would exceed device capacity",
+            ));
+        }
+        unsafe { copy_to_media(self.map.ptr.as_ptr().add(start), bytes) };
+        self.persist(start, bytes.len())?;
+        if notify {
+            self.ready.push(pos);
+        }
+        Ok(())
+    }
+
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        std::mem::take(&mut self.ready).into_iter()
+    }
+
+    // Every write is durable by the time it returns.
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.map.read(byte_offset, len)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            dsync_writes: true,
+            ..Default::default()
+        }
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(self.map.clone())
+    }
+}
+
+// Copies data to dst, which is block aligned like data, with non-temporal stores on x86_64 so the
+// data goes to the media rather than staying in the CPU caches.
+unsafe fn copy_to_media(dst: *mut u8, data: &[u8]) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::{__m128i, _mm_load_si128, _mm_stream_si128};
+        // Both are block aligned and a whole number of blocks long, so of whole 16 byte lanes.
+        for lane in 0..data.len() / 16 {
+            let value = _mm_load_si128(data.as_ptr().add(lane * 16) as *const __m128i);
+            _mm_stream_si128(dst.add(lane * 16) as *mut __m128i, value);
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    std::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len());
+}
+
+// A shared read-write mapping of a whole file, written only by the DaxDevice that owns it.
+struct DaxMapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// Readers only read blocks the wal has finished writing.
+unsafe impl Send for DaxMapping {}
+unsafe impl Sync for DaxMapping {}
+
+impl DeviceReader for DaxMapping {
+    fn read(&self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let start = byte_offset as usize;
+        if start + len > self.len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("read of {len} bytes at {byte_offset} is past the end of the file"),
+            ));
+        }
+        Ok(unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().add(start), len) }.to_vec())
+    }
+}
+
+impl Drop for DaxMapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::BLOCK_SIZE;
+    use crate::wal::Wal;
+
+    #[test]
+    fn test_dax_device() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        drop(Wal::create(&path, 64)?);
+        let url: url::Url = format!("file://{}?device=dax", path.display())
+            .parse()
+            .unwrap();
+
+        let mut wal = Wal::open(url.clone())?;
+        let first = wal.append(b"one")?;
+        wal.append(&[7; 2 * BLOCK_SIZE as usize])?;
+        // Writes are durable when they return, so both complete on the next call.
+        assert_eq!(wal.process_completions().count(), 2);
+        assert_eq!(wal.read_at(first)?, b"one");
+        drop(wal);
+
+        let wal = Wal::open(url)?;
+        let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(
+            data,
+            vec![b"one".to_vec(), vec![7; 2 * BLOCK_SIZE as usize]]
+        );
+        Ok(())
+    }
+}
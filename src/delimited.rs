@@ -0,0 +1,153 @@
+use crate::wal::Wal;
+use std::io::{BufRead, Read, Write};
+
+// A varint is at most 10 bytes for a u64.
+const MAX_VARINT_LEN: usize = 10;
+
+/// Write the value as a protobuf base 128 varint.
+pub fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> std::io::Result<()> {
+    let mut buf = [0u8; MAX_VARINT_LEN];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&buf[..len])
+}
+
+/// Read a protobuf base 128 varint. Returns None if the reader is at the end of the stream before
+/// the first byte.
+pub fn read_varint<R: Read>(reader: &mut R) -> std::io::Result<Option<u64>> {
+    let mut value: u64 = 0;
+    for i in 0..MAX_VARINT_LEN {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte)? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "stream ended inside a varint",
+            ));
+        }
+        value |= ((byte[0] & 0x7f) as u64) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "varint is too long",
+    ))
+}
+
+/// DelimitedReader returns each message from a varint-length-delimited stream, the framing used by
+/// protobuf's writeDelimitedTo / parseDelimitedFrom.
+pub struct DelimitedReader<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> DelimitedReader<R> {
+    pub fn new(reader: R) -> Self {
+        DelimitedReader { reader }
+    }
+
+    fn read_message(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let len = match read_varint(&mut self.reader)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        // Entry lengths are stored as u32 in the wal, reject anything larger before allocating.
+        if len > u32::MAX as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("message length {len} is too large"),
+            ));
+        }
+        let mut message = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut message)?;
+        if message.len() as u64 != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "stream ended inside a message",
+            ));
+        }
+        Ok(Some(message))
+    }
+}
+
+impl<R: BufRead> Iterator for DelimitedReader<R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+/// Write every entry in the live region of the wal as a length-delimited message. Returns the
+/// number of entries written.
+pub fn export<W: Write>(wal: &mut Wal, mut writer: W) -> std::io::Result<usize> {
+    let mut count = 0;
+    for entry in wal.iterate() {
+        let (_, payload) = entry?;
+        write_varint(&mut writer, payload.len() as u64)?;
+        writer.write_all(&payload)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Append each message of a length-delimited stream to the wal and wait until they are durable.
+/// Returns the number of messages imported.
+pub fn import<R: BufRead>(reader: R, wal: &mut Wal) -> std::io::Result<usize> {
+    wal.import(DelimitedReader::new(reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300)?;
+        assert_eq!(buf, vec![0xac, 0x02]);
+
+        for value in [0, 1, 127, 128, 16384, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value)?;
+            assert_eq!(read_varint(&mut &buf[..])?, Some(value));
+        }
+        assert_eq!(read_varint(&mut &[][..])?, None);
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_import() -> std::io::Result<()> {
+        let mut source = Wal::open("mem:64".parse().unwrap())?;
+        let entries = vec![b"first".to_vec(), vec![7; 5000], b"third".to_vec()];
+        source.import(entries.iter().cloned().map(Ok))?;
+
+        let mut stream = Vec::new();
+        assert_eq!(export(&mut source, &mut stream)?, 3);
+
+        let mut dest = Wal::open("mem:64".parse().unwrap())?;
+        assert_eq!(import(&stream[..], &mut dest)?, 3);
+        let copied: Vec<Vec<u8>> = dest.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(copied, entries);
+
+        // A truncated stream is an error rather than a silently shorter import.
+        let mut dest = Wal::open("mem:64".parse().unwrap())?;
+        assert!(import(&stream[..stream.len() - 1], &mut dest).is_err());
+        Ok(())
+    }
+}
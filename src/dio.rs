@@ -0,0 +1,179 @@
+use crate::common::*;
+use libc::{O_DIRECT, O_WRONLY};
+use log::warn;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+/// Whether a file can be written with O_DIRECT using buffers and offsets aligned to BLOCK_SIZE.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DirectIo {
+    /// Block aligned writes meet the alignment the file requires.
+    Supported,
+    /// The file needs a larger alignment than BLOCK_SIZE, or doesn't support direct I/O at all.
+    Unsupported,
+    /// The kernel doesn't report the alignment, so the only way to know is to try.
+    Unknown,
+}
+
+/// The flag of pwritev2 and io_uring writes that asks for the write to be atomic. The libc crate
+/// doesn't have it yet.
+pub(crate) const RWF_ATOMIC: i32 = 0x40;
+
+// Asks statx for the atomic write unit, also missing from the libc crate.
+const STATX_WRITE_ATOMIC: u32 = 0x0001_0000;
+
+// Where stx_atomic_write_unit_min and stx_atomic_write_unit_max are in struct statx. The libc crate
+// still has them as padding.
+const ATOMIC_UNIT_MIN_OFFSET: usize = 0xa8;
+const ATOMIC_UNIT_MAX_OFFSET: usize = 0xac;
+const _: () = assert!(std::mem::size_of::<libc::statx>() == 0x100);
+
+/// The sizes of writes that a file takes without tearing. Atomic writes must use O_DIRECT and be
+/// aligned to their size.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AtomicWriteUnit {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl AtomicWriteUnit {
+    /// Whether a write of a single block can be atomic.
+    pub fn covers_block(&self) -> bool {
+        self.min <= BLOCK_SIZE && BLOCK_SIZE <= self.max
+    }
+}
+
+/// Query the direct I/O alignment of the file at path with statx(STATX_DIOALIGN), which needs
+/// Linux 6.1 or later.
+pub fn probe(path: &Path) -> std::io::Result<DirectIo> {
+    let stat = match statx(path, libc::STATX_DIOALIGN)? {
+        Some(stat) if stat.stx_mask & libc::STATX_DIOALIGN != 0 => stat,
+        _ => return Ok(DirectIo::Unknown),
+    };
+    Ok(check_alignment(
+        stat.stx_dio_mem_align,
+        stat.stx_dio_offset_align,
+    ))
+}
+
+/// Query the atomic write unit of the file at path with statx(STATX_WRITE_ATOMIC), which needs
+/// Linux 6.11 or later and a drive that guarantees untorn writes. None if the file doesn't support
+/// atomic writes or the kernel doesn't report them.
+pub fn probe_atomic(path: &Path) -> std::io::Result<Option<AtomicWriteUnit>> {
+    let stat = match statx(path, STATX_WRITE_ATOMIC)? {
+        Some(stat) if stat.stx_mask & STATX_WRITE_ATOMIC != 0 => stat,
+        _ => return Ok(None),
+    };
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            &stat as *const libc::statx as *const u8,
+            std::mem::size_of::<libc::statx>(),
+        )
+    };
+    let field = |offset: usize| u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let unit = AtomicWriteUnit {
+        min: field(ATOMIC_UNIT_MIN_OFFSET),
+        max: field(ATOMIC_UNIT_MAX_OFFSET),
+    };
+    // A unit of 0 means the file doesn't support atomic writes.
+    Ok((unit.min != 0).then_some(unit))
+}
+
+// Returns None if statx itself is missing, or blocked by a seccomp filter.
+fn statx(path: &Path, mask: u32) -> std::io::Result<Option<libc::statx>> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statx = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::statx(libc::AT_FDCWD, path.as_ptr(), 0, mask, &mut stat) };
+    if res != 0 {
+        let err = std::io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EPERM) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(stat))
+}
+
+// The kernel reports 0 for both when the file doesn't support direct I/O. Alignments are powers of
+// two, so one that is no larger than BLOCK_SIZE divides every block aligned buffer and offset.
+fn check_alignment(mem_align: u32, offset_align: u32) -> DirectIo {
+    if mem_align == 0 || offset_align == 0 || mem_align > BLOCK_SIZE || offset_align > BLOCK_SIZE {
+        DirectIo::Unsupported
+    } else {
+        DirectIo::Supported
+    }
+}
+
+/// Open the file at path for writing, with O_DIRECT when the file supports it. Otherwise the file
+/// is opened for buffered writes with a warning, which are still made durable by syncing the file
+/// but go through the page cache. Returns the descriptor and whether it uses direct I/O.
+pub(crate) fn open_for_writes(path: &Path) -> std::io::Result<(RawFd, bool)> {
+    let direct = probe(path)?;
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if direct != DirectIo::Unsupported {
+        let fd = unsafe { libc::open(c_path.as_ptr(), O_WRONLY | O_DIRECT, 0o644) };
+        if fd >= 0 {
+            return Ok((fd, true));
+        }
+        let err = std::io::Error::last_os_error();
+        if direct == DirectIo::Supported || err.raw_os_error() != Some(libc::EINVAL) {
+            return Err(err);
+        }
+    }
+    warn!(
+        "{} doesn't support direct I/O with {BLOCK_SIZE} byte alignment, using buffered writes",
+        path.display()
+    );
+    let fd = unsafe { libc::open(c_path.as_ptr(), O_WRONLY, 0o644) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok((fd, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_alignment() {
+        assert_eq!(check_alignment(512, 512), DirectIo::Supported);
+        assert_eq!(check_alignment(4, BLOCK_SIZE), DirectIo::Supported);
+        assert_eq!(check_alignment(512, 2 * BLOCK_SIZE), DirectIo::Unsupported);
+        assert_eq!(check_alignment(0, 0), DirectIo::Unsupported);
+    }
+
+    #[test]
+    fn test_probe_atomic() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        // Most filesystems and drives don't support atomic writes, but any unit reported is sane.
+        if let Some(unit) = probe_atomic(file.path())? {
+            assert!(unit.min <= unit.max, "{unit:?}");
+        }
+        let unit = AtomicWriteUnit {
+            min: 512,
+            max: 16 * 1024,
+        };
+        assert!(unit.covers_block());
+        assert!(!AtomicWriteUnit { min: 512, max: 512 }.covers_block());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_for_writes() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(BLOCK_SIZE as u64)?;
+        // Whichever way the temporary directory supports, the file opens for block writes.
+        let (fd, direct) = open_for_writes(file.path())?;
+        let mut slice = AlignedSlice::new(BLOCK_SIZE as usize);
+        slice.as_slice()[0] = 7;
+        let written =
+            unsafe { libc::pwrite(fd, slice.as_ptr() as *const _, slice.size() as usize, 0) };
+        unsafe { libc::close(fd) };
+        assert_eq!(written, BLOCK_SIZE as isize, "direct: {direct}");
+        assert_eq!(std::fs::read(file.path())?[0], 7);
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+use crate::common::*;
+use crate::primitives::{thread, Arc, Condvar, Mutex, MutexGuard};
+use crate::wal::Wal;
+use crate::watch::Watch;
+use log::warn;
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use {
+    crate::wal::WalFailed,
+    std::collections::HashMap,
+    std::future::Future,
+    std::pin::Pin,
+    std::task::{Context, Poll, Waker},
+};
+
+// How often the driver polls the device while writes are outstanding. Devices don't offer a way to
+// block until a write completes, so this bounds how late a completion is delivered.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// The most blocks of truncated entries the driver erases each time it polls, so appends aren't
+// held up for long by a large truncation.
+const ERASE_BLOCKS: u64 = 256;
+
+// How often the driver scrubs while it has nothing else to poll for.
+const SCRUB_INTERVAL: Duration = Duration::from_millis(100);
+
+type Callback = Box<dyn FnMut(WalPosition) + Send>;
+
+#[derive(Default)]
+struct SignalState {
+    // Set when writes were submitted since the driver last looked.
+    kicked: bool,
+    stopped: bool,
+}
+
+// The handoff between the threads using the wal and the driver thread. The driver parks on it
+// while it has nothing to poll for, and appends kick it awake.
+#[derive(Default)]
+struct Signal {
+    state: Mutex<SignalState>,
+    cond: Condvar,
+}
+
+impl Signal {
+    fn kick(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.kicked {
+            state.kicked = true;
+            self.cond.notify_one();
+        }
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.cond.notify_one();
+    }
+
+    // Wait until kicked or stopped, or until timeout passes if there is one. Returns false once
+    // stopped.
+    fn park(&self, timeout: Option<Duration>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.kicked && !state.stopped {
+            state = match timeout {
+                Some(timeout) => self.cond.wait_timeout(state, timeout).unwrap().0,
+                None => self.cond.wait(state).unwrap(),
+            };
+        }
+        state.kicked = false;
+        !state.stopped
+    }
+}
+
+struct Shared {
+    wal: Mutex<Wal>,
+    signal: Signal,
+    // Called from the driver thread with every completion, in the order they are reported.
+    listeners: Mutex<Vec<Callback>>,
+    // The entries appended with append_async that aren't durable yet.
+    #[cfg(feature = "async")]
+    waiting: Mutex<HashMap<WalPosition, Arc<Mutex<Waiter>>>>,
+}
+
+// Where the driver thread leaves the outcome of an append_async for its Durable.
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct Waiter {
+    result: Option<std::io::Result<WalPosition>>,
+    waker: Option<Waker>,
+}
+
+#[cfg(feature = "async")]
+impl Shared {
+    // Resolve the waiters of the entries at positions, or of every entry with error.
+    fn resolve(&self, positions: &[WalPosition], error: Option<&dyn Fn() -> std::io::Error>) {
+        let mut waiting = self.waiting.lock().unwrap();
+        let resolved: Vec<(WalPosition, Arc<Mutex<Waiter>>)> = match error {
+            Some(_) => waiting.drain().collect(),
+            None => positions
+                .iter()
+                .filter_map(|pos| waiting.remove_entry(pos))
+                .collect(),
+        };
+        drop(waiting);
+        for (pos, waiter) in resolved {
+            let mut waiter = waiter.lock().unwrap();
+            waiter.result = Some(error.map_or(Ok(pos), |error| Err(error())));
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Future returned by WalDriver::append_async. It resolves to the position of the entry once it is
+/// durable, or to an error if the append failed, the wal failed before the entry was durable, or
+/// the driver was stopped first.
+#[cfg(feature = "async")]
+pub struct Durable {
+    error: Option<std::io::Error>,
+    waiter: Option<Arc<Mutex<Waiter>>>,
+}
+
+#[cfg(feature = "async")]
+impl Future for Durable {
+    type Output = std::io::Result<WalPosition>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let durable = self.get_mut();
+        if let Some(e) = durable.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        let Some(waiter) = durable.waiter.as_ref() else {
+            return Poll::Pending;
+        };
+        let mut waiter = waiter.lock().unwrap();
+        match waiter.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                waiter.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// WalDriver owns a wal along with a thread that drives its completions, so the application
+/// doesn't need a loop calling process_completions. Completions are delivered to every Watch, to
+/// the channels returned by completions and to the callbacks added with on_complete. Use the wal
+/// through lock, but don't call process_completions on it, since the completions it returns are
+/// not delivered. Dropping the driver stops the thread and then drops the wal.
+///
+/// The thread parks while no writes are outstanding and polls the device every POLL_INTERVAL
+/// while they are. Once a write has been outstanding for the wal's max sync interval, the thread
+/// syncs the device, so a quiet period after a burst of appends doesn't leave them waiting to
+/// become durable. With Wal::set_erase_reclaimed, the thread also erases truncated entries a
+/// few blocks at a time, and with Wal::set_scrub_rate it checks the entries for damage.
+///
+/// With the async feature, append_async returns a future that resolves once the entry is
+/// durable, so async code can wait for its entries without polling or a thread of its own.
+pub struct WalDriver {
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Exclusive access to the wal of a WalDriver. Dropping it wakes the driver thread if writes were
+/// submitted while it was held.
+pub struct WalGuard<'a> {
+    wal: MutexGuard<'a, Wal>,
+    signal: &'a Signal,
+}
+
+impl Deref for WalGuard<'_> {
+    type Target = Wal;
+
+    fn deref(&self) -> &Wal {
+        &self.wal
+    }
+}
+
+impl DerefMut for WalGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Wal {
+        &mut self.wal
+    }
+}
+
+impl Drop for WalGuard<'_> {
+    fn drop(&mut self) {
+        if self.wal.has_outstanding() || self.wal.has_unerased() {
+            self.signal.kick();
+        }
+    }
+}
+
+impl WalDriver {
+    pub(crate) fn spawn(wal: Wal) -> std::io::Result<WalDriver> {
+        let shared = Arc::new(Shared {
+            wal: Mutex::new(wal),
+            signal: Signal::default(),
+            listeners: Mutex::new(Vec::new()),
+            #[cfg(feature = "async")]
+            waiting: Mutex::new(HashMap::new()),
+        });
+        let driver = shared.clone();
+        let thread = thread::Builder::new()
+            .name("wal-completions".to_string())
+            .spawn(move || run(&driver))?;
+        Ok(WalDriver {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Lock the wal for exclusive use. Completions are not delivered while it is held.
+    pub fn lock(&self) -> WalGuard<'_> {
+        WalGuard {
+            wal: self.shared.wal.lock().unwrap(),
+            signal: &self.shared.signal,
+        }
+    }
+
+    /// Append an entry, with the completion delivered by the driver thread.
+    pub fn append(&self, data: &[u8]) -> std::io::Result<WalPosition> {
+        self.lock().append(data)
+    }
+
+    /// Append an entry and return a future that resolves to its position once it is durable. The
+    /// entry is appended before this returns, so entries are in the order append_async is called
+    /// in, whenever the futures are polled. Dropping the future doesn't undo the append.
+    #[cfg(feature = "async")]
+    pub fn append_async(&self, data: &[u8]) -> Durable {
+        let mut wal = self.lock();
+        // The completion can't be delivered before the waiter is registered, since the driver
+        // only collects completions while holding the wal.
+        match wal.append(data) {
+            Ok(pos) => {
+                let waiter = Arc::new(Mutex::new(Waiter::default()));
+                self.shared
+                    .waiting
+                    .lock()
+                    .unwrap()
+                    .insert(pos, waiter.clone());
+                Durable {
+                    error: None,
+                    waiter: Some(waiter),
+                }
+            }
+            Err(e) => Durable {
+                error: Some(e),
+                waiter: None,
+            },
+        }
+    }
+
+    /// Return a handle that is woken whenever the driver reports new durable entries.
+    pub fn watch(&self) -> Watch {
+        self.lock().watch()
+    }
+
+    /// Return a channel that receives every completion delivered from now on. Dropping the
+    /// receiver unsubscribes it.
+    pub fn completions(&self) -> mpsc::Receiver<WalPosition> {
+        let (sender, receiver) = mpsc::channel();
+        let mut sender = Some(sender);
+        self.on_complete(move |pos| {
+            if sender.as_ref().is_some_and(|s| s.send(pos).is_err()) {
+                sender = None;
+            }
+        });
+        receiver
+    }
+
+    /// Call f from the driver thread with every completion delivered from now on. f must not
+    /// lock the wal or add callbacks.
+    pub fn on_complete<F: FnMut(WalPosition) + Send + 'static>(&self, f: F) {
+        self.shared.listeners.lock().unwrap().push(Box::new(f));
+    }
+
+    /// Stop the driver thread and return the wal. Completions that arrive afterwards are returned
+    /// from process_completions as usual.
+    pub fn into_inner(mut self) -> Wal {
+        self.stop();
+        let shared = self.shared.clone();
+        drop(self);
+        match Arc::try_unwrap(shared) {
+            Ok(shared) => shared.wal.into_inner().unwrap(),
+            Err(_) => unreachable!("the driver thread has exited"),
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shared.signal.stop();
+            thread.join().unwrap();
+        }
+        #[cfg(feature = "async")]
+        self.shared.resolve(
+            &[],
+            Some(&|| std::io::Error::other("the driver stopped before the entry was durable")),
+        );
+    }
+}
+
+impl Drop for WalDriver {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// The body of the driver thread. The wal is only locked while polling so appends aren't held up,
+// and the completions are delivered after unlocking it so listeners can't stall appends.
+fn run(shared: &Shared) {
+    // When the driver first saw writes outstanding since the last sync.
+    let mut unsynced_since: Option<Instant> = None;
+    loop {
+        let (completions, outstanding, interval, unerased, scrubbing) = {
+            let mut wal = shared.wal.lock().unwrap();
+            let interval = wal.max_sync_interval();
+            if let (Some(since), Some(interval)) = (unsynced_since, interval) {
+                if since.elapsed() >= interval {
+                    if let Err(e) = wal.sync_device() {
+                        warn!("Failed to sync the device: {}", e);
+                    }
+                    unsynced_since = None;
+                }
+            }
+            if let Err(e) = wal.erase_reclaimed(ERASE_BLOCKS) {
+                warn!("Failed to erase truncated entries: {}", e);
+            }
+            wal.scrub();
+            let completions: Vec<WalPosition> = wal.process_completions().collect();
+            // The entries that completed before the wal failed are still durable.
+            #[cfg(feature = "async")]
+            {
+                shared.resolve(&completions, None);
+                if let Some(failed) = wal.failure() {
+                    let failed: WalFailed = failed.clone();
+                    shared.resolve(&[], Some(&|| failed.error()));
+                }
+            }
+            (
+                completions,
+                wal.has_outstanding(),
+                interval,
+                wal.has_unerased(),
+                wal.is_scrubbing(),
+            )
+        };
+        if !outstanding {
+            unsynced_since = None;
+        } else if unsynced_since.is_none() {
+            unsynced_since = Some(Instant::now());
+        }
+        if !completions.is_empty() {
+            let mut listeners = shared.listeners.lock().unwrap();
+            for pos in completions {
+                for listener in listeners.iter_mut() {
+                    listener(pos);
+                }
+            }
+        }
+        let poll = interval.map_or(POLL_INTERVAL, |interval| interval.min(POLL_INTERVAL));
+        let timeout = if outstanding || unerased {
+            Some(poll)
+        } else {
+            scrubbing.then_some(SCRUB_INTERVAL)
+        };
+        if !shared.signal.park(timeout) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::SyncDevice;
+    use crate::wal::{SyncPolicy, DEFAULT_MAX_SYNC_INTERVAL};
+
+    #[test]
+    fn test_driver() -> std::io::Result<()> {
+        let driver = Wal::open("mem:64".parse().unwrap())?.spawn_driver()?;
+        let completions = driver.completions();
+        let mut watch = driver.watch();
+        let (sender, called) = mpsc::channel();
+        driver.on_complete(move |pos| sender.send(pos).unwrap());
+
+        let one = driver.append(b"one")?;
+        let two = driver.lock().append(b"two")?;
+        let timeout = Duration::from_secs(5);
+        assert_eq!(completions.recv_timeout(timeout), Ok(one));
+        assert_eq!(completions.recv_timeout(timeout), Ok(two));
+        assert_eq!(called.recv_timeout(timeout), Ok(one));
+        assert_eq!(called.recv_timeout(timeout), Ok(two));
+        let mut latest = None;
+        while latest != Some(two) {
+            latest = watch.wait_timeout(timeout);
+            assert!(latest.is_some());
+        }
+
+        // A dropped receiver doesn't stop the other listeners.
+        drop(completions);
+        let three = driver.append(b"three")?;
+        assert_eq!(called.recv_timeout(timeout), Ok(three));
+
+        let mut wal = driver.into_inner();
+        assert!(!wal.has_outstanding());
+        assert_eq!(wal.iterate().count(), 3);
+        wal.append_with(b"four", SyncPolicy::Immediate)?;
+        assert_eq!(wal.process_completions().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_sync_interval() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        wal.set_max_sync_interval(None);
+        // Writes only complete when the device is synced.
+        sim.set_complete_probability(0.0);
+        let driver = wal.spawn_driver()?;
+        let completions = driver.completions();
+
+        let one = driver.append(b"one")?;
+        let wait = Duration::from_millis(20);
+        assert!(completions.recv_timeout(wait).is_err());
+        assert_eq!(sim.pending(), 1);
+
+        driver
+            .lock()
+            .set_max_sync_interval(Some(DEFAULT_MAX_SYNC_INTERVAL));
+        assert_eq!(completions.recv_timeout(Duration::from_secs(5)), Ok(one));
+        let two = driver.append(b"two")?;
+        assert_eq!(completions.recv_timeout(Duration::from_secs(5)), Ok(two));
+        assert_eq!(sim.pending(), 0);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_driver_file() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let wal = Wal::open_device(Box::new(SyncDevice::new(file.path())?), 64)?;
+        let driver = wal.spawn_driver()?;
+        let completions = driver.completions();
+        let positions: Vec<WalPosition> = (0..20u32)
+            .map(|i| driver.append(&i.to_le_bytes()))
+            .collect::<std::io::Result<_>>()?;
+
+        let mut completed: Vec<WalPosition> = (0..positions.len())
+            .map(|_| completions.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        completed.sort();
+        assert_eq!(completed, positions);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "async")]
+    fn test_append_async() -> std::io::Result<()> {
+        use crate::wal::WalFailed;
+        use futures::executor::block_on;
+
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let driver = Wal::open_device(Box::new(sim.clone()), 64)?.spawn_driver()?;
+        let one = driver.append_async(b"one");
+        let two = driver.append_async(b"two");
+        let two = block_on(two)?;
+        let one = block_on(one)?;
+        assert!(one < two);
+        assert!(driver.lock().durable_head() > two);
+
+        // Entries that won't become durable fail once the wal does.
+        sim.set_complete_probability(0.0);
+        driver.lock().set_max_sync_interval(None);
+        let three = driver.append_async(b"three");
+        sim.fail_pending();
+        let err = block_on(three).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<WalFailed>());
+        let err = block_on(driver.append_async(b"four")).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<WalFailed>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_driver_erases() -> std::io::Result<()> {
+        let driver = Wal::open("mem:64".parse().unwrap())?.spawn_driver()?;
+        driver.append(b"one")?;
+        {
+            let mut wal = driver.lock();
+            wal.set_erase_reclaimed(Some(0));
+            let head = wal.head();
+            wal.truncate(head)?;
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while driver.lock().stats().erase_bytes == 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        assert_eq!(driver.lock().stats().erase_bytes, BLOCK_SIZE as u64);
+        Ok(())
+    }
+}
+
+// These cover the handoff between the threads using the wal and the driver thread.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    // An append that kicks the driver while it is deciding to park must not be lost, or the
+    // completion would only be delivered on the next append.
+    #[test]
+    fn loom_kick_wakes_driver() {
+        loom::model(|| {
+            let signal = Arc::new(Signal::default());
+            let appender = signal.clone();
+            let thread = loom::thread::spawn(move || appender.kick());
+            assert!(signal.park(None));
+            thread.join().unwrap();
+        });
+    }
+
+    // Stopping must release the driver whether it parks before or after the stop, and a kick that
+    // races with the stop must not hide it.
+    #[test]
+    fn loom_stop_releases_driver() {
+        loom::model(|| {
+            let signal = Arc::new(Signal::default());
+            let appender = signal.clone();
+            let stopper = signal.clone();
+            let kick = loom::thread::spawn(move || appender.kick());
+            let stop = loom::thread::spawn(move || stopper.stop());
+            while signal.park(None) {}
+            kick.join().unwrap();
+            stop.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,26 @@
+// Failpoints let tests force rare error paths deterministically. They are compiled in with the
+// failpoints feature and configured at runtime through the fail crate, e.g.
+// fail::cfg("wal::submit", "return"). Without the feature every check is a no-op. Failpoints are
+// global to the process, so tests that use them live in tests/failpoints.rs rather than running
+// alongside the unit tests.
+//
+// The failpoints are:
+//   wal::submit       - an append fails before anything is written.
+//   wal::rollover     - the padding write at the end of the ring fails.
+//   wal::header_parse - an entry header fails to parse during recovery or iteration.
+//   sync::fsync       - the fsync in SyncDevice::process_completions fails.
+//   mem::completion   - a MemDevice write completes with an error.
+//   uring::completion - an io_uring write completes with an error.
+
+/// Returns an error if the named failpoint is configured to fire.
+#[inline]
+pub(crate) fn check(name: &str) -> std::io::Result<()> {
+    #[cfg(feature = "failpoints")]
+    fail::fail_point!(name, |arg| Err(std::io::Error::other(format!(
+        "failpoint {name} triggered{}",
+        arg.map(|a| format!(": {a}")).unwrap_or_default()
+    ))));
+    #[cfg(not(feature = "failpoints"))]
+    let _ = name;
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use crate::common::*;
+use crate::delimited::{write_varint, DelimitedReader};
+use crate::wal::{StreamId, StreamIterator, Wal, HEADER_SIZE};
+use std::time::{Duration, Instant};
+
+// The first byte of the payload of every group, so a group is told apart from an entry that
+// wasn't written by GroupCommit, and the layout can change.
+const GROUP_FORMAT: u8 = 1;
+
+/// The most payload that fits in a single block along with the entry header. Groups of up to this
+/// many bytes take up one block, unless the wal has block CRCs.
+pub const BLOCK_PAYLOAD: usize = BLOCK_SIZE as usize - HEADER_SIZE;
+
+/// Where a record written by GroupCommit is: the entry of its group and its index within it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RecordPosition {
+    pub entry: WalPosition,
+    pub index: u32,
+}
+
+/// A group GroupCommit wrote to the wal. Its records are durable once the wal reports the entry
+/// at position complete.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub position: WalPosition,
+    /// The sequence number of the first record of the group, as returned by GroupCommit::append.
+    pub first: u64,
+    pub records: u32,
+}
+
+/// GroupCommit packs small records into shared entries, so records much smaller than a block
+/// don't each take up a block and a write of their own. Records are collected into a group until
+/// it holds max_bytes or its oldest record has waited max_delay, and the group is then appended to
+/// a stream as a single entry. The stream should only hold groups; read them back as records with
+/// records.
+///
+/// A group is only written when a record is appended or flush_if_due or flush is called, so a
+/// caller that can go quiet should call flush_if_due periodically. Records still pending when the GroupCommit
+/// is dropped are lost, call flush first.
+pub struct GroupCommit {
+    stream: StreamId,
+    max_bytes: usize,
+    max_delay: Duration,
+    // The payload of the pending group.
+    buffer: Vec<u8>,
+    records: u32,
+    // When the oldest pending record was appended.
+    since: Option<Instant>,
+    next_record: u64,
+    written: Vec<Group>,
+}
+
+impl GroupCommit {
+    /// Collect records into groups of up to max_bytes, BLOCK_PAYLOAD to fill a block, appended
+    /// to stream once full or max_delay after their first record.
+    pub fn new(stream: StreamId, max_bytes: usize, max_delay: Duration) -> Self {
+        GroupCommit {
+            stream,
+            max_bytes,
+            max_delay,
+            buffer: Vec::new(),
+            records: 0,
+            since: None,
+            next_record: 0,
+            written: Vec::new(),
+        }
+    }
+
+    /// Add record to the pending group and return its sequence number, which take_written maps to
+    /// the entry of its group. The pending group is written first if it is full, its oldest record
+    /// has waited max_delay, or record doesn't fit in what is left of it. If that write fails,
+    /// record isn't added, so the append can be retried. A record larger than max_bytes makes up a
+    /// group of its own.
+    pub fn append(&mut self, wal: &mut Wal, record: &[u8]) -> std::io::Result<u64> {
+        let mut framed = Vec::with_capacity(record.len() + 10);
+        write_varint(&mut framed, record.len() as u64)?;
+        framed.extend_from_slice(record);
+        if self.buffer.len() + framed.len() > self.max_bytes {
+            self.flush(wal)?;
+        } else {
+            self.flush_if_due(wal)?;
+        }
+        if self.buffer.is_empty() {
+            self.buffer.push(GROUP_FORMAT);
+            self.since = Some(Instant::now());
+        }
+        self.buffer.extend_from_slice(&framed);
+        self.records += 1;
+        let record = self.next_record;
+        self.next_record += 1;
+        Ok(record)
+    }
+
+    /// Write the pending group if it is full or its oldest record has waited max_delay.
+    pub fn flush_if_due(&mut self, wal: &mut Wal) -> std::io::Result<()> {
+        match self.since {
+            Some(since)
+                if self.buffer.len() >= self.max_bytes || since.elapsed() >= self.max_delay =>
+            {
+                self.flush(wal)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the pending group, if there is one. If the append fails, the records stay pending.
+    pub fn flush(&mut self, wal: &mut Wal) -> std::io::Result<()> {
+        if self.records == 0 {
+            return Ok(());
+        }
+        let position = wal.append_to(self.stream, &self.buffer)?;
+        self.written.push(Group {
+            position,
+            first: self.next_record - self.records as u64,
+            records: self.records,
+        });
+        self.buffer.clear();
+        self.records = 0;
+        self.since = None;
+        Ok(())
+    }
+
+    /// The number of records waiting for their group to be written.
+    pub fn pending(&self) -> usize {
+        self.records as usize
+    }
+
+    /// Return the groups written since the last call, oldest first.
+    pub fn take_written(&mut self) -> Vec<Group> {
+        std::mem::take(&mut self.written)
+    }
+}
+
+/// Split the payload of a group into its records. Fails with InvalidData if it isn't a group.
+pub fn unpack(payload: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
+    match payload.split_first() {
+        Some((&GROUP_FORMAT, records)) => DelimitedReader::new(records).collect(),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "entry is not a group of records",
+        )),
+    }
+}
+
+/// Records returns the records of the groups in a stream, in the order they were appended.
+pub struct Records<'a> {
+    groups: StreamIterator<'a>,
+    // The records of the current group that haven't been returned yet, with its position.
+    current: Option<(WalPosition, u32, std::vec::IntoIter<Vec<u8>>)>,
+}
+
+/// Iterate over the records of the groups GroupCommit wrote to stream. A damaged group, or an
+/// entry of the stream that isn't a group, is returned as an error.
+pub fn records(wal: &Wal, stream: StreamId) -> Records<'_> {
+    Records {
+        groups: wal.iterate_stream(stream),
+        current: None,
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = std::io::Result<(RecordPosition, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((entry, index, records)) = self.current.as_mut() {
+                if let Some(record) = records.next() {
+                    let position = RecordPosition {
+                        entry: *entry,
+                        index: *index,
+                    };
+                    *index += 1;
+                    return Some(Ok((position, record)));
+                }
+            }
+            let (entry, payload) = match self.groups.next()? {
+                Ok(group) => group,
+                Err(e) => return Some(Err(e)),
+            };
+            match unpack(&payload) {
+                Ok(records) => self.current = Some((entry, 0, records.into_iter())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_commit() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let stream = wal.stream("records")?;
+        let mut group = GroupCommit::new(stream, BLOCK_PAYLOAD, Duration::from_secs(60));
+        for i in 0..100u32 {
+            assert_eq!(group.append(&mut wal, &i.to_le_bytes())?, i as u64);
+        }
+        // A hundred small records fit in a single block.
+        assert!(group.take_written().is_empty());
+        assert_eq!(group.pending(), 100);
+        group.flush(&mut wal)?;
+        let written = group.take_written();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].records, 100);
+        assert_eq!(wal.head(), written[0].position.advance(1, 64));
+
+        // A record that doesn't fit starts a new group, and a large one is a group of its own.
+        group.append(&mut wal, &[1; 3000])?;
+        group.append(&mut wal, &[2; 3000])?;
+        let large = group.append(&mut wal, &[3; 5000])?;
+        assert_eq!(group.take_written().len(), 2);
+        assert_eq!(group.pending(), 1);
+        group.flush_if_due(&mut wal)?;
+        let written = group.take_written();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].first, large);
+        assert_eq!(written[0].records, 1);
+
+        // Pending records are written once the oldest has waited long enough.
+        let mut group = GroupCommit::new(stream, BLOCK_PAYLOAD, Duration::from_millis(10));
+        group.append(&mut wal, b"late")?;
+        group.flush_if_due(&mut wal)?;
+        assert_eq!(group.pending(), 1);
+        std::thread::sleep(Duration::from_millis(10));
+        group.flush_if_due(&mut wal)?;
+        assert_eq!(group.pending(), 0);
+
+        let all: Vec<(RecordPosition, Vec<u8>)> =
+            records(&wal, stream).collect::<std::io::Result<_>>()?;
+        assert_eq!(all.len(), 104);
+        assert_eq!(all[5].1, 5u32.to_le_bytes());
+        assert_eq!(all[5].0.index, 5);
+        assert_eq!(all[100].1, vec![1; 3000]);
+        assert_eq!(all[103].1, b"late");
+
+        wal.append_to(stream, b"not a group")?;
+        assert!(records(&wal, stream).last().unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_append_is_not_kept() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let stream = wal.stream("records")?;
+        let mut group = GroupCommit::new(stream, 64, Duration::from_secs(60));
+        group.append(&mut wal, &[1; 40])?;
+        // Writing the pending group fails, so the record that needs it written isn't taken.
+        wal.set_stream_quota(stream, Some(0))?;
+        assert!(group.append(&mut wal, &[2; 40]).is_err());
+        assert_eq!(group.pending(), 1);
+        wal.set_stream_quota(stream, None)?;
+        group.append(&mut wal, &[2; 40])?;
+        group.flush(&mut wal)?;
+
+        let all: Vec<Vec<u8>> = records(&wal, stream)
+            .map(|record| record.map(|(_, record)| record))
+            .collect::<std::io::Result<_>>()?;
+        assert_eq!(all, vec![vec![1; 40], vec![2; 40]]);
+        Ok(())
+    }
+}
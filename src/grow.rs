@@ -0,0 +1,239 @@
+use crate::common::*;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The number of blocks a growable file is extended by at a time unless WalOptions says
+/// otherwise, 64 MiB.
+pub const DEFAULT_GROW_CHUNK_BLOCKS: u64 = 16384;
+
+/// GrowableDevice wraps the device of a file that is shorter than the ring, so the ring doesn't
+/// have to be allocated up front. Before a write past the end of the file, the file is extended
+/// to the next multiple of the chunk size, up to the capacity of the ring. Extending in chunks
+/// keeps the file from fragmenting and the metadata updates off most writes. The part of the ring
+/// past the end of the file reads as zeros, like blocks that were never written.
+///
+/// The new length is made durable by the sync that makes the write past the old end durable, so
+/// after a crash the file may be longer than the entries in it but never shorter.
+pub struct GrowableDevice {
+    inner: Box<dyn PersistentDevice>,
+    file: File,
+    capacity_bytes: u64,
+    chunk_bytes: u64,
+    // The length of the file, shared with the readers.
+    len: Arc<AtomicU64>,
+}
+
+impl GrowableDevice {
+    /// Wrap inner, the device of the file at path, growing the file up to capacity blocks
+    /// chunk_blocks at a time.
+    pub fn new(
+        inner: Box<dyn PersistentDevice>,
+        path: &Path,
+        capacity: u64,
+        chunk_blocks: u64,
+    ) -> std::io::Result<Self> {
+        if chunk_blocks == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "chunk of 0 blocks can't grow the file",
+            ));
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(GrowableDevice {
+            inner,
+            file,
+            capacity_bytes: capacity * BLOCK_SIZE as u64,
+            chunk_bytes: chunk_blocks * BLOCK_SIZE as u64,
+            len: Arc::new(AtomicU64::new(len)),
+        })
+    }
+
+    // Extend the file so it holds the bytes up to end.
+    fn grow(&mut self, end: u64) -> std::io::Result<()> {
+        let len = self.len.load(Ordering::Acquire);
+        if end <= len {
+            return Ok(());
+        }
+        if end > self.capacity_bytes {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Write would exceed device capacity",
+            ));
+        }
+        let new_len = end
+            .next_multiple_of(self.chunk_bytes)
+            .min(self.capacity_bytes);
+        allocate(&self.file, len, new_len)?;
+        self.len.store(new_len, Ordering::Release);
+        Ok(())
+    }
+}
+
+// Allocate the blocks of the file from its current length up to len. fallocate reserves the space
+// so later writes can't fail for lack of it, but not every filesystem supports it.
+#[cfg(all(target_os = "linux", not(miri)))]
+fn allocate(file: &File, from: u64, to: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            0,
+            from as libc::off_t,
+            (to - from) as libc::off_t,
+        )
+    };
+    if res == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+        return Err(err);
+    }
+    file.set_len(to)
+}
+
+#[cfg(not(all(target_os = "linux", not(miri))))]
+fn allocate(file: &File, _from: u64, to: u64) -> std::io::Result<()> {
+    file.set_len(to)
+}
+
+// Read len bytes at byte_offset through read, with the part past the end of the file filled with
+// zeros.
+fn read_zero_filled(
+    file_len: u64,
+    byte_offset: u64,
+    len: usize,
+    read: impl FnOnce(u64, usize) -> std::io::Result<Vec<u8>>,
+) -> std::io::Result<Vec<u8>> {
+    let available = file_len.saturating_sub(byte_offset).min(len as u64) as usize;
+    let mut buffer = if available > 0 {
+        read(byte_offset, available)?
+    } else {
+        Vec::new()
+    };
+    buffer.resize(len, 0);
+    Ok(buffer)
+}
+
+impl PersistentDevice for GrowableDevice {
+    fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
+        self.grow(pos.byte_offset() + data.size())?;
+        self.inner.write(pos, data, notify)
+    }
+
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        self.inner.process_completions()
+    }
+
+    fn process_watermark(&mut self) -> Option<Watermark> {
+        self.inner.process_watermark()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        self.inner.take_failure()
+    }
+
+    fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let file_len = self.len.load(Ordering::Acquire);
+        read_zero_filled(file_len, byte_offset, len, |offset, len| {
+            self.inner.read(offset, len)
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(Arc::new(GrowableReader {
+            inner: self.inner.reader()?,
+            len: self.len.clone(),
+        }))
+    }
+
+    fn write_sidecar(&mut self, byte_offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.inner.write_sidecar(byte_offset, data)
+    }
+
+    fn read_sidecar(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.inner.read_sidecar(byte_offset, len)
+    }
+}
+
+// Reads the file of a GrowableDevice, with the part past its end as zeros.
+struct GrowableReader {
+    inner: Arc<dyn DeviceReader>,
+    len: Arc<AtomicU64>,
+}
+
+impl DeviceReader for GrowableReader {
+    fn read(&self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        read_zero_filled(
+            self.len.load(Ordering::Acquire),
+            byte_offset,
+            len,
+            |offset, len| self.inner.read(offset, len),
+        )
+    }
+
+    // Only the part the file has grown to can be loaded.
+    fn prefetch(&self, byte_offset: u64, len: usize) -> std::io::Result<()> {
+        let end = (byte_offset + len as u64).min(self.len.load(Ordering::Acquire));
+        if end <= byte_offset {
+            return Ok(());
+        }
+        self.inner
+            .prefetch(byte_offset, (end - byte_offset) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::SyncDevice;
+    use crate::wal::{Wal, RING_START};
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_grow_in_chunks() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let file_len = || file.as_file().metadata().unwrap().len() / BLOCK_SIZE as u64;
+        file.as_file()
+            .set_len((RING_START + 1) * BLOCK_SIZE as u64)?;
+        let open = || -> std::io::Result<Wal> {
+            let dev = SyncDevice::new(file.path())?;
+            Wal::open_device(
+                Box::new(GrowableDevice::new(Box::new(dev), file.path(), 60, 8)?),
+                60,
+            )
+        };
+
+        let mut wal = open()?;
+        wal.append(b"one")?;
+        assert_eq!(file_len(), RING_START + 1);
+        wal.append(b"two")?;
+        assert_eq!(file_len(), 8);
+        // An entry that crosses the end of a chunk grows the file by as many chunks as it needs.
+        wal.append(&[3; 20 * BLOCK_SIZE as usize])?;
+        assert_eq!(file_len(), 32);
+        wal.process_completions().for_each(drop);
+        drop(wal);
+
+        // Recovery reads past the end of the file as zeros, where it finds the end of the ring.
+        let mut wal = open()?;
+        assert_eq!(wal.len(), 3);
+        // The file never grows past the capacity, even if that isn't a whole chunk.
+        while wal.head().rollover == 0 {
+            wal.append(&[4; BLOCK_SIZE as usize])?;
+        }
+        assert_eq!(file_len(), 60);
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+use crate::common::*;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+// lsn (8) + offset (8) + rollover (4)
+const INDEX_RECORD_SIZE: usize = 20;
+
+// Unpersisted records are appended to the file by the next process_completions once this many
+// accumulate.
+const FLUSH_THRESHOLD: usize = 64;
+
+/// LsnIndex is a sparse index from LSN to the position of the entry with that LSN. Only every
+/// interval'th LSN is recorded, a lookup finds the closest record before the LSN and scans forward
+/// from there. The index can be kept in a sidecar file so it doesn't need to be rebuilt at open.
+/// The file is written lazily, never from append, and anything missing from it is filled in from
+/// the wal.
+pub struct LsnIndex {
+    file: Option<File>,
+    interval: u64,
+    // Sorted by LSN.
+    records: Vec<(u64, WalPosition)>,
+    // Number of records that have been written to the file.
+    persisted: usize,
+}
+
+impl LsnIndex {
+    /// Keep the index in memory only. It is rebuilt from the wal when enabled.
+    pub fn in_memory(interval: u64) -> Self {
+        assert!(interval > 0, "interval must be positive");
+        LsnIndex {
+            file: None,
+            interval,
+            records: Vec::new(),
+            persisted: 0,
+        }
+    }
+
+    /// Open or create the index file at path. A torn record at the end of the file is ignored.
+    pub fn open(path: &Path, interval: u64) -> std::io::Result<Self> {
+        let mut index = Self::in_memory(interval);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        for record in buffer.chunks_exact(INDEX_RECORD_SIZE) {
+            let lsn = u64::from_le_bytes(record[..8].try_into().unwrap());
+            let offset = u64::from_le_bytes(record[8..16].try_into().unwrap());
+            let rollover = u32::from_le_bytes(record[16..20].try_into().unwrap());
+            index.records.push((lsn, WalPosition { offset, rollover }));
+        }
+        index.persisted = index.records.len();
+        let valid = (index.persisted * INDEX_RECORD_SIZE) as u64;
+        if valid != buffer.len() as u64 {
+            file.set_len(valid)?;
+        }
+        file.seek(std::io::SeekFrom::Start(valid))?;
+        index.file = Some(file);
+        Ok(index)
+    }
+
+    /// The last LSN recorded in the index.
+    pub fn last(&self) -> Option<(u64, WalPosition)> {
+        self.records.last().copied()
+    }
+
+    // Called for every entry appended to the wal.
+    pub(crate) fn record(&mut self, lsn: u64, pos: WalPosition) {
+        if !lsn.is_multiple_of(self.interval) {
+            return;
+        }
+        if let Some((last, _)) = self.last() {
+            if lsn <= last {
+                return;
+            }
+        }
+        self.records.push((lsn, pos));
+    }
+
+    // Whether enough records are waiting to be written to make a flush worthwhile.
+    pub(crate) fn needs_flush(&self) -> bool {
+        self.records.len() - self.persisted >= FLUSH_THRESHOLD
+    }
+
+    /// Returns the closest indexed record at or before lsn.
+    pub(crate) fn lookup(&self, lsn: u64) -> Option<(u64, WalPosition)> {
+        match self.records.binary_search_by_key(&lsn, |r| r.0) {
+            Ok(i) => Some(self.records[i]),
+            Err(0) => None,
+            Err(i) => Some(self.records[i - 1]),
+        }
+    }
+
+    /// Drop records for lsn and later. This is needed if the index is ahead of the recovered wal.
+    pub(crate) fn truncate_from(&mut self, lsn: u64) -> std::io::Result<()> {
+        let keep = self.records.partition_point(|r| r.0 < lsn);
+        if keep == self.records.len() {
+            return Ok(());
+        }
+        self.records.truncate(keep);
+        if keep < self.persisted {
+            self.persisted = keep;
+            if let Some(file) = self.file.as_mut() {
+                let len = (keep * INDEX_RECORD_SIZE) as u64;
+                file.set_len(len)?;
+                file.seek(std::io::SeekFrom::Start(len))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append any unpersisted records to the index file and sync it.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                self.persisted = self.records.len();
+                return Ok(());
+            }
+        };
+        if self.persisted == self.records.len() {
+            return Ok(());
+        }
+        let mut buffer =
+            Vec::with_capacity((self.records.len() - self.persisted) * INDEX_RECORD_SIZE);
+        for (lsn, pos) in &self.records[self.persisted..] {
+            buffer.extend_from_slice(&lsn.to_le_bytes());
+            buffer.extend_from_slice(&pos.offset.to_le_bytes());
+            buffer.extend_from_slice(&pos.rollover.to_le_bytes());
+        }
+        file.write_all(&buffer)?;
+        file.sync_data()?;
+        self.persisted = self.records.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::Wal;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_lsn_lookup() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let mut positions = Vec::new();
+        for i in 0..20u32 {
+            positions.push(wal.append(&i.to_le_bytes())?);
+        }
+        wal.enable_lsn_index(LsnIndex::in_memory(4))?;
+        for i in 20..40u32 {
+            positions.push(wal.append(&i.to_le_bytes())?);
+        }
+
+        for (lsn, pos) in positions.iter().enumerate() {
+            assert_eq!(wal.position_of(lsn as u64)?, Some(*pos));
+            assert_eq!(
+                wal.read_lsn(lsn as u64)?,
+                Some((lsn as u32).to_le_bytes().to_vec())
+            );
+        }
+        assert_eq!(wal.position_of(40)?, None);
+
+        // Truncated entries can't be found.
+        wal.truncate(positions[10])?;
+        assert_eq!(wal.position_of(9)?, None);
+        assert_eq!(wal.position_of(10)?, Some(positions[10]));
+
+        let tail: Vec<WalPosition> = wal
+            .iterate_from(positions[38])
+            .map(|e| e.unwrap().0)
+            .collect();
+        assert_eq!(tail, positions[38..]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_flush_from_completions() -> std::io::Result<()> {
+        let file = NamedTempFile::new()?;
+        let mut wal = Wal::open("mem:256".parse().unwrap())?;
+        wal.enable_lsn_index(LsnIndex::open(file.path(), 1)?)?;
+        for i in 0..FLUSH_THRESHOLD as u32 {
+            wal.append(&i.to_le_bytes())?;
+        }
+        // Appending never writes the index.
+        assert_eq!(file.as_file().metadata()?.len(), 0);
+
+        wal.process_completions().for_each(drop);
+        assert_eq!(
+            file.as_file().metadata()?.len(),
+            (FLUSH_THRESHOLD * INDEX_RECORD_SIZE) as u64
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_index_file() -> std::io::Result<()> {
+        let file = NamedTempFile::new()?;
+        let mut index = LsnIndex::open(file.path(), 2)?;
+        for lsn in 0..10 {
+            index.record(
+                lsn,
+                WalPosition {
+                    offset: lsn + 1,
+                    rollover: 0,
+                },
+            );
+        }
+        index.flush()?;
+        drop(index);
+
+        let mut index = LsnIndex::open(file.path(), 2)?;
+        assert_eq!(index.records.len(), 5);
+        assert_eq!(index.lookup(7).unwrap().0, 6);
+
+        index.truncate_from(6)?;
+        drop(index);
+        let index = LsnIndex::open(file.path(), 2)?;
+        assert_eq!(index.last().unwrap().0, 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+// The ioprio value packs the class into the top bits and the level into the bottom ones, see
+// ioprio_set(2).
+const IOPRIO_CLASS_SHIFT: u16 = 13;
+#[cfg(all(target_os = "linux", not(miri)))]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// The scheduling class of an IoPriority, as with ionice.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoPriorityClass {
+    /// Served before any other class. Needs CAP_SYS_ADMIN.
+    RealTime,
+    /// The class of I/O without a priority, ordered by level within it.
+    BestEffort,
+    /// Only served when the disk has nothing else to do.
+    Idle,
+}
+
+/// The priority the kernel gives the wal's I/O over other I/O to the same disk, so commits aren't
+/// held up behind checkpoints or compactions from other components. Within the real time and best
+/// effort classes, level 0 is served first and 7 last. Only Linux has I/O priorities, and only the
+/// schedulers that honour them, such as BFQ and mq-deadline, act on them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IoPriority {
+    pub class: IoPriorityClass,
+    pub level: u8,
+}
+
+impl IoPriority {
+    /// The value the kernel takes, with the level clamped to 0 to 7.
+    pub fn value(&self) -> u16 {
+        let class = match self.class {
+            IoPriorityClass::RealTime => 1,
+            IoPriorityClass::BestEffort => 2,
+            IoPriorityClass::Idle => 3,
+        };
+        (class << IOPRIO_CLASS_SHIFT) | self.level.min(7) as u16
+    }
+}
+
+/// Give the I/O the calling thread makes from now on priority. Devices that write from the thread
+/// that appends, such as SyncDevice, take the priority of that thread rather than
+/// WalOptions::io_priority, so this is how their writes are prioritized.
+#[cfg(all(target_os = "linux", not(miri)))]
+pub fn set_thread_io_priority(priority: IoPriority) -> std::io::Result<()> {
+    // Who 0 is the calling thread.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            priority.value() as libc::c_int,
+        )
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value() {
+        let priority = |class, level| IoPriority { class, level }.value();
+        assert_eq!(priority(IoPriorityClass::RealTime, 0), 1 << 13);
+        assert_eq!(priority(IoPriorityClass::BestEffort, 4), (2 << 13) | 4);
+        assert_eq!(priority(IoPriorityClass::Idle, 9), (3 << 13) | 7);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(miri)))]
+    fn test_set_thread_io_priority() -> std::io::Result<()> {
+        // Neither class needs privileges, and the test thread ends with the test.
+        std::thread::spawn(|| {
+            set_thread_io_priority(IoPriority {
+                class: IoPriorityClass::BestEffort,
+                level: 2,
+            })?;
+            set_thread_io_priority(IoPriority {
+                class: IoPriorityClass::Idle,
+                level: 0,
+            })
+        })
+        .join()
+        .unwrap()
+    }
+}
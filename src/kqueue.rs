@@ -3,10 +3,11 @@ use crate::common::WalPosition;
 use log::debug;
 use log::warn;
 
-use crate::common::PersistentDevice;
+use crate::common::{DeviceReader, PersistentDevice};
+use crate::sync::{is_transient, lock_exclusive, FileReader, MAX_WRITE_RETRIES};
 
 use libc::{self, c_void, F_NOCACHE, O_NONBLOCK, O_WRONLY};
-use std::ffi::c_int;
+use std::collections::VecDeque;
 use std::ffi::CString;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -15,217 +16,291 @@ use std::io::Seek;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::RawFd;
 use std::path::Path;
+use std::sync::Arc;
 
-// TODO: Figure out the right import for this
-pub const SIGEV_KEVENT: c_int = 3;
-pub const SIGIO: c_int = 23;
+// How long sync and drop wait in aio_suspend before checking the writes again.
+const SUSPEND_TIMEOUT: libc::timespec = libc::timespec {
+    tv_sec: 0,
+    tv_nsec: 10_000_000,
+};
 
-// Same as Linux implementation.
-struct CompletionData {
+// A write along with the AIO control block the kernel writes it through. It is boxed so the
+// control block doesn't move while the kernel holds it.
+struct AioRequest {
+    aio: libc::aiocb,
     wal_position: WalPosition,
     slice: AlignedSlice,
     notify: bool,
+    // The bytes written so far, more than zero after a short write.
+    written: usize,
+    // The number of times the write was submitted again after a transient error.
+    retries: u32,
 }
 
-/// KQueue uses kqueue and aio to write to the underlying device.
+// The control block only points into the buffer the request owns, and both move between threads
+// together with the device.
+unsafe impl Send for AioRequest {}
+
+/// KQueue writes to the underlying device with POSIX aio, so writes are submitted without a
+/// background thread. Select it with file:///path/to/file?device=kqueue. macOS doesn't deliver
+/// aio completions through kqueue, so they are collected by polling aio_error from
+/// process_completions, and sync waits for them with aio_suspend. The system limits how many aio
+/// requests a process can have outstanding, so writes beyond the limit are queued and submitted
+/// as earlier ones complete.
 pub struct KQueue {
     fd: RawFd,
-    kq: RawFd,
     file: File,
-}
-
-// Struct to hold completion data along with the AIO control block.
-struct AioRequest {
-    aio: libc::aiocb,
-    completion_data: CompletionData,
+    // Writes the kernel is working on. They stay boxed since the kernel holds their addresses.
+    #[allow(clippy::vec_box)]
+    in_flight: Vec<Box<AioRequest>>,
+    // Writes waiting for the kernel to accept more requests, submitted in order.
+    pending: VecDeque<Box<AioRequest>>,
+    // Completions reaped by sync that process_completions hasn't returned yet.
+    ready: Vec<WalPosition>,
 }
 
 impl KQueue {
     pub fn new(path: &Path) -> std::io::Result<Self> {
         let file = OpenOptions::new().read(true).open(path)?;
+        lock_exclusive(&file, path)?;
         let path = CString::new(path.as_os_str().as_bytes())?;
         let fd = unsafe { libc::open(path.as_ptr(), O_NONBLOCK | O_WRONLY | F_NOCACHE, 0o644) };
         if fd < 0 {
             return Err(std::io::Error::last_os_error());
         }
 
-        let kq = unsafe { libc::kqueue() };
-        if kq == -1 {
-            let err = std::io::Error::last_os_error();
-            unsafe { libc::close(fd) };
-            return Err(err);
+        Ok(KQueue {
+            fd,
+            file,
+            in_flight: Vec::new(),
+            pending: VecDeque::new(),
+            ready: Vec::new(),
+        })
+    }
+
+    // Submit the rest of the write. The request is handed back with the error if it can't be,
+    // which is EAGAIN when the kernel won't take more requests for now.
+    fn submit(
+        &mut self,
+        mut request: Box<AioRequest>,
+    ) -> Result<(), (Box<AioRequest>, std::io::Error)> {
+        let rest = &request.slice.as_bytes()[request.written..];
+        let aio = &mut request.aio;
+        aio.aio_fildes = self.fd;
+        aio.aio_offset = (request.wal_position.byte_offset() + request.written as u64) as i64;
+        aio.aio_buf = rest.as_ptr() as *mut c_void;
+        aio.aio_nbytes = rest.len();
+        aio.aio_sigevent.sigev_notify = libc::SIGEV_NONE;
+
+        if unsafe { libc::aio_write(&mut request.aio) } != 0 {
+            return Err((request, std::io::Error::last_os_error()));
+        }
+        self.in_flight.push(request);
+        Ok(())
+    }
+
+    // Submit the write, or queue it behind the pending writes if the kernel won't take it yet.
+    fn submit_or_queue(&mut self, request: Box<AioRequest>) -> std::io::Result<()> {
+        match self.submit(request) {
+            Err((request, e)) if e.raw_os_error() == Some(libc::EAGAIN) => {
+                self.pending.push_back(request);
+                Ok(())
+            }
+            res => res.map_err(|(_, e)| e),
+        }
+    }
+
+    // Submit the pending writes in order until the kernel won't take more.
+    fn submit_pending(&mut self) {
+        while let Some(request) = self.pending.pop_front() {
+            match self.submit(request) {
+                Ok(()) => {}
+                Err((request, e)) if e.raw_os_error() == Some(libc::EAGAIN) => {
+                    self.pending.push_front(request);
+                    break;
+                }
+                Err((request, e)) => warn!("Write at {:?} failed: {}", request.wal_position, e),
+            }
+        }
+    }
+
+    // Moves the completed writes to ready. Short writes and transient errors are submitted again,
+    // so only hard errors are dropped.
+    fn reap(&mut self) {
+        self.submit_pending();
+        for mut request in std::mem::take(&mut self.in_flight) {
+            let res = unsafe { libc::aio_error(&request.aio) };
+            if res == libc::EINPROGRESS {
+                self.in_flight.push(request);
+                continue;
+            }
+            // Releases the kernel's resources for the request, which aio_error doesn't.
+            let written = unsafe { libc::aio_return(&mut request.aio) };
+            let resubmit = match res {
+                0 if written > 0 => {
+                    request.written += written as usize;
+                    if request.written < request.slice.size() as usize {
+                        true
+                    } else {
+                        debug!("Completed write at {:?}", request.wal_position);
+                        if request.notify {
+                            self.ready.push(request.wal_position);
+                        }
+                        false
+                    }
+                }
+                0 => {
+                    warn!("Write at {:?} wrote nothing", request.wal_position);
+                    false
+                }
+                res if is_transient(res) && request.retries < MAX_WRITE_RETRIES => {
+                    request.retries += 1;
+                    true
+                }
+                res => {
+                    warn!(
+                        "Write at {:?} failed: {}",
+                        request.wal_position,
+                        std::io::Error::from_raw_os_error(res)
+                    );
+                    false
+                }
+            };
+            if resubmit {
+                let position = request.wal_position;
+                if let Err(e) = self.submit_or_queue(request) {
+                    warn!("Write at {:?} failed: {}", position, e);
+                }
+            }
         }
+    }
 
-        Ok(KQueue { fd, kq, file })
+    // Block until at least one write in flight completes or the timeout passes.
+    fn wait(&self) {
+        if self.in_flight.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            return;
+        }
+        let list: Vec<*const libc::aiocb> = self
+            .in_flight
+            .iter()
+            .map(|request| &request.aio as *const libc::aiocb)
+            .collect();
+        // An error, such as EINTR, just means looking at the writes again sooner.
+        unsafe { libc::aio_suspend(list.as_ptr(), list.len() as _, &SUSPEND_TIMEOUT) };
     }
 }
 
 impl Drop for KQueue {
+    // The kernel may still be writing from the buffers, so they can't be freed until it is done.
     fn drop(&mut self) {
-        unsafe {
-            libc::close(self.fd);
-            libc::close(self.kq);
+        self.pending.clear();
+        while !self.in_flight.is_empty() {
+            self.wait();
+            self.reap();
         }
+        unsafe { libc::close(self.fd) };
     }
 }
 
-const MAX_COMPLETIONS: usize = 1024;
-
 impl PersistentDevice for KQueue {
     fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
-        let completion_data = CompletionData {
+        let request = Box::new(AioRequest {
+            aio: unsafe { std::mem::zeroed() },
             wal_position: pos,
             slice: data,
             notify,
-        };
-
-        // Allocate AioRequest on the heap.
-        let aio_request = Box::new(AioRequest {
-            aio: unsafe { std::mem::zeroed() },
-            completion_data,
+            written: 0,
+            retries: 0,
         });
-
-        // Convert to raw pointer to manage ownership.
-        let aio_request_ptr = Box::into_raw(aio_request);
-
-        // Initialize the AIO control block. The aio struct is self-referencial which requires unsafe rust to accomplish.
-        unsafe {
-            (*aio_request_ptr).aio.aio_fildes = self.fd;
-            (*aio_request_ptr).aio.aio_offset = pos.byte_offset() as i64;
-            let mut event: libc::sigevent = std::mem::zeroed();
-            event.sigev_notify = SIGEV_KEVENT;
-            event.sigev_signo = SIGIO;
-            event.sigev_value = libc::sigval {
-                sival_ptr: aio_request_ptr as *mut c_void,
-            };
-
-            (*aio_request_ptr).aio.aio_sigevent = event;
-            (*aio_request_ptr).aio.aio_buf =
-                (*aio_request_ptr).completion_data.slice.buffer_ptr as *mut c_void;
-            (*aio_request_ptr).aio.aio_nbytes =
-                (*aio_request_ptr).completion_data.slice.size() as usize;
-            println!("{:#?}", (*aio_request_ptr).aio);
+        // Writes queued earlier go first.
+        if !self.pending.is_empty() {
+            self.pending.push_back(request);
+            return Ok(());
         }
+        self.submit_or_queue(request)
+    }
 
-        // Submit the aio_write.
-        let result = unsafe { libc::aio_write(&mut (*aio_request_ptr).aio) };
-        if result != 0 {
-            // Reclaim the Box on failure.
-            let _ = unsafe { Box::from_raw(aio_request_ptr) };
-            let err = Err(std::io::Error::last_os_error());
-            warn!("kevent error: {:?}", err);
-            return err;
-        }
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        self.reap();
+        std::mem::take(&mut self.ready).into_iter()
+    }
 
-        // Register the AIO event with kqueue
-        let mut kev = libc::kevent {
-            ident: self.fd as usize,
-            filter: libc::EVFILT_AIO,
-            flags: libc::EV_ADD | libc::EV_ENABLE,
-            fflags: 0,
-            data: 0,
-            udata: aio_request_ptr as *mut c_void,
-        };
-
-        let result = unsafe {
-            libc::kevent(
-                self.kq,
-                &mut kev as *mut libc::kevent,
-                1,
-                std::ptr::null_mut(),
-                0,
-                std::ptr::null(),
-            )
-        };
-
-        if result == -1 {
-            // Cancel the AIO request and clean up
-            unsafe { libc::aio_cancel(self.fd, &mut (*aio_request_ptr).aio) };
-            let _ = unsafe { Box::from_raw(aio_request_ptr) };
+    // Waits for every submitted write to complete, then flushes them to stable storage.
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.reap();
+        while !self.in_flight.is_empty() || !self.pending.is_empty() {
+            self.wait();
+            self.reap();
+        }
+        // F_FULLFSYNC also flushes the drive's cache, which fsync doesn't on macOS.
+        if unsafe { libc::fcntl(self.fd, libc::F_FULLFSYNC) } == -1 {
             return Err(std::io::Error::last_os_error());
         }
-
         Ok(())
     }
 
-    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
-        let mut completed_positions = Vec::new();
-        let mut events = vec![unsafe { std::mem::zeroed::<libc::kevent>() }; MAX_COMPLETIONS];
-
-        loop {
-            let nev = unsafe {
-                libc::kevent(
-                    self.kq,
-                    std::ptr::null(),
-                    0,
-                    events.as_mut_ptr(),
-                    events.len() as _,
-                    // Use non-blocking mode with zero timeout
-                    &libc::timespec {
-                        tv_sec: 0,
-                        tv_nsec: 0,
-                    },
-                )
-            };
-
-            if nev == -1 {
-                let err = std::io::Error::last_os_error();
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    // No more events available
-                    break;
-                }
-                warn!("kevent error: {}", err);
-                break;
-            }
+    fn read(&mut self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0; len];
+        self.file.seek(std::io::SeekFrom::Start(pos))?;
+        self.file.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
 
-            if nev == 0 {
-                // No events available
-                break;
-            }
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        FileReader::shared(&self.file)
+    }
+}
 
-            debug!("Found {nev} events");
-
-            for event in events.iter().take(nev as usize) {
-                if event.filter == libc::EVFILT_AIO {
-                    let aio_request_ptr = event.udata as *mut AioRequest;
-                    let mut aio_request = unsafe { Box::from_raw(aio_request_ptr) };
-
-                    let result = unsafe { libc::aio_error(&aio_request.aio) };
-                    if result == 0 {
-                        // Success case
-                        let bytes_written = unsafe { libc::aio_return(&mut aio_request.aio) };
-                        if bytes_written >= 0 && aio_request.completion_data.notify {
-                            debug!(
-                                "Completed write at {:?} ({} bytes)",
-                                aio_request.completion_data.wal_position, bytes_written
-                            );
-                            completed_positions.push(aio_request.completion_data.wal_position);
-                        }
-                    } else if result == libc::EINPROGRESS {
-                        // Still in progress, put it back
-                        let _ = Box::into_raw(aio_request);
-                        continue;
-                    } else {
-                        // Error case
-                        warn!(
-                            "AIO error for position {:?}: {}",
-                            aio_request.completion_data.wal_position,
-                            std::io::Error::from_raw_os_error(result)
-                        );
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::BLOCK_SIZE;
+    use crate::wal::Wal;
 
-                    // AlignedSlice will be dropped when aio_request goes out of scope
-                }
-            }
+    #[test]
+    fn test_writes() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        // More writes than a process can have outstanding, so some wait in pending.
+        let blocks = 64;
+        file.as_file().set_len(blocks as u64 * BLOCK_SIZE as u64)?;
+        let mut dev = KQueue::new(file.path())?;
+        for offset in 0..blocks {
+            let mut slice = AlignedSlice::new(BLOCK_SIZE as usize);
+            slice.as_slice()[0] = offset as u8;
+            let pos = WalPosition {
+                offset,
+                rollover: 0,
+            };
+            dev.write(pos, slice, true)?;
         }
+        dev.sync()?;
 
-        completed_positions.into_iter()
+        let mut completed: Vec<u64> = dev.process_completions().map(|pos| pos.offset).collect();
+        completed.sort();
+        assert_eq!(completed, (0..blocks).collect::<Vec<_>>());
+        assert_eq!(dev.read(0, 1)?, [0]);
+        assert_eq!(dev.read((blocks - 1) as u64 * BLOCK_SIZE as u64, 1)?, [63]);
+        Ok(())
     }
 
-    fn read(&mut self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
-        let mut buffer = vec![0; len];
-        self.file.seek(std::io::SeekFrom::Start(pos))?;
-        self.file.read_exact(&mut buffer)?;
-        Ok(buffer)
+    #[test]
+    fn test_wal() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let open = || Wal::open_device(Box::new(KQueue::new(file.path())?), 64);
+
+        let mut wal = open()?;
+        wal.append(b"one")?;
+        wal.append(&[7; 2 * BLOCK_SIZE as usize])?;
+        drop(wal);
+
+        let wal = open()?;
+        let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(
+            data,
+            vec![b"one".to_vec(), vec![7; 2 * BLOCK_SIZE as usize]]
+        );
+        Ok(())
     }
 }
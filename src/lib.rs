@@ -1,13 +1,62 @@
+pub mod archive;
+pub mod cdc;
 pub mod common;
+mod control;
+pub mod cursor;
+pub mod delimited;
+pub mod driver;
+mod failpoint;
+pub mod group;
+pub mod grow;
+pub mod index;
+pub mod ioprio;
 pub mod mem;
+#[cfg(any(test, feature = "testing"))]
+pub mod powerfail;
+mod primitives;
+pub mod reader;
+#[cfg(any(test, feature = "testing"))]
+pub mod recording;
+pub mod retention;
+pub mod rocksdb;
+pub mod rollover;
+pub mod shipper;
+pub mod sidecar;
+#[cfg(any(test, feature = "testing"))]
+pub mod sim;
+pub mod snapshot;
 pub mod sync;
+pub mod token;
+pub mod txn;
 pub mod wal;
+pub mod watch;
+pub mod workqueue;
 
-#[cfg(target_os = "linux")]
+#[cfg(feature = "arrow")]
+pub mod arrow_export;
+
+#[cfg(feature = "replication")]
+pub mod replication;
+
+// The native backends make system calls Miri can't run, so they are left out under Miri and files
+// use the SyncDevice.
+#[cfg(all(target_os = "linux", not(miri)))]
+pub mod dax;
+
+#[cfg(all(target_os = "linux", not(miri)))]
+pub mod dio;
+
+#[cfg(all(target_os = "linux", not(miri)))]
 pub mod uring;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "macos", not(miri)))]
 pub mod pwrite;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "macos", not(miri)))]
 pub mod kqueue;
+
+#[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+pub mod mmap;
+
+#[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+pub mod shared;
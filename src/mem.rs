@@ -1,23 +1,31 @@
-use log::info;
+use log::{info, warn};
 
 use crate::common::*;
+use crate::failpoint;
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+// The data of each write keyed by the block it starts at. Shared with the readers of the device.
+type Blocks = Arc<RwLock<HashMap<u64, Vec<u8>>>>;
 
 /// MemDevice is an in-memory implementation of PersistentDevice that
 /// holds the buffer in memory.
 pub struct MemDevice {
-    buffer: HashMap<u32, Vec<u8>>,
+    buffer: Blocks,
     completions: Vec<WalPosition>,
-    capacity_blocks: u32,
+    capacity_blocks: u64,
+    // The first error a write completed with that take_failure hasn't returned.
+    failure: Option<std::io::Error>,
 }
 
 impl MemDevice {
-    pub fn new(capacity_blocks: u32) -> Self {
+    pub fn new(capacity_blocks: u64) -> Self {
         info!("Initalizing mem device with capacity {}", capacity_blocks);
         Self {
-            buffer: HashMap::new(),
+            buffer: Blocks::default(),
             completions: Vec::new(),
             capacity_blocks,
+            failure: None,
         }
     }
 }
@@ -25,7 +33,7 @@ impl MemDevice {
 impl PersistentDevice for MemDevice {
     fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
         // Check if write would exceed capacity
-        let write_end = pos.offset + data.blocks;
+        let write_end = pos.offset + data.blocks();
         if write_end > self.capacity_blocks {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -34,8 +42,10 @@ impl PersistentDevice for MemDevice {
         }
 
         // Store the data in memory
-        let slice = unsafe { std::slice::from_raw_parts(data.buffer_ptr, data.size() as usize) };
-        self.buffer.insert(pos.offset, slice.to_vec());
+        self.buffer
+            .write()
+            .unwrap()
+            .insert(pos.offset, data.as_bytes().to_vec());
 
         // Track completion if requested
         if notify {
@@ -48,26 +58,70 @@ impl PersistentDevice for MemDevice {
     fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
         // Return the completions and clear the list
         let completions = std::mem::take(&mut self.completions);
-        completions.into_iter()
+        let failure = &mut self.failure;
+        completions
+            .into_iter()
+            .filter(|pos| match failpoint::check("mem::completion") {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Write at {:?} failed: {}", pos, e);
+                    failure.get_or_insert(e);
+                    false
+                }
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        self.failure.take()
+    }
+
+    // Nothing is lost when memory is all there is.
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 
     fn read(&mut self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
-        self.buffer
-            .get(&((pos / BLOCK_SIZE as u64) as u32))
-            .map(|data| {
-                if len > data.len() {
-                    Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Read length exceeds available data",
-                    ))
-                } else {
-                    Ok(data[..len].to_vec())
-                }
-            })
-            .unwrap_or_else(|| Ok(vec![0; len]))
+        read_blocks(&self.buffer, pos, len)
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(Arc::new(MemReader {
+            buffer: self.buffer.clone(),
+        }))
+    }
+}
+
+struct MemReader {
+    buffer: Blocks,
+}
+
+impl DeviceReader for MemReader {
+    fn read(&self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        read_blocks(&self.buffer, pos, len)
     }
 }
 
+// Reads are only served from the start of a write, anything else reads as zeros.
+fn read_blocks(buffer: &Blocks, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    buffer
+        .read()
+        .unwrap()
+        .get(&(pos / BLOCK_SIZE as u64))
+        .map(|data| {
+            if len > data.len() {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Read length exceeds available data",
+                ))
+            } else {
+                Ok(data[..len].to_vec())
+            }
+        })
+        .unwrap_or_else(|| Ok(vec![0; len]))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -100,11 +154,11 @@ mod tests {
 
         // Verify data was stored (only check first 16 bytes)
         assert_eq!(
-            &device.buffer.get(&pos1.offset).unwrap()[..16],
+            &device.buffer.read().unwrap().get(&pos1.offset).unwrap()[..16],
             b"hello\0\0\0\0\0\0\0\0\0\0\0"
         );
         assert_eq!(
-            &device.buffer.get(&pos2.offset).unwrap()[..16],
+            &device.buffer.read().unwrap().get(&pos2.offset).unwrap()[..16],
             b"world\0\0\0\0\0\0\0\0\0\0\0"
         );
 
@@ -0,0 +1,188 @@
+use crate::common::*;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+/// MappedDevice sends writes to another device and reads from a read-only mapping of the same
+/// file, so iterating over the wal, recovery in particular, needs no read system calls and the
+/// iterator parses headers straight out of the mapping. Writes keep going through the wrapped
+/// device and show up in the mapping once they reach the page cache.
+pub struct MappedDevice {
+    inner: Box<dyn PersistentDevice>,
+    map: Arc<Mapping>,
+}
+
+impl MappedDevice {
+    /// Wrap inner, which must write to the file at path.
+    pub fn new(inner: Box<dyn PersistentDevice>, path: &Path) -> std::io::Result<Self> {
+        Ok(MappedDevice {
+            inner,
+            map: Arc::new(Mapping::new(path)?),
+        })
+    }
+}
+
+impl PersistentDevice for MappedDevice {
+    fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
+        self.inner.write(pos, data, notify)
+    }
+
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        self.inner.process_completions()
+    }
+
+    fn process_watermark(&mut self) -> Option<Watermark> {
+        self.inner.process_watermark()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        self.inner.take_failure()
+    }
+
+    fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.map.read(byte_offset, len)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(self.map.clone())
+    }
+}
+
+// A shared read-only mapping of a whole file. The file can be closed once it is mapped.
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// The mapping is only ever read, and unmapped once the last reference is gone.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is empty and can't be mapped", path.display()),
+            ));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        // Only a hint, reading works the same without it.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mapping {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            len,
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DeviceReader for Mapping {
+    fn read(&self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        match self
+            .bytes()
+            .get(byte_offset as usize..byte_offset as usize + len)
+        {
+            Some(data) => Ok(data.to_vec()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("read of {len} bytes at {byte_offset} is past the end of the file"),
+            )),
+        }
+    }
+
+    // madvise needs a page aligned start, the end is rounded up by the kernel.
+    fn prefetch(&self, byte_offset: u64, len: usize) -> std::io::Result<()> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = (byte_offset as usize).min(self.len) / page * page;
+        let end = (byte_offset as usize + len).min(self.len);
+        if end <= start {
+            return Ok(());
+        }
+        let res = unsafe {
+            libc::madvise(
+                self.ptr.as_ptr().add(start) as *mut libc::c_void,
+                end - start,
+                libc::MADV_WILLNEED,
+            )
+        };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn mapped(&self) -> Option<&[u8]> {
+        Some(self.bytes())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::SyncDevice;
+    use crate::wal::Wal;
+
+    #[test]
+    fn test_mapped_reads() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let open = || -> std::io::Result<Wal> {
+            let sync = SyncDevice::new(file.path())?;
+            Wal::open_device(
+                Box::new(MappedDevice::new(Box::new(sync), file.path())?),
+                64,
+            )
+        };
+
+        let mut wal = open()?;
+        let first = wal.append(b"one")?;
+        wal.append(&[7; 2 * BLOCK_SIZE as usize])?;
+        // Entries are readable as soon as they are written.
+        assert_eq!(wal.read_at(first)?, b"one");
+        drop(wal);
+
+        let wal = open()?;
+        let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(
+            data,
+            vec![b"one".to_vec(), vec![7; 2 * BLOCK_SIZE as usize]]
+        );
+        let err = wal.reader().read_at(wal.head()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use crate::common::*;
+use std::sync::{Arc, Mutex};
+
+// A write that has been accepted but is still only in the volatile cache.
+struct CachedWrite {
+    pos: WalPosition,
+    data: Vec<u8>,
+    notify: bool,
+}
+
+struct Shared<D> {
+    inner: D,
+    rng: fastrand::Rng,
+    cache: Vec<CachedWrite>,
+    // Incremented on every power cut. Handles from an earlier generation no longer work.
+    generation: u64,
+}
+
+/// PowerFailDevice wraps a device with a volatile write cache. Writes are held in the cache until
+/// process_completions syncs them to the inner device, which is the only point the wal treats them
+/// as durable. power_cut throws away everything that was not synced, optionally tearing the last
+/// write, and returns a handle to reopen the wal on what survived.
+///
+/// Clones share the same device, so a test keeps one clone to cut the power while the wal owns the
+/// other.
+pub struct PowerFailDevice<D> {
+    shared: Arc<Mutex<Shared<D>>>,
+    generation: u64,
+}
+
+impl<D: PersistentDevice> Shared<D> {
+    // Moves the cached writes to the inner device.
+    fn flush_cache(&mut self) {
+        for write in std::mem::take(&mut self.cache) {
+            let mut aligned = AlignedSlice::new(write.data.len());
+            aligned.as_slice().copy_from_slice(&write.data);
+            if let Err(e) = self.inner.write(write.pos, aligned, write.notify) {
+                log::warn!("Failed to sync write at {:?}: {}", write.pos, e);
+            }
+        }
+    }
+}
+
+impl<D> Clone for PowerFailDevice<D> {
+    fn clone(&self) -> Self {
+        PowerFailDevice {
+            shared: self.shared.clone(),
+            generation: self.generation,
+        }
+    }
+}
+
+impl<D: PersistentDevice> PowerFailDevice<D> {
+    /// Wrap inner. The seed decides where torn writes are cut.
+    pub fn new(inner: D, seed: u64) -> Self {
+        PowerFailDevice {
+            shared: Arc::new(Mutex::new(Shared {
+                inner,
+                rng: fastrand::Rng::with_seed(seed),
+                cache: Vec::new(),
+                generation: 0,
+            })),
+            generation: 0,
+        }
+    }
+
+    /// The number of writes that would be lost if the power was cut now.
+    pub fn unsynced(&self) -> usize {
+        self.shared.lock().unwrap().cache.len()
+    }
+
+    /// Cut the power. Every write that was not synced is lost. If tear is set, the most recent
+    /// write is instead partially written, up to a random byte boundary, on top of what was there
+    /// before. Existing handles stop working, including the one owned by the wal, and the returned
+    /// handle should be passed to Wal::open_device to recover.
+    pub fn power_cut(&self, tear: bool) -> std::io::Result<Self> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.generation += 1;
+        let cache = std::mem::take(&mut shared.cache);
+        if let (true, Some(last)) = (tear, cache.last()) {
+            let cut = shared.rng.usize(1..last.data.len());
+            let mut torn = AlignedSlice::new(last.data.len());
+            let buffer = torn.as_slice();
+            buffer.copy_from_slice(&shared.inner.read(last.pos.byte_offset(), last.data.len())?);
+            buffer[..cut].copy_from_slice(&last.data[..cut]);
+            shared.inner.write(last.pos, torn, false)?;
+            for _ in shared.inner.process_completions() {}
+        }
+        Ok(PowerFailDevice {
+            shared: self.shared.clone(),
+            generation: shared.generation,
+        })
+    }
+
+    fn lock(&self) -> std::io::Result<std::sync::MutexGuard<'_, Shared<D>>> {
+        let shared = self.shared.lock().unwrap();
+        if shared.generation != self.generation {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "device lost power",
+            ));
+        }
+        Ok(shared)
+    }
+}
+
+impl<D: PersistentDevice + 'static> PersistentDevice for PowerFailDevice<D> {
+    fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
+        let mut shared = self.lock()?;
+        shared.cache.push(CachedWrite {
+            pos,
+            data: data.as_bytes().to_vec(),
+            notify,
+        });
+        Ok(())
+    }
+
+    // Syncs the cache to the inner device and returns its completions.
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        let mut shared = match self.lock() {
+            Ok(shared) => shared,
+            Err(_) => return Vec::new().into_iter(),
+        };
+        shared.flush_cache();
+        shared.inner.process_completions()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        let mut shared = self.lock()?;
+        shared.flush_cache();
+        shared.inner.sync()
+    }
+
+    fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        DeviceReader::read(self, byte_offset, len)
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(Arc::new(self.clone()))
+    }
+}
+
+impl<D: PersistentDevice> DeviceReader for PowerFailDevice<D> {
+    // Reads see the inner device with the cached writes applied on top, in the order written.
+    fn read(&self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut shared = self.lock()?;
+        let mut buffer = shared.inner.read(byte_offset, len)?;
+        let end = byte_offset + len as u64;
+        for write in &shared.cache {
+            let write_start = write.pos.byte_offset();
+            let write_end = write_start + write.data.len() as u64;
+            let start = write_start.max(byte_offset);
+            let stop = write_end.min(end);
+            if start < stop {
+                buffer[(start - byte_offset) as usize..(stop - byte_offset) as usize]
+                    .copy_from_slice(
+                        &write.data[(start - write_start) as usize..(stop - write_start) as usize],
+                    );
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemDevice;
+    use crate::wal::Wal;
+
+    fn entry(i: u32) -> Vec<u8> {
+        vec![i as u8; 100]
+    }
+
+    #[test]
+    fn test_power_cut() -> std::io::Result<()> {
+        for seed in 0..20 {
+            let device = PowerFailDevice::new(MemDevice::new(64), seed);
+            let mut wal = Wal::open_device(Box::new(device.clone()), 64)?;
+            // The writes lost in the power cut never complete.
+            wal.set_drain_timeout(std::time::Duration::ZERO);
+            let mut synced = Vec::new();
+            for i in 0..10 {
+                synced.push(wal.append(&entry(i))?);
+            }
+            assert_eq!(wal.process_completions().count(), 10);
+            // Tear a single unsynced entry so the tear is what recovery sees next.
+            let tear = seed % 2 == 0;
+            let unsynced = if tear { 1 } else { 5 };
+            for i in 10..10 + unsynced {
+                wal.append(&entry(i))?;
+            }
+            assert_eq!(device.unsynced(), unsynced as usize);
+
+            let device = device.power_cut(tear)?;
+            // The old wal can no longer reach the device.
+            assert!(wal.append(b"lost").is_err());
+            drop(wal);
+
+            let wal = Wal::open_device(Box::new(device), 64)?;
+            let recovered: Vec<(WalPosition, Vec<u8>)> =
+                wal.iterate().collect::<std::io::Result<_>>()?;
+            // A tear past the end of the entry's data leaves a complete entry behind.
+            assert!(recovered.len() == 10 || (tear && recovered.len() == 11));
+            for (i, (pos, data)) in recovered.iter().enumerate() {
+                if i < 10 {
+                    assert_eq!(*pos, synced[i]);
+                }
+                assert_eq!(*data, entry(i as u32));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+// Synchronization primitives used by code that is shared between threads. Under `--cfg loom` they
+// are replaced by the loom versions so the loom tests can explore every interleaving. Run them
+// with:
+//   RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+//
+// The models cover the Notifier behind Watch and the handoff to the completion thread of
+// WalDriver.
+
+#[cfg(loom)]
+pub(crate) use loom::{
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+
+#[cfg(not(loom))]
+pub(crate) use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
@@ -1,14 +1,24 @@
 use crate::common::*;
-use log::debug;
+use crate::sync::{lock_exclusive, pwrite_all, FileReader};
+use log::{debug, warn};
 //use crossbeam::channel::{self, TrySendError};
 use libc::{self, F_NOCACHE, O_WRONLY};
+use std::collections::VecDeque;
 use std::ffi::CString;
 use std::fs::OpenOptions;
 use std::io::{Read, Seek};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::sync::mpsc;
 use std::sync::mpsc::TrySendError;
+use std::sync::Arc;
+
+// The number of tasks that can wait for each worker thread.
+const QUEUE_DEPTH: usize = 1000;
+
+/// The number of worker threads unless the options ask for more.
+pub const DEFAULT_WORKERS: usize = 1;
 
 struct CompletionData {
     wal_position: WalPosition,
@@ -16,65 +26,142 @@ struct CompletionData {
     notify: bool,
 }
 
-/// MacOsAsyncIO uses a background thread with pwrite and F_NOCACHE for direct I/O
+// Work for a worker thread, done in the order it is sent.
+enum Task {
+    Write(CompletionData),
+    // Reply once the writes sent before it have been written.
+    Drain(mpsc::Sender<()>),
+}
+
+// The descriptor the workers write through, closed once the device and every worker are done.
+struct WriteFd(RawFd);
+
+impl Drop for WriteFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+// A worker thread and the writes waiting for room in its task queue, which are sent in order
+// before any later task.
+struct Worker {
+    sender: mpsc::SyncSender<Task>,
+    pending: VecDeque<Task>,
+}
+
+impl Worker {
+    // Sends the pending writes until the task queue is full. The rest wait for the next call.
+    fn send_pending(&mut self) -> std::io::Result<()> {
+        while let Some(task) = self.pending.pop_front() {
+            match self.sender.try_send(task) {
+                Ok(()) => {}
+                Err(TrySendError::Full(task)) => {
+                    self.pending.push_front(task);
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(disconnected()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// MacOsAsyncIO writes with pwrite and F_NOCACHE for direct I/O from a pool of background threads.
+/// A single thread caps the throughput of fast SSDs, so writes are spread over the workers by
+/// position. Writes to the same position always go to the same worker, so they are applied in the
+/// order they were made, while writes to different positions complete in any order.
 pub struct MacOsAsyncIO {
-    task_sender: mpsc::SyncSender<CompletionData>,
-    completion_receiver: mpsc::Receiver<WalPosition>,
-    // Only used for startup reads.
+    workers: Vec<Worker>,
+    // Each write that asks for it, or the error it failed with.
+    completion_receiver: mpsc::Receiver<std::io::Result<WalPosition>>,
+    // The first write error or lost worker that take_failure hasn't returned.
+    failure: Option<std::io::Error>,
+    fd: Arc<WriteFd>,
+    // Used for reads, writes go through the workers.
     file: std::fs::File,
 }
 
 impl MacOsAsyncIO {
+    /// Open the file at path with DEFAULT_WORKERS worker threads.
     pub fn new(path: &Path) -> std::io::Result<Self> {
+        Self::with_workers(path, DEFAULT_WORKERS)
+    }
+
+    /// Open the file at path with the given number of worker threads, which must be at least one.
+    pub fn with_workers(path: &Path, workers: usize) -> std::io::Result<Self> {
+        if workers == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "at least one worker is needed",
+            ));
+        }
         let file = OpenOptions::new().read(true).open(path)?;
+        lock_exclusive(&file, path)?;
         let path = CString::new(path.as_os_str().as_bytes())?;
-        // Only used for starup reads.
 
         // Open file with write-only mode and NOCACHE,
         let fd = unsafe { libc::open(path.as_ptr(), O_WRONLY | F_NOCACHE, 0o644) };
         if fd < 0 {
             return Err(std::io::Error::last_os_error());
         }
+        let fd = Arc::new(WriteFd(fd));
 
-        // Create communication channels
-        let (task_sender, task_receiver) = mpsc::sync_channel::<CompletionData>(1000);
-        let (completion_sender, completion_receiver) = mpsc::channel::<WalPosition>();
-
-        // Spawn worker thread that owns the file descriptor
-        std::thread::spawn(move || {
-            // Main worker loop
-            while let Ok(data) = task_receiver.recv() {
-                let res = unsafe {
-                    libc::pwrite(
-                        fd,
-                        data.slice.buffer_ptr as *const libc::c_void,
-                        data.slice.size() as usize,
-                        data.wal_position.byte_offset() as i64,
-                    )
-                };
-
-                // Handle completion notification
-                if res >= 0 && data.notify {
-                    debug!("pwrite completed at {:?}", data.wal_position);
-                    let _ = completion_sender.send(data.wal_position);
+        let (completion_sender, completion_receiver) = mpsc::channel();
+        let workers = (0..workers)
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel::<Task>(QUEUE_DEPTH);
+                let completion_sender = completion_sender.clone();
+                let fd = fd.clone();
+                std::thread::spawn(move || run_worker(&fd, receiver, completion_sender));
+                Worker {
+                    sender,
+                    pending: VecDeque::new(),
                 }
-
-                // Explicitly drop the AlignedSlice to release resources
-                drop(data.slice);
-            }
-
-            // Cleanup: close file descriptor when done
-            unsafe { libc::close(fd) };
-        });
+            })
+            .collect();
 
         Ok(Self {
-            file,
-            task_sender,
+            workers,
             completion_receiver,
+            failure: None,
+            fd,
+            file,
         })
     }
 }
 
+// Runs until the device is dropped. Each worker holds a reference to the descriptor, so it is
+// closed by whichever of them finishes last.
+fn run_worker(
+    fd: &WriteFd,
+    tasks: mpsc::Receiver<Task>,
+    completion_sender: mpsc::Sender<std::io::Result<WalPosition>>,
+) {
+    while let Ok(task) = tasks.recv() {
+        let data = match task {
+            Task::Write(data) => data,
+            Task::Drain(reply) => {
+                let _ = reply.send(());
+                continue;
+            }
+        };
+        let res = pwrite_all(fd.0, data.slice.as_bytes(), data.wal_position.byte_offset());
+
+        // Handle completion notification
+        match res {
+            Ok(()) if data.notify => {
+                debug!("pwrite completed at {:?}", data.wal_position);
+                let _ = completion_sender.send(Ok(data.wal_position));
+            }
+            Ok(()) => {}
+            Err(e) => {
+                warn!("Write at {:?} failed: {}", data.wal_position, e);
+                let _ = completion_sender.send(Err(e));
+            }
+        }
+    }
+}
+
 impl PersistentDevice for MacOsAsyncIO {
     fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
         let data = CompletionData {
@@ -82,32 +169,114 @@ impl PersistentDevice for MacOsAsyncIO {
             slice: data,
             notify,
         };
-
-        self.task_sender.try_send(data).map_err(|e| match e {
-            TrySendError::Full(_) => {
-                std::io::Error::new(std::io::ErrorKind::WouldBlock, "task queue full")
-            }
-            TrySendError::Disconnected(_) => {
-                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "worker thread disconnected")
-            }
-        })
+        let index = pos.offset as usize % self.workers.len();
+        let worker = &mut self.workers[index];
+        worker.pending.push_back(Task::Write(data));
+        worker.send_pending()
     }
 
     fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        for worker in self.workers.iter_mut() {
+            if let Err(e) = worker.send_pending() {
+                // The writes waiting for the worker will never be made.
+                warn!("Failed to send writes: {}", e);
+                self.failure.get_or_insert(e);
+            }
+        }
         let mut completions = Vec::new();
 
         // Drain all available completion notifications
-        while let Ok(pos) = self.completion_receiver.try_recv() {
-            completions.push(pos);
+        while let Ok(res) = self.completion_receiver.try_recv() {
+            match res {
+                Ok(pos) => completions.push(pos),
+                Err(e) => {
+                    self.failure.get_or_insert(e);
+                }
+            }
         }
 
         completions.into_iter()
     }
 
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        self.failure.take()
+    }
+
+    // Every worker drains the writes sent to it before the file is synced, so they are all on
+    // disk when it returns.
+    fn sync(&mut self) -> std::io::Result<()> {
+        let (reply, drained) = mpsc::channel();
+        for worker in self.workers.iter_mut() {
+            for task in worker.pending.drain(..) {
+                worker.sender.send(task).map_err(|_| disconnected())?;
+            }
+            worker
+                .sender
+                .send(Task::Drain(reply.clone()))
+                .map_err(|_| disconnected())?;
+        }
+        for _ in 0..self.workers.len() {
+            drained.recv().map_err(|_| disconnected())?;
+        }
+        // F_FULLFSYNC also flushes the drive's cache, which fsync doesn't on macOS.
+        if unsafe { libc::fcntl(self.fd.0, libc::F_FULLFSYNC) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     fn read(&mut self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
         let mut buffer = vec![0; len];
         self.file.seek(std::io::SeekFrom::Start(pos))?;
         self.file.read_exact(&mut buffer)?;
         Ok(buffer)
     }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        FileReader::shared(&self.file)
+    }
+}
+
+fn disconnected() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "worker thread disconnected")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workers() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(16 * BLOCK_SIZE as u64)?;
+        let mut dev = MacOsAsyncIO::with_workers(file.path(), 4)?;
+        for offset in 0..16 {
+            let mut slice = AlignedSlice::new(BLOCK_SIZE as usize);
+            slice.as_slice()[0] = offset as u8;
+            let pos = WalPosition {
+                offset,
+                rollover: 0,
+            };
+            dev.write(pos, slice, true)?;
+        }
+        // Writes to the same position are applied in order.
+        let mut slice = AlignedSlice::new(BLOCK_SIZE as usize);
+        slice.as_slice()[0] = 100;
+        dev.write(WalPosition::default(), slice, false)?;
+        dev.sync()?;
+
+        let mut completed: Vec<u64> = dev.process_completions().map(|pos| pos.offset).collect();
+        completed.sort();
+        assert_eq!(completed, (0..16).collect::<Vec<_>>());
+        assert_eq!(dev.read(0, 1)?, [100]);
+        assert_eq!(dev.read(15 * BLOCK_SIZE as u64, 1)?, [15]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_workers() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let err = MacOsAsyncIO::with_workers(file.path(), 0).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
 }
@@ -0,0 +1,167 @@
+use crate::common::*;
+use crate::primitives::{Arc, Mutex};
+use crate::wal::{
+    check_range, open_entry, read_entries, read_entry, verify_entry, BlockLayout, EntryMeta,
+    EntryReader, WalIterator,
+};
+
+// The live region of the wal.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Bounds {
+    pub tail: WalPosition,
+    pub head: WalPosition,
+}
+
+/// WalReader reads the entries of a wal through a shared reference, so it can be moved to another
+/// thread, for example to stream the log to a replica, while the owner of the wal keeps appending.
+/// Each call sees the live region as of the last append or truncation. With devices that write in
+/// the background an entry can be visible before its write has landed, in which case it reads as
+/// damaged and should be retried from the same position later.
+#[derive(Clone)]
+pub struct WalReader {
+    pub(crate) dev: std::sync::Arc<dyn DeviceReader>,
+    pub(crate) bounds: Arc<Mutex<Bounds>>,
+    pub(crate) capacity: u64,
+    pub(crate) layout: BlockLayout,
+}
+
+impl WalReader {
+    /// The position the next entry is appended at.
+    pub fn head(&self) -> WalPosition {
+        self.bounds().head
+    }
+
+    /// The position of the oldest live entry, or the head if there are none.
+    pub fn tail(&self) -> WalPosition {
+        self.bounds().tail
+    }
+
+    /// Iterate over the live entries from the tail to the current head.
+    pub fn iterate(&self) -> WalIterator<'_> {
+        let bounds = self.bounds();
+        WalIterator::new(&*self.dev, bounds.tail, bounds.head, self.capacity)
+            .with_layout(self.layout)
+    }
+
+    /// Iterate from position, or from the tail if position has been truncated, to the current head.
+    pub fn iterate_from(&self, position: WalPosition) -> WalIterator<'_> {
+        let bounds = self.bounds();
+        let start = position.max(bounds.tail);
+        WalIterator::new(&*self.dev, start, bounds.head, self.capacity).with_layout(self.layout)
+    }
+
+    /// Iterate over the entries from start up to end, see Wal::read_range.
+    pub fn read_range(
+        &self,
+        start: WalPosition,
+        end: WalPosition,
+    ) -> std::io::Result<WalIterator<'_>> {
+        check_range(start, end, self.bounds())?;
+        Ok(WalIterator::new(&*self.dev, start, end, self.capacity).with_layout(self.layout))
+    }
+
+    /// Read the data of the live entry at position.
+    pub fn read_at(&self, position: WalPosition) -> std::io::Result<Vec<u8>> {
+        read_entry(
+            &*self.dev,
+            position,
+            self.bounds(),
+            self.capacity,
+            self.layout,
+        )
+    }
+
+    /// Read the data of the live entries at positions, see Wal::read_many.
+    pub fn read_many<F: FnMut(WalPosition, std::io::Result<Vec<u8>>)>(
+        &self,
+        positions: &[WalPosition],
+        mut done: F,
+    ) {
+        read_entries(
+            &*self.dev,
+            positions,
+            self.bounds(),
+            self.capacity,
+            self.layout,
+            &mut done,
+        )
+    }
+
+    /// Open the live entry at position for reading its payload a piece at a time, see
+    /// Wal::entry_reader.
+    pub fn entry_reader(&self, position: WalPosition) -> std::io::Result<EntryReader<'_>> {
+        open_entry(
+            &*self.dev,
+            position,
+            self.bounds(),
+            self.capacity,
+            self.layout,
+        )
+    }
+
+    /// Check the live entry at position against its CRC, see Wal::verify_at.
+    pub fn verify_at(&self, position: WalPosition) -> std::io::Result<EntryMeta> {
+        verify_entry(
+            &*self.dev,
+            position,
+            self.bounds(),
+            self.capacity,
+            self.layout,
+        )
+    }
+
+    fn bounds(&self) -> Bounds {
+        *self.bounds.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::Wal;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_read_while_appending() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let reader = wal.reader();
+        let (tx, rx) = mpsc::channel::<WalPosition>();
+
+        let tailer = std::thread::spawn(move || -> std::io::Result<Vec<Vec<u8>>> {
+            let mut next = reader.tail();
+            let mut received = Vec::new();
+            // Each position sent is the head after an append, later appends may be seen too.
+            for head in rx {
+                let mut iter = reader.iterate_from(next);
+                for entry in iter.by_ref() {
+                    received.push(entry?.1);
+                }
+                next = iter.position();
+                assert!(next >= head);
+            }
+            Ok(received)
+        });
+
+        let mut first = None;
+        for i in 0..10u8 {
+            let pos = wal.append(&[i; 100])?;
+            first.get_or_insert(pos);
+            tx.send(wal.head()).unwrap();
+        }
+        drop(tx);
+
+        let received = tailer.join().unwrap()?;
+        assert_eq!(received.len(), 10);
+        assert_eq!(received[3], vec![3; 100]);
+
+        // Truncation is visible to the reader too.
+        let reader = wal.reader();
+        assert_eq!(reader.read_at(first.unwrap())?, vec![0; 100]);
+        wal.truncate(wal.head())?;
+        assert_eq!(reader.tail(), wal.head());
+        let err = reader.read_at(first.unwrap()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(reader.iterate().count(), 0);
+        Ok(())
+    }
+}
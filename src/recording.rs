@@ -0,0 +1,152 @@
+use crate::common::*;
+use std::sync::{Arc, Mutex};
+
+/// An operation seen by a RecordingDevice.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A write was submitted.
+    Write {
+        pos: WalPosition,
+        blocks: u64,
+        notify: bool,
+    },
+    /// process_completions was called on the device. Whether that syncs depends on the device.
+    ProcessCompletions,
+    /// sync was called on the device.
+    Sync,
+    /// A completion was returned from process_completions.
+    Complete(WalPosition),
+}
+
+/// Trace is a handle to the events recorded by a RecordingDevice. It stays usable after the
+/// device has been moved into a wal.
+#[derive(Clone, Default)]
+pub struct Trace {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Trace {
+    /// A copy of every event recorded so far, in order.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Only the write events.
+    pub fn writes(&self) -> Vec<Event> {
+        self.events()
+            .into_iter()
+            .filter(|e| matches!(e, Event::Write { .. }))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    fn push(&self, event: Event) {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+/// RecordingDevice wraps a device and records the exact sequence of writes, process_completions
+/// calls and completions that pass through it, so tests can assert ordering properties of the wal.
+pub struct RecordingDevice<D> {
+    inner: D,
+    trace: Trace,
+}
+
+impl<D: PersistentDevice> RecordingDevice<D> {
+    pub fn new(inner: D) -> Self {
+        RecordingDevice {
+            inner,
+            trace: Trace::default(),
+        }
+    }
+
+    pub fn trace(&self) -> Trace {
+        self.trace.clone()
+    }
+}
+
+impl<D: PersistentDevice> PersistentDevice for RecordingDevice<D> {
+    fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
+        let blocks = data.blocks();
+        self.inner.write(pos, data, notify)?;
+        self.trace.push(Event::Write {
+            pos,
+            blocks,
+            notify,
+        });
+        Ok(())
+    }
+
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        self.trace.push(Event::ProcessCompletions);
+        let completions: Vec<WalPosition> = self.inner.process_completions().collect();
+        for pos in &completions {
+            self.trace.push(Event::Complete(*pos));
+        }
+        completions.into_iter()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.trace.push(Event::Sync);
+        self.inner.sync()
+    }
+
+    fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.inner.read(byte_offset, len)
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        self.inner.reader()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::MemDevice;
+    use crate::wal::Wal;
+
+    #[test]
+    fn test_padding_precedes_rollover() -> std::io::Result<()> {
+        let device = RecordingDevice::new(MemDevice::new(5));
+        let trace = device.trace();
+        let mut wal = Wal::open_device(Box::new(device), 5)?;
+        wal.append(b"one")?;
+        wal.append(b"two")?;
+        trace.clear();
+
+        // Doesn't fit in the last block, so the end of the ring is padded first.
+        let pos = wal.append(&[0; 5000])?;
+        assert_eq!(
+            trace.writes(),
+            vec![
+                Event::Write {
+                    pos: WalPosition {
+                        offset: 4,
+                        rollover: 0
+                    },
+                    blocks: 1,
+                    notify: true,
+                },
+                Event::Write {
+                    pos,
+                    blocks: 2,
+                    notify: true,
+                },
+            ]
+        );
+        assert_eq!(pos.rollover, 1);
+
+        // Only the entries the caller appended are completed.
+        trace.clear();
+        assert_eq!(wal.process_completions().count(), 3);
+        assert_eq!(trace.events()[0], Event::ProcessCompletions);
+        assert!(trace.events()[1..]
+            .iter()
+            .all(|e| matches!(e, Event::Complete(_))));
+        Ok(())
+    }
+}
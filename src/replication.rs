@@ -0,0 +1,456 @@
+use crate::archive::{decode_records, encode_record, ArchivedEntry};
+use crate::common::*;
+use crate::wal::Wal;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+// The cursor each follower holds in the leader's wal is named with this prefix.
+const CURSOR_PREFIX: &str = "replication/";
+
+// The shipping state of a follower of a Leader.
+struct FollowerState {
+    // The position of the next entry to ship.
+    next: WalPosition,
+    // The entries shipped and not yet acknowledged, with the position after each.
+    shipped: VecDeque<(u64, WalPosition)>,
+    // Every entry before this position has been acknowledged.
+    acked: WalPosition,
+}
+
+/// When Leader::process_completions reports an entry durable.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum AckPolicy {
+    /// Once it is durable in the leader's wal.
+    #[default]
+    Local,
+    /// Once it is durable in a majority of the leader and its followers.
+    Quorum,
+    /// Once every follower has acknowledged it.
+    All,
+}
+
+/// Leader ships the durable entries of a wal to its followers and tracks how far each has
+/// acknowledged them. A shipment is a run of the records archive segments are made of, so it can
+/// be sent over any transport and handed to Follower::receive on the other end. The transport is up
+/// to the application: Leader only decides what to send next and what has been acknowledged.
+///
+/// Every follower keeps a named cursor in the leader's wal at the first entry it hasn't
+/// acknowledged, see Wal::ack. If the ring overwrites entries a follower is still waiting for,
+/// shipping to that follower fails until it is added again.
+#[derive(Default)]
+pub struct Leader {
+    followers: BTreeMap<String, FollowerState>,
+    policy: AckPolicy,
+    // The entries durable in the leader's wal that the policy is holding back.
+    held: BTreeSet<WalPosition>,
+}
+
+impl Leader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a follower, or reset one shipping was already tracked for, that has applied every entry
+    /// up to the LSN applied, or none with None, as returned by Follower::applied. Shipping starts
+    /// with the entry after it, or at the tail if it has been truncated.
+    pub fn add_follower(
+        &mut self,
+        wal: &mut Wal,
+        name: &str,
+        applied: Option<u64>,
+    ) -> std::io::Result<()> {
+        let next = match applied {
+            Some(lsn) if lsn + 1 >= wal.next_lsn() => wal.head(),
+            Some(lsn) => match wal.position_of(lsn + 1)? {
+                Some(pos) => pos,
+                None => {
+                    warn!("Entries after {lsn} were truncated before they were shipped to {name}");
+                    wal.tail()
+                }
+            },
+            None => wal.tail(),
+        };
+        let cursor = format!("{CURSOR_PREFIX}{name}");
+        // The cursor can only move forward, so it is recreated for a follower that is behind it.
+        if wal.cursor(&cursor).is_some_and(|pos| pos > next) {
+            wal.remove_cursor(&cursor)?;
+        }
+        wal.ack(&cursor, next)?;
+        self.followers.insert(
+            name.to_string(),
+            FollowerState {
+                next,
+                shipped: VecDeque::new(),
+                acked: next,
+            },
+        );
+        Ok(())
+    }
+
+    /// Stop shipping to the follower and remove its cursor from the wal.
+    pub fn remove_follower(&mut self, wal: &mut Wal, name: &str) -> std::io::Result<()> {
+        self.followers.remove(name);
+        wal.remove_cursor(&format!("{CURSOR_PREFIX}{name}"))
+    }
+
+    /// The names of the followers.
+    pub fn followers(&self) -> impl Iterator<Item = &str> {
+        self.followers.keys().map(String::as_str)
+    }
+
+    /// Encode the durable entries that haven't been shipped to the follower yet, stopping once
+    /// max_bytes of payload have been, though always shipping at least one entry. Returns an empty
+    /// shipment if there is nothing new. Fails with NotFound if the entries the follower needs next
+    /// have been overwritten.
+    pub fn ship(&mut self, wal: &Wal, name: &str, max_bytes: usize) -> std::io::Result<Vec<u8>> {
+        let follower = self.follower(name)?;
+        if follower.next < wal.tail() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "entries from {:?} to {:?} were overwritten before they were shipped to {name}",
+                    follower.next,
+                    wal.tail()
+                ),
+            ));
+        }
+        // A follower catching up reads a long run of entries, have the device load them up front.
+        wal.prefetch(follower.next..wal.durable_head())?;
+        let mut iter = wal.iterate_durable(follower.next);
+        let mut shipment = Vec::new();
+        let mut payload = 0;
+        while shipment.is_empty() || payload < max_bytes {
+            let entry = match iter.next() {
+                Some(entry) => entry?,
+                None => break,
+            };
+            payload += entry.payload.len();
+            let lsn = entry.lsn;
+            let record = ArchivedEntry {
+                lsn,
+                timestamp: entry.timestamp,
+                stream: entry.stream,
+                payload: entry.payload,
+            };
+            encode_record(&record, &mut shipment);
+            follower.next = iter.position();
+            follower.shipped.push_back((lsn, follower.next));
+        }
+        Ok(shipment)
+    }
+
+    /// Ship again everything the follower hasn't acknowledged, for when shipments may have been
+    /// lost in transit. The follower skips the entries it already applied.
+    pub fn rewind(&mut self, name: &str) -> std::io::Result<()> {
+        let follower = self.follower(name)?;
+        follower.next = follower.acked;
+        follower.shipped.clear();
+        Ok(())
+    }
+
+    /// Record that the follower has made every entry up to the LSN lsn durable, as returned by
+    /// Follower::durable, and move its cursor in the wal past them.
+    pub fn acknowledge(&mut self, wal: &mut Wal, name: &str, lsn: u64) -> std::io::Result<()> {
+        let follower = self.follower(name)?;
+        let mut acked = follower.acked;
+        while let Some(&(shipped, next)) = follower.shipped.front() {
+            if shipped > lsn {
+                break;
+            }
+            acked = next;
+            follower.shipped.pop_front();
+        }
+        if acked > follower.acked {
+            follower.acked = acked;
+            wal.ack(&format!("{CURSOR_PREFIX}{name}"), acked)?;
+        }
+        Ok(())
+    }
+
+    /// The position before which the follower has acknowledged every entry.
+    pub fn acknowledged(&self, name: &str) -> Option<WalPosition> {
+        self.followers.get(name).map(|f| f.acked)
+    }
+
+    /// The position before which every follower has acknowledged every entry, or None if there
+    /// are no followers.
+    pub fn replicated(&self) -> Option<WalPosition> {
+        self.followers.values().map(|f| f.acked).min()
+    }
+
+    /// Set when process_completions reports an entry durable. Applies to the entries it holds
+    /// back as well as to later ones.
+    pub fn set_ack_policy(&mut self, policy: AckPolicy) {
+        self.policy = policy;
+    }
+
+    /// The position before which every entry is durable according to the policy, or None if the
+    /// policy is satisfied as soon as an entry is durable in the leader's wal.
+    pub fn policy_durable(&self) -> Option<WalPosition> {
+        let mut acked: Vec<WalPosition> = self.followers.values().map(|f| f.acked).collect();
+        // The leader counts towards a quorum.
+        let needed = match self.policy {
+            AckPolicy::Local => 0,
+            AckPolicy::Quorum => acked.len().div_ceil(2),
+            AckPolicy::All => acked.len(),
+        };
+        if needed == 0 {
+            return None;
+        }
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        Some(acked[needed - 1])
+    }
+
+    /// Process the completions of the wal and return the entries that are now durable according to
+    /// the policy, in place of calling process_completions on the wal. The entries durable in the
+    /// wal are held back until enough followers have acknowledged them. A Watch of the wal still
+    /// reports entries once they are durable in the leader's wal.
+    pub fn process_completions(&mut self, wal: &mut Wal) -> Vec<WalPosition> {
+        self.held.extend(wal.process_completions());
+        let held = match self.policy_durable() {
+            Some(durable) => self.held.split_off(&durable),
+            None => BTreeSet::new(),
+        };
+        std::mem::replace(&mut self.held, held)
+            .into_iter()
+            .collect()
+    }
+
+    fn follower(&mut self, name: &str) -> std::io::Result<&mut FollowerState> {
+        self.followers.get_mut(name).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("unknown follower {name}"),
+            )
+        })
+    }
+}
+
+/// Follower applies the shipments of a Leader to its own wal. Each entry is appended to the same
+/// stream with the leader's LSN plus one as its idempotency key, so an entry shipped twice is only
+/// appended once, and the last entry applied is found again when the follower's wal is reopened.
+/// Named streams must be created in the follower's wal in the same order as in the leader's so
+/// their ids match. The follower's wal must not be appended to other than through the follower.
+pub struct Follower {
+    wal: Wal,
+    // The LSN of the last entry appended.
+    applied: Option<u64>,
+    // The entries appended that may not be durable yet, with their LSN in the leader.
+    pending: VecDeque<(WalPosition, u64)>,
+    // The LSN of the last entry that every entry before it is durable up to.
+    durable: Option<u64>,
+}
+
+impl Follower {
+    /// Apply shipments to wal, continuing after the entries already in it.
+    pub fn new(wal: Wal) -> std::io::Result<Self> {
+        let mut applied = None;
+        for entry in wal.iterate_with_meta() {
+            if let Some(key) = entry?.key {
+                applied = Some(key - 1);
+            }
+        }
+        Ok(Follower {
+            wal,
+            applied,
+            pending: VecDeque::new(),
+            durable: applied,
+        })
+    }
+
+    /// The LSN of the last entry applied, to pass to Leader::add_follower.
+    pub fn applied(&self) -> Option<u64> {
+        self.applied
+    }
+
+    /// Append the entries of a shipment that haven't been applied yet. Fails with InvalidData if the
+    /// shipment is damaged or doesn't continue from the last entry applied.
+    pub fn receive(&mut self, shipment: &[u8]) -> std::io::Result<()> {
+        for entry in decode_records(shipment, "shipment")? {
+            match self.applied {
+                Some(applied) if entry.lsn <= applied => continue,
+                Some(applied) if entry.lsn != applied + 1 => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("shipment skips from {applied} to {}", entry.lsn),
+                    ))
+                }
+                _ => {}
+            }
+            let pos = self
+                .wal
+                .append_keyed_to(entry.stream, entry.lsn + 1, &entry.payload)?;
+            self.pending.push_back((pos, entry.lsn));
+            self.applied = Some(entry.lsn);
+        }
+        Ok(())
+    }
+
+    /// Process the completions of the wal and return the LSN every entry applied is durable up
+    /// to, to acknowledge with Leader::acknowledge. The completions are consumed here.
+    pub fn durable(&mut self) -> Option<u64> {
+        self.wal.process_completions().for_each(drop);
+        let durable_head = self.wal.durable_head();
+        while let Some(&(pos, lsn)) = self.pending.front() {
+            if pos >= durable_head {
+                break;
+            }
+            self.durable = Some(lsn);
+            self.pending.pop_front();
+        }
+        self.durable
+    }
+
+    /// The follower's wal, for reading the entries applied.
+    pub fn wal(&self) -> &Wal {
+        &self.wal
+    }
+
+    /// Stop following and return the wal.
+    pub fn into_inner(self) -> Wal {
+        self.wal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimDevice;
+
+    fn payloads(wal: &Wal) -> Vec<Vec<u8>> {
+        wal.iterate().map(|e| e.unwrap().1).collect()
+    }
+
+    #[test]
+    fn test_replicate() -> std::io::Result<()> {
+        let mut leader_wal = Wal::open("mem:64".parse().unwrap())?;
+        let sim = SimDevice::eager(0, 64);
+        let mut follower = Follower::new(Wal::open_device(Box::new(sim.clone()), 64)?)?;
+        let mut leader = Leader::new();
+        leader.add_follower(&mut leader_wal, "b", follower.applied())?;
+        assert_eq!(leader.replicated(), Some(leader_wal.head()));
+
+        for i in 0..5u32 {
+            leader_wal.append(&i.to_le_bytes())?;
+        }
+        leader_wal.process_completions().for_each(drop);
+
+        // Shipments are cut at max_bytes, and a lost one is shipped again after a rewind.
+        let first = leader.ship(&leader_wal, "b", 8)?;
+        let lost = leader.ship(&leader_wal, "b", 8)?;
+        assert!(!lost.is_empty());
+        follower.receive(&first)?;
+        assert_eq!(follower.applied(), Some(1));
+        leader.acknowledge(&mut leader_wal, "b", follower.durable().unwrap())?;
+        leader.rewind("b")?;
+        let rest = leader.ship(&leader_wal, "b", usize::MAX)?;
+        assert!(leader.ship(&leader_wal, "b", usize::MAX)?.is_empty());
+        follower.receive(&first)?;
+        follower.receive(&rest)?;
+        assert_eq!(follower.durable(), Some(4));
+        let expected: Vec<Vec<u8>> = (0..5u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        assert_eq!(payloads(follower.wal()), expected);
+
+        leader.acknowledge(&mut leader_wal, "b", 4)?;
+        assert_eq!(leader.replicated(), Some(leader_wal.head()));
+        assert_eq!(leader_wal.cursor("replication/b"), Some(leader_wal.head()));
+
+        // The follower picks up where it left off once its wal is reopened.
+        drop(follower);
+        let follower = Follower::new(Wal::open_device(Box::new(sim), 64)?)?;
+        assert_eq!(follower.applied(), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ack_policy() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let mut leader = Leader::new();
+        leader.set_ack_policy(AckPolicy::Quorum);
+        let one = wal.append(b"one")?;
+        // Without followers the leader is a quorum of its own.
+        assert_eq!(leader.process_completions(&mut wal), vec![one]);
+
+        let mut followers = Vec::new();
+        for name in ["b", "c", "d"] {
+            leader.add_follower(&mut wal, name, Some(0))?;
+            followers.push((name, Follower::new(Wal::open("mem:64".parse().unwrap())?)?));
+        }
+        let two = wal.append(b"two")?;
+        let three = wal.append(b"three")?;
+        assert!(leader.process_completions(&mut wal).is_empty());
+
+        // Two of the three followers and the leader make a quorum.
+        for (name, follower) in &mut followers[..2] {
+            follower.receive(&leader.ship(&wal, name, usize::MAX)?)?;
+            leader.acknowledge(&mut wal, name, follower.durable().unwrap())?;
+            if *name == "b" {
+                assert!(leader.process_completions(&mut wal).is_empty());
+            }
+        }
+        assert_eq!(leader.process_completions(&mut wal), vec![two, three]);
+
+        // Every follower has to acknowledge with All.
+        leader.set_ack_policy(AckPolicy::All);
+        let four = wal.append(b"four")?;
+        assert!(leader.process_completions(&mut wal).is_empty());
+        for (name, follower) in &mut followers[..2] {
+            follower.receive(&leader.ship(&wal, name, usize::MAX)?)?;
+            leader.acknowledge(&mut wal, name, follower.durable().unwrap())?;
+        }
+        assert!(leader.process_completions(&mut wal).is_empty());
+        let (name, follower) = &mut followers[2];
+        follower.receive(&leader.ship(&wal, name, usize::MAX)?)?;
+        leader.acknowledge(&mut wal, name, follower.durable().unwrap())?;
+        assert_eq!(leader.process_completions(&mut wal), vec![four]);
+
+        leader.set_ack_policy(AckPolicy::Local);
+        let five = wal.append(b"five")?;
+        assert_eq!(leader.process_completions(&mut wal), vec![five]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replicated_is_slowest_follower() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let mut leader = Leader::new();
+        leader.add_follower(&mut wal, "b", None)?;
+        leader.add_follower(&mut wal, "c", None)?;
+        let one = wal.append(b"one")?;
+        let two = wal.append(b"two")?;
+        wal.process_completions().for_each(drop);
+
+        let mut b = Follower::new(Wal::open("mem:64".parse().unwrap())?)?;
+        b.receive(&leader.ship(&wal, "b", usize::MAX)?)?;
+        leader.acknowledge(&mut wal, "b", b.durable().unwrap())?;
+        let mut c = Follower::new(Wal::open("mem:64".parse().unwrap())?)?;
+        c.receive(&leader.ship(&wal, "c", 1)?)?;
+        leader.acknowledge(&mut wal, "c", c.durable().unwrap())?;
+        assert_eq!(leader.acknowledged("b"), Some(wal.head()));
+        assert_eq!(leader.acknowledged("c"), Some(two));
+        assert_eq!(leader.replicated(), Some(two));
+
+        // The slowest follower holds back truncation.
+        wal.truncate(wal.head())?;
+        assert_eq!(wal.tail(), two);
+        assert!(one < wal.tail());
+
+        // A shipment that skips entries is rejected.
+        leader.add_follower(&mut wal, "e", None)?;
+        let mut skipped = Vec::new();
+        let record = ArchivedEntry {
+            lsn: 5,
+            timestamp: 0,
+            stream: crate::wal::StreamId::DEFAULT,
+            payload: b"five".to_vec(),
+        };
+        encode_record(&record, &mut skipped);
+        let mut e = Follower::new(Wal::open("mem:64".parse().unwrap())?)?;
+        e.receive(&leader.ship(&wal, "e", usize::MAX)?)?;
+        assert_eq!(
+            e.receive(&skipped).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+/// RetentionPolicy bounds how long and how many entries are kept. An entry expires once any of the
+/// limits is exceeded, and expired entries are truncated from every stream as the wal is appended
+/// to, or by calling Wal::expire. Consumer cursors still hold back the tail, so an expired entry a
+/// consumer hasn't acked stays readable until it does. The limits apply on top of the ring itself,
+/// which overwrites the oldest entries once it is full.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Entries appended longer ago than this are expired. None keeps entries regardless of age.
+    pub max_age: Option<Duration>,
+    /// The most space the live entries may take up in the ring, counting whole blocks.
+    pub max_bytes: Option<u64>,
+    /// The most live entries to keep.
+    pub max_entries: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::BLOCK_SIZE;
+    use crate::wal::Wal;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_max_age() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let audit = wal.stream("audit")?;
+        wal.set_retention(RetentionPolicy {
+            max_age: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        });
+        wal.append(b"one")?;
+        wal.append_to(audit, b"two")?;
+        assert_eq!(wal.expire()?, 0);
+        assert_eq!(wal.len(), 2);
+
+        sleep(Duration::from_millis(20));
+        wal.set_retention(RetentionPolicy {
+            max_age: Some(Duration::from_millis(10)),
+            ..Default::default()
+        });
+        let three = wal.append(b"three")?;
+        // The append expired the older entries of both streams.
+        assert_eq!(wal.tail(), three);
+        assert_eq!(wal.iterate_stream(audit).count(), 0);
+
+        sleep(Duration::from_millis(20));
+        assert_eq!(wal.expire()?, 1);
+        assert!(wal.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_entries() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        wal.set_retention(RetentionPolicy {
+            max_entries: Some(2),
+            ..Default::default()
+        });
+        for i in 0..5u8 {
+            wal.append(&[i])?;
+        }
+        assert_eq!(wal.len(), 2);
+        let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(data, vec![vec![3], vec![4]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_bytes() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        wal.set_retention(RetentionPolicy {
+            max_bytes: Some(3 * BLOCK_SIZE as u64),
+            ..Default::default()
+        });
+        wal.append(b"one")?;
+        wal.append(&[0; BLOCK_SIZE as usize])?;
+        assert_eq!(wal.len(), 2);
+
+        // Another block pushes the live entries over three blocks, so the first one expires.
+        let third = wal.append(b"three")?;
+        assert_eq!(wal.len(), 2);
+        assert_eq!(wal.utilization(), 3.0 / 62.0);
+
+        // An entry larger than the limit expires everything before it.
+        wal.append(&[0; 3 * BLOCK_SIZE as usize])?;
+        assert_eq!(wal.len(), 0);
+        assert!(wal.tail() > third);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursor_holds_back_expiry() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let first = wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        wal.ack("reader", second)?;
+        wal.set_retention(RetentionPolicy {
+            max_age: Some(Duration::ZERO),
+            ..Default::default()
+        });
+
+        sleep(Duration::from_millis(1));
+        assert_eq!(wal.expire()?, 1);
+        assert_eq!(wal.tail(), second);
+        assert!(first < wal.tail());
+
+        wal.remove_cursor("reader")?;
+        assert_eq!(wal.expire()?, 1);
+        assert!(wal.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+use crate::wal::Wal;
+use log::{debug, warn};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+// RocksDB (and LevelDB) split the log into fixed 32K blocks. A physical record never spans a block
+// boundary, instead large logical records are split into FIRST/MIDDLE/LAST fragments.
+const LOG_BLOCK_SIZE: usize = 32768;
+
+// checksum (4) + length (2) + type (1)
+const HEADER_SIZE: usize = 7;
+// Recyclable records additionally carry the log number (4) so that stale records from a previous
+// use of a recycled file can be detected.
+const RECYCLABLE_HEADER_SIZE: usize = HEADER_SIZE + 4;
+
+const ZERO_TYPE: u8 = 0;
+const FULL_TYPE: u8 = 1;
+const FIRST_TYPE: u8 = 2;
+const MIDDLE_TYPE: u8 = 3;
+const LAST_TYPE: u8 = 4;
+const RECYCLABLE_FULL_TYPE: u8 = 5;
+const RECYCLABLE_FIRST_TYPE: u8 = 6;
+const RECYCLABLE_MIDDLE_TYPE: u8 = 7;
+const RECYCLABLE_LAST_TYPE: u8 = 8;
+const SET_COMPRESSION_TYPE: u8 = 9;
+const USER_DEFINED_TIMESTAMP_SIZE_TYPE: u8 = 10;
+const RECYCLABLE_USER_DEFINED_TIMESTAMP_SIZE_TYPE: u8 = 11;
+
+const MASK_DELTA: u32 = 0xa282ead8;
+
+// CRC32C (Castagnoli) as used by RocksDB. crc32fast only implements the IEEE polynomial.
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+fn crc32c(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for part in parts {
+        for b in part.iter() {
+            crc = CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
+
+// RocksDB stores a rotated CRC since computing the CRC of a string that contains embedded CRCs is
+// problematic.
+fn unmask_crc(masked: u32) -> u32 {
+    let rot = masked.wrapping_sub(MASK_DELTA);
+    rot.rotate_left(15)
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// LogReader parses a RocksDB or LevelDB write-ahead-log and returns each logical record (for
+/// RocksDB this is a serialized WriteBatch). A torn record at the end of the file is treated as the
+/// end of the log as RocksDB does during recovery.
+pub struct LogReader<R: Read> {
+    reader: R,
+    block: Vec<u8>,
+    // Read position and valid length within block.
+    pos: usize,
+    len: usize,
+    eof: bool,
+    // The log number of the first recyclable record. Records with a different number are left
+    // over from a previous use of the file.
+    log_number: Option<u32>,
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(reader: R) -> Self {
+        LogReader {
+            reader,
+            block: vec![0; LOG_BLOCK_SIZE],
+            pos: 0,
+            len: 0,
+            eof: false,
+            log_number: None,
+        }
+    }
+
+    fn read_block(&mut self) -> std::io::Result<()> {
+        let mut filled = 0;
+        while filled < LOG_BLOCK_SIZE {
+            let n = self.reader.read(&mut self.block[filled..])?;
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+            filled += n;
+        }
+        self.pos = 0;
+        self.len = filled;
+        Ok(())
+    }
+
+    // Returns the next physical record as (type, payload) or None at the end of the log.
+    fn read_physical(&mut self) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+        loop {
+            // The writer fills the rest of a block that is too short for another header with
+            // zeros. Once the log is known to be recyclable its records use the larger header.
+            let min_header_size = match self.log_number {
+                Some(_) => RECYCLABLE_HEADER_SIZE,
+                None => HEADER_SIZE,
+            };
+            if self.len - self.pos < min_header_size {
+                // The remainder of the block is a zero trailer, move to the next one.
+                if self.eof {
+                    return Ok(None);
+                }
+                self.read_block()?;
+                continue;
+            }
+
+            let header = &self.block[self.pos..self.len];
+            let masked_crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let length = u16::from_le_bytes(header[4..6].try_into().unwrap()) as usize;
+            let record_type = header[6];
+
+            if record_type == ZERO_TYPE && length == 0 {
+                // Either a trailer that was long enough to look like a header, or preallocated
+                // space that was never written. Like RocksDB, skip the rest of the block.
+                debug!("Found zero record, skipping the rest of the block");
+                self.pos = self.len;
+                continue;
+            }
+
+            let header_size = match record_type {
+                RECYCLABLE_FULL_TYPE..=RECYCLABLE_LAST_TYPE
+                | RECYCLABLE_USER_DEFINED_TIMESTAMP_SIZE_TYPE => RECYCLABLE_HEADER_SIZE,
+                _ => HEADER_SIZE,
+            };
+            if header.len() < header_size + length {
+                if self.eof {
+                    warn!("Truncated record at end of log, ignoring");
+                    return Ok(None);
+                }
+                return Err(invalid_data(format!(
+                    "record of length {length} overruns the block"
+                )));
+            }
+
+            let crc = crc32c(&[&header[6..header_size], &header[header_size..][..length]]);
+            if crc != unmask_crc(masked_crc) {
+                return Err(invalid_data(format!(
+                    "rocksdb record CRC mismatch {crc} != {}",
+                    unmask_crc(masked_crc)
+                )));
+            }
+
+            if header_size == RECYCLABLE_HEADER_SIZE {
+                let log_number = u32::from_le_bytes(header[7..11].try_into().unwrap());
+                match self.log_number {
+                    None => self.log_number = Some(log_number),
+                    Some(n) if n != log_number => {
+                        debug!("Found record from old log {log_number}, end of log");
+                        return Ok(None);
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            let payload = header[header_size..][..length].to_vec();
+            self.pos += header_size + length;
+            return Ok(Some((record_type, payload)));
+        }
+    }
+
+    fn read_record(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut fragments: Option<Vec<u8>> = None;
+        loop {
+            let Some((record_type, payload)) = self.read_physical()? else {
+                if fragments.is_some() {
+                    warn!("Partial record at end of log, ignoring");
+                }
+                return Ok(None);
+            };
+            match record_type {
+                FULL_TYPE | RECYCLABLE_FULL_TYPE => {
+                    if fragments.is_some() {
+                        warn!("Dropping partial record followed by a full record");
+                    }
+                    return Ok(Some(payload));
+                }
+                FIRST_TYPE | RECYCLABLE_FIRST_TYPE => {
+                    if fragments.is_some() {
+                        warn!("Dropping partial record followed by a new first fragment");
+                    }
+                    fragments = Some(payload);
+                }
+                MIDDLE_TYPE | RECYCLABLE_MIDDLE_TYPE => match fragments.as_mut() {
+                    Some(buffer) => buffer.extend_from_slice(&payload),
+                    None => return Err(invalid_data("middle fragment without first".into())),
+                },
+                LAST_TYPE | RECYCLABLE_LAST_TYPE => match fragments.take() {
+                    Some(mut buffer) => {
+                        buffer.extend_from_slice(&payload);
+                        return Ok(Some(buffer));
+                    }
+                    None => return Err(invalid_data("last fragment without first".into())),
+                },
+                SET_COMPRESSION_TYPE => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "compressed rocksdb logs are not supported",
+                    ))
+                }
+                USER_DEFINED_TIMESTAMP_SIZE_TYPE | RECYCLABLE_USER_DEFINED_TIMESTAMP_SIZE_TYPE => {
+                    // Metadata for the following records, not part of the data.
+                    debug!("Skipping timestamp size record");
+                }
+                _ => return Err(invalid_data(format!("unknown record type {record_type}"))),
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = std::io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Append every record of the RocksDB log to the wal, one entry per record, and wait until they
+/// are all durable. Returns the number of records imported.
+pub fn import<R: Read>(reader: R, wal: &mut Wal) -> std::io::Result<usize> {
+    wal.import(LogReader::new(reader))
+}
+
+/// Import the RocksDB log file at the given path into the wal.
+pub fn import_file(path: &Path, wal: &mut Wal) -> std::io::Result<usize> {
+    import(BufReader::new(File::open(path)?), wal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal version of the RocksDB log writer, used to generate test input.
+    fn write_log(records: &[Vec<u8>]) -> Vec<u8> {
+        write_log_with(records, None)
+    }
+
+    // Writes recyclable records with the given log number if there is one.
+    fn write_log_with(records: &[Vec<u8>], log_number: Option<u32>) -> Vec<u8> {
+        let header_size = match log_number {
+            Some(_) => RECYCLABLE_HEADER_SIZE,
+            None => HEADER_SIZE,
+        };
+        let mut out = Vec::new();
+        for record in records {
+            let mut left: &[u8] = record;
+            let mut first = true;
+            loop {
+                let remaining = LOG_BLOCK_SIZE - out.len() % LOG_BLOCK_SIZE;
+                if remaining < header_size {
+                    out.resize(out.len() + remaining, 0);
+                    continue;
+                }
+                let avail = remaining - header_size;
+                let fragment = left.len().min(avail);
+                let end = fragment == left.len();
+                let mut record_type = match (first, end) {
+                    (true, true) => FULL_TYPE,
+                    (true, false) => FIRST_TYPE,
+                    (false, true) => LAST_TYPE,
+                    (false, false) => MIDDLE_TYPE,
+                };
+                let mut checked = Vec::new();
+                if let Some(log_number) = log_number {
+                    record_type += RECYCLABLE_FULL_TYPE - FULL_TYPE;
+                    checked.extend_from_slice(&log_number.to_le_bytes());
+                }
+                let crc = crc32c(&[&[record_type], &checked, &left[..fragment]]);
+                let masked = crc.rotate_right(15).wrapping_add(MASK_DELTA);
+                out.extend_from_slice(&masked.to_le_bytes());
+                out.extend_from_slice(&(fragment as u16).to_le_bytes());
+                out.push(record_type);
+                out.extend_from_slice(&checked);
+                out.extend_from_slice(&left[..fragment]);
+                left = &left[fragment..];
+                first = false;
+                if end {
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_crc32c() {
+        // Test vector from RFC 3720.
+        assert_eq!(crc32c(&[&[0u8; 32]]), 0x8a9136aa);
+        assert_eq!(crc32c(&[b"123456789"]), 0xe3069283);
+    }
+
+    #[test]
+    fn test_import_rocksdb_log() -> std::io::Result<()> {
+        let records = vec![
+            b"small".to_vec(),
+            (0..40000).map(|i| i as u8).collect::<Vec<u8>>(),
+            b"after".to_vec(),
+        ];
+        let log = write_log(&records);
+
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        assert_eq!(import(&log[..], &mut wal)?, 3);
+
+        let recovered: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(recovered, records);
+        Ok(())
+    }
+
+    #[test]
+    fn test_torn_tail_is_ignored() -> std::io::Result<()> {
+        let records = vec![b"one".to_vec(), b"two".to_vec()];
+        let mut log = write_log(&records);
+        log.truncate(log.len() - 1);
+
+        let parsed = LogReader::new(&log[..]).collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(parsed, vec![b"one".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recyclable_trailer() -> std::io::Result<()> {
+        // The first record leaves 8 bytes in the block, too few for a recyclable header, so the
+        // writer pads them with zeros and the second record starts in the next block.
+        let records = vec![
+            vec![1; LOG_BLOCK_SIZE - RECYCLABLE_HEADER_SIZE - 8],
+            b"after".to_vec(),
+            b"last".to_vec(),
+        ];
+        let mut log = write_log_with(&records, Some(7));
+        assert_eq!(log[LOG_BLOCK_SIZE - 8..LOG_BLOCK_SIZE], [0; 8]);
+        // Preallocated space at the end of the file is not a record.
+        log.resize(log.len() + 100, 0);
+
+        let parsed = LogReader::new(&log[..]).collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(parsed, records);
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_record() {
+        let mut log = write_log(&[b"hello".to_vec()]);
+        log[HEADER_SIZE] ^= 0xff;
+        assert!(LogReader::new(&log[..]).next().unwrap().is_err());
+    }
+}
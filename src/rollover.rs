@@ -0,0 +1,633 @@
+use crate::common::*;
+use crate::wal::{
+    check_entry, wait_for, BlockLayout, Entry, MetaIterator, StreamId, Wal, WalIterator, RING_START,
+};
+use crc32fast::Hasher;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const MAGIC: [u8; 8] = *b"WALLAP01";
+
+// magic (8) + rollover (4) + flags (4) + capacity (8)
+const LAP_HEADER_SIZE: usize = 24;
+
+// The CRC of the uncompressed ring follows the compressed body.
+const LAP_TRAILER_SIZE: usize = 4;
+
+const FLAG_BLOCK_CRCS: u32 = 1;
+
+// The ring is read and compressed this many blocks at a time.
+const CHUNK_BLOCKS: u64 = 256;
+
+// The bytes of the ring compressed together. Runs never cross from one chunk to the next.
+const CHUNK_BYTES: usize = (CHUNK_BLOCKS * BLOCK_SIZE as u64) as usize;
+
+// Literal runs are read, and runs of zeros hashed, this many bytes at a time when an archive is
+// opened.
+const SCRATCH_BYTES: usize = 64 * 1024;
+
+// Runs of zeros at least this long are stored as a count, shorter ones are kept as they are.
+const MIN_ZERO_RUN: usize = 32;
+
+// The op and length of a run.
+const RUN_HEADER_SIZE: usize = 5;
+
+const OP_ZEROS: u8 = 0;
+const OP_LITERAL: u8 = 1;
+
+/// The name of the archive file of the pass of the ring with the given rollover.
+pub fn lap_file_name(rollover: u32) -> String {
+    format!("{rollover:010}.lap")
+}
+
+/// Copy the ring as the pass with the given rollover left it into an archive file in dir, and
+/// return the size of the file. The file is synced and in place before this returns, so a lap
+/// either has a complete archive or none.
+pub(crate) fn write_lap(
+    dir: &Path,
+    rollover: u32,
+    capacity: u64,
+    layout: BlockLayout,
+    dev: &dyn DeviceReader,
+) -> std::io::Result<u64> {
+    let temp = tempfile::Builder::new().prefix(".lap").tempfile_in(dir)?;
+    // Each chunk is written as soon as it is compressed, so the ring is never held in memory.
+    let mut out = BufWriter::new(temp);
+    let mut len = LAP_HEADER_SIZE + LAP_TRAILER_SIZE;
+    out.write_all(&MAGIC)?;
+    out.write_all(&rollover.to_le_bytes())?;
+    let flags = if layout.block_crcs() {
+        FLAG_BLOCK_CRCS
+    } else {
+        0
+    };
+    out.write_all(&flags.to_le_bytes())?;
+    out.write_all(&capacity.to_le_bytes())?;
+
+    let mut hasher = Hasher::new();
+    let mut body = Vec::new();
+    let mut offset = RING_START;
+    while offset < capacity {
+        let blocks = (capacity - offset).min(CHUNK_BLOCKS);
+        let chunk = dev.read(
+            offset * BLOCK_SIZE as u64,
+            (blocks * BLOCK_SIZE as u64) as usize,
+        )?;
+        hasher.update(&chunk);
+        body.clear();
+        compress(&chunk, &mut body);
+        out.write_all(&body)?;
+        len += body.len();
+        offset += blocks;
+    }
+    out.write_all(&hasher.finalize().to_le_bytes())?;
+
+    let temp = out.into_inner().map_err(|e| e.into_error())?;
+    temp.as_file().sync_all()?;
+    temp.persist(dir.join(lap_file_name(rollover)))
+        .map_err(|e| e.error)?;
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(len as u64)
+}
+
+// Appends data to out as runs of zeros, which make up the padding of blocks and of the end of the
+// ring, and literal bytes.
+fn compress(data: &[u8], out: &mut Vec<u8>) {
+    let mut literal = 0;
+    let mut at = 0;
+    while at < data.len() {
+        let zeros = data[at..].iter().take_while(|b| **b == 0).count();
+        if zeros < MIN_ZERO_RUN && at + zeros < data.len() {
+            at += zeros.max(1);
+            continue;
+        }
+        if zeros < MIN_ZERO_RUN {
+            // A short run at the very end stays part of the literal.
+            at += zeros;
+            break;
+        }
+        push_literal(&data[literal..at], out);
+        out.push(OP_ZEROS);
+        out.extend_from_slice(&(zeros as u32).to_le_bytes());
+        at += zeros;
+        literal = at;
+    }
+    push_literal(&data[literal..at], out);
+}
+
+fn push_literal(bytes: &[u8], out: &mut Vec<u8>) {
+    if !bytes.is_empty() {
+        out.push(OP_LITERAL);
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(bytes);
+    }
+}
+
+// Expands the runs in body, returning None if they are malformed or come to more than len bytes.
+// The output grows as the runs are expanded, so a damaged len doesn't allocate up front.
+fn decompress(body: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut ring = Vec::new();
+    let mut at = 0;
+    while at < body.len() {
+        let op = body[at];
+        let run = u32::from_le_bytes(body.get(at + 1..at + 5)?.try_into().unwrap()) as usize;
+        at += 5;
+        if ring.len() + run > len {
+            return None;
+        }
+        match op {
+            OP_ZEROS => ring.resize(ring.len() + run, 0),
+            OP_LITERAL => {
+                ring.extend_from_slice(body.get(at..at + run)?);
+                at += run;
+            }
+            _ => return None,
+        }
+    }
+    Some(ring)
+}
+
+// The ring of an archived lap, read at the byte offsets it had on the device. Each read expands
+// the chunks it covers from the file, so only the chunk read last is held in memory.
+struct LapImage {
+    path: PathBuf,
+    // The length of the ring in bytes.
+    len: usize,
+    // Where the runs of each chunk start in the file, followed by where the body ends.
+    chunks: Vec<u64>,
+    file: Mutex<LapFile>,
+}
+
+struct LapFile {
+    file: File,
+    // The chunk expanded last, with its index.
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl LapImage {
+    fn damaged(&self) -> std::io::Error {
+        damaged_lap(&self.path)
+    }
+
+    // Returns chunk index of the ring, expanding it from the file unless it was read last.
+    fn chunk<'a>(&self, lap: &'a mut LapFile, index: usize) -> std::io::Result<&'a [u8]> {
+        if lap
+            .cached
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != index)
+        {
+            let (start, end) = (self.chunks[index], self.chunks[index + 1]);
+            let mut body = vec![0; (end - start) as usize];
+            lap.file.seek(SeekFrom::Start(start))?;
+            lap.file.read_exact(&mut body)?;
+            let len = (self.len - index * CHUNK_BYTES).min(CHUNK_BYTES);
+            let ring = decompress(&body, len)
+                .filter(|ring| ring.len() == len)
+                .ok_or_else(|| self.damaged())?;
+            lap.cached = Some((index, ring));
+        }
+        Ok(&lap.cached.as_ref().unwrap().1)
+    }
+}
+
+impl DeviceReader for LapImage {
+    fn read(&self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let start = byte_offset
+            .checked_sub(RING_START * BLOCK_SIZE as u64)
+            .and_then(|start| usize::try_from(start).ok())
+            .filter(|start| start.checked_add(len).is_some_and(|end| end <= self.len));
+        let Some(start) = start else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("read of {len} bytes at {byte_offset} is outside the archived ring"),
+            ));
+        };
+        let mut data = Vec::with_capacity(len);
+        let mut lap = self.file.lock().unwrap();
+        let mut at = start;
+        while at < start + len {
+            let index = at / CHUNK_BYTES;
+            let ring = self.chunk(&mut lap, index)?;
+            let within = at - index * CHUNK_BYTES;
+            let n = (start + len - at).min(ring.len() - within);
+            data.extend_from_slice(&ring[within..within + n]);
+            at += n;
+        }
+        Ok(data)
+    }
+}
+
+fn damaged_lap(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("damaged lap archive {}", path.display()),
+    )
+}
+
+/// LapArchive is a pass of the ring copied into an archive file by Wal::set_rollover_archive.
+/// Opening it checks the whole file, but its entries are read from the file as they are iterated,
+/// so the ring is never held in memory at once.
+pub struct LapArchive {
+    rollover: u32,
+    capacity: u64,
+    layout: BlockLayout,
+    image: LapImage,
+}
+
+impl LapArchive {
+    /// Open the archive file at path, failing with InvalidData if it is damaged.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let damaged = || damaged_lap(path);
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut header = [0; LAP_HEADER_SIZE];
+        if file_len < (LAP_HEADER_SIZE + LAP_TRAILER_SIZE) as u64 {
+            return Err(damaged());
+        }
+        file.read_exact(&mut header)?;
+        if header[..8] != MAGIC {
+            return Err(damaged());
+        }
+        let rollover = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let flags = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        let capacity = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let len = capacity
+            .checked_sub(RING_START)
+            .and_then(|blocks| blocks.checked_mul(BLOCK_SIZE as u64))
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(damaged)?;
+        let body_end = file_len - LAP_TRAILER_SIZE as u64;
+        // Each chunk of the ring is compressed into at least one run, so a body too short for the
+        // chunks of a ring of len bytes means the capacity in the header is damaged.
+        let body_len = body_end - LAP_HEADER_SIZE as u64;
+        if body_len < (len.div_ceil(CHUNK_BYTES) * RUN_HEADER_SIZE) as u64 {
+            return Err(damaged());
+        }
+
+        // Check the CRC of the ring by expanding the runs as they are read, and note where each
+        // chunk starts. A run never crosses from one chunk to the next.
+        let mut reader = BufReader::new(file);
+        let mut hasher = Hasher::new();
+        let mut scratch = vec![0; SCRATCH_BYTES];
+        let mut chunks = vec![LAP_HEADER_SIZE as u64];
+        let mut at = LAP_HEADER_SIZE as u64;
+        let mut expanded = 0;
+        while at < body_end {
+            if body_end - at < RUN_HEADER_SIZE as u64 {
+                return Err(damaged());
+            }
+            let mut run_header = [0; RUN_HEADER_SIZE];
+            reader.read_exact(&mut run_header)?;
+            at += RUN_HEADER_SIZE as u64;
+            let run = u32::from_le_bytes(run_header[1..].try_into().unwrap()) as usize;
+            let chunk_end = (chunks.len() * CHUNK_BYTES).min(len);
+            if expanded + run > chunk_end {
+                return Err(damaged());
+            }
+            let mut left = run;
+            match run_header[0] {
+                OP_ZEROS => {
+                    scratch.fill(0);
+                    while left > 0 {
+                        let n = left.min(scratch.len());
+                        hasher.update(&scratch[..n]);
+                        left -= n;
+                    }
+                }
+                OP_LITERAL if run as u64 <= body_end - at => {
+                    while left > 0 {
+                        let n = left.min(scratch.len());
+                        reader.read_exact(&mut scratch[..n])?;
+                        hasher.update(&scratch[..n]);
+                        left -= n;
+                    }
+                    at += run as u64;
+                }
+                _ => return Err(damaged()),
+            }
+            expanded += run;
+            if expanded == chunk_end && expanded < len {
+                chunks.push(at);
+            }
+        }
+        chunks.push(body_end);
+        if expanded != len {
+            return Err(damaged());
+        }
+        let mut crc = [0; LAP_TRAILER_SIZE];
+        reader.read_exact(&mut crc)?;
+        if hasher.finalize() != u32::from_le_bytes(crc) {
+            return Err(damaged());
+        }
+
+        Ok(LapArchive {
+            rollover,
+            capacity,
+            layout: BlockLayout::new(flags & FLAG_BLOCK_CRCS != 0),
+            image: LapImage {
+                path: path.to_path_buf(),
+                len,
+                chunks,
+                file: Mutex::new(LapFile {
+                    file: reader.into_inner(),
+                    cached: None,
+                }),
+            },
+        })
+    }
+
+    /// The rollover of the pass of the ring the archive holds.
+    pub fn rollover(&self) -> u32 {
+        self.rollover
+    }
+
+    /// Iterate over the entries written in the pass, from the start of the ring, including those
+    /// that had been truncated by the time it was archived.
+    pub fn entries(&self) -> MetaIterator<'_> {
+        let start = WalPosition {
+            offset: RING_START,
+            rollover: self.rollover,
+        };
+        WalIterator::new(&self.image, start, start.next_pass(), self.capacity)
+            .with_layout(self.layout)
+            .with_meta()
+    }
+}
+
+/// How far restore replays the history of a wal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Every entry that is durable in the live wal.
+    End,
+    /// Up to and including the entry with this LSN.
+    Lsn(u64),
+    /// Up to, but not including, the entry at this position.
+    Position(WalPosition),
+    /// Up to the first entry appended after this time, in microseconds since the Unix epoch.
+    Timestamp(u64),
+}
+
+impl RestorePoint {
+    fn includes(&self, entry: &Entry) -> bool {
+        match *self {
+            RestorePoint::End => true,
+            RestorePoint::Lsn(lsn) => entry.lsn <= lsn,
+            RestorePoint::Position(position) => entry.position < position,
+            RestorePoint::Timestamp(timestamp) => entry.timestamp <= timestamp,
+        }
+    }
+}
+
+/// Replay the history of a wal up to until, handing each entry to apply in LSN order, and return
+/// how many there were. The history is made of the passes of the ring archived in dir by
+/// Wal::set_rollover_archive, oldest first, followed by the durable entries of live, if it is
+/// given, that haven't been archived yet, including truncated ones the ring still holds. Entries
+/// found twice are only replayed once. A gap in the LSNs, from a missing archive file or entries
+/// erased before they were archived, fails with InvalidData rather than replaying a history with a
+/// hole in it.
+pub fn restore(
+    dir: &Path,
+    live: Option<&Wal>,
+    until: RestorePoint,
+    apply: &mut dyn FnMut(Entry) -> std::io::Result<()>,
+) -> std::io::Result<u64> {
+    let mut laps = Vec::new();
+    for file in std::fs::read_dir(dir)? {
+        let path = file?.path();
+        if path.extension().is_some_and(|ext| ext == "lap") {
+            laps.push(path);
+        }
+    }
+    // The names are zero padded, so they sort by rollover.
+    laps.sort();
+
+    let mut last: Option<u64> = None;
+    let mut count = 0;
+    // Returns whether to go on.
+    let mut replay = |entry: std::io::Result<Entry>| -> std::io::Result<bool> {
+        let entry = entry?;
+        if last.is_some_and(|last| entry.lsn <= last) {
+            return Ok(true);
+        }
+        if !until.includes(&entry) {
+            return Ok(false);
+        }
+        if let Some(last) = last.filter(|last| entry.lsn != last + 1) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "history is missing the entries from {} to {}",
+                    last + 1,
+                    entry.lsn - 1
+                ),
+            ));
+        }
+        let entry = check_entry(entry)?;
+        last = Some(entry.lsn);
+        apply(entry)?;
+        count += 1;
+        Ok(true)
+    };
+    for path in laps {
+        let lap = LapArchive::open(&path)?;
+        for entry in lap.entries() {
+            if !replay(entry)? {
+                return Ok(count);
+            }
+        }
+    }
+    if let Some(live) = live {
+        for entry in live.iterate_current_pass() {
+            if !replay(entry)? {
+                break;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Replay the history of a wal up to until into target, like restore, and wait until it is all
+/// durable. Entries keep their stream: named streams are created in target with the name they have
+/// in live, and streams live doesn't know the name of, such as when it isn't given, are restored
+/// into streams named stream-<id>.
+pub fn restore_into(
+    dir: &Path,
+    live: Option<&Wal>,
+    until: RestorePoint,
+    target: &mut Wal,
+) -> std::io::Result<u64> {
+    let mut outstanding = HashSet::new();
+    let count = restore(dir, live, until, &mut |entry| {
+        let stream = match entry.stream {
+            StreamId::DEFAULT => StreamId::DEFAULT,
+            stream => match live.and_then(|live| live.stream_name(stream)) {
+                Some(name) => target.stream(name)?,
+                None => target.stream(&format!("stream-{}", stream.0))?,
+            },
+        };
+        outstanding.insert(target.append_to(stream, &entry.payload)?);
+        for pos in target.process_completions() {
+            outstanding.remove(&pos);
+        }
+        Ok(())
+    })?;
+    wait_for(&mut outstanding, || target.completions_or_failure())?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimDevice;
+
+    #[test]
+    fn test_compress() {
+        let mut data = vec![0; 3 * MIN_ZERO_RUN];
+        data[0] = 1;
+        data[MIN_ZERO_RUN + 1] = 2;
+        data.extend_from_slice(&[3; 5]);
+        data.extend_from_slice(&[0; 4]);
+        let mut out = Vec::new();
+        compress(&data, &mut out);
+        assert!(out.len() < data.len());
+        assert_eq!(decompress(&out, data.len()), Some(data.clone()));
+        // Runs that go past the expected length are rejected.
+        assert_eq!(decompress(&out, data.len() - 1), None);
+    }
+
+    #[test]
+    fn test_rollover_archive() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sim = SimDevice::eager(0, 16);
+        let mut wal = Wal::open_device(Box::new(sim), 16)?;
+        wal.set_rollover_archive(Some(dir.path()))?;
+        let mut lsns = Vec::new();
+        while wal.head().rollover < 3 {
+            lsns.push(wal.next_lsn());
+            let len = lsns.len() % 3 * BLOCK_SIZE as usize;
+            wal.append(&vec![lsns.len() as u8; len])?;
+            wal.process_completions().for_each(drop);
+        }
+        // The pass the head is on is only archived once the next one starts.
+        let mut archived = Vec::new();
+        for rollover in 0..3 {
+            let lap = LapArchive::open(&dir.path().join(lap_file_name(rollover)))?;
+            assert_eq!(lap.rollover(), rollover);
+            for entry in lap.entries() {
+                let entry = entry?;
+                assert!(entry.crc_valid);
+                assert_eq!(entry.position.rollover, rollover);
+                archived.push(entry.lsn);
+            }
+        }
+        assert!(!dir.path().join(lap_file_name(3)).exists());
+        assert_eq!(archived, lsns[..archived.len()]);
+
+        // Damage is caught by the CRC of the ring.
+        let path = dir.path().join(lap_file_name(1));
+        let mut buffer = std::fs::read(&path)?;
+        let at = buffer.len() - LAP_TRAILER_SIZE - 1;
+        buffer[at] ^= 1;
+        std::fs::write(&path, buffer)?;
+        let err = LapArchive::open(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // So is a capacity far larger than the body could hold, without allocating for it.
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&MAGIC);
+        buffer.extend_from_slice(&1u32.to_le_bytes());
+        buffer.extend_from_slice(&0u32.to_le_bytes());
+        buffer.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        buffer.extend_from_slice(&[OP_ZEROS, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        std::fs::write(&path, buffer)?;
+        let err = LapArchive::open(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lap_spanning_chunks() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sim = SimDevice::eager(0, 600);
+        let mut wal = Wal::open_device(Box::new(sim), 600)?;
+        wal.set_rollover_archive(Some(dir.path()))?;
+        // Entries of several blocks, some of which cross from one chunk of the archive into the
+        // next.
+        let mut payloads = Vec::new();
+        while wal.head().rollover == 0 {
+            let payload = vec![payloads.len() as u8; 6 * BLOCK_SIZE as usize];
+            wal.append(&payload)?;
+            wal.process_completions().for_each(drop);
+            payloads.push(payload);
+        }
+        payloads.pop();
+
+        let lap = LapArchive::open(&dir.path().join(lap_file_name(0)))?;
+        let archived: Vec<Vec<u8>> = lap.entries().map(|entry| entry.unwrap().payload).collect();
+        assert_eq!(archived, payloads);
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sim = SimDevice::eager(0, 16);
+        let mut wal = Wal::open_device(Box::new(sim), 16)?;
+        wal.set_rollover_archive(Some(dir.path()))?;
+        let audit = wal.stream("audit")?;
+        let mut entries = Vec::new();
+        for i in 0..40u32 {
+            let stream = if i % 4 == 0 { audit } else { StreamId::DEFAULT };
+            let pos = wal.append_to(stream, &i.to_le_bytes())?;
+            wal.process_completions().for_each(drop);
+            entries.push((pos, stream));
+            // Truncating keeps the ring from filling, the history is still restored.
+            wal.truncate_stream(stream, pos)?;
+        }
+
+        let mut lsns = Vec::new();
+        let all = restore(dir.path(), Some(&wal), RestorePoint::End, &mut |entry| {
+            assert_eq!(entry.payload, (entry.lsn as u32).to_le_bytes());
+            lsns.push(entry.lsn);
+            Ok(())
+        })?;
+        assert_eq!(all, 40);
+        assert_eq!(lsns, (0..40).collect::<Vec<u64>>());
+
+        let until = |point| restore(dir.path(), Some(&wal), point, &mut |_| Ok(()));
+        assert_eq!(until(RestorePoint::Lsn(24))?, 25);
+        assert_eq!(until(RestorePoint::Position(entries[30].0))?, 30);
+
+        // Without the live wal the history ends with the last archived pass.
+        let archived = restore(dir.path(), None, RestorePoint::End, &mut |_| Ok(()))?;
+        assert!(archived > 0 && archived < 40);
+
+        let mut target = Wal::open("mem:64".parse().unwrap())?;
+        assert_eq!(
+            restore_into(dir.path(), Some(&wal), RestorePoint::Lsn(9), &mut target)?,
+            10
+        );
+        let restored = target.stream("audit")?;
+        let payloads: Vec<Vec<u8>> = target
+            .iterate_stream(restored)
+            .map(|e| e.unwrap().1)
+            .collect();
+        assert_eq!(
+            payloads,
+            vec![
+                0u32.to_le_bytes().to_vec(),
+                4u32.to_le_bytes().to_vec(),
+                8u32.to_le_bytes().to_vec()
+            ]
+        );
+
+        // A missing pass leaves a gap that is reported.
+        std::fs::remove_file(dir.path().join(lap_file_name(1)))?;
+        let err = restore(dir.path(), Some(&wal), RestorePoint::End, &mut |_| Ok(())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
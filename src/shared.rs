@@ -0,0 +1,413 @@
+use crate::common::*;
+use crate::primitives::{Arc, Mutex};
+use crate::reader::{Bounds, WalReader};
+use crate::sync::FileReader;
+use crate::wal::BlockLayout;
+use crc32fast::Hasher;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const MAGIC: [u8; 8] = *b"WALPOSN1";
+
+// magic (8) + sequence (8) + tail (12) + durable head (12) + capacity (8) + flags (4) + crc (4),
+// padded so a slot never straddles a sector.
+const SLOT_SIZE: usize = 64;
+const CRC_OFFSET: usize = 56;
+
+const FLAG_BLOCK_CRCS: u32 = 1;
+
+// The doorbell counter follows the two slots, in the first page of the file, which the writer and
+// every reader map.
+const DOORBELL_OFFSET: usize = 2 * SLOT_SIZE;
+const MAP_SIZE: usize = 4096;
+
+// How often a reader without a doorbell checks for new positions.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// The positions published in one slot of the file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Published {
+    sequence: u64,
+    tail: WalPosition,
+    durable: WalPosition,
+    capacity: u64,
+    block_crcs: bool,
+}
+
+impl Published {
+    fn encode(&self) -> [u8; SLOT_SIZE] {
+        let mut slot = [0; SLOT_SIZE];
+        slot[..8].copy_from_slice(&MAGIC);
+        slot[8..16].copy_from_slice(&self.sequence.to_le_bytes());
+        slot[16..24].copy_from_slice(&self.tail.offset.to_le_bytes());
+        slot[24..28].copy_from_slice(&self.tail.rollover.to_le_bytes());
+        slot[28..36].copy_from_slice(&self.durable.offset.to_le_bytes());
+        slot[36..40].copy_from_slice(&self.durable.rollover.to_le_bytes());
+        slot[40..48].copy_from_slice(&self.capacity.to_le_bytes());
+        let flags = if self.block_crcs { FLAG_BLOCK_CRCS } else { 0 };
+        slot[48..52].copy_from_slice(&flags.to_le_bytes());
+        let mut hasher = Hasher::new();
+        hasher.update(&slot[..CRC_OFFSET]);
+        slot[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&hasher.finalize().to_le_bytes());
+        slot
+    }
+
+    // Returns None for a slot that was never written or is being written.
+    fn decode(slot: &[u8]) -> Option<Published> {
+        let mut hasher = Hasher::new();
+        hasher.update(&slot[..CRC_OFFSET]);
+        let crc = u32::from_le_bytes(slot[CRC_OFFSET..CRC_OFFSET + 4].try_into().unwrap());
+        if slot[..8] != MAGIC || hasher.finalize() != crc {
+            return None;
+        }
+        let u64_at = |at: usize| u64::from_le_bytes(slot[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(slot[at..at + 4].try_into().unwrap());
+        Some(Published {
+            sequence: u64_at(8),
+            tail: WalPosition {
+                offset: u64_at(16),
+                rollover: u32_at(24),
+            },
+            durable: WalPosition {
+                offset: u64_at(28),
+                rollover: u32_at(36),
+            },
+            capacity: u64_at(40),
+            block_crcs: u32_at(48) & FLAG_BLOCK_CRCS != 0,
+        })
+    }
+}
+
+// Reads both slots of the file and returns the newest one that is intact.
+fn read_latest(file: &File) -> std::io::Result<Option<Published>> {
+    let mut buffer = [0; 2 * SLOT_SIZE];
+    let mut read = 0;
+    while read < buffer.len() {
+        match file.read_at(&mut buffer[read..], read as u64)? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(buffer
+        .chunks_exact(SLOT_SIZE)
+        .filter_map(Published::decode)
+        .max_by_key(|published| published.sequence))
+}
+
+// Doorbell is a counter in a shared mapping of the positions file that the writer increments every
+// time it publishes. On Linux readers sleep on it with a futex, which the writer wakes, so they
+// see new entries as soon as they are durable. Elsewhere readers poll it.
+struct Doorbell {
+    ptr: NonNull<u8>,
+}
+
+// The counter is only accessed atomically, and unmapped when the doorbell is dropped.
+unsafe impl Send for Doorbell {}
+unsafe impl Sync for Doorbell {}
+
+impl Doorbell {
+    // Maps the first page of file, which must be at least MAP_SIZE long. Readers map it read-only.
+    fn map(file: &File, writable: bool) -> std::io::Result<Self> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                MAP_SIZE,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Doorbell {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+        })
+    }
+
+    fn counter(&self) -> &AtomicU32 {
+        unsafe { &*(self.ptr.as_ptr().add(DOORBELL_OFFSET) as *const AtomicU32) }
+    }
+
+    fn ring(&self) {
+        self.counter().fetch_add(1, Ordering::SeqCst);
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                self.counter().as_ptr(),
+                libc::FUTEX_WAKE,
+                i32::MAX,
+                std::ptr::null::<libc::timespec>(),
+                std::ptr::null::<u32>(),
+                0,
+            );
+        }
+    }
+
+    // Sleeps until the counter moves on from seen or timeout passes. It can return early.
+    fn wait(&self, seen: u32, timeout: Duration) {
+        #[cfg(target_os = "linux")]
+        {
+            let timeout = libc::timespec {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as libc::c_long,
+            };
+            unsafe {
+                libc::syscall(
+                    libc::SYS_futex,
+                    self.counter().as_ptr(),
+                    libc::FUTEX_WAIT,
+                    seen,
+                    &timeout as *const libc::timespec,
+                    std::ptr::null::<u32>(),
+                    0,
+                );
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = seen;
+            std::thread::sleep(timeout.min(POLL_INTERVAL));
+        }
+    }
+}
+
+impl Drop for Doorbell {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, MAP_SIZE) };
+    }
+}
+
+/// Publisher is owned by the wal and writes its tail and durable head to the positions file every
+/// time they move. The two slots of the file are written in turn, each with a sequence number and
+/// a CRC, so a reader always finds the last complete one even while the other is being written.
+/// The file is only shared through the page cache and is never synced. Each publish also rings the
+/// doorbell to wake readers waiting for it.
+pub(crate) struct Publisher {
+    file: File,
+    last: Published,
+    doorbell: Doorbell,
+}
+
+impl Publisher {
+    pub fn open(
+        path: &Path,
+        tail: WalPosition,
+        durable: WalPosition,
+        capacity: u64,
+        layout: BlockLayout,
+    ) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() < MAP_SIZE as u64 {
+            file.set_len(MAP_SIZE as u64)?;
+        }
+        // Continue the sequence of an earlier writer so readers don't keep its last slot.
+        let sequence = read_latest(&file)?.map_or(0, |published| published.sequence + 1);
+        let doorbell = Doorbell::map(&file, true)?;
+        let mut publisher = Publisher {
+            file,
+            doorbell,
+            last: Published {
+                sequence,
+                tail,
+                durable,
+                capacity,
+                block_crcs: layout.block_crcs(),
+            },
+        };
+        publisher.write()?;
+        Ok(publisher)
+    }
+
+    pub fn publish(&mut self, tail: WalPosition, durable: WalPosition) -> std::io::Result<()> {
+        if (tail, durable) == (self.last.tail, self.last.durable) {
+            return Ok(());
+        }
+        self.last.sequence += 1;
+        self.last.tail = tail;
+        self.last.durable = durable;
+        self.write()
+    }
+
+    fn write(&mut self) -> std::io::Result<()> {
+        let slot = (self.last.sequence % 2) as usize * SLOT_SIZE;
+        self.file.write_all_at(&self.last.encode(), slot as u64)?;
+        self.doorbell.ring();
+        Ok(())
+    }
+}
+
+/// SharedReader reads a wal that another process has open for writing. The writer publishes its
+/// tail and durable head to a positions file with Wal::publish_positions, and the reader picks
+/// them up each time refresh is called, so it only ever sees entries that are durable. The reader
+/// opens the wal file read-only and takes no lock, while the writer's exclusive lock still keeps
+/// out a second writer.
+///
+/// The writer can overwrite an entry as soon as it is truncated, before the new tail is published,
+/// so like WalReader an entry can read as damaged. An entry that reads as damaged and is before the
+/// tail after the next refresh was overwritten, otherwise it is damaged on disk.
+///
+/// Rather than polling refresh, a reader can block in wait until the writer publishes new
+/// positions. On Linux it sleeps on a futex in a shared mapping of the positions file, which the
+/// writer wakes, so it sees new entries well within a millisecond of them becoming durable.
+pub struct SharedReader {
+    positions: File,
+    // None if the positions file is too short to hold a doorbell.
+    doorbell: Option<Doorbell>,
+    sequence: u64,
+    reader: WalReader,
+}
+
+impl SharedReader {
+    /// Attach to the wal file at path with the positions file the writer publishes to. Fails with
+    /// NotFound if the writer hasn't published to it yet.
+    pub fn open(path: &Path, positions: &Path) -> std::io::Result<Self> {
+        let positions = File::open(positions)?;
+        let published = read_latest(&positions)?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no positions have been published",
+            )
+        })?;
+        let doorbell = if positions.metadata()?.len() >= MAP_SIZE as u64 {
+            Some(Doorbell::map(&positions, false)?)
+        } else {
+            None
+        };
+        let file = File::open(path)?;
+        Ok(SharedReader {
+            positions,
+            doorbell,
+            sequence: published.sequence,
+            reader: WalReader {
+                dev: FileReader::shared(&file)?,
+                bounds: Arc::new(Mutex::new(Bounds {
+                    tail: published.tail,
+                    head: published.durable,
+                })),
+                capacity: published.capacity,
+                layout: BlockLayout::new(published.block_crcs),
+            },
+        })
+    }
+
+    /// Pick up the positions the writer published since the last call, returning whether they
+    /// moved.
+    pub fn refresh(&mut self) -> std::io::Result<bool> {
+        let published = match read_latest(&self.positions)? {
+            Some(published) if published.sequence > self.sequence => published,
+            _ => return Ok(false),
+        };
+        self.sequence = published.sequence;
+        let mut bounds = self.reader.bounds.lock().unwrap();
+        let moved = (bounds.tail, bounds.head) != (published.tail, published.durable);
+        *bounds = Bounds {
+            tail: published.tail,
+            head: published.durable,
+        };
+        Ok(moved)
+    }
+
+    /// Wait up to timeout for the writer to publish new positions, refreshing them, and return
+    /// whether they moved.
+    pub fn wait(&mut self, timeout: Duration) -> std::io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // The counter is read before refreshing, so a publish in between ends the wait at once.
+            let seen = self
+                .doorbell
+                .as_ref()
+                .map(|d| d.counter().load(Ordering::SeqCst));
+            if self.refresh()? {
+                return Ok(true);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(false);
+            }
+            match (&self.doorbell, seen) {
+                (Some(doorbell), Some(seen)) => doorbell.wait(seen, left),
+                _ => std::thread::sleep(left.min(POLL_INTERVAL)),
+            }
+        }
+    }
+
+    /// A reader over the entries as of the last refresh. Every reader it returns shares its
+    /// positions, so they see later refreshes too.
+    pub fn reader(&self) -> WalReader {
+        self.reader.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::Wal;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_shared_reader() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("wal");
+        let positions = dir.path().join("wal.positions");
+        let mut wal = Wal::create(&path, 64)?;
+        assert!(SharedReader::open(&path, &positions).is_err());
+        wal.publish_positions(&positions)?;
+        let mut shared = SharedReader::open(&path, &positions)?;
+        assert_eq!(shared.reader().iterate().count(), 0);
+
+        // Entries only show up once they are durable and the reader refreshes.
+        let one = wal.append(b"one")?;
+        assert!(!shared.refresh()?);
+        while wal.durable_head() <= one {
+            wal.process_completions().for_each(drop);
+        }
+        assert!(shared.refresh()?);
+        let payloads: Vec<Vec<u8>> = shared.reader().iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(payloads, vec![b"one".to_vec()]);
+
+        wal.truncate(wal.head())?;
+        wal.process_completions().for_each(drop);
+        assert!(shared.refresh()?);
+        assert_eq!(shared.reader().tail(), wal.tail());
+        assert_eq!(shared.reader().iterate().count(), 0);
+
+        // A reader waiting in another thread is woken by the next publish.
+        let mut waiting = SharedReader::open(&path, &positions)?;
+        let waiter = std::thread::spawn(move || -> std::io::Result<usize> {
+            assert!(waiting.wait(Duration::from_secs(30))?);
+            Ok(waiting.reader().iterate().count())
+        });
+        wal.append(b"two")?;
+        while wal.durable_head() < wal.head() {
+            wal.process_completions().for_each(drop);
+        }
+        assert_eq!(waiter.join().unwrap()?, 1);
+        assert!(shared.wait(Duration::ZERO)?);
+
+        // A torn write of the newer slot leaves the older one to read.
+        let file = OpenOptions::new().read(true).write(true).open(&positions)?;
+        let newest = read_latest(&file)?.unwrap();
+        file.write_all_at(&[0xff], (newest.sequence % 2) * SLOT_SIZE as u64 + 20)?;
+        let older = read_latest(&file)?.unwrap();
+        assert_eq!(older.sequence, newest.sequence - 1);
+        Ok(())
+    }
+}
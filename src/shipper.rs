@@ -0,0 +1,282 @@
+use crate::archive::{collect_durable, decode_records, ArchivedEntry};
+use crate::wal::{now_micros, Wal};
+use crc32fast::Hasher;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const MANIFEST: &str = "MANIFEST";
+
+// first lsn (8) + last lsn (8) + timestamp (8)
+const MANIFEST_RECORD_SIZE: usize = 24;
+
+// The manifest ends with the crc of its records.
+const MANIFEST_CRC_SIZE: usize = 4;
+
+/// ObjectStore is the archive tier a Shipper uploads to, such as an S3 or GCS bucket. Objects are
+/// only ever written whole, so a put must either store all of data under key or leave the key as
+/// it was, which is what object stores guarantee.
+pub trait ObjectStore: Send {
+    /// Store data under key, replacing any object already there.
+    fn put(&mut self, key: &str, data: &[u8]) -> std::io::Result<()>;
+
+    /// Read the object under key, failing with NotFound if there isn't one.
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>>;
+
+    /// Delete the object under key. Deleting a key with no object is not an error.
+    fn delete(&mut self, key: &str) -> std::io::Result<()>;
+}
+
+/// DirStore keeps objects as files in a local directory, for tests and for object stores mounted as
+/// a file system. Keys containing a / are stored in subdirectories.
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    pub fn new(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(DirStore {
+            dir: dir.to_path_buf(),
+        })
+    }
+}
+
+impl ObjectStore for DirStore {
+    fn put(&mut self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self.dir.join(key);
+        let parent = path.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(parent)?;
+        let mut temp = tempfile::Builder::new()
+            .prefix(".obj")
+            .tempfile_in(parent)?;
+        temp.write_all(data)?;
+        temp.as_file().sync_all()?;
+        temp.persist(&path).map_err(|e| e.error)?;
+        std::fs::File::open(parent)?.sync_all()
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.dir.join(key))
+    }
+
+    fn delete(&mut self, key: &str) -> std::io::Result<()> {
+        match std::fs::remove_file(self.dir.join(key)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// A chunk object in the store, holding the entries from first_lsn to last_lsn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub first_lsn: u64,
+    pub last_lsn: u64,
+    /// When the last entry of the chunk was appended, in microseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Chunk {
+    fn key(&self) -> String {
+        format!("chunks/{:020}.seg", self.first_lsn)
+    }
+}
+
+/// ChunkRetention bounds how many chunks the store keeps. A chunk is deleted once either limit is
+/// exceeded, oldest first.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ChunkRetention {
+    /// Chunks whose last entry was appended longer ago than this are deleted.
+    pub max_age: Option<Duration>,
+    /// The most chunks to keep.
+    pub max_chunks: Option<usize>,
+}
+
+/// Shipper uploads the durable entries of a wal to an object store, so the wal can stay on fast
+/// local disk while its history is kept in cheaper storage. Each call to ship uploads the entries
+/// that became durable since the last call as one immutable chunk object, in the record format of
+/// archive segments, and then replaces the manifest object listing the chunks, so a chunk is only
+/// ever read once it is complete. Chunks past the retention limits are dropped from the manifest
+/// before they are deleted.
+///
+/// Like Archiver, the shipper keeps a named cursor in the wal at the first entry it hasn't shipped,
+/// see Wal::ack, so ship must be called well before the ring fills.
+pub struct Shipper {
+    store: Box<dyn ObjectStore>,
+    name: String,
+    chunks: Vec<Chunk>,
+    retention: ChunkRetention,
+}
+
+impl Shipper {
+    /// Open the shipped log in store, reading its manifest if there is one. The name is used for
+    /// the shipper's cursor in the wal.
+    pub fn open(store: Box<dyn ObjectStore>, name: &str) -> std::io::Result<Self> {
+        let chunks = match store.get(MANIFEST) {
+            Ok(buffer) => decode_manifest(&buffer)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Shipper {
+            store,
+            name: name.to_string(),
+            chunks,
+            retention: ChunkRetention::default(),
+        })
+    }
+
+    /// The chunks in the store, oldest first.
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Set the limits on the chunks kept, which are applied by every call to ship.
+    pub fn set_retention(&mut self, retention: ChunkRetention) {
+        self.retention = retention;
+    }
+
+    /// Upload the entries that are durable but not yet shipped as a new chunk, delete the chunks
+    /// past the retention limits, and return how many entries were shipped.
+    pub fn ship(&mut self, wal: &mut Wal) -> std::io::Result<usize> {
+        let last_lsn = self.chunks.last().map(|c| c.last_lsn);
+        let pending = collect_durable(wal, &self.name, last_lsn)?;
+        let count = match pending.segment {
+            Some(segment) => {
+                let chunk = Chunk {
+                    first_lsn: segment.first_lsn,
+                    last_lsn: segment.last_lsn,
+                    timestamp: pending.timestamp,
+                };
+                // A chunk uploaded before a crash stopped the manifest from listing it is replaced.
+                self.store.put(&chunk.key(), &pending.records)?;
+                self.chunks.push(chunk);
+                if let Err(e) = self.store.put(MANIFEST, &encode_manifest(&self.chunks)) {
+                    self.chunks.pop();
+                    return Err(e);
+                }
+                (segment.last_lsn - segment.first_lsn + 1) as usize
+            }
+            None => 0,
+        };
+        pending.commit(wal, &self.name)?;
+        self.collect_garbage()?;
+        Ok(count)
+    }
+
+    /// Delete the chunks past the retention limits and return how many there were. The manifest is
+    /// replaced first, so a crash part way through leaves unlisted chunks behind rather than a
+    /// manifest listing missing ones.
+    pub fn collect_garbage(&mut self) -> std::io::Result<usize> {
+        let mut expired = 0;
+        if let Some(max_chunks) = self.retention.max_chunks {
+            expired = self.chunks.len().saturating_sub(max_chunks);
+        }
+        if let Some(max_age) = self.retention.max_age {
+            let cutoff = now_micros().saturating_sub(max_age.as_micros() as u64);
+            let old = self.chunks.iter().take_while(|c| c.timestamp < cutoff);
+            expired = expired.max(old.count());
+        }
+        if expired == 0 {
+            return Ok(0);
+        }
+        self.store
+            .put(MANIFEST, &encode_manifest(&self.chunks[expired..]))?;
+        for chunk in self.chunks.drain(..expired) {
+            self.store.delete(&chunk.key())?;
+        }
+        Ok(expired)
+    }
+
+    /// Read back the entries of a chunk.
+    pub fn read_chunk(&self, chunk: &Chunk) -> std::io::Result<Vec<ArchivedEntry>> {
+        let buffer = self.store.get(&chunk.key())?;
+        decode_records(&buffer, &chunk.key())
+    }
+}
+
+fn encode_manifest(chunks: &[Chunk]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(chunks.len() * MANIFEST_RECORD_SIZE + MANIFEST_CRC_SIZE);
+    for chunk in chunks {
+        buffer.extend_from_slice(&chunk.first_lsn.to_le_bytes());
+        buffer.extend_from_slice(&chunk.last_lsn.to_le_bytes());
+        buffer.extend_from_slice(&chunk.timestamp.to_le_bytes());
+    }
+    let mut hasher = Hasher::new();
+    hasher.update(&buffer);
+    buffer.extend_from_slice(&hasher.finalize().to_le_bytes());
+    buffer
+}
+
+fn decode_manifest(buffer: &[u8]) -> std::io::Result<Vec<Chunk>> {
+    let damaged = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "damaged manifest in the object store",
+        )
+    };
+    let split = buffer
+        .len()
+        .checked_sub(MANIFEST_CRC_SIZE)
+        .ok_or_else(damaged)?;
+    let (records, crc) = buffer.split_at(split);
+    let mut hasher = Hasher::new();
+    hasher.update(records);
+    if records.len() % MANIFEST_RECORD_SIZE != 0
+        || hasher.finalize() != u32::from_le_bytes(crc.try_into().unwrap())
+    {
+        return Err(damaged());
+    }
+    Ok(records
+        .chunks_exact(MANIFEST_RECORD_SIZE)
+        .map(|record| Chunk {
+            first_lsn: u64::from_le_bytes(record[..8].try_into().unwrap()),
+            last_lsn: u64::from_le_bytes(record[8..16].try_into().unwrap()),
+            timestamp: u64::from_le_bytes(record[16..].try_into().unwrap()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimDevice;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_ship_and_retain() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let sim = SimDevice::eager(0, 16);
+        let mut wal = Wal::open_device(Box::new(sim), 16)?;
+        let mut shipper = Shipper::open(Box::new(DirStore::new(dir.path())?), "shipper")?;
+        shipper.set_retention(ChunkRetention {
+            max_chunks: Some(3),
+            ..Default::default()
+        });
+
+        for i in 0..10u32 {
+            wal.append(&i.to_le_bytes())?;
+            wal.process_completions().for_each(drop);
+            assert_eq!(shipper.ship(&mut wal)?, 1);
+        }
+        assert_eq!(shipper.ship(&mut wal)?, 0);
+        assert_eq!(wal.cursor("shipper"), Some(wal.head()));
+
+        // Only the newest chunks are left, in the manifest and in the store.
+        let shipper = Shipper::open(Box::new(DirStore::new(dir.path())?), "shipper")?;
+        let lsns: Vec<u64> = shipper.chunks().iter().map(|c| c.first_lsn).collect();
+        assert_eq!(lsns, vec![7, 8, 9]);
+        assert_eq!(std::fs::read_dir(dir.path().join("chunks"))?.count(), 3);
+        for chunk in shipper.chunks() {
+            let entries = shipper.read_chunk(chunk)?;
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].payload, (chunk.first_lsn as u32).to_le_bytes());
+        }
+
+        // A damaged manifest is reported rather than treated as empty.
+        std::fs::write(dir.path().join(MANIFEST), b"bad")?;
+        let err = Shipper::open(Box::new(DirStore::new(dir.path())?), "shipper").err();
+        assert_eq!(err.map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use crate::common::*;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+// A sidecar write waiting for the device writes submitted before it.
+struct PendingWrite {
+    byte_offset: u64,
+    data: Vec<u8>,
+    waiting: HashSet<WalPosition>,
+}
+
+/// SidecarDevice pairs a device with a small secondary file for metadata that belongs with the
+/// wal, such as an index of its entries. A write to the sidecar is held back until every device
+/// write submitted before it has completed, and is synced by the same process_completions or sync
+/// call that makes it visible, so after a crash the sidecar never refers to entries that didn't
+/// survive. Sidecar writes are applied in the order they were made.
+pub struct SidecarDevice {
+    inner: Box<dyn PersistentDevice>,
+    file: File,
+    // Device writes that asked for a completion and haven't completed yet.
+    in_flight: HashSet<WalPosition>,
+    pending: VecDeque<PendingWrite>,
+}
+
+impl SidecarDevice {
+    /// Wrap inner, keeping the sidecar in the file at path, which is created if it doesn't exist.
+    pub fn new(inner: Box<dyn PersistentDevice>, path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(SidecarDevice {
+            inner,
+            file,
+            in_flight: HashSet::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    // Write the sidecar writes at the front of the queue that no longer wait for the device, or
+    // all of them after a sync, and sync the file if there were any.
+    fn flush_sidecar(&mut self, all: bool) -> std::io::Result<()> {
+        let mut written = false;
+        while let Some(write) = self.pending.front() {
+            if !all && !write.waiting.is_empty() {
+                break;
+            }
+            self.file.write_all_at(&write.data, write.byte_offset)?;
+            self.pending.pop_front();
+            written = true;
+        }
+        if written {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl PersistentDevice for SidecarDevice {
+    fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
+        self.inner.write(pos, data, notify)?;
+        if notify {
+            self.in_flight.insert(pos);
+        }
+        Ok(())
+    }
+
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        let completions: Vec<WalPosition> = self.inner.process_completions().collect();
+        for pos in &completions {
+            self.in_flight.remove(pos);
+            for write in self.pending.iter_mut() {
+                write.waiting.remove(pos);
+            }
+        }
+        // The writes stay queued and are tried again on the next call.
+        if let Err(e) = self.flush_sidecar(false) {
+            log::warn!("Failed to write the sidecar: {}", e);
+        }
+        completions.into_iter()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync()?;
+        self.flush_sidecar(true)
+    }
+
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        self.inner.take_failure()
+    }
+
+    fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.inner.read(byte_offset, len)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        self.inner.reader()
+    }
+
+    fn write_sidecar(&mut self, byte_offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.pending.push_back(PendingWrite {
+            byte_offset,
+            data: data.to_vec(),
+            waiting: self.in_flight.clone(),
+        });
+        self.flush_sidecar(false)
+    }
+
+    fn read_sidecar(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0; len];
+        let read = self.file.read_at(&mut buffer, byte_offset)?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimDevice;
+    use crate::wal::{SyncPolicy, Wal};
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_ordered_after_entries() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let sim = SimDevice::eager(0, 64);
+        let dev = SidecarDevice::new(Box::new(sim.clone()), file.path())?;
+        let mut wal = Wal::open_device(Box::new(dev), 64)?;
+
+        sim.set_complete_probability(0.0);
+        wal.append(b"one")?;
+        wal.write_sidecar(0, b"index")?;
+        wal.process_completions().for_each(drop);
+        // The entry hasn't completed, so neither has the sidecar write.
+        assert_eq!(wal.read_sidecar(0, 5)?, b"");
+
+        sim.set_complete_probability(1.0);
+        wal.process_completions().for_each(drop);
+        assert_eq!(std::fs::read(file.path())?, b"index");
+
+        // Without entries in flight the write goes straight through, and sync flushes the rest.
+        wal.write_sidecar(5, b"!")?;
+        assert_eq!(wal.read_sidecar(0, 6)?, b"index!");
+        sim.set_complete_probability(0.0);
+        wal.append(b"two")?;
+        wal.write_sidecar(0, b"INDEX")?;
+        assert_eq!(wal.read_sidecar(0, 6)?, b"index!");
+        wal.append_with(b"three", SyncPolicy::Immediate)?;
+        assert_eq!(wal.read_sidecar(0, 6)?, b"INDEX!");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut wal = Wal::open("mem:64".parse().unwrap()).unwrap();
+        let err = wal.write_sidecar(0, b"index").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+}
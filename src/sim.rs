@@ -0,0 +1,296 @@
+use crate::common::*;
+use std::sync::{Arc, Mutex};
+
+// A write that has been submitted but not completed yet.
+struct PendingWrite {
+    pos: WalPosition,
+    data: Vec<u8>,
+    notify: bool,
+}
+
+struct SimState {
+    rng: fastrand::Rng,
+    // Chance that each pending write completes on a call to process_completions.
+    complete_probability: f64,
+    // Whether pending writes can complete in a different order than they were submitted.
+    reorder: bool,
+    // What reads see, every submitted write is applied here immediately like a page cache.
+    volatile: Vec<u8>,
+    // What survives a crash, only completed writes are applied here.
+    durable: Vec<u8>,
+    pending: Vec<PendingWrite>,
+    // Writes made durable by sync whose completions haven't been returned yet.
+    synced: Vec<WalPosition>,
+    // The number of writes failed by fail_pending that take_failure hasn't reported yet.
+    failed: usize,
+    // Writes that end past this many blocks fail for lack of space.
+    space_blocks: Option<u64>,
+}
+
+impl SimState {
+    fn apply(image: &mut [u8], write: &PendingWrite) {
+        let start = write.pos.byte_offset() as usize;
+        image[start..start + write.data.len()].copy_from_slice(&write.data);
+    }
+}
+
+/// SimDevice is an in-memory device for tests that completes writes under a seeded deterministic
+/// scheduler. Each call to process_completions completes a random subset of the outstanding writes
+/// in a random order, and crash discards or keeps each outstanding write at random. Running a test
+/// with the same seed always produces the same sequence of events, so a failing interleaving can be
+/// reproduced from its seed.
+///
+/// Clones share the same state, so a test keeps one clone to drive crashes while the wal owns the
+/// other.
+#[derive(Clone)]
+pub struct SimDevice {
+    state: Arc<Mutex<SimState>>,
+    capacity_blocks: u64,
+}
+
+impl SimDevice {
+    pub fn new(seed: u64, capacity_blocks: u64) -> Self {
+        let size = (capacity_blocks * BLOCK_SIZE as u64) as usize;
+        Self::from_image(
+            fastrand::Rng::with_seed(seed),
+            vec![0; size],
+            capacity_blocks,
+        )
+    }
+
+    /// A device like new that completes every outstanding write on each call to
+    /// process_completions, for tests that aren't about writes completing late.
+    pub fn eager(seed: u64, capacity_blocks: u64) -> Self {
+        let sim = Self::new(seed, capacity_blocks);
+        sim.set_complete_probability(1.0);
+        sim
+    }
+
+    fn from_image(rng: fastrand::Rng, image: Vec<u8>, capacity_blocks: u64) -> Self {
+        SimDevice {
+            state: Arc::new(Mutex::new(SimState {
+                rng,
+                complete_probability: 0.5,
+                reorder: true,
+                volatile: image.clone(),
+                durable: image,
+                pending: Vec::new(),
+                synced: Vec::new(),
+                failed: 0,
+                space_blocks: None,
+            })),
+            capacity_blocks,
+        }
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity_blocks
+    }
+
+    /// Set the chance (0.0 to 1.0) that each outstanding write completes on a call to
+    /// process_completions. 1.0 completes everything, though still in a random order.
+    pub fn set_complete_probability(&self, probability: f64) {
+        self.state.lock().unwrap().complete_probability = probability;
+    }
+
+    /// Set whether outstanding writes can complete, or survive a crash, in a different order than
+    /// they were submitted. Reordering is on by default. Note that two outstanding writes to the
+    /// same block can then leave either one on disk. Without reordering a crash keeps the writes
+    /// submitted before some random point and loses the rest, like a device that persists in order.
+    pub fn set_reorder(&self, reorder: bool) {
+        self.state.lock().unwrap().reorder = reorder;
+    }
+
+    /// The number of writes that have been submitted and not completed.
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    /// Fail every outstanding write with a hard error, like a drive returning EIO. They never
+    /// complete or reach the disk, and the failure is reported by take_failure.
+    pub fn fail_pending(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failed += std::mem::take(&mut state.pending).len();
+    }
+
+    /// Fail writes that end past the first blocks of the device with StorageFull, like a file
+    /// that can't grow past them because the filesystem is full. None lifts the limit.
+    pub fn set_space_limit(&self, blocks: Option<u64>) {
+        self.state.lock().unwrap().space_blocks = blocks;
+    }
+
+    /// Simulate a crash. Every outstanding write independently either reaches the disk or is lost,
+    /// and the surviving writes are applied in a random order. Without reordering only a prefix of
+    /// the outstanding writes survives. Returns a new device holding what
+    /// was on disk, which can be passed to Wal::open_device to recover. This device should not be
+    /// used afterwards.
+    pub fn crash(&self) -> SimDevice {
+        let mut state = self.state.lock().unwrap();
+        let mut pending = std::mem::take(&mut state.pending);
+        if state.reorder {
+            state.rng.shuffle(&mut pending);
+            for write in &pending {
+                if state.rng.bool() {
+                    SimState::apply(&mut state.durable, write);
+                }
+            }
+        } else {
+            let survivors = state.rng.usize(..=pending.len());
+            for write in &pending[..survivors] {
+                SimState::apply(&mut state.durable, write);
+            }
+        }
+        let rng = state.rng.fork();
+        let device = SimDevice::from_image(rng, state.durable.clone(), self.capacity_blocks);
+        device.set_reorder(state.reorder);
+        device
+    }
+}
+
+impl PersistentDevice for SimDevice {
+    fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
+        let write_end = pos.offset + data.blocks();
+        if write_end > self.capacity_blocks {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Write would exceed device capacity",
+            ));
+        }
+
+        let write = PendingWrite {
+            pos,
+            data: data.as_bytes().to_vec(),
+            notify,
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.space_blocks.is_some_and(|blocks| write_end > blocks) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "no space left on device",
+            ));
+        }
+        SimState::apply(&mut state.volatile, &write);
+        state.pending.push(write);
+        Ok(())
+    }
+
+    fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        let mut state = self.state.lock().unwrap();
+        let mut pending = std::mem::take(&mut state.pending);
+        if state.reorder {
+            state.rng.shuffle(&mut pending);
+        }
+
+        let mut completions = std::mem::take(&mut state.synced);
+        for write in pending {
+            if state.rng.f64() < state.complete_probability {
+                SimState::apply(&mut state.durable, &write);
+                if write.notify {
+                    completions.push(write.pos);
+                }
+            } else {
+                state.pending.push(write);
+            }
+        }
+        completions.into_iter()
+    }
+
+    // Every outstanding write reaches the disk, their completions are returned by the next call to
+    // process_completions.
+    fn sync(&mut self) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for write in std::mem::take(&mut state.pending) {
+            SimState::apply(&mut state.durable, &write);
+            if write.notify {
+                state.synced.push(write.pos);
+            }
+        }
+        Ok(())
+    }
+
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        let failed = std::mem::take(&mut self.state.lock().unwrap().failed);
+        (failed > 0).then(|| std::io::Error::other(format!("{failed} writes failed")))
+    }
+
+    fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        DeviceReader::read(self, byte_offset, len)
+    }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(Arc::new(self.clone()))
+    }
+}
+
+impl DeviceReader for SimDevice {
+    fn read(&self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let start = byte_offset as usize;
+        match state.volatile.get(start..start + len) {
+            Some(data) => Ok(data.to_vec()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Read past the end of the device",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::Wal;
+
+    // Appends entries while completing a random subset of the writes, then crashes.
+    // Returns the appended positions and the ones that were reported complete.
+    fn run(seed: u64) -> std::io::Result<(SimDevice, Vec<WalPosition>, Vec<WalPosition>)> {
+        let sim = SimDevice::new(seed, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), sim.capacity())?;
+        // The writes lost in the crash never complete.
+        wal.set_drain_timeout(std::time::Duration::ZERO);
+        let mut appended = Vec::new();
+        let mut completed = Vec::new();
+        for i in 0..40u32 {
+            appended.push(wal.append(&i.to_le_bytes())?);
+            if i % 3 == 0 {
+                completed.extend(wal.process_completions());
+            }
+        }
+        let crashed = sim.crash();
+        drop(wal);
+        Ok((crashed, appended, completed))
+    }
+
+    #[test]
+    fn test_deterministic() -> std::io::Result<()> {
+        let (_, _, first) = run(7)?;
+        let (_, _, second) = run(7)?;
+        assert_eq!(first, second);
+        let (_, _, other) = run(8)?;
+        assert_ne!(first, other);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovers_completed_prefix() -> std::io::Result<()> {
+        for seed in 0..50 {
+            let (crashed, appended, completed) = run(seed)?;
+            // Completions may arrive out of order, only the entries before the first one that
+            // never completed are guaranteed to be recovered.
+            let guaranteed = appended
+                .iter()
+                .take_while(|pos| completed.contains(pos))
+                .count();
+
+            let wal = Wal::open_device(Box::new(crashed.clone()), crashed.capacity())?;
+            let recovered: Vec<(WalPosition, Vec<u8>)> =
+                wal.iterate().collect::<std::io::Result<_>>()?;
+            assert!(recovered.len() >= guaranteed, "seed {seed}");
+            for (i, (pos, data)) in recovered.iter().enumerate() {
+                assert_eq!(*pos, appended[i], "seed {seed}");
+                assert_eq!(data[..], (i as u32).to_le_bytes(), "seed {seed}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use crate::common::*;
+
+/// SnapshotProvider is implemented by the application to persist its state outside the wal so
+/// that the entries covered by the snapshot can be truncated.
+pub trait SnapshotProvider: Send {
+    /// Called by the wal once utilization reaches the configured threshold. head is the position
+    /// after the last appended entry. The provider should snapshot its state and return the
+    /// position of the first entry that is not covered by the snapshot, at which point the wal
+    /// truncates up to it. A provider that snapshots in the background returns None and calls
+    /// Wal::snapshot_completed when done. No new snapshot is requested while one is outstanding.
+    fn snapshot(&mut self, head: WalPosition) -> std::io::Result<Option<WalPosition>>;
+}
+
+// The registered provider along with when to call it.
+pub(crate) struct SnapshotHook {
+    pub provider: Box<dyn SnapshotProvider>,
+    // Fraction of the ring that must be in use before a snapshot is requested, inclusive.
+    pub threshold: f64,
+    pub in_progress: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::Wal;
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder {
+        calls: Arc<Mutex<Vec<WalPosition>>>,
+        sync: bool,
+    }
+
+    impl SnapshotProvider for Recorder {
+        fn snapshot(&mut self, head: WalPosition) -> std::io::Result<Option<WalPosition>> {
+            self.calls.lock().unwrap().push(head);
+            Ok(if self.sync { Some(head) } else { None })
+        }
+    }
+
+    #[test]
+    fn test_snapshot_truncates() -> std::io::Result<()> {
+        // 2 control blocks and 10 blocks of ring.
+        let mut wal = Wal::open("mem:12".parse().unwrap())?;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder {
+            calls: calls.clone(),
+            sync: true,
+        };
+        wal.set_snapshot_provider(Box::new(recorder), 0.5);
+
+        for _ in 0..4 {
+            wal.append(b"entry")?;
+        }
+        assert!(calls.lock().unwrap().is_empty());
+        assert_eq!(wal.utilization(), 0.4);
+
+        // The fifth entry crosses the threshold and everything is covered by the snapshot.
+        wal.append(b"entry")?;
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        assert_eq!(wal.utilization(), 0.0);
+        assert_eq!(wal.iterate().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_threshold_is_inclusive() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:12".parse().unwrap())?;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder {
+            calls: calls.clone(),
+            sync: false,
+        };
+        wal.set_snapshot_provider(Box::new(recorder), 0.3);
+
+        wal.append(b"one")?;
+        wal.append(b"two")?;
+        assert_eq!(wal.utilization(), 0.2);
+        assert!(calls.lock().unwrap().is_empty());
+
+        // Exactly at the threshold.
+        wal.append(b"three")?;
+        assert_eq!(wal.utilization(), 0.3);
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_background_snapshot() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:12".parse().unwrap())?;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder {
+            calls: calls.clone(),
+            sync: false,
+        };
+        wal.set_snapshot_provider(Box::new(recorder), 0.2);
+
+        let first = wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        wal.append(b"three")?;
+        // Only one request while the snapshot is outstanding.
+        assert_eq!(calls.lock().unwrap().len(), 1);
+
+        wal.snapshot_completed(second)?;
+        let remaining: Vec<WalPosition> = wal.iterate().map(|e| e.unwrap().0).collect();
+        assert_eq!(remaining.len(), 2);
+        assert!(remaining[0] > first);
+
+        // The next append over the threshold requests a new snapshot.
+        wal.append(b"four")?;
+        assert_eq!(calls.lock().unwrap().len(), 2);
+        Ok(())
+    }
+}
@@ -1,37 +1,248 @@
 use crate::common::*;
+use crate::failpoint;
 use log::warn;
 use std::collections::VecDeque;
+use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{Read, Seek, Write};
+use std::io::{Read, Seek};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 
 /// SyncDevice uses standard synchronous file operations with deferred fsync
 pub struct SyncDevice {
     file: std::fs::File,
     pending_syncs: VecDeque<WalPosition>,
+    // Whether writes that ask for a completion are made with RWF_DSYNC.
+    dsync: bool,
+    // Whether a write was made without RWF_DSYNC since the last sync.
+    unsynced: bool,
+    // Whether an fsync failed. The kernel may have dropped the writes it failed to flush, and a
+    // later fsync succeeds without them, so the pending writes are never reported complete.
+    failed: bool,
+    // The error the fsync failed with, until take_failure returns it.
+    failure: Option<std::io::Error>,
 }
 
 impl SyncDevice {
     // The user must create the file before calling new.
     pub fn new(path: &Path) -> std::io::Result<Self> {
+        Self::open(path, false)
+    }
+
+    /// Open the file at path like new, but write every write that asks for a completion with
+    /// pwritev2 and RWF_DSYNC, so it is durable as soon as it returns and completions don't wait
+    /// for an fdatasync. Needs Linux 4.7 or later, older kernels fail the writes.
+    #[cfg(target_os = "linux")]
+    pub fn with_dsync(path: &Path) -> std::io::Result<Self> {
+        Self::open(path, true)
+    }
+
+    fn open(path: &Path, dsync: bool) -> std::io::Result<Self> {
         let file = OpenOptions::new()
             .write(true)
             .read(true)
             .truncate(false)
             .create(false)
             .open(path)?;
+        lock_exclusive(&file, path)?;
 
         Ok(Self {
             file,
             pending_syncs: VecDeque::new(),
+            dsync,
+            unsynced: false,
+            failed: false,
+            failure: None,
         })
     }
+
+    // Make the pending writes durable, returning false if the sync failed.
+    fn sync_pending(&mut self) -> bool {
+        // With RWF_DSYNC the writes are durable already.
+        if self.dsync {
+            return true;
+        }
+        if self.failed {
+            return false;
+        }
+        if let Err(e) = failpoint::check("sync::fsync").and_then(|_| self.file.sync_data()) {
+            warn!("Failed to sync data: {}", e);
+            self.failed = true;
+            self.failure = Some(e);
+            return false;
+        }
+        true
+    }
+}
+
+/// Take an advisory lock on the file for as long as it stays open, so a second writer fails to
+/// open it rather than corrupting the wal. The lock is per open file, so it also stops a second
+/// wal in the same process.
+pub(crate) fn lock_exclusive(file: &File, path: &Path) -> std::io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    if err.kind() == std::io::ErrorKind::WouldBlock {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ResourceBusy,
+            format!("{} is already open by another writer", path.display()),
+        ));
+    }
+    Err(err)
+}
+
+/// How many times a write that fails with a transient error is submitted again before the error
+/// is treated as a hard failure.
+pub(crate) const MAX_WRITE_RETRIES: u32 = 8;
+
+// The most threads FileReader::read_many reads with at once.
+const READ_THREADS: usize = 8;
+
+/// Whether a write that failed with errno can succeed if it is submitted again.
+pub(crate) fn is_transient(errno: i32) -> bool {
+    errno == libc::EINTR || errno == libc::EAGAIN
+}
+
+/// Write all of buf at offset of fd, continuing after short writes and retrying transient errors
+/// up to MAX_WRITE_RETRIES times, so only hard errors are returned.
+pub(crate) fn pwrite_all(fd: RawFd, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    write_all_at(buf, offset, |rest, at| unsafe {
+        libc::pwrite(
+            fd,
+            rest.as_ptr() as *const libc::c_void,
+            rest.len(),
+            at as libc::off_t,
+        )
+    })
+}
+
+/// Like pwrite_all, but written with pwritev2 and the given RWF flags.
+#[cfg(target_os = "linux")]
+pub(crate) fn pwritev2_all(fd: RawFd, buf: &[u8], offset: u64, flags: i32) -> std::io::Result<()> {
+    write_all_at(buf, offset, |rest, at| {
+        let iov = libc::iovec {
+            iov_base: rest.as_ptr() as *mut libc::c_void,
+            iov_len: rest.len(),
+        };
+        unsafe { libc::pwritev2(fd, &iov, 1, at as libc::off_t, flags) }
+    })
+}
+
+// Calls write with the rest of buf and its offset until all of it is written, following the
+// errno convention of pwrite.
+fn write_all_at(
+    buf: &[u8],
+    offset: u64,
+    mut write: impl FnMut(&[u8], u64) -> isize,
+) -> std::io::Result<()> {
+    let mut written = 0;
+    let mut retries = 0;
+    while written < buf.len() {
+        let res = write(&buf[written..], offset + written as u64);
+        if res > 0 {
+            written += res as usize;
+            continue;
+        }
+        if res == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("write at {} wrote nothing", offset + written as u64),
+            ));
+        }
+        let err = std::io::Error::last_os_error();
+        if !is_transient(err.raw_os_error().unwrap_or(0)) || retries == MAX_WRITE_RETRIES {
+            return Err(err);
+        }
+        retries += 1;
+    }
+    Ok(())
+}
+
+/// Have the kernel read len bytes of file at offset into the page cache in the background.
+pub(crate) fn fadvise_willneed(file: &File, offset: u64, len: usize) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let res = unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        // posix_fadvise returns the error rather than setting errno.
+        if res != 0 {
+            return Err(std::io::Error::from_raw_os_error(res));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, offset, len);
+    Ok(())
+}
+
+/// FileReader reads a file with positional reads, so it never moves the offset of the handle the
+/// device writes through.
+pub(crate) struct FileReader {
+    file: File,
+}
+
+impl FileReader {
+    pub(crate) fn shared(file: &File) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(Arc::new(FileReader {
+            file: file.try_clone()?,
+        }))
+    }
+}
+
+impl DeviceReader for FileReader {
+    fn read(&self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0; len];
+        self.file.read_exact_at(&mut buffer, pos)?;
+        Ok(buffer)
+    }
+
+    fn prefetch(&self, pos: u64, len: usize) -> std::io::Result<()> {
+        fadvise_willneed(&self.file, pos, len)
+    }
+
+    // Spread the reads over up to READ_THREADS threads, so the drive gets several at a time.
+    fn read_many(
+        &self,
+        reads: &[(u64, usize)],
+        done: &mut dyn FnMut(usize, std::io::Result<Vec<u8>>),
+    ) {
+        let next = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        std::thread::scope(|s| {
+            for _ in 0..READ_THREADS.min(reads.len()) {
+                let sender = sender.clone();
+                let next = &next;
+                s.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(&(pos, len)) = reads.get(i) else {
+                        break;
+                    };
+                    if sender.send((i, self.read(pos, len))).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+            for (i, res) in receiver {
+                done(i, res);
+            }
+        });
+    }
 }
 
 impl PersistentDevice for SyncDevice {
     fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
         // Convert AlignedSlice to a regular slice
-        let buffer = unsafe { std::slice::from_raw_parts(data.buffer_ptr, data.size() as usize) };
+        let buffer = data.as_bytes();
 
         // Perform the write using standard file operations
         let file_len = self.file.metadata()?.len();
@@ -42,9 +253,19 @@ impl PersistentDevice for SyncDevice {
                 "Write position exceeds file length",
             ));
         }
-        self.file
-            .seek(std::io::SeekFrom::Start(pos.byte_offset()))?;
-        self.file.write_all(buffer)?;
+        #[cfg(target_os = "linux")]
+        if self.dsync && notify {
+            pwritev2_all(
+                self.file.as_raw_fd(),
+                buffer,
+                pos.byte_offset(),
+                libc::RWF_DSYNC,
+            )?;
+            self.pending_syncs.push_back(pos);
+            return Ok(());
+        }
+        pwrite_all(self.file.as_raw_fd(), buffer, pos.byte_offset())?;
+        self.unsynced = true;
 
         // Queue position for sync if requested
         if notify {
@@ -55,23 +276,60 @@ impl PersistentDevice for SyncDevice {
     }
 
     fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
-        // Sync all pending writes
-        if let Err(e) = self.file.sync_data() {
-            warn!("Failed to sync data: {}", e);
+        if !self.sync_pending() {
             return Vec::new().into_iter();
         }
-
         // Return an iterator over the completed positions
         let completed = self.pending_syncs.drain(..).collect::<Vec<_>>();
         completed.into_iter()
     }
 
+    // The completions are counted straight out of the queue.
+    fn process_watermark(&mut self) -> Option<Watermark> {
+        if !self.sync_pending() {
+            return None;
+        }
+        Watermark::of(self.pending_syncs.drain(..))
+    }
+
+    // Writes are made synchronously, so only the fsync is needed, and not even that if every write
+    // since the last one used RWF_DSYNC.
+    fn sync(&mut self) -> std::io::Result<()> {
+        if self.dsync && !self.unsynced {
+            return Ok(());
+        }
+        if self.failed {
+            return Err(std::io::Error::other("an earlier fsync failed"));
+        }
+        if let Err(e) = failpoint::check("sync::fsync").and_then(|_| self.file.sync_data()) {
+            self.failed = true;
+            return Err(e);
+        }
+        self.unsynced = false;
+        Ok(())
+    }
+
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        self.failure.take()
+    }
+
     fn read(&mut self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
         let mut buffer = vec![0; len];
         self.file.seek(std::io::SeekFrom::Start(pos))?;
         self.file.read_exact(&mut buffer)?;
         Ok(buffer)
     }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        FileReader::shared(&self.file)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            dsync_writes: self.dsync,
+            ..Capabilities::default()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -81,6 +339,39 @@ mod tests {
     use tempfile::NamedTempFile;
 
     #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_exclusive_lock() -> std::io::Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        temp_file.as_file().set_len(16 * 1024)?;
+        let device = SyncDevice::new(temp_file.path())?;
+        let err = SyncDevice::new(temp_file.path()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::ResourceBusy);
+
+        // Closing the device releases the lock.
+        drop(device);
+        SyncDevice::new(temp_file.path())?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_pwrite_all() -> std::io::Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+        pwrite_all(temp_file.as_file().as_raw_fd(), &data, 100)?;
+        let contents = std::fs::read(temp_file.path())?;
+        assert_eq!(&contents[100..], &data[..]);
+
+        // Hard errors are returned.
+        let read_only = std::fs::File::open(temp_file.path())?;
+        let err = pwrite_all(read_only.as_raw_fd(), &data, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        assert!(!is_transient(libc::EBADF) && is_transient(libc::EINTR));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
     fn test_sync_device_basic_operations() -> std::io::Result<()> {
         let temp_file = NamedTempFile::new()?;
         temp_file.as_file().set_len(16 * 1024)?;
@@ -125,6 +416,27 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_reader() -> std::io::Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        temp_file.as_file().set_len(16 * 1024)?;
+        let mut device = SyncDevice::new(temp_file.path())?;
+        let reader = device.reader()?;
+
+        let pos = WalPosition {
+            offset: 1,
+            rollover: 0,
+        };
+        let mut aligned = AlignedSlice::new(5);
+        aligned.as_slice()[..5].copy_from_slice(b"hello");
+        device.write(pos, aligned, true)?;
+        assert_eq!(reader.read(pos.byte_offset(), 5)?, b"hello");
+        assert_eq!(device.read(pos.byte_offset(), 5)?, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
     fn test_sync_device_multiple_writes() -> std::io::Result<()> {
         let temp_file = NamedTempFile::new()?;
         temp_file.as_file().set_len(16 * 1024)?;
@@ -166,6 +478,29 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_process_watermark() -> std::io::Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        temp_file.as_file().set_len(16 * 1024)?;
+        let mut device = SyncDevice::new(temp_file.path())?;
+        assert_eq!(device.process_watermark(), None);
+
+        for offset in 0..3 {
+            let pos = WalPosition {
+                offset,
+                rollover: 0,
+            };
+            device.write(pos, AlignedSlice::new(10), offset != 1)?;
+        }
+        let watermark = device.process_watermark().unwrap();
+        assert_eq!(watermark.position.offset, 2);
+        assert_eq!(watermark.count, 2);
+        assert_eq!(device.process_completions().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
     fn test_sync_device_error_handling() -> std::io::Result<()> {
         let temp_file = NamedTempFile::new()?;
         temp_file.as_file().set_len(16 * 1024)?;
@@ -184,4 +519,41 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(miri, ignore)]
+    fn test_dsync() -> std::io::Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        temp_file.as_file().set_len(4 * BLOCK_SIZE as u64)?;
+        let mut device = SyncDevice::with_dsync(temp_file.path())?;
+        assert!(device.capabilities().dsync_writes);
+        assert!(
+            !SyncDevice::new(NamedTempFile::new()?.path())?
+                .capabilities()
+                .dsync_writes
+        );
+
+        let mut slice = AlignedSlice::new(BLOCK_SIZE as usize);
+        slice.as_slice()[0] = 7;
+        let pos = WalPosition {
+            offset: 1,
+            rollover: 0,
+        };
+        device.write(pos, slice, true)?;
+        assert!(!device.unsynced);
+        assert_eq!(device.process_completions().collect::<Vec<_>>(), vec![pos]);
+        assert_eq!(device.read(BLOCK_SIZE as u64, 1)?, [7]);
+
+        // Only a write without RWF_DSYNC needs the sync.
+        device.write(
+            WalPosition::default(),
+            AlignedSlice::new(BLOCK_SIZE as usize),
+            false,
+        )?;
+        assert!(device.unsynced);
+        device.sync()?;
+        assert!(!device.unsynced);
+        Ok(())
+    }
 }
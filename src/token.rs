@@ -0,0 +1,100 @@
+use crate::common::*;
+
+// offset (8) + rollover (4) + wal id (8)
+const TOKEN_SIZE: usize = 20;
+
+/// WalToken is a position tied to the wal it came from. A bare WalPosition means something in
+/// every wal, so one handed to the wrong wal, or to the same wal after it was reformatted, reads
+/// whatever happens to be there. Wal::resolve only returns the position of a token from the same
+/// wal.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct WalToken {
+    pub(crate) position: WalPosition,
+    pub(crate) wal_id: u64,
+}
+
+impl WalToken {
+    /// The id of the wal the token came from.
+    pub fn wal_id(&self) -> u64 {
+        self.wal_id
+    }
+
+    pub fn encode(&self) -> [u8; TOKEN_SIZE] {
+        let mut buffer = [0; TOKEN_SIZE];
+        buffer[..8].copy_from_slice(&self.position.offset.to_le_bytes());
+        buffer[8..12].copy_from_slice(&self.position.rollover.to_le_bytes());
+        buffer[12..].copy_from_slice(&self.wal_id.to_le_bytes());
+        buffer
+    }
+
+    pub fn decode(buffer: &[u8]) -> std::io::Result<WalToken> {
+        if buffer.len() != TOKEN_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("token of {} bytes, expected {TOKEN_SIZE}", buffer.len()),
+            ));
+        }
+        Ok(WalToken {
+            position: WalPosition {
+                offset: u64::from_le_bytes(buffer[..8].try_into().unwrap()),
+                rollover: u32::from_le_bytes(buffer[8..12].try_into().unwrap()),
+            },
+            wal_id: u64::from_le_bytes(buffer[12..].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::Wal;
+
+    #[test]
+    fn test_resolve() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let mut other = Wal::open("mem:64".parse().unwrap())?;
+        let first = wal.append(b"one")?;
+        other.append(b"other")?;
+        let second = wal.append(b"two")?;
+
+        let token = WalToken::decode(&wal.token(second).encode())?;
+        assert_eq!(wal.read_at(wal.resolve(&token)?)?, b"two");
+
+        // The same position in another wal is rejected.
+        let err = other.resolve(&token).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // So are positions that are truncated or past the head.
+        let truncated = wal.token(first);
+        wal.truncate(second)?;
+        assert_eq!(
+            wal.resolve(&truncated).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        let ahead = wal.token(wal.head().advance(1, 64));
+        assert_eq!(
+            wal.resolve(&ahead).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reformat() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let position = wal.append(b"one")?;
+        let token = wal.token(position);
+        drop(wal);
+
+        // Reopening keeps the id, reformatting picks a new one.
+        let wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        assert_eq!(wal.resolve(&token)?, token.position);
+        drop(wal);
+        let mut wal = Wal::format_device(Box::new(sim), 64)?;
+        wal.append(b"two")?;
+        assert_ne!(wal.id(), token.wal_id());
+        assert!(wal.resolve(&token).is_err());
+        Ok(())
+    }
+}
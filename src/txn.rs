@@ -0,0 +1,193 @@
+use crate::common::*;
+use crate::wal::{SyncPolicy, Wal};
+use log::warn;
+use std::collections::HashMap;
+
+// kind (1) + transaction id (8)
+const RECORD_HEADER_SIZE: usize = 9;
+
+const BEGIN: u8 = 1;
+const DATA: u8 = 2;
+const COMMIT: u8 = 3;
+const ABORT: u8 = 4;
+
+/// Record is the payload of an entry written as part of a transaction. Every record carries the id
+/// of its transaction so records of concurrent transactions can be interleaved in the wal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Begin(u64),
+    Data(u64, Vec<u8>),
+    Commit(u64),
+    Abort(u64),
+}
+
+impl Record {
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, id, data): (u8, u64, &[u8]) = match self {
+            Record::Begin(id) => (BEGIN, *id, &[]),
+            Record::Data(id, data) => (DATA, *id, data),
+            Record::Commit(id) => (COMMIT, *id, &[]),
+            Record::Abort(id) => (ABORT, *id, &[]),
+        };
+        let mut buffer = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+        buffer.push(kind);
+        buffer.extend_from_slice(&id.to_le_bytes());
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    pub fn decode(buffer: &[u8]) -> std::io::Result<Record> {
+        if buffer.len() < RECORD_HEADER_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "transaction record is too short",
+            ));
+        }
+        let id = u64::from_le_bytes(buffer[1..RECORD_HEADER_SIZE].try_into().unwrap());
+        match buffer[0] {
+            BEGIN => Ok(Record::Begin(id)),
+            DATA => Ok(Record::Data(id, buffer[RECORD_HEADER_SIZE..].to_vec())),
+            COMMIT => Ok(Record::Commit(id)),
+            ABORT => Ok(Record::Abort(id)),
+            kind => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown transaction record kind {kind}"),
+            )),
+        }
+    }
+}
+
+/// Append a transaction record to the wal. Commit records are synced before this returns, so a
+/// transaction is durable once its Commit record has been appended. The other records are batched.
+pub fn append_record(wal: &mut Wal, record: &Record) -> std::io::Result<WalPosition> {
+    let policy = match record {
+        Record::Commit(_) => SyncPolicy::Immediate,
+        _ => SyncPolicy::Batched,
+    };
+    wal.append_with(&record.encode(), policy)
+}
+
+/// A committed transaction returned by Committed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    pub id: u64,
+    /// The position of the Commit record.
+    pub commit: WalPosition,
+    /// The Data records of the transaction in the order they were appended.
+    pub entries: Vec<(WalPosition, Vec<u8>)>,
+}
+
+/// Committed adapts an iterator over wal entries into an iterator over committed transactions, in
+/// commit order. Aborted transactions and transactions without a Commit record, for instance ones
+/// that were in flight at a crash, are discarded.
+pub struct Committed<I> {
+    inner: I,
+    open: HashMap<u64, Vec<(WalPosition, Vec<u8>)>>,
+}
+
+impl<I> Committed<I>
+where
+    I: Iterator<Item = std::io::Result<(WalPosition, Vec<u8>)>>,
+{
+    pub fn new(inner: I) -> Self {
+        Committed {
+            inner,
+            open: HashMap::new(),
+        }
+    }
+}
+
+impl<I> Iterator for Committed<I>
+where
+    I: Iterator<Item = std::io::Result<(WalPosition, Vec<u8>)>>,
+{
+    type Item = std::io::Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (pos, payload) = match self.inner.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            let record = match Record::decode(&payload) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            match record {
+                Record::Begin(id) => {
+                    if self.open.insert(id, Vec::new()).is_some() {
+                        return Some(Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("transaction {id} began twice at {pos:?}"),
+                        )));
+                    }
+                }
+                Record::Data(id, data) => match self.open.get_mut(&id) {
+                    Some(entries) => entries.push((pos, data)),
+                    // The Begin was truncated away, the transaction can't be complete.
+                    None => warn!("Skipping data for unknown transaction {id} at {pos:?}"),
+                },
+                Record::Commit(id) => match self.open.remove(&id) {
+                    Some(entries) => {
+                        return Some(Ok(Transaction {
+                            id,
+                            commit: pos,
+                            entries,
+                        }))
+                    }
+                    None => warn!("Skipping commit for unknown transaction {id} at {pos:?}"),
+                },
+                Record::Abort(id) => {
+                    self.open.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_committed_transactions() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let records = [
+            Record::Begin(1),
+            Record::Begin(2),
+            Record::Data(1, b"one-a".to_vec()),
+            Record::Data(2, b"two-a".to_vec()),
+            Record::Begin(3),
+            Record::Data(1, b"one-b".to_vec()),
+            Record::Abort(2),
+            Record::Data(3, b"three-a".to_vec()),
+            Record::Commit(1),
+            // Transaction 3 never commits, as if the writer crashed.
+        ];
+        for record in &records {
+            append_record(&mut wal, record)?;
+        }
+
+        let committed = Committed::new(wal.iterate()).collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(committed.len(), 1);
+        assert_eq!(committed[0].id, 1);
+        let data: Vec<&[u8]> = committed[0].entries.iter().map(|e| &e.1[..]).collect();
+        assert_eq!(data, vec![&b"one-a"[..], &b"one-b"[..]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_round_trip() -> std::io::Result<()> {
+        for record in [
+            Record::Begin(7),
+            Record::Data(7, b"payload".to_vec()),
+            Record::Commit(u64::MAX),
+            Record::Abort(0),
+        ] {
+            assert_eq!(Record::decode(&record.encode())?, record);
+        }
+        assert!(Record::decode(&[9; RECORD_HEADER_SIZE]).is_err());
+        assert!(Record::decode(&[BEGIN]).is_err());
+        Ok(())
+    }
+}
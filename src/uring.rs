@@ -1,41 +1,113 @@
 use crate::common::*;
+use crate::dio;
+use crate::failpoint;
+use crate::ioprio::IoPriority;
+use crate::sync::{fadvise_willneed, is_transient, lock_exclusive, MAX_WRITE_RETRIES};
+use crate::wal::DEFAULT_QUEUE_DEPTH;
 use io_uring::{opcode, types, IoUring, Probe};
-use libc::{O_DIRECT, O_WRONLY};
-use std::ffi::CString;
+use log::warn;
 use std::fs::OpenOptions;
 use std::io::{Read, Seek};
-use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::RawFd;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::sync::Arc;
+
+// The number of entries in the submission queue of UringReader::read_many.
+const READ_QUEUE_DEPTH: u32 = 128;
 
 struct CompletionData {
     wal_position: WalPosition,
     slice: AlignedSlice,
     notify: bool,
+    // The bytes of slice written so far, more than 0 after a short write.
+    written: u64,
+    // The number of times the write was resubmitted after a transient error.
+    retries: u32,
 }
 
-/// LinuxUring uses io_uring to write to the underlying device.
+/// LinuxUring uses io_uring to write to the underlying device. Writes bypass the page cache with
+/// O_DIRECT unless the file doesn't support it with block aligned buffers, see dio::probe. Writes
+/// of a single block are made with RWF_ATOMIC when the file supports atomic writes of a block, see
+/// dio::probe_atomic, so a crash never tears them.
 pub struct LinuxUring {
     fd: RawFd,
+    // Whether fd was opened with O_DIRECT.
+    direct: bool,
+    // Whether single block writes are made with RWF_ATOMIC.
+    atomic: bool,
+    // Whether writes that ask for a completion are made with RWF_DSYNC.
+    dsync: bool,
+    // Whether a write was submitted without RWF_DSYNC since the last sync.
+    unsynced: bool,
     uring: IoUring,
-    // Only used for startup reads.
+    // Used for reads and syncs, writes go through fd.
     file: std::fs::File,
+    // The number of writes submitted that haven't completed.
+    in_flight: usize,
+    // Completions reaped by sync that process_completions hasn't returned yet.
+    ready: Vec<WalPosition>,
+    // Writes that didn't fit in the submission queue, including the rest of short writes and
+    // writes retried after a transient error. They are submitted by the next reap.
+    pending: Vec<CompletionData>,
+    // The ioprio every write is submitted with, 0 for none.
+    ioprio: u16,
+    // The first hard error a write completed with that take_failure hasn't returned.
+    failure: Option<std::io::Error>,
 }
 
 impl LinuxUring {
+    /// Open the file at path. Fails with Unsupported if io_uring isn't available, because the
+    /// kernel predates it, it is disabled, or a seccomp filter blocks it as many container runtimes
+    /// do, so the caller can fall back to another device.
     pub fn new(path: &Path) -> std::io::Result<Self> {
+        Self::with_queue_depth(path, false, DEFAULT_QUEUE_DEPTH)
+    }
+
+    /// Open the file at path like new, but make every write that asks for a completion with
+    /// RWF_DSYNC, so it is durable when it completes and sync only waits for the writes rather
+    /// than also calling fdatasync. Needs Linux 5.6 or later for RWF flags on io_uring writes.
+    pub fn with_dsync(path: &Path) -> std::io::Result<Self> {
+        Self::with_queue_depth(path, true, DEFAULT_QUEUE_DEPTH)
+    }
+
+    /// Open the file at path like new, or like with_dsync if dsync is set, with a submission
+    /// queue of queue_depth writes. Writes past it wait for earlier ones to complete before they
+    /// are submitted.
+    pub fn with_queue_depth(path: &Path, dsync: bool, queue_depth: u32) -> std::io::Result<Self> {
         let file: std::fs::File = OpenOptions::new().read(true).open(path)?;
-        let path = CString::new(path.as_os_str().as_bytes())?;
-        let fd = unsafe { libc::open(path.as_ptr(), O_WRONLY | O_DIRECT, 0o644) };
-        if fd < 0 {
-            return Err(std::io::Error::last_os_error());
-        }
+        lock_exclusive(&file, path)?;
+        let uring = Self::setup(queue_depth).map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EACCES) => std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("io_uring is not available: {e}"),
+            ),
+            _ => e,
+        })?;
+        let (fd, direct) = dio::open_for_writes(path)?;
+        // Atomic writes need O_DIRECT.
+        let atomic = direct && dio::probe_atomic(path)?.is_some_and(|unit| unit.covers_block());
 
-        let uring = IoUring::builder()
-            .setup_sqpoll(100)
-            .build(1024)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(LinuxUring {
+            fd,
+            direct,
+            atomic,
+            dsync,
+            unsynced: false,
+            uring,
+            file,
+            in_flight: 0,
+            ready: Vec::new(),
+            pending: Vec::new(),
+            ioprio: 0,
+            failure: None,
+        })
+    }
+}
 
+impl LinuxUring {
+    fn setup(queue_depth: u32) -> std::io::Result<IoUring> {
+        let uring = IoUring::builder().setup_sqpoll(100).build(queue_depth)?;
         let mut probe = Probe::new();
         uring.submitter().register_probe(&mut probe)?;
         if !probe.is_supported(opcode::Write::CODE) {
@@ -44,8 +116,114 @@ impl LinuxUring {
                 "io_uring write not supported",
             ));
         }
+        Ok(uring)
+    }
 
-        Ok(LinuxUring { fd, uring, file })
+    /// Submit every write from now on with priority, so the kernel serves it ahead of lower
+    /// priority I/O to the same disk.
+    pub fn set_io_priority(&mut self, priority: Option<IoPriority>) {
+        self.ioprio = priority.map_or(0, |priority| priority.value());
+    }
+
+    /// Whether writes bypass the page cache.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    // Queues the rest of the write. The box is handed back if the submission queue is full.
+    fn push(&mut self, data: Box<CompletionData>) -> Result<(), Box<CompletionData>> {
+        let written = data.written;
+        // The slice stays alive in the box until the write completes.
+        let ptr = unsafe { data.slice.as_ptr().add(written as usize) };
+        let dsync = self.dsync && data.notify;
+        // An atomic write is never short, so written is only more than 0 for other writes.
+        let atomic = self.atomic && data.slice.size() == BLOCK_SIZE as u64;
+        let mut flags = 0;
+        if dsync {
+            flags |= libc::RWF_DSYNC;
+        }
+        if atomic {
+            flags |= dio::RWF_ATOMIC;
+        }
+        let len = (data.slice.size() - written) as u32;
+        let entry = opcode::Write::new(types::Fd(self.fd), ptr, len)
+            .offset(data.wal_position.byte_offset() + written)
+            .rw_flags(flags)
+            .ioprio(self.ioprio)
+            .build();
+
+        // The kernel hands user_data back as an integer, so expose the provenance of the box for
+        // reap to recover it.
+        let raw = Box::into_raw(data);
+        let entry = entry.user_data(raw.expose_provenance() as u64);
+        if unsafe { self.uring.submission().push(&entry) }.is_err() {
+            return Err(unsafe { Box::from_raw(raw) });
+        }
+        self.in_flight += 1;
+        self.unsynced |= !dsync;
+        Ok(())
+    }
+
+    // Moves the completed writes to ready. Short writes and transient errors are submitted again,
+    // so only hard errors are dropped.
+    fn reap(&mut self) {
+        for data in std::mem::take(&mut self.pending) {
+            self.retry(Box::new(data));
+        }
+        // TODO: Return the iterator live as we go rather than collecting first.
+        let completed: Vec<(u64, i32)> = self
+            .uring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completed {
+            let mut data = unsafe {
+                Box::from_raw(std::ptr::with_exposed_provenance_mut::<CompletionData>(
+                    user_data as usize,
+                ))
+            };
+            self.in_flight -= 1;
+
+            match failpoint::check("uring::completion").map(|_| result) {
+                Ok(res) if res > 0 => {
+                    data.written += res as u64;
+                    if data.written < data.slice.size() {
+                        self.retry(data);
+                    } else if data.notify {
+                        self.ready.push(data.wal_position);
+                    }
+                }
+                Ok(res) if is_transient(-res) && data.retries < MAX_WRITE_RETRIES => {
+                    data.retries += 1;
+                    self.retry(data);
+                }
+                Ok(0) => {
+                    warn!("Write at {:?} wrote nothing", data.wal_position);
+                    self.failure.get_or_insert(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        format!("write at {:?} wrote nothing", data.wal_position),
+                    ));
+                }
+                Ok(res) => {
+                    let e = std::io::Error::from_raw_os_error(-res);
+                    warn!("Write at {:?} failed: {}", data.wal_position, e);
+                    self.failure.get_or_insert(e);
+                }
+                Err(e) => {
+                    warn!("Write at {:?} failed: {}", data.wal_position, e);
+                    self.failure.get_or_insert(e);
+                }
+            }
+        }
+        if let Err(e) = self.uring.submitter().submit() {
+            warn!("Failed to resubmit writes: {}", e);
+        }
+    }
+
+    fn retry(&mut self, data: Box<CompletionData>) {
+        if let Err(data) = self.push(data) {
+            self.pending.push(*data);
+        }
     }
 }
 
@@ -57,28 +235,19 @@ impl Drop for LinuxUring {
 
 impl PersistentDevice for LinuxUring {
     fn write(&mut self, pos: WalPosition, data: AlignedSlice, notify: bool) -> std::io::Result<()> {
-        let entry = opcode::Write::new(types::Fd(self.fd), data.buffer_ptr, data.size())
-            .offset(pos.byte_offset())
-            .build();
-
-        let data_box = Box::new(CompletionData {
+        let data = Box::new(CompletionData {
             slice: data,
             wal_position: pos,
             notify,
+            written: 0,
+            retries: 0,
         });
-
-        let entry = entry.user_data(Box::into_raw(data_box) as _);
-
-        unsafe {
-            let res = self.uring.submission().push(&entry);
-            if res.is_err() {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::WouldBlock,
-                    "submission queue full",
-                ));
-            }
+        // Writes queued earlier go first.
+        if self.pending.is_empty() {
+            self.retry(data);
+        } else {
+            self.pending.push(*data);
         }
-
         self.uring.submitter().submit().map(|_| ())
     }
 
@@ -87,24 +256,34 @@ impl PersistentDevice for LinuxUring {
     // appends as the data will be left around until the next append is called, and the user won't
     // be notified the data has been synced.
     fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
-        let mut v: Vec<WalPosition> = Vec::new();
+        self.reap();
+        std::mem::take(&mut self.ready).into_iter()
+    }
 
-        // TODO: Return the iterator live as we go rather than collecting first.
-        for cqe in self.uring.completion() {
-            let data = cqe.user_data();
-            let data = unsafe { Box::from_raw(data as *mut CompletionData) };
-            drop(data.slice);
+    // Keeps the allocation of ready for the next completions.
+    fn process_watermark(&mut self) -> Option<Watermark> {
+        self.reap();
+        Watermark::of(self.ready.drain(..))
+    }
 
-            if cqe.result() >= 0 && data.notify {
-                v.push(data.wal_position);
-            }
-            // TODO: How should an error result be handled, especially once this is converted to an
-            // iterator. If we get an error here, its not clear if the underlying device is still
-            // valid.
-            //
-            // The initial write buffer can now be dropped as the data is written to disk.
+    // Waits for every submitted write to complete, then syncs the file so the writes don't sit in
+    // the volatile cache of the drive. Writes made with RWF_DSYNC are durable already.
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.reap();
+        while self.in_flight > 0 || !self.pending.is_empty() {
+            self.uring.submitter().submit_and_wait(1)?;
+            self.reap();
+        }
+        if self.dsync && !self.unsynced {
+            return Ok(());
         }
-        v.into_iter()
+        self.file.sync_data()?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    fn take_failure(&mut self) -> Option<std::io::Error> {
+        self.failure.take()
     }
 
     fn read(&mut self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
@@ -113,4 +292,109 @@ impl PersistentDevice for LinuxUring {
         self.file.read_exact(&mut buffer)?;
         Ok(buffer)
     }
+
+    fn reader(&self) -> std::io::Result<Arc<dyn DeviceReader>> {
+        Ok(Arc::new(UringReader {
+            file: self.file.try_clone()?,
+        }))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            dsync_writes: self.dsync,
+            atomic_write_unit: self.atomic.then_some(BLOCK_SIZE),
+            ..Capabilities::default()
+        }
+    }
+}
+
+/// UringReader reads the file of a LinuxUring. read_many submits a batch of reads through a ring
+/// of its own, so the drive works on all of them at once, and reads made one at a time use pread.
+struct UringReader {
+    file: std::fs::File,
+}
+
+impl UringReader {
+    fn read_exact(&self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0; len];
+        self.file.read_exact_at(&mut buffer, pos)?;
+        Ok(buffer)
+    }
+}
+
+impl DeviceReader for UringReader {
+    fn read(&self, pos: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.read_exact(pos, len)
+    }
+
+    fn prefetch(&self, pos: u64, len: usize) -> std::io::Result<()> {
+        fadvise_willneed(&self.file, pos, len)
+    }
+
+    // Reads that come back short or with an error are made again with pread, which continues
+    // after short reads and reports the error.
+    fn read_many(
+        &self,
+        reads: &[(u64, usize)],
+        done: &mut dyn FnMut(usize, std::io::Result<Vec<u8>>),
+    ) {
+        let mut uring = match IoUring::new(READ_QUEUE_DEPTH) {
+            Ok(uring) => uring,
+            Err(e) => {
+                warn!("Reading one at a time, io_uring is not available: {}", e);
+                for (i, (pos, len)) in reads.iter().enumerate() {
+                    done(i, self.read_exact(*pos, *len));
+                }
+                return;
+            }
+        };
+        let fd = types::Fd(self.file.as_raw_fd());
+        // Allocated up front so the buffers never move while the kernel reads into them.
+        let mut buffers: Vec<Vec<u8>> = reads.iter().map(|(_, len)| vec![0; *len]).collect();
+        let mut next = 0;
+        let mut in_flight = 0;
+        while next < reads.len() || in_flight > 0 {
+            while next < reads.len() && in_flight < READ_QUEUE_DEPTH as usize {
+                let (pos, len) = reads[next];
+                let entry = opcode::Read::new(fd, buffers[next].as_mut_ptr(), len as u32)
+                    .offset(pos)
+                    .build()
+                    .user_data(next as u64);
+                if unsafe { uring.submission().push(&entry) }.is_err() {
+                    break;
+                }
+                next += 1;
+                in_flight += 1;
+            }
+            match uring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if is_transient(e.raw_os_error().unwrap_or(0)) => continue,
+                Err(e) => {
+                    // The kernel may still write to the buffers of the reads in flight, so they
+                    // are leaked rather than freed.
+                    warn!("Failed to submit reads: {}", e);
+                    let first = next - in_flight;
+                    std::mem::forget(buffers);
+                    for (i, (pos, len)) in reads.iter().enumerate().skip(first) {
+                        done(i, self.read_exact(*pos, *len));
+                    }
+                    return;
+                }
+            }
+            let completed: Vec<(u64, i32)> = uring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            for (user_data, result) in completed {
+                let i = user_data as usize;
+                in_flight -= 1;
+                let (pos, len) = reads[i];
+                if result >= 0 && result as usize == len {
+                    done(i, Ok(std::mem::take(&mut buffers[i])));
+                } else {
+                    done(i, self.read_exact(pos, len));
+                }
+            }
+        }
+    }
 }
@@ -1,419 +1,6067 @@
 use crate::common::*;
-use log::{debug, info, warn};
+use crate::control::{self, Checkpoint, ControlBlock, StreamState, CONTROL_BLOCKS, FORMAT_VERSION};
+use crate::cursor::WalCursor;
+use crate::driver::WalDriver;
+use crate::failpoint;
+use crate::grow::{GrowableDevice, DEFAULT_GROW_CHUNK_BLOCKS};
+use crate::index::LsnIndex;
+use crate::ioprio::IoPriority;
+use crate::primitives::{Arc, Mutex};
+use crate::reader::{Bounds, WalReader};
+use crate::retention::RetentionPolicy;
+use crate::rollover::{lap_file_name, write_lap};
+use crate::sidecar::SidecarDevice;
+use crate::snapshot::{SnapshotHook, SnapshotProvider};
+use crate::token::WalToken;
+use crate::watch::{Notifier, Watch};
+use log::{debug, error, info, warn};
 
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", not(miri)))]
+use crate::dax::DaxDevice;
+#[cfg(all(target_os = "linux", not(miri)))]
 use crate::uring::LinuxUring;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "macos", not(miri)))]
+use crate::kqueue::KQueue;
+#[cfg(all(target_os = "macos", not(miri)))]
 use crate::pwrite::MacOsAsyncIO;
 
+#[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+use crate::mmap::MappedDevice;
+#[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+use crate::shared::Publisher;
+
 use crate::sync::SyncDevice;
 
 use crc32fast::Hasher;
-use std::io::Error;
-use std::path::Path;
-use zerocopy::{FromBytes, IntoBytes};
-use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{Error, Read};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use zerocopy::byteorder::{LittleEndian, U32, U64};
+use zerocopy::{FromBytes as _, IntoBytes as _};
+use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+/// The size of the header written before the data of every entry.
+pub const HEADER_SIZE: usize = std::mem::size_of::<EntryHeader>();
+
+/// The number of most recent idempotency keys append_keyed remembers.
+pub const KEY_WINDOW: usize = 4096;
+
+// How long import and format wait for outstanding writes to make progress before giving up.
+const COMPLETION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The number of blocks zeroed by each write when formatting.
+const FORMAT_CHUNK_BLOCKS: u64 = 256;
+
+// The number of blocks of payload read at a time when verifying an entry.
+const VERIFY_CHUNK_BLOCKS: u64 = 64;
+
+// The number of blocks each device writes when calibrating.
+#[cfg(all(target_os = "macos", not(miri)))]
+const CALIBRATION_BLOCKS: u64 = 256;
+
+/// How long dropping a wal waits for outstanding writes unless set_drain_timeout is called.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
 
-static HEADER_SIZE: usize = std::mem::size_of::<EntryHeader>();
+/// How long a WalDriver lets a write stay outstanding before syncing the device, unless
+/// set_max_sync_interval is called.
+pub const DEFAULT_MAX_SYNC_INTERVAL: Duration = Duration::from_millis(5);
 
-#[repr(C, packed)]
-#[derive(Copy, Clone, Debug, KnownLayout, Immutable, FromBytes, IntoBytes)]
+/// The number of writes io_uring can have submitted at once unless WalOptions::queue_depth is
+/// set.
+pub const DEFAULT_QUEUE_DEPTH: u32 = 1024;
+
+// Catches a change to the header that doesn't come with a new FORMAT_VERSION.
+const _: () =
+    assert!(FORMAT_VERSION == 13 && HEADER_SIZE == 52 && TRAILER_SIZE == 8 && BLOCK_CRC_SIZE == 4);
+
+/// The size of the trailer at the end of the last block of an entry longer than a block. It holds
+/// TRAILER_MAGIC and the CRC of the header, so recovery can tell an entry whose last block was
+/// never written from its trailer alone, rather than reading the whole entry for its CRC.
+pub const TRAILER_SIZE: usize = 8;
+
+// Marks the trailer of an entry.
+const TRAILER_MAGIC: [u8; 4] = *b"WALT";
+
+// The stream of the entries erase_reclaimed writes over truncated entries. They keep recovery
+// following the entries of a pass, but are skipped by everything that reads entries.
+const ERASED_STREAM: u32 = u32::MAX;
+
+/// The size of the CRC at the end of every block of a wal formatted with WalOptions::block_crcs.
+pub const BLOCK_CRC_SIZE: usize = 4;
+
+/// The first block of the ring of entries. The blocks before it hold the control region.
+pub const RING_START: u64 = CONTROL_BLOCKS;
+
+// The layout of the header is part of the on-disk format. Adding or changing a field requires a new
+// FORMAT_VERSION, and devices written with another version are rejected when the control region is
+// read. Every field is little-endian, so a wal can be copied between hosts of either byte order.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, KnownLayout, Immutable, Unaligned, FromBytes, IntoBytes)]
 struct EntryHeader {
-    crc: u32,
-    rollover: u32,
+    crc: U32<LittleEndian>,
+    rollover: U32<LittleEndian>,
     // The length of the data.
-    len: u32,
+    len: U64<LittleEndian>,
+    // The stream this entry was appended to.
+    stream: U32<LittleEndian>,
+    // Sequence number of the entry, incremented by one for each entry.
+    lsn: U64<LittleEndian>,
+    // Wall clock time of the append in microseconds since the Unix epoch.
+    timestamp: U64<LittleEndian>,
+    // The idempotency key given to append_keyed, or 0 if there was none.
+    key: U64<LittleEndian>,
+    // The digest of the entry before it in a wal formatted with WalOptions::hash_chain, or 0.
+    chain: U64<LittleEndian>,
 }
 
 impl EntryHeader {
     // computes the crc skipping the first 4 bytes (which is where the CRC goes).
     fn compute_crc(&self, buffer: &[u8]) -> u32 {
         let mut hasher = Hasher::new();
-        hasher.update(&buffer[4..HEADER_SIZE + self.len as usize]);
+        hasher.update(&buffer[4..HEADER_SIZE + self.len.get() as usize]);
         hasher.finalize()
     }
 
     // This returns how many blocks are required to store the full entry.
-    fn num_blocks(&self) -> u32 {
-        (HEADER_SIZE + self.len as usize).div_ceil(BLOCK_SIZE as usize) as u32
+    fn num_blocks(&self, layout: BlockLayout) -> u64 {
+        layout.blocks_for(self.len.get() as usize)
+    }
+
+    // The trailer written at the end of the entry if it is longer than a block. The header holds
+    // the lsn and the rollover, so a trailer left by an earlier entry never matches.
+    fn trailer(&self) -> [u8; TRAILER_SIZE] {
+        let mut hasher = Hasher::new();
+        hasher.update(self.as_bytes());
+        let mut trailer = [0; TRAILER_SIZE];
+        trailer[..4].copy_from_slice(&TRAILER_MAGIC);
+        trailer[4..].copy_from_slice(&hasher.finalize().to_le_bytes());
+        trailer
+    }
+
+    // Padding is written as zeros, so it looks like an empty entry without a valid CRC. A real
+    // empty entry always has a valid CRC. buffer must hold at least the header.
+    fn is_padding(&self, buffer: &[u8]) -> bool {
+        self.len.get() == 0 && self.compute_crc(buffer) != self.crc.get()
+    }
+
+    // The digest of this entry with payload, held by the next entry of a hash chained wal: the
+    // first 8 bytes of the SHA-256 of the header and the payload.
+    fn digest(&self, payload: &[u8]) -> u64 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.as_bytes());
+        hasher.update(payload);
+        u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+
+    // Whether this is the header of an entry written by erase_reclaimed.
+    fn is_erased(&self) -> bool {
+        self.stream.get() == ERASED_STREAM
+    }
+
+    // Checks that this header, read from position, could have been written there, before its
+    // length is trusted for sizing a read. The rollover is only checked if same_pass is set, as
+    // recovery learns the pass from the headers it finds.
+    fn validate(
+        &self,
+        position: WalPosition,
+        same_pass: bool,
+        capacity: u64,
+        layout: BlockLayout,
+    ) -> std::io::Result<()> {
+        let fault = if self.len.get() > layout.max_len(capacity) {
+            HeaderFault::TooLong(self.len.get())
+        } else if position.offset + self.num_blocks(layout) > capacity {
+            HeaderFault::PastEnd(self.num_blocks(layout))
+        } else if same_pass && self.rollover.get() != position.rollover {
+            HeaderFault::Rollover(self.rollover.get())
+        } else {
+            return Ok(());
+        };
+        Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            CorruptHeader { position, fault },
+        ))
     }
 }
 
-pub struct WalIterator<'a> {
-    dev: &'a mut Box<dyn PersistentDevice>,
-    current: WalPosition,
-    end: WalPosition,
-    // number of blocks in the file.
-    capacity: u32,
+// The current wall clock time in microseconds since the Unix epoch, or 0 if the clock is set before
+// it.
+pub(crate) fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
 }
 
-impl<'a> WalIterator<'a> {
-    pub fn new(
-        dev: &'a mut Box<dyn PersistentDevice>,
-        start: WalPosition,
-        end: WalPosition,
-        capacity: u32,
-    ) -> Self {
-        WalIterator {
-            dev,
-            current: start,
-            end,
-            capacity,
-        }
+// Reads the entry at position, which must be in the live region described by bounds.
+pub(crate) fn read_entry(
+    dev: &dyn DeviceReader,
+    position: WalPosition,
+    bounds: Bounds,
+    capacity: u64,
+    layout: BlockLayout,
+) -> std::io::Result<Vec<u8>> {
+    if position < bounds.tail || position >= bounds.head {
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{position:?} is not in the live region"),
+        ));
+    }
+    let mut iter = WalIterator::new(dev, position, bounds.head, capacity).with_layout(layout);
+    match iter.next_entry() {
+        Some(Ok((_, pos, data))) if pos == position => Ok(data),
+        Some(Err(e)) => Err(e),
+        _ => Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("no entry at {position:?}"),
+        )),
     }
 }
 
-impl Iterator for WalIterator<'_> {
-    type Item = std::io::Result<(WalPosition, Vec<u8>)>;
+// Checks that start to end is a range of the live region described by bounds.
+pub(crate) fn check_range(
+    start: WalPosition,
+    end: WalPosition,
+    bounds: Bounds,
+) -> std::io::Result<()> {
+    if start > end || end > bounds.head {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{start:?} to {end:?} is not a range before the head {:?}",
+                bounds.head
+            ),
+        ));
+    }
+    if start < bounds.tail {
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{start:?} is before the tail {:?}", bounds.tail),
+        ));
+    }
+    Ok(())
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current >= self.end {
-            return None;
-        }
+// Opens the entry at position, which must be in the live region described by bounds, for reading
+// its payload a chunk at a time.
+pub(crate) fn open_entry(
+    dev: &dyn DeviceReader,
+    position: WalPosition,
+    bounds: Bounds,
+    capacity: u64,
+    layout: BlockLayout,
+) -> std::io::Result<EntryReader<'_>> {
+    if position < bounds.tail || position >= bounds.head {
+        return Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{position:?} is not in the live region"),
+        ));
+    }
+    let buffer = dev.read(position.byte_offset(), HEADER_SIZE)?;
+    let header = parse_header(&buffer, position, bounds, capacity, layout)?;
+    Ok(EntryReader::new(dev, header, position, layout))
+}
 
-        // Read header
-        let buffer = self
-            .dev
-            .read(self.current.byte_offset(), HEADER_SIZE)
-            .ok()?;
-        let header = match EntryHeader::read_from_bytes(&buffer) {
-            Ok(h) => h,
-            Err(_) => {
-                return Some(Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid header",
-                )))
-            }
-        };
-        debug!("Found header {:?}", header);
-        // Now we need to create a big enough buffer to hold the entire content if its bigger than
-        // one block. We could use an aligned slice, but its not strictly necessary.
-        let buffer = self
-            .dev
-            .read(
-                self.current.byte_offset(),
-                HEADER_SIZE + header.len as usize,
-            )
-            .ok()?;
+// Parses the header at the start of buffer, read from position, checking that it is the header of
+// an entry that ends within the live region described by bounds.
+fn parse_header(
+    buffer: &[u8],
+    position: WalPosition,
+    bounds: Bounds,
+    capacity: u64,
+    layout: BlockLayout,
+) -> std::io::Result<EntryHeader> {
+    let header = EntryHeader::read_from_bytes(&buffer[..HEADER_SIZE])
+        .map_err(|_| Error::new(std::io::ErrorKind::InvalidData, "Invalid header"))?;
+    if header.is_padding(buffer) || header.is_erased() {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("no entry at {position:?}"),
+        ));
+    }
+    header.validate(position, true, capacity, layout)?;
+    if position.advance(header.num_blocks(layout), capacity) > bounds.head {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("no entry at {position:?}"),
+        ));
+    }
+    Ok(header)
+}
 
-        // Verify CRC - somewhat redundant, but done anyways.
-        let crc = header.compute_crc(&buffer);
-        if header.len != 0 && header.crc != 0 && crc != header.crc {
-            return Some(Err(std::io::Error::new(
+// Reads the entries at positions, which must be in the live region described by bounds, calling
+// done with each position and its result as it completes. The first block of every entry is read
+// in one batch, and the rest of the entries that don't fit in it in a second.
+pub(crate) fn read_entries(
+    dev: &dyn DeviceReader,
+    positions: &[WalPosition],
+    bounds: Bounds,
+    capacity: u64,
+    layout: BlockLayout,
+    done: &mut dyn FnMut(WalPosition, std::io::Result<Vec<u8>>),
+) {
+    // The bytes the entry read from the start of buffer takes up with its header, and its payload
+    // if buffer holds all of it.
+    let payload = |position: WalPosition, buffer: &[u8]| {
+        let header = parse_header(buffer, position, bounds, capacity, layout)?;
+        let end = HEADER_SIZE + header.len.get() as usize;
+        if end > buffer.len() {
+            return Ok((end, None));
+        }
+        let crc = header.compute_crc(buffer);
+        if crc != header.crc.get() {
+            return Err(Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!(
-                    "iterator CRC mismatch {crc} != {:?} at {:?} with bytes {:?}",
-                    header, self.current, &buffer
-                ),
-            )));
+                format!("CRC mismatch {crc} != {} at {position:?}", header.crc.get()),
+            ));
         }
+        Ok((end, Some(buffer[HEADER_SIZE..end].to_vec())))
+    };
 
-        // Calculate next position
-        let next_offset = self.current.offset + header.num_blocks();
-        let current_pos = WalPosition {
-            offset: self.current.offset,
-            rollover: header.rollover,
-        };
-
-        if next_offset >= self.capacity {
-            self.current = WalPosition {
-                offset: 0,
-                rollover: header.rollover + 1,
-            };
+    let mut first = Vec::with_capacity(positions.len());
+    let mut live = Vec::with_capacity(positions.len());
+    for &position in positions {
+        if position < bounds.tail || position >= bounds.head {
+            done(
+                position,
+                Err(Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{position:?} is not in the live region"),
+                )),
+            );
         } else {
-            self.current = WalPosition {
-                offset: next_offset,
-                rollover: header.rollover,
-            };
+            first.push((position.byte_offset(), BLOCK_SIZE as usize));
+            live.push(position);
         }
+    }
 
-        Some(Ok((
-            current_pos,
-            buffer[HEADER_SIZE..][..header.len as usize].to_vec(),
-        )))
+    // The entries longer than a block, with the bytes to read for each.
+    let mut rest = Vec::new();
+    dev.read_many(&first, &mut |i, res| {
+        let position = live[i];
+        let buffer = match res.and_then(|buffer| layout.unpack(position, 0, buffer)) {
+            Ok(buffer) => buffer,
+            Err(e) => return done(position, Err(e)),
+        };
+        match payload(position, &buffer) {
+            Ok((_, Some(data))) => done(position, Ok(data)),
+            Ok((len, None)) => rest.push((position, len)),
+            Err(e) => done(position, Err(e)),
+        }
+    });
+    if rest.is_empty() {
+        return;
     }
+
+    let reads: Vec<(u64, usize)> = rest
+        .iter()
+        .map(|(position, len)| (position.byte_offset(), layout.device_len(*len)))
+        .collect();
+    dev.read_many(&reads, &mut |i, res| {
+        let position = rest[i].0;
+        // The whole entry was read, so the payload is always there.
+        let data = res
+            .and_then(|buffer| layout.unpack(position, 0, buffer))
+            .and_then(|buffer| payload(position, &buffer));
+        done(position, data.map(|(_, data)| data.unwrap_or_default()))
+    });
 }
 
-pub struct Wal {
-    dev: Box<dyn PersistentDevice>,
+// Checks the CRC of the entry at position, which must be in the live region described by bounds.
+// The payload is read a chunk at a time, so a large entry is never held in memory.
+pub(crate) fn verify_entry(
+    dev: &dyn DeviceReader,
+    position: WalPosition,
+    bounds: Bounds,
+    capacity: u64,
+    layout: BlockLayout,
+) -> std::io::Result<EntryMeta> {
+    let mut reader = open_entry(dev, position, bounds, capacity, layout)?;
+    let chunk = (VERIFY_CHUNK_BLOCKS * BLOCK_SIZE as u64) as usize;
+    let mut buffer = vec![0; chunk.min(reader.meta().len as usize)];
+    while reader.read(&mut buffer)? > 0 {}
+    Ok(reader.meta())
+}
 
-    // capacity in blocks
-    capacity: u32,
-    // offset into the file.
-    head: WalPosition,
-    // offset into the file.
-    tail: WalPosition,
+/// The number of blocks needed to store an entry with len bytes of data, including the trailer of
+/// an entry longer than a block, in a wal formatted without block CRCs.
+pub fn blocks_for(len: usize) -> u64 {
+    BlockLayout::default().blocks_for(len)
 }
 
-pub type WalResult = Result<WalPosition, Error>;
+/// The typed error, such as QuotaExceeded or WalFailed, carried by an io::Error the wal returned.
+/// Each of them comes inside an io::Error of the kind its doc names, so callers that only look at
+/// the kind don't need to know about them, and this gets the details back out. Returns None if e
+/// doesn't carry a T.
+pub fn wal_error<T: std::error::Error + 'static>(e: &std::io::Error) -> Option<&T> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<T>())
+}
 
-impl Wal {
-    // appends an entry to this WAL. The data is copied. The data is not guaranteed to be persisted
-    // to disk when this returns. To get the completion, listen on the receiver channel.
-    pub fn append(&mut self, data: &[u8]) -> std::io::Result<WalPosition> {
-        let mut aligned = AlignedSlice::new(data.len() + HEADER_SIZE);
-        let write_size = aligned.blocks;
+/// The error carried by the InvalidData error returned when a block of an entry doesn't match its
+/// own CRC, in a wal formatted with WalOptions::block_crcs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DamagedBlock {
+    /// The position of the entry the block belongs to.
+    pub entry: WalPosition,
+    /// The position of the damaged block.
+    pub block: WalPosition,
+}
 
-        // Move the head for the next write and clear out all the existing data between the
-        // head and that position.
-        if self.head.offset + write_size > self.capacity {
-            // TODO: This is going to confuse the caller since this will get returned from the call
-            // to process_completions. We should figure out a way to exclude this write. as the
-            // user never asked for it.
-            let aligned =
-                AlignedSlice::new(((self.capacity - self.head.offset) * BLOCK_SIZE) as usize);
-            self.dev
-                .write(self.head, aligned, false)
-                .map(|_| self.head)?;
+impl std::fmt::Display for DamagedBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "block {:?} of the entry at {:?} doesn't match its CRC",
+            self.block, self.entry
+        )
+    }
+}
 
-            self.head = WalPosition {
-                offset: 0,
-                rollover: self.head.rollover + 1,
-            }
+impl std::error::Error for DamagedBlock {}
+
+/// The error carried by the InvalidData error returned when a header could not have been written by
+/// the wal where it was found.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CorruptHeader {
+    /// The position the header was read from.
+    pub position: WalPosition,
+    pub fault: HeaderFault,
+}
+
+/// What is wrong with a CorruptHeader.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HeaderFault {
+    /// The length of the payload is more than the ring can hold.
+    TooLong(u64),
+    /// The entry of this many blocks would run past the end of the ring.
+    PastEnd(u64),
+    /// The header belongs to the pass over the ring with this rollover rather than the one it was
+    /// read in.
+    Rollover(u32),
+}
+
+impl std::fmt::Display for CorruptHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.fault {
+            HeaderFault::TooLong(len) => write!(
+                f,
+                "header at {:?} has a length of {len} bytes, more than the wal holds",
+                self.position
+            ),
+            HeaderFault::PastEnd(blocks) => write!(
+                f,
+                "header at {:?} is for an entry of {blocks} blocks past the end of the ring",
+                self.position
+            ),
+            HeaderFault::Rollover(rollover) => write!(
+                f,
+                "header at {:?} is from rollover {rollover}",
+                self.position
+            ),
         }
+    }
+}
 
-        // Create an aligned buffer that outlives this function. It is destroyed when completion
-        // happens.
-        let buffer = aligned.as_slice();
+impl std::error::Error for CorruptHeader {}
 
-        let mut header = EntryHeader {
-            crc: 0,
-            rollover: self.head.rollover,
-            len: data.len() as u32,
-        };
-        debug!("Writing header {:?}", header);
+/// The error carried by the InvalidData error returned from Wal::verify_chain when an entry doesn't
+/// hold the digest of the entry before it, so one of the two was changed after it was written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BrokenChain {
+    /// The position of the entry before the break.
+    pub previous: WalPosition,
+    /// The position of the entry that doesn't hold its digest.
+    pub position: WalPosition,
+}
 
-        buffer[..HEADER_SIZE].copy_from_slice(header.as_bytes());
-        buffer[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+impl std::fmt::Display for BrokenChain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "entry at {:?} doesn't hold the digest of the entry at {:?}",
+            self.position, self.previous
+        )
+    }
+}
 
-        header.crc = header.compute_crc(buffer);
-        // Re-copy the header with the CRC filled.
-        buffer[..HEADER_SIZE].copy_from_slice(header.as_bytes());
+impl std::error::Error for BrokenChain {}
 
-        let res = self.dev.write(self.head, aligned, true).map(|_| self.head);
+// How entries are laid out in the blocks of the ring. Without block CRCs an entry is stored as is.
+// With them, each block holds BLOCK_CRC_SIZE bytes less of the entry followed by the CRC of the
+// rest of the block, so the bytes of an entry are no longer contiguous on the device.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct BlockLayout {
+    block_crcs: bool,
+}
 
-        // move the head to the next position for the next write. Note that this might be the end
-        // of the file, but that is OK as it will be fixed by the subsequent write.
-        self.head.offset += write_size;
-        res
+impl BlockLayout {
+    pub(crate) fn new(block_crcs: bool) -> Self {
+        BlockLayout { block_crcs }
     }
 
-    // truncate will move the tail forward to this position. If the position is behind the current
-    // tail, then truncate is a no-op.
-    pub fn truncate(&mut self, position: WalPosition) {
-        let cur_rollover = self.head.rollover;
-        if position.rollover < cur_rollover {
-            // nothing to do, we are already past this position.
-        }
+    pub(crate) fn block_crcs(&self) -> bool {
+        self.block_crcs
+    }
 
-        if position > self.tail {
-            self.tail = position
+    // The bytes of an entry each block holds.
+    fn block_data(&self) -> usize {
+        if self.block_crcs {
+            BLOCK_SIZE as usize - BLOCK_CRC_SIZE
+        } else {
+            BLOCK_SIZE as usize
         }
     }
 
-    pub fn iterate(&mut self) -> WalIterator {
-        let iterator = WalIterator::new(&mut self.dev, self.tail, self.head, self.capacity);
-        info!("Recovering from {:?} to {:?}", self.tail, self.head);
-        iterator
+    fn blocks_for(&self, len: usize) -> u64 {
+        let bytes = HEADER_SIZE + len;
+        if bytes <= self.block_data() {
+            return 1;
+        }
+        (bytes + TRAILER_SIZE).div_ceil(self.block_data()) as u64
     }
 
-    // Note that truncated entries can be revived during a recover as truncation is not persistent.
-    // The caller needs to handle this and should call truncate after processing all the entries.
-    /// Open the given URI and begin recovery. The WalIterator is returned.
-    /// Supported URIs:
-    ///   - mem:// - Use an in-memory device
-    ///   - file:///path/to/file - Use a file-based device
-    ///   - /path/to/file - Use a file-based device (backwards compatibility)
-    pub fn open(url: url::Url) -> std::io::Result<Self> {
-        info!("Starting recovery from {}", url);
+    // The longest payload of an entry in a ring of capacity blocks, which takes up all of it.
+    fn max_len(&self, capacity: u64) -> u64 {
+        self.fill_len(capacity - RING_START)
+    }
 
-        let (dev, capacity) = Self::create_device(url)?;
+    // The longest payload of an entry of blocks blocks.
+    fn fill_len(&self, blocks: u64) -> u64 {
+        let data = self.block_data() as u64;
+        if blocks == 1 {
+            return data - HEADER_SIZE as u64;
+        }
+        blocks * data - (HEADER_SIZE + TRAILER_SIZE) as u64
+    }
 
-        let init_position = WalPosition {
-            offset: 0,
-            rollover: 0,
-        };
-        let mut wal = Wal {
-            dev,
-            capacity,
-            head: init_position,
-            tail: init_position,
-        };
+    // Lays out the entry with header and payload data in buffer, which must be as many blocks as
+    // it takes, filling in the CRC of the header and the trailer of an entry longer than a block.
+    // Returns the header with its CRC.
+    fn encode(&self, mut header: EntryHeader, data: &[u8], buffer: &mut [u8]) -> EntryHeader {
+        buffer[..HEADER_SIZE].copy_from_slice(header.as_bytes());
+        buffer[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+        header.crc = U32::new(header.compute_crc(buffer));
+        // Re-copy the header with the CRC filled.
+        buffer[..HEADER_SIZE].copy_from_slice(header.as_bytes());
+        let blocks = buffer.len() / BLOCK_SIZE as usize;
+        if blocks > 1 {
+            let end = blocks * self.block_data();
+            buffer[end - TRAILER_SIZE..end].copy_from_slice(&header.trailer());
+        }
+        self.seal(buffer);
+        header
+    }
 
-        recover(&mut wal)?;
+    // The offset on the device of byte at of the entry at pos.
+    fn device_offset(&self, pos: WalPosition, at: usize) -> u64 {
+        let block = at / self.block_data();
+        pos.byte_offset() + (block * BLOCK_SIZE as usize + at % self.block_data()) as u64
+    }
 
-        Ok(wal)
+    // The bytes to read from the start of an entry to get its first len bytes. With block CRCs this
+    // is every block they are in.
+    fn device_len(&self, len: usize) -> usize {
+        if !self.block_crcs {
+            return len;
+        }
+        len.div_ceil(self.block_data()) * BLOCK_SIZE as usize
     }
 
-    pub fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
-        self.dev.process_completions()
+    // Spreads the entry written to the start of buffer over its blocks and fills in the CRC at the
+    // end of each. The blocks are moved last first, so none is overwritten before it has moved.
+    fn seal(&self, buffer: &mut [u8]) {
+        if !self.block_crcs {
+            return;
+        }
+        let data = self.block_data();
+        let block = BLOCK_SIZE as usize;
+        for i in (0..buffer.len() / block).rev() {
+            buffer.copy_within(i * data..(i + 1) * data, i * block);
+            let crc = crc32fast::hash(&buffer[i * block..][..data]);
+            buffer[i * block + data..(i + 1) * block].copy_from_slice(&crc.to_le_bytes());
+        }
     }
 
-    fn create_device(url: url::Url) -> std::io::Result<(Box<dyn PersistentDevice>, u32)> {
-        if url.scheme() == "mem" {
-            // Parse size from path (e.g. mem://64 means 64 blocks)
-            let blocks = url.path().parse::<u32>().unwrap();
-            let dev: Box<dyn PersistentDevice> = Box::new(crate::mem::MemDevice::new(blocks));
-            Ok((dev, blocks))
-        } else if url.scheme() == "file" {
-            let dev: Box<dyn PersistentDevice>;
-            // Handle file paths
-            let path = Path::new(url.path());
-            println!("{:?}", path);
+    // Strips the CRCs from the blocks read from the entry at pos starting at its block first,
+    // returning the block that doesn't match its CRC if there is one.
+    fn strip(
+        &self,
+        pos: WalPosition,
+        first: usize,
+        buffer: Vec<u8>,
+    ) -> (Vec<u8>, Option<DamagedBlock>) {
+        if !self.block_crcs {
+            return (buffer, None);
+        }
+        let data = self.block_data();
+        let mut entry = Vec::with_capacity(buffer.len() / BLOCK_SIZE as usize * data);
+        let mut damaged = None;
+        for (i, block) in buffer.chunks(BLOCK_SIZE as usize).enumerate() {
+            let crc = u32::from_le_bytes(block[data..].try_into().unwrap());
+            if damaged.is_none() && crc32fast::hash(&block[..data]) != crc {
+                damaged = Some(DamagedBlock {
+                    entry: pos,
+                    block: WalPosition {
+                        offset: pos.offset + (first + i) as u64,
+                        rollover: pos.rollover,
+                    },
+                });
+            }
+            entry.extend_from_slice(&block[..data]);
+        }
+        (entry, damaged)
+    }
 
-            // Check if we should force using specific devices
-            // TODO: This would be better as a different url scheme.
-            let use_sync = std::env::var("WAL_SYNC_DEVICE").is_ok();
+    // Like strip, but fails with the DamagedBlock.
+    fn unpack(&self, pos: WalPosition, first: usize, buffer: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        match self.strip(pos, first, buffer) {
+            (_, Some(damaged)) => Err(Error::new(std::io::ErrorKind::InvalidData, damaged)),
+            (entry, None) => Ok(entry),
+        }
+    }
 
-            if use_sync {
-                dev = Box::new(SyncDevice::new(path)?);
-            } else {
-                // Use platform-specific device implementations
-                #[cfg(target_os = "linux")]
-                {
-                    dev = Box::new(LinuxUring::new(path)?);
-                }
-                #[cfg(target_os = "macos")]
-                {
-                    dev = Box::new(MacOsAsyncIO::new(path)?);
-                }
-                #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-                {
-                    dev = Box::new(SyncDevice::new(path)?);
-                }
-            }
+    // Reads len bytes of the entry at pos from its byte at, along with the first block they are in
+    // that doesn't match its CRC.
+    fn read_raw(
+        &self,
+        dev: &dyn DeviceReader,
+        pos: WalPosition,
+        at: usize,
+        len: usize,
+    ) -> std::io::Result<(Vec<u8>, Option<DamagedBlock>)> {
+        if !self.block_crcs || len == 0 {
+            return Ok((dev.read(self.device_offset(pos, at), len)?, None));
+        }
+        let data = self.block_data();
+        let first = at / data;
+        let blocks = (at + len).div_ceil(data) - first;
+        let buffer = dev.read(
+            pos.byte_offset() + (first * BLOCK_SIZE as usize) as u64,
+            blocks * BLOCK_SIZE as usize,
+        )?;
+        let (entry, damaged) = self.strip(pos, first, buffer);
+        Ok((entry[at - first * data..][..len].to_vec(), damaged))
+    }
 
-            let path = Path::new(url.path());
-            let capacity_bytes = path.metadata()?.len();
-            if capacity_bytes % BLOCK_SIZE as u64 != 0 {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!(
-                        "size {} is not a multiple of BLOCK_SIZE {}",
-                        capacity_bytes, BLOCK_SIZE
-                    ),
-                ));
-            }
-            let capacity = (capacity_bytes / BLOCK_SIZE as u64) as u32;
-            Ok((dev, capacity))
-        } else {
-            Err(Error::new(
-                std::io::ErrorKind::Unsupported,
-                "Unknown URI scheme",
-            ))
+    // Reads len bytes of the entry at pos from its byte at, failing with a DamagedBlock if a block
+    // they are in doesn't match its CRC.
+    fn read(
+        &self,
+        dev: &dyn DeviceReader,
+        pos: WalPosition,
+        at: usize,
+        len: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        match self.read_raw(dev, pos, at, len)? {
+            (_, Some(damaged)) => Err(Error::new(std::io::ErrorKind::InvalidData, damaged)),
+            (entry, None) => Ok(entry),
         }
     }
 }
 
-// Reads from the device to initialize the wal head and tail.
-fn recover(wal: &mut Wal) -> Result<(), Error> {
-    loop {
-        let buffer = wal.dev.read(wal.head.byte_offset(), BLOCK_SIZE as usize)?;
+// An entry as read from the device along with the CRC computed over it, which doesn't match the one
+// in the header if the entry is damaged.
+struct RawEntry {
+    header: EntryHeader,
+    pos: WalPosition,
+    buffer: Vec<u8>,
+    crc: u32,
+    // The first block of the entry that doesn't match its own CRC.
+    damaged: Option<DamagedBlock>,
+}
 
-        // Read the header including the CRC.
-        let header = match EntryHeader::read_from_bytes(&buffer[..HEADER_SIZE]) {
-            Ok(h) => h,
-            Err(_) => break,
-        };
+pub struct WalIterator<'a> {
+    dev: &'a dyn DeviceReader,
+    current: WalPosition,
+    end: WalPosition,
+    // number of blocks in the file.
+    capacity: u64,
+    layout: BlockLayout,
+}
 
-        // We don't support writing 0 length entries. If we find a zero it means the data
-        // wasn't initialized.
-        // TODO: Enforce not allowing 0 length writes.
-        if header.len == 0 {
-            debug!("Found empty entry");
-            break;
+impl<'a> WalIterator<'a> {
+    pub fn new(
+        dev: &'a dyn DeviceReader,
+        start: WalPosition,
+        end: WalPosition,
+        capacity: u64,
+    ) -> Self {
+        WalIterator {
+            dev,
+            current: start,
+            end,
+            capacity,
+            layout: BlockLayout::default(),
         }
+    }
 
-        // Back up and read the entire data in one buffer.
-        let buffer = wal
-            .dev
-            .read(wal.head.byte_offset(), HEADER_SIZE + header.len as usize)?;
+    // Read the entries as laid out by a wal formatted with or without block CRCs.
+    pub(crate) fn with_layout(mut self, layout: BlockLayout) -> Self {
+        self.layout = layout;
+        self
+    }
 
-        // Verify CRC
-        let crc = header.compute_crc(&buffer);
-        if crc != header.crc {
-            warn!("open CRC mismatch {crc}, {:?}", header);
-            break;
-        }
+    /// The position the iterator will continue from. After an entry is returned this is the
+    /// position just past it, which is what a consumer should ack once it has processed the entry.
+    pub fn position(&self) -> WalPosition {
+        self.current
+    }
 
-        debug!("Head {:?}, found {:?}", wal.head, header);
-        // Stop once we find an entry that goes backwards.
-        if header.rollover < wal.head.rollover {
-            debug!("Found older entry");
-            break;
-        }
+    /// Turn this into an iterator that returns the header information along with each entry.
+    pub fn with_meta(self) -> MetaIterator<'a> {
+        MetaIterator { inner: self }
+    }
 
-        // Otherwise find the next place to try and read from (TODO: Handle the overflow case).
-        let next_offset = wal.head.offset + header.num_blocks();
-        if next_offset >= wal.capacity {
-            debug!("Found end of file");
-            break;
+    /// Open the next entry for reading its payload a piece at a time, rather than reading all of
+    /// it like next does.
+    pub fn next_reader(&mut self) -> Option<std::io::Result<EntryReader<'a>>> {
+        let dev = self.dev;
+        let layout = self.layout;
+        Some(
+            self.next_header()?
+                .map(|(header, pos)| EntryReader::new(dev, header, pos, layout)),
+        )
+    }
+
+    /// Turn this into an iterator over the entries whose header information passes filter. The
+    /// filter is called before the payload is read, so entries it rejects are skipped over
+    /// without reading them. Damaged entries that pass fail with InvalidData like they do here.
+    pub fn filter_meta<F: FnMut(&EntryMeta) -> bool>(self, filter: F) -> FilterIterator<'a, F> {
+        FilterIterator {
+            inner: self,
+            filter,
         }
-        wal.head.offset = next_offset;
-        wal.head.rollover = header.rollover;
-        debug!("Moving head to {:?}", wal.head);
     }
-    Ok(if wal.head.rollover > 0 {
-        wal.tail = WalPosition {
-            offset: wal.head.offset,
-            rollover: wal.head.rollover - 1,
-        };
 
-        debug!("Finding tail starting from {:?}", wal.tail);
+    /// Only return the entries of stream, see filter_meta.
+    pub fn in_stream(self, stream: StreamId) -> FilterIterator<'a, impl FnMut(&EntryMeta) -> bool> {
+        self.filter_meta(move |meta| meta.stream == stream)
+    }
 
-        // We need to find the old tail based on where the head ended. Scan forward from where the
-        // head currently is until we find a valid entry that is one rollover behind us.
-        for offset in (wal.tail.offset..wal.capacity).step_by(BLOCK_SIZE as usize) {
-            debug!("Checking offset {}", offset);
+    /// Only return the entries at positions in range, see filter_meta. The iterator stops once it
+    /// reaches the end of the range.
+    pub fn in_range(
+        mut self,
+        range: Range<WalPosition>,
+    ) -> FilterIterator<'a, impl FnMut(&EntryMeta) -> bool> {
+        self.end = self.end.min(range.end);
+        self.filter_meta(move |meta| meta.position >= range.start)
+    }
 
-            let buffer = wal.dev.read(wal.head.byte_offset(), BLOCK_SIZE as usize)?;
-            // Read the header including the CRC.
-            let header = EntryHeader::read_from_bytes(&buffer[..HEADER_SIZE]);
+    /// Only return the entries appended during range, in microseconds since the Unix epoch, see
+    /// filter_meta. The timestamps come from the wall clock, which can go backwards, so the
+    /// iterator keeps looking at the entries after the first one appended past the range.
+    pub fn appended_between(
+        self,
+        range: Range<u64>,
+    ) -> FilterIterator<'a, impl FnMut(&EntryMeta) -> bool> {
+        self.filter_meta(move |meta| range.contains(&meta.timestamp))
+    }
+}
 
-            // This can happen because there was garbage before our first entry, keep scanning
-            // forwards until we find something useful.
-            // TODO: This might not ever happen
-            if header.is_err() {
-                debug!("Found undecodable header, skipping");
-                continue;
+impl WalIterator<'_> {
+    // Reads the header at the current position and moves to the next entry without reading the
+    // data.
+    fn next_header(&mut self) -> Option<std::io::Result<(EntryHeader, WalPosition)>> {
+        let header = loop {
+            if self.current >= self.end {
+                return None;
             }
 
-            let header = header.unwrap();
+            // Read header
+            if let Err(e) = failpoint::check("wal::header_parse") {
+                return Some(Err(e));
+            }
+            let start = self.current.byte_offset() as usize;
+            let read;
+            let buffer = match self.dev.mapped() {
+                Some(map) => map.get(start..start + HEADER_SIZE)?,
+                None => {
+                    read = self.dev.read(start as u64, HEADER_SIZE).ok()?;
+                    &read[..]
+                }
+            };
+            let header = match EntryHeader::read_from_bytes(buffer) {
+                Ok(h) => h,
+                Err(_) => {
+                    return Some(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Invalid header",
+                    )))
+                }
+            };
+            debug!("Found header {:?}", header);
 
-            if header.rollover != wal.tail.rollover {
-                debug!(
-                    "Found a header with the wrong rollover, skipping {:?}",
-                    header
-                );
+            // The rest of the ring after the last entry of a pass is zeroed padding, continue
+            // from the start of the ring.
+            if header.is_padding(buffer) {
+                self.current = self.current.next_pass();
                 continue;
             }
-
-            // TODO: Add a security mechanism against someone writing a bad block that looks like a
-            // header and checks out from a CRC perspective.
-            //
-            // Make sure the data really is valid by checking the CRC.
-            let buffer = wal
-                .dev
-                .read(wal.head.byte_offset(), HEADER_SIZE + header.len as usize)?;
-
-            // Verify CRC
-            let crc = header.compute_crc(&buffer);
-            if crc != header.crc {
-                warn!("Tail CRC mismatch {crc}, {:?}", header);
+            if let Err(e) = header.validate(self.current, true, self.capacity, self.layout) {
+                return Some(Err(e));
+            }
+            if header.is_erased() {
+                self.current = self
+                    .current
+                    .advance(header.num_blocks(self.layout), self.capacity);
                 continue;
             }
+            break header;
+        };
 
-            // At this point we found a valid old entry. Set this as our tail and we are done.
-            wal.tail.offset = offset;
-            break;
-        }
-    })
-}
+        // Calculate next position
+        let current_pos = WalPosition {
+            offset: self.current.offset,
+            rollover: header.rollover.get(),
+        };
+        self.current = current_pos.advance(header.num_blocks(self.layout), self.capacity);
+
+        Some(Ok((header, current_pos)))
+    }
+
+    // Returns the next entry without checking its CRC.
+    fn next_unverified(&mut self) -> Option<std::io::Result<RawEntry>> {
+        let (header, pos) = match self.next_header()? {
+            Ok(h) => h,
+            Err(e) => return Some(Err(e)),
+        };
+        self.read_unverified(header, pos).map(Ok)
+    }
+
+    // Reads the entry with the header at pos without checking its CRC.
+    fn read_unverified(&self, header: EntryHeader, pos: WalPosition) -> Option<RawEntry> {
+        // Now we need to create a big enough buffer to hold the entire content if its bigger than
+        // one block. We could use an aligned slice, but its not strictly necessary.
+        let (buffer, damaged) = self
+            .layout
+            .read_raw(self.dev, pos, 0, HEADER_SIZE + header.len.get() as usize)
+            .ok()?;
+        let crc = header.compute_crc(&buffer);
+        Some(RawEntry {
+            header,
+            pos,
+            buffer,
+            crc,
+            damaged,
+        })
+    }
+
+    // Returns the next entry whose header information passes filter, skipping the others without
+    // reading their payloads.
+    fn next_matching(
+        &mut self,
+        filter: &mut impl FnMut(&EntryMeta) -> bool,
+    ) -> Option<std::io::Result<Entry>> {
+        loop {
+            let (header, pos) = match self.next_header()? {
+                Ok(h) => h,
+                Err(e) => return Some(Err(e)),
+            };
+            if !filter(&entry_meta(&header, pos)) {
+                continue;
+            }
+            let raw = self.read_unverified(header, pos)?;
+            return Some(
+                verify_raw(raw).map(|(header, pos, payload)| verified_entry(&header, pos, payload)),
+            );
+        }
+    }
+
+    // Returns the next entry along with its header.
+    fn next_entry(&mut self) -> Option<std::io::Result<(EntryHeader, WalPosition, Vec<u8>)>> {
+        Some(self.next_unverified()?.and_then(verify_raw))
+    }
+}
+
+// Checks the CRCs of an entry, returning its payload if they match.
+fn verify_raw(raw: RawEntry) -> std::io::Result<(EntryHeader, WalPosition, Vec<u8>)> {
+    let RawEntry {
+        header,
+        pos,
+        buffer,
+        crc,
+        damaged,
+    } = raw;
+    if let Some(damaged) = damaged {
+        return Err(Error::new(std::io::ErrorKind::InvalidData, damaged));
+    }
+
+    // Verify CRC - somewhat redundant, but done anyways.
+    if crc != header.crc.get() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "iterator CRC mismatch {crc} != {:?} at {:?} with bytes {:?}",
+                header, pos, &buffer
+            ),
+        ));
+    }
+
+    let payload = buffer[HEADER_SIZE..][..header.len.get() as usize].to_vec();
+    Ok((header, pos, payload))
+}
+
+fn entry_meta(header: &EntryHeader, position: WalPosition) -> EntryMeta {
+    EntryMeta {
+        position,
+        len: header.len.get(),
+        stream: StreamId(header.stream.get()),
+        lsn: header.lsn.get(),
+        timestamp: header.timestamp.get(),
+        key: entry_key(header),
+    }
+}
+
+fn verified_entry(header: &EntryHeader, position: WalPosition, payload: Vec<u8>) -> Entry {
+    Entry {
+        position,
+        len: header.len.get(),
+        crc_valid: true,
+        stream: StreamId(header.stream.get()),
+        lsn: header.lsn.get(),
+        timestamp: header.timestamp.get(),
+        key: entry_key(header),
+        payload,
+    }
+}
+
+impl Iterator for WalIterator<'_> {
+    type Item = std::io::Result<(WalPosition, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry()
+            .map(|entry| entry.map(|(_, pos, data)| (pos, data)))
+    }
+}
+
+/// An entry along with the information from its header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The position of the entry, which includes the rollover from its header.
+    pub position: WalPosition,
+    /// The length of the payload.
+    pub len: u64,
+    /// Whether the CRC in the header matches the entry, and with block CRCs whether every block
+    /// matches its own. A damaged entry is still returned so tools can report it.
+    pub crc_valid: bool,
+    /// The stream the entry was appended to.
+    pub stream: StreamId,
+    pub lsn: u64,
+    /// When the entry was appended, in microseconds since the Unix epoch. This is the wall clock
+    /// of the appending process, so it can go backwards if the clock is adjusted.
+    pub timestamp: u64,
+    /// The idempotency key the entry was appended with by append_keyed.
+    pub key: Option<u64>,
+    pub payload: Vec<u8>,
+}
+
+/// The header information of an entry.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub position: WalPosition,
+    /// The length of the payload.
+    pub len: u64,
+    pub stream: StreamId,
+    pub lsn: u64,
+    /// When the entry was appended, in microseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub key: Option<u64>,
+}
+
+/// EntryReader reads the payload of an entry a piece at a time, so an entry of any size can be
+/// replayed without holding all of it in memory. The CRC covers the whole entry, so it is only
+/// checked once the last of the payload is read: a damaged entry fails that last read with
+/// InvalidData, and anything done with the earlier pieces must be undone or discarded.
+pub struct EntryReader<'a> {
+    dev: &'a dyn DeviceReader,
+    layout: BlockLayout,
+    meta: EntryMeta,
+    crc: u32,
+    hasher: Hasher,
+    // The number of payload bytes read so far.
+    offset: usize,
+}
+
+impl<'a> EntryReader<'a> {
+    fn new(
+        dev: &'a dyn DeviceReader,
+        header: EntryHeader,
+        position: WalPosition,
+        layout: BlockLayout,
+    ) -> Self {
+        let mut hasher = Hasher::new();
+        hasher.update(&header.as_bytes()[4..]);
+        EntryReader {
+            dev,
+            layout,
+            meta: entry_meta(&header, position),
+            crc: header.crc.get(),
+            hasher,
+            offset: 0,
+        }
+    }
+
+    /// The header information of the entry.
+    pub fn meta(&self) -> EntryMeta {
+        self.meta
+    }
+
+    /// The number of bytes of the payload not read yet.
+    pub fn remaining(&self) -> usize {
+        self.meta.len as usize - self.offset
+    }
+}
+
+impl std::io::Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.remaining());
+        if len == 0 {
+            return Ok(0);
+        }
+        let data =
+            self.layout
+                .read(self.dev, self.meta.position, HEADER_SIZE + self.offset, len)?;
+        let mut hasher = self.hasher.clone();
+        hasher.update(&data);
+        if len == self.remaining() {
+            let crc = hasher.clone().finalize();
+            if crc != self.crc {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "CRC mismatch {crc} != {} at {:?}",
+                        self.crc, self.meta.position
+                    ),
+                ));
+            }
+        }
+        buf[..len].copy_from_slice(&data);
+        self.hasher = hasher;
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+/// FilterIterator returns the entries of a WalIterator whose header information passes a filter,
+/// along with that information. See WalIterator::filter_meta.
+pub struct FilterIterator<'a, F> {
+    inner: WalIterator<'a>,
+    filter: F,
+}
+
+impl<F> FilterIterator<'_, F> {
+    /// The position the iterator will continue from, see WalIterator::position.
+    pub fn position(&self) -> WalPosition {
+        self.inner.position()
+    }
+}
+
+impl<F: FnMut(&EntryMeta) -> bool> Iterator for FilterIterator<'_, F> {
+    type Item = std::io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next_matching(&mut self.filter)
+    }
+}
+
+/// MetaIterator returns every entry with its header information, including damaged entries.
+pub struct MetaIterator<'a> {
+    inner: WalIterator<'a>,
+}
+
+impl MetaIterator<'_> {
+    /// The position the iterator will continue from, see WalIterator::position.
+    pub fn position(&self) -> WalPosition {
+        self.inner.position()
+    }
+}
+
+impl Iterator for MetaIterator<'_> {
+    type Item = std::io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let RawEntry {
+            header,
+            pos,
+            buffer,
+            crc,
+            damaged,
+        } = match self.inner.next_unverified()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(Ok(Entry {
+            position: pos,
+            len: header.len.get(),
+            crc_valid: crc == header.crc.get() && damaged.is_none(),
+            stream: StreamId(header.stream.get()),
+            lsn: header.lsn.get(),
+            timestamp: header.timestamp.get(),
+            key: entry_key(&header),
+            payload: buffer[HEADER_SIZE..].to_vec(),
+        }))
+    }
+}
+
+/// DurableIterator returns the durable entries from a position on, see Wal::iterate_durable.
+pub struct DurableIterator<'a> {
+    inner: MetaIterator<'a>,
+    end: WalPosition,
+}
+
+impl DurableIterator<'_> {
+    /// The position after the last entry returned, where iterating again would continue from.
+    pub fn position(&self) -> WalPosition {
+        self.inner.position()
+    }
+}
+
+impl Iterator for DurableIterator<'_> {
+    type Item = std::io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.inner.position() >= self.end {
+            return None;
+        }
+        Some(self.inner.next()?.and_then(check_entry))
+    }
+}
+
+// Returns entry, or InvalidData if it doesn't match its CRC.
+pub(crate) fn check_entry(entry: Entry) -> std::io::Result<Entry> {
+    if !entry.crc_valid {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("damaged entry at {:?}", entry.position),
+        ));
+    }
+    Ok(entry)
+}
+
+// The entries written that the device hasn't reported complete, with the bytes of each write.
+#[derive(Default)]
+struct InFlight {
+    writes: BTreeMap<WalPosition, u64>,
+    bytes: u64,
+}
+
+impl InFlight {
+    fn insert(&mut self, pos: WalPosition, bytes: u64) {
+        self.writes.insert(pos, bytes);
+        self.bytes += bytes;
+    }
+
+    // Returns whether pos was in flight.
+    fn remove(&mut self, pos: &WalPosition) -> bool {
+        match self.writes.remove(pos) {
+            Some(bytes) => {
+                self.bytes -= bytes;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn first(&self) -> Option<WalPosition> {
+        self.writes.keys().next().copied()
+    }
+
+    fn any_before(&self, pos: WalPosition) -> bool {
+        self.writes.range(..pos).next().is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.writes.len()
+    }
+}
+
+// Holds back the completions of entries appended after a fence until every entry before it has
+// completed.
+#[derive(Default)]
+struct Fences {
+    // The head at each fence that is still waiting on earlier entries, oldest first.
+    at: VecDeque<WalPosition>,
+    // Completions of entries after the first fence.
+    held: BTreeSet<WalPosition>,
+}
+
+impl Fences {
+    // Returns the completions that can be reported now. in_flight holds the entries that haven't
+    // completed yet.
+    fn release(&mut self, completions: Vec<WalPosition>, in_flight: &InFlight) -> Vec<WalPosition> {
+        let mut released = Vec::new();
+        for pos in completions {
+            match self.at.front() {
+                Some(fence) if pos >= *fence => {
+                    self.held.insert(pos);
+                }
+                _ => released.push(pos),
+            }
+        }
+        while let Some(fence) = self.at.front().copied() {
+            if in_flight.any_before(fence) {
+                break;
+            }
+            self.at.pop_front();
+            // Completions after the next fence stay held for it.
+            let rest = match self.at.front() {
+                Some(next) => self.held.split_off(next),
+                None => BTreeSet::new(),
+            };
+            released.extend(std::mem::replace(&mut self.held, rest));
+        }
+        released
+    }
+}
+
+// Holds back completions that arrive ahead of the completion of an earlier entry.
+#[derive(Default)]
+struct CompletionOrder {
+    // Entries appended while ordering was on whose completion hasn't been reported, oldest first.
+    pending: VecDeque<WalPosition>,
+    // Completions of entries in pending that arrived early.
+    done: HashSet<WalPosition>,
+}
+
+impl CompletionOrder {
+    // Returns the completions that can be reported now. Completions of entries appended while
+    // ordering was off are passed through as they arrive.
+    fn release(&mut self, completions: Vec<WalPosition>) -> Vec<WalPosition> {
+        let (first, last) = match (self.pending.front(), self.pending.back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return completions,
+        };
+        let mut released = Vec::new();
+        for pos in completions {
+            if pos >= first && pos <= last {
+                self.done.insert(pos);
+            } else {
+                released.push(pos);
+            }
+        }
+        while let Some(pos) = self.pending.front() {
+            if !self.done.remove(pos) {
+                break;
+            }
+            released.push(*pos);
+            self.pending.pop_front();
+        }
+        released
+    }
+}
+
+fn entry_key(header: &EntryHeader) -> Option<u64> {
+    (header.key.get() != 0).then_some(header.key.get())
+}
+
+// The positions of the entries appended with the most recent idempotency keys.
+#[derive(Default)]
+struct RecentKeys {
+    positions: HashMap<u64, WalPosition>,
+    // Oldest first.
+    order: VecDeque<u64>,
+}
+
+impl RecentKeys {
+    fn insert(&mut self, key: u64, pos: WalPosition) {
+        if self.positions.insert(key, pos).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > KEY_WINDOW {
+            let key = self.order.pop_front().unwrap();
+            self.positions.remove(&key);
+        }
+    }
+}
+
+/// How an appended entry is made durable.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// The entry is durable once its completion is returned from process_completions. Devices
+    /// batch the writes of many entries into each sync.
+    #[default]
+    Batched,
+    /// The device is synced before append returns, so the entry and every entry appended before it
+    /// are durable once the call succeeds. Its completion is still returned from
+    /// process_completions.
+    Immediate,
+}
+
+/// The device a file is written through.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum FileDevice {
+    /// io_uring on Linux, falling back to Sync where it isn't available, macos_device on macOS
+    /// and Sync elsewhere.
+    #[default]
+    Platform,
+    /// Synchronous writes from the appending thread, see SyncDevice. Works on any filesystem,
+    /// including ones that don't support O_DIRECT.
+    Sync,
+    /// io_uring, see LinuxUring. Fails with Unsupported rather than falling back where io_uring
+    /// isn't available, and on other platforms.
+    Uring,
+}
+
+/// The device a file is written through on macOS.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum MacOsDevice {
+    /// pwrite from background threads, see MacOsAsyncIO.
+    #[default]
+    Pwrite,
+    /// POSIX aio, see KQueue.
+    Kqueue,
+    /// Time the same writes with each device on a scratch file beside the wal when it is opened,
+    /// and use the faster one. Which is faster depends on the drive and the filesystem.
+    Calibrate,
+}
+
+/// Settings for the device a wal is opened on. The defaults suit most uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalOptions {
+    /// The device files are written through, unless the URL names one with device=sync,
+    /// device=uring or one of the platform's other devices.
+    pub device: FileDevice,
+    /// The number of writes io_uring can have submitted at once. Further writes wait for earlier
+    /// ones to complete before they are submitted. Ignored by other devices.
+    pub queue_depth: u32,
+    /// The number of threads writing to the file on macOS, where writes are made with pwrite from
+    /// background threads. More than one helps keep fast SSDs busy. Ignored on other platforms.
+    pub pwrite_workers: usize,
+    /// The device files are written through on macOS, unless the URL names one with device=pwrite,
+    /// device=kqueue or device=calibrate. Ignored on other platforms.
+    pub macos_device: MacOsDevice,
+    /// On Linux, write each entry with RWF_DSYNC so it is durable as soon as its write completes,
+    /// rather than once a later fdatasync returns. This saves a system call per durable append
+    /// when few appends are in flight at a time. Ignored on other platforms.
+    pub dsync_writes: bool,
+    /// The number of blocks a growable wal file is extended by at a time, see create_growable.
+    pub grow_chunk_blocks: u64,
+    /// Format the wal with a CRC at the end of every block, so damage to a large entry is traced
+    /// to the block it is in, see DamagedBlock, rather than only failing the CRC of the whole
+    /// entry. Each block holds BLOCK_CRC_SIZE bytes less of the entry. Only applies when the wal
+    /// is formatted, including when a zeroed device is opened, after which the wal keeps the
+    /// layout it was formatted with.
+    pub block_crcs: bool,
+    /// Format the wal so every entry holds a digest of the entry before it, making a hash chain
+    /// over the log that Wal::verify_chain checks. Changing an entry after it was written then
+    /// breaks the chain, unless every entry after it is rewritten too, which is caught by keeping
+    /// the digest verify_chain returns somewhere else. Only applies when the wal is formatted,
+    /// like block_crcs.
+    pub hash_chain: bool,
+    /// Allocate the buffers entries are written from up front, so appending doesn't allocate
+    /// them, and fail appends with AllocationLimit rather than going past the limits. None
+    /// allocates a buffer for each write.
+    pub bounded: Option<BoundedAllocation>,
+    /// On Linux, submit writes to files with this priority, so they are served ahead of other I/O
+    /// to the same disk. Only writes made through io_uring carry it, writes made from the
+    /// appending thread, as by SyncDevice, take the priority of that thread, see
+    /// set_thread_io_priority. Ignored on other platforms.
+    pub io_priority: Option<IoPriority>,
+}
+
+/// The limits of a wal whose write buffers are allocated when it is opened, see
+/// WalOptions::bounded. A buffer is in use from the append that fills it until the device is done
+/// with it, which for devices that write in the background is until the write completes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BoundedAllocation {
+    /// The number of buffers, which bounds the writes the device can have at once. An append
+    /// that wraps around the end of the ring takes a second buffer to pad out the end.
+    pub buffers: usize,
+    /// The largest entry that can be appended, in bytes.
+    pub max_entry_len: usize,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        WalOptions {
+            device: FileDevice::default(),
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            pwrite_workers: 1,
+            macos_device: MacOsDevice::default(),
+            dsync_writes: false,
+            grow_chunk_blocks: DEFAULT_GROW_CHUNK_BLOCKS,
+            block_crcs: false,
+            hash_chain: false,
+            bounded: None,
+            io_priority: None,
+        }
+    }
+}
+
+/// Counts of the bytes written to the device since the wal was opened, split by what they hold,
+/// how the device was opened and how far the wal is durable.
+/// Every write is a whole number of blocks, so each entry is padded out to the end of its last
+/// block, and the end of the ring is padded when an entry doesn't fit in what is left of it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct WalStats {
+    /// The number of entries appended.
+    pub entries: u64,
+    /// The bytes of entry payloads.
+    pub payload_bytes: u64,
+    /// The bytes of entry headers, trailers and block CRCs.
+    pub header_bytes: u64,
+    /// The bytes after each entry up to the end of its last block.
+    pub block_padding_bytes: u64,
+    /// The bytes skipped at the end of the ring.
+    pub ring_padding_bytes: u64,
+    /// The bytes of control blocks.
+    pub control_bytes: u64,
+    /// The bytes written over truncated entries by erase_reclaimed.
+    pub erase_bytes: u64,
+    /// Whether io_uring wasn't available, so the file is written with synchronous writes instead.
+    pub io_uring_fallback: bool,
+    /// Entries found damaged when the wal was opened that a crash can't explain, because single
+    /// block entries were written atomically. Recovery stops at them like at a torn entry.
+    pub damaged_entries: u64,
+    /// Every entry before this position is durable, see Wal::durable_head.
+    pub durable_head: WalPosition,
+    /// The bytes of entries checked by Wal::scrub.
+    pub scrubbed_bytes: u64,
+    /// The damaged entries found by Wal::scrub, counting an entry again on each pass that finds
+    /// it.
+    pub scrub_damaged: u64,
+}
+
+impl WalStats {
+    /// The total bytes written to the device.
+    pub fn device_bytes(&self) -> u64 {
+        self.payload_bytes
+            + self.header_bytes
+            + self.block_padding_bytes
+            + self.ring_padding_bytes
+            + self.control_bytes
+            + self.erase_bytes
+    }
+
+    /// The bytes written to the device for each byte of payload, or 0.0 if no payload was written.
+    pub fn write_amplification(&self) -> f64 {
+        if self.payload_bytes == 0 {
+            return 0.0;
+        }
+        self.device_bytes() as f64 / self.payload_bytes as f64
+    }
+}
+
+/// Identifies a stream of entries within a wal. All streams share the same ring and the same
+/// device, but are iterated and truncated independently.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId(pub u32);
+
+impl StreamId {
+    /// The unnamed stream used by append and truncate.
+    pub const DEFAULT: StreamId = StreamId(0);
+}
+
+/// The error carried by the StorageFull error returned when appending to a stream would take it
+/// past its quota.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub stream: StreamId,
+    /// The quota of the stream in bytes.
+    pub quota: u64,
+    /// The bytes the stream took up when the append failed.
+    pub usage: u64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stream {:?} is using {} of its quota of {} bytes",
+            self.stream, self.usage, self.quota
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// The error carried by the OutOfMemory error returned when an append would go past the limits of a
+/// wal opened with WalOptions::bounded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AllocationLimit {
+    /// The entry needs more blocks than a buffer holds.
+    TooLarge { blocks: u64, max: u64 },
+    /// Every buffer is waiting for the device. Processing completions frees them.
+    NoFreeBuffer { buffers: usize },
+}
+
+impl std::fmt::Display for AllocationLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AllocationLimit::TooLarge { blocks, max } => {
+                write!(
+                    f,
+                    "write of {blocks} blocks is larger than the {max} blocks of a buffer"
+                )
+            }
+            AllocationLimit::NoFreeBuffer { buffers } => {
+                write!(f, "all {buffers} write buffers are in use")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AllocationLimit {}
+
+/// The error carried by the error returned when opening a device or file that doesn't hold a wal,
+/// rather than recovering whatever it holds as entries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NotAWal {
+    /// The control region holds something other than a wal control block. InvalidData.
+    Foreign,
+    /// The control region was never written, but the ring holds data. Format the device to reuse
+    /// it. InvalidData.
+    Unformatted,
+    /// The device was written by a version of the wal from before the control region.
+    /// Unsupported.
+    Legacy,
+    /// The file is this many bytes long, which isn't a whole number of blocks. InvalidData.
+    BlockSize(u64),
+    /// The file is this many bytes long, too short to hold the control region and a block of the
+    /// ring. InvalidData.
+    TooSmall(u64),
+}
+
+impl std::fmt::Display for NotAWal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotAWal::Foreign => write!(f, "device does not start with a wal control block"),
+            NotAWal::Unformatted => write!(
+                f,
+                "device holds data but was never formatted as a wal, use format to reuse it"
+            ),
+            NotAWal::Legacy => write!(
+                f,
+                "wal was written by a version without a control region and can't be opened"
+            ),
+            NotAWal::BlockSize(size) => write!(
+                f,
+                "size {size} is not a multiple of BLOCK_SIZE {BLOCK_SIZE}"
+            ),
+            NotAWal::TooSmall(size) => write!(f, "size {size} is too small for a wal"),
+        }
+    }
+}
+
+impl std::error::Error for NotAWal {}
+
+/// The error carried by the Unsupported error returned when opening a wal written with another
+/// version of the on-disk format. A wal from a newer version is never opened, not even to be read,
+/// since an older version can't tell how its entries are laid out and appending would corrupt it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UnsupportedVersion {
+    /// The format version the wal was written with.
+    pub version: u32,
+    /// The format version this library reads and writes.
+    pub supported: u32,
+}
+
+impl UnsupportedVersion {
+    /// Whether the wal was written by a newer version of the library than this one.
+    pub fn is_newer(&self) -> bool {
+        self.version > self.supported
+    }
+}
+
+impl std::fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "wal format version {} is not supported, expected {}",
+            self.version, self.supported
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// What an append does when the entries in flight would go past the limit set with
+/// Wal::set_in_flight_limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for writes to complete, processing completions while it waits. The completions are
+    /// returned from the next process_completions as usual. Fails with TimedOut if no write
+    /// completes for a while, as when the device has failed.
+    Block,
+    /// Fail with WouldBlock carrying an InFlightLimit, so the caller can process completions and
+    /// try again.
+    Fail,
+}
+
+/// The error carried by the WouldBlock error returned when an append would go past the limit set
+/// with Wal::set_in_flight_limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InFlightLimit {
+    /// The bytes of entries in flight when the append failed.
+    pub in_flight: u64,
+    pub max_bytes: u64,
+}
+
+impl std::fmt::Display for InFlightLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes of writes are in flight, the limit is {}",
+            self.in_flight, self.max_bytes
+        )
+    }
+}
+
+impl std::error::Error for InFlightLimit {}
+
+/// The error carried by the errors returned from a wal that has failed, see Wal::failure. It has
+/// the kind of the device error the wal failed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalFailed {
+    pub kind: std::io::ErrorKind,
+    /// The device error the wal failed with.
+    pub message: String,
+}
+
+impl WalFailed {
+    // The io::Error carrying this.
+    pub(crate) fn error(&self) -> Error {
+        Error::new(self.kind, self.clone())
+    }
+}
+
+impl std::fmt::Display for WalFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "wal failed after a device error and must be reopened: {}",
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for WalFailed {}
+
+/// What an append does when the device is out of space for its entry, such as a growable file
+/// that can't grow because the filesystem is full. Only the blocks the ring has already been
+/// written up to are known to be allocated, so room is made by truncating entries at the start of
+/// the ring and starting the next pass there early, which is read back like the padding at the
+/// end of a pass.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DiskFullPolicy {
+    /// The append fails with StorageFull carrying a DiskFull.
+    #[default]
+    Fail,
+    /// Expire entries under the retention policy, see Wal::expire, and start the next pass if
+    /// that frees the start of the ring.
+    Expire,
+    /// Ask the snapshot provider for a snapshot, whatever the utilization of the ring, and start
+    /// the next pass if the snapshot completes and frees the start of the ring.
+    Snapshot,
+}
+
+/// The error carried by the StorageFull error returned when the device is out of space for an entry
+/// and the DiskFullPolicy couldn't make room for it. The wal hasn't failed, appends can be retried
+/// once there is space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskFull {
+    /// Where the entry would have been written.
+    pub position: WalPosition,
+    /// The blocks it would have taken up.
+    pub blocks: u64,
+    /// The error from the device.
+    pub message: String,
+}
+
+impl std::fmt::Display for DiskFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no space on the device for {} blocks at {:?}: {}",
+            self.blocks, self.position, self.message
+        )
+    }
+}
+
+impl std::error::Error for DiskFull {}
+
+// Whether the device failed for lack of space, ENOSPC or EDQUOT.
+fn is_disk_full(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+    )
+}
+
+impl NotAWal {
+    // The io::Error carrying this.
+    pub(crate) fn error(self) -> Error {
+        let kind = match self {
+            NotAWal::Legacy => std::io::ErrorKind::Unsupported,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        Error::new(kind, self)
+    }
+}
+
+// An entry between the tail and the head.
+#[derive(Debug, Copy, Clone)]
+struct LiveEntry {
+    position: WalPosition,
+    timestamp: u64,
+    stream: StreamId,
+    // The bytes it takes up in the ring.
+    size: u64,
+}
+
+/// StreamIterator returns the entries of a single stream that are at or after the stream's
+/// truncation point.
+pub struct StreamIterator<'a> {
+    inner: WalIterator<'a>,
+    stream: StreamId,
+    low_water: WalPosition,
+}
+
+impl Iterator for StreamIterator<'_> {
+    type Item = std::io::Result<(WalPosition, Vec<u8>)>;
+
+    // Entries of other streams are skipped over without reading their payloads.
+    fn next(&mut self) -> Option<Self::Item> {
+        let (stream, low_water) = (self.stream, self.low_water);
+        let entry = self
+            .inner
+            .next_matching(&mut |meta| meta.stream == stream && meta.position >= low_water)?;
+        Some(entry.map(|entry| (entry.position, entry.payload)))
+    }
+}
+
+// Returns whether it wants further completions.
+type Listener = Box<dyn FnMut(WalPosition) -> bool + Send>;
+
+type DamageListener = Box<dyn FnMut(WalPosition) + Send>;
+
+// The progress of the background scrub, see Wal::set_scrub_rate.
+struct Scrub {
+    blocks_per_second: u64,
+    // The entry to check next.
+    next: WalPosition,
+    // The blocks that can be checked before waiting, topped up as time passes.
+    budget: f64,
+    refilled: Instant,
+}
+
+pub struct Wal {
+    dev: Box<dyn PersistentDevice>,
+    // Reads go through this rather than dev so they only need a shared reference.
+    reader: std::sync::Arc<dyn DeviceReader>,
+    // The live region as seen by every WalReader, updated whenever the head or tail moves.
+    bounds: Arc<Mutex<Bounds>>,
+
+    // capacity in blocks
+    capacity: u64,
+    layout: BlockLayout,
+    // offset into the file.
+    head: WalPosition,
+    // offset into the file.
+    tail: WalPosition,
+    // Every entry between the tail and the head, oldest first.
+    entries: VecDeque<LiveEntry>,
+    // Metadata persisted in the control region.
+    control: ControlBlock,
+    // Sequence number of the last control block written. It also picks the block of the control
+    // region the next one goes to.
+    control_sequence: u64,
+    // The last entry appended to each stream. A stream only holds back the tail while it has
+    // entries after its truncation point.
+    stream_heads: HashMap<StreamId, WalPosition>,
+    // The bytes of the ring taken up by the entries of each stream that haven't been truncated.
+    stream_usage: HashMap<StreamId, u64>,
+    // The most bytes each stream may take up.
+    quotas: HashMap<StreamId, u64>,
+    // Publishes durable positions to watchers.
+    notifier: Notifier,
+    // Called with every completion process_completions returns, and dropped once they return
+    // false.
+    listeners: Vec<Listener>,
+    // Set once the device reports a write that will never complete.
+    failed: Option<WalFailed>,
+    scrub: Option<Scrub>,
+    // Called with every damaged entry scrub finds.
+    damage_listeners: Vec<DamageListener>,
+    // The entries written that the device hasn't reported complete.
+    in_flight: InFlight,
+    // The most bytes of entries in flight, and what an append that would go past it does.
+    in_flight_limit: Option<(u64, Backpressure)>,
+    // Completions processed while an append waited for room, returned from the next
+    // process_completions.
+    reaped: Vec<WalPosition>,
+    // The padding and control block writes that the device hasn't reported complete. They never
+    // share a position with an entry.
+    internal: HashSet<WalPosition>,
+    fences: Fences,
+    // Whether completions are reported in append order.
+    ordered: bool,
+    // Whether the header of an entry longer than a block is written after the rest of it.
+    header_last: bool,
+    // The digest of the last entry appended, which the next one holds if the wal is hash chained.
+    chain: u64,
+    // The byte truncated entries are overwritten with, if they are erased.
+    erase: Option<u8>,
+    // The blocks before this position have been erased or overwritten.
+    erased: WalPosition,
+    // Where each pass of the ring is archived before the next one overwrites it.
+    lap_dir: Option<PathBuf>,
+    completion_order: CompletionOrder,
+    // How long drop waits for in_flight to reach zero.
+    drain_timeout: Duration,
+    // How long a WalDriver waits for an outstanding write before syncing the device.
+    max_sync_interval: Option<Duration>,
+    // Optional provider to call when the ring fills up.
+    snapshot: Option<SnapshotHook>,
+    retention: RetentionPolicy,
+    disk_full: DiskFullPolicy,
+    // The LSN for the next entry.
+    next_lsn: u64,
+    lsn_index: Option<LsnIndex>,
+    // Publishes the tail and durable head for readers in other processes.
+    #[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+    publisher: Option<Publisher>,
+    // The buffers entries are written from when allocation is bounded.
+    pool: Option<BufferPool>,
+    keys: RecentKeys,
+    stats: WalStats,
+}
+
+pub type WalResult = Result<WalPosition, Error>;
+
+impl Wal {
+    // appends an entry to this WAL. The data is copied. The data is not guaranteed to be persisted
+    // to disk when this returns. To get the completion, listen on the receiver channel.
+    pub fn append(&mut self, data: &[u8]) -> std::io::Result<WalPosition> {
+        self.append_to(StreamId::DEFAULT, data)
+    }
+
+    /// Append an entry to the given stream. The stream must have been created with `stream`.
+    pub fn append_to(&mut self, stream: StreamId, data: &[u8]) -> std::io::Result<WalPosition> {
+        self.append_to_with(stream, data, SyncPolicy::Batched)
+    }
+
+    /// Append an entry that is made durable according to policy.
+    pub fn append_with(&mut self, data: &[u8], policy: SyncPolicy) -> std::io::Result<WalPosition> {
+        self.append_to_with(StreamId::DEFAULT, data, policy)
+    }
+
+    /// Append an entry to the given stream that is made durable according to policy. If an
+    /// immediate sync fails the error is returned, but the entry has still been appended and may
+    /// become durable later.
+    pub fn append_to_with(
+        &mut self,
+        stream: StreamId,
+        data: &[u8],
+        policy: SyncPolicy,
+    ) -> std::io::Result<WalPosition> {
+        self.write_entry(stream, data, policy, 0)
+    }
+
+    /// Append the entries to the default stream with a single write to the device, so a burst of
+    /// small entries takes one submission rather than one each. The entries are laid out back to
+    /// back like separate appends and read back as separate entries, but complete together:
+    /// process_completions only returns the position of the first, once all of them are durable.
+    /// Returns the position of each entry. If any of them can't be appended, none are.
+    pub fn append_batch(&mut self, entries: &[&[u8]]) -> std::io::Result<Vec<WalPosition>> {
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let batch: Vec<(StreamId, &[u8], u64)> = entries
+            .iter()
+            .map(|data| (StreamId::DEFAULT, *data, 0))
+            .collect();
+        self.write_entries(&batch, SyncPolicy::Batched)
+    }
+
+    /// Append an entry with an idempotency key, so an append that is retried after an ambiguous
+    /// failure isn't written twice. If an entry with the same key was appended recently, its
+    /// position is returned and nothing is written. The last KEY_WINDOW keys are remembered, and
+    /// the ones in the ring are found again when the wal is opened, except by open_append_only. The
+    /// key must not be 0.
+    pub fn append_keyed(&mut self, key: u64, data: &[u8]) -> std::io::Result<WalPosition> {
+        self.append_keyed_to(StreamId::DEFAULT, key, data)
+    }
+
+    /// Append an entry with an idempotency key to the given stream, see append_keyed.
+    pub fn append_keyed_to(
+        &mut self,
+        stream: StreamId,
+        key: u64,
+        data: &[u8],
+    ) -> std::io::Result<WalPosition> {
+        if key == 0 {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "idempotency key must not be 0",
+            ));
+        }
+        if let Some(pos) = self.keys.positions.get(&key) {
+            return Ok(*pos);
+        }
+        self.write_entry(stream, data, SyncPolicy::Batched, key)
+    }
+
+    // Called when the device is out of space for an entry of blocks at the head. Makes room at the
+    // start of the ring as the disk full policy says and moves the head there, or fails with
+    // DiskFull. The blocks at the start of the ring were allocated by the previous pass.
+    fn make_room(&mut self, e: Error, blocks: u64) -> std::io::Result<()> {
+        warn!(
+            "Out of space for {} blocks at {:?}: {}",
+            blocks, self.head, e
+        );
+        let full = DiskFull {
+            position: self.head,
+            blocks,
+            message: e.to_string(),
+        };
+        if self.head.offset == RING_START {
+            return Err(Error::new(std::io::ErrorKind::StorageFull, full));
+        }
+        match self.disk_full {
+            DiskFullPolicy::Fail => {
+                return Err(Error::new(std::io::ErrorKind::StorageFull, full));
+            }
+            DiskFullPolicy::Expire => {
+                if let Err(e) = self.expire() {
+                    warn!("Failed to expire entries: {}", e);
+                }
+            }
+            DiskFullPolicy::Snapshot => self.request_snapshot(),
+        }
+        let end = self.head.next_pass().advance(blocks, self.capacity);
+        if !self.entries.is_empty() && self.tail.is_overwritten(end, self.capacity) {
+            return Err(Error::new(std::io::ErrorKind::StorageFull, full));
+        }
+
+        // The rest of the pass has to read as padding. Past the end of a growable file it already
+        // does, elsewhere a zeroed block marks it.
+        let buffer = self.dev.read(self.head.byte_offset(), HEADER_SIZE)?;
+        let padding =
+            EntryHeader::read_from_bytes(&buffer).is_ok_and(|header| header.is_padding(&buffer));
+        if !padding {
+            let aligned = self.write_buffer(1)?;
+            let size = aligned.size();
+            match self.write_internal(self.head, aligned) {
+                Ok(()) => {}
+                Err(e) if is_disk_full(&e) => {
+                    return Err(Error::new(std::io::ErrorKind::StorageFull, full));
+                }
+                Err(e) => {
+                    return Err(Error::new(
+                        e.kind(),
+                        format!("padding at the end of the pass: {e}"),
+                    ))
+                }
+            }
+            self.stats.ring_padding_bytes += size;
+        }
+        // Writes to the same blocks can reach the disk in any order, so the ones still in flight
+        // at the start of the ring have to be durable before the next pass overwrites them.
+        let start = WalPosition {
+            offset: RING_START + blocks,
+            rollover: self.head.rollover,
+        };
+        if self.in_flight.any_before(start) {
+            self.dev.sync()?;
+        }
+        info!("Starting the next pass early at {:?}", self.head);
+        self.head = self.head.next_pass();
+        self.normalize_tail();
+        Ok(())
+    }
+
+    // A zeroed buffer of the given number of blocks, taken from the pool if allocation is bounded.
+    fn write_buffer(&self, blocks: u64) -> std::io::Result<AlignedSlice> {
+        let size = (blocks * BLOCK_SIZE as u64) as usize;
+        let Some(pool) = &self.pool else {
+            return Ok(AlignedSlice::new(size));
+        };
+        pool.take(size).ok_or_else(|| {
+            let limit = if blocks > pool.buffer_blocks() {
+                AllocationLimit::TooLarge {
+                    blocks,
+                    max: pool.buffer_blocks(),
+                }
+            } else {
+                AllocationLimit::NoFreeBuffer {
+                    buffers: pool.buffers(),
+                }
+            };
+            Error::new(std::io::ErrorKind::OutOfMemory, limit)
+        })
+    }
+
+    // Applies the in flight limit to a write of size bytes. A write larger than the limit on its
+    // own goes ahead once nothing else is in flight.
+    fn wait_for_room(&mut self, size: u64) -> std::io::Result<()> {
+        let Some((max_bytes, backpressure)) = self.in_flight_limit else {
+            return Ok(());
+        };
+        let fits =
+            |in_flight: &InFlight| in_flight.is_empty() || in_flight.bytes + size <= max_bytes;
+        if fits(&self.in_flight) {
+            return Ok(());
+        }
+        if backpressure == Backpressure::Fail {
+            return Err(Error::new(
+                std::io::ErrorKind::WouldBlock,
+                InFlightLimit {
+                    in_flight: self.in_flight.bytes,
+                    max_bytes,
+                },
+            ));
+        }
+        let mut deadline = Instant::now() + COMPLETION_TIMEOUT;
+        loop {
+            let completions = self.reap_completions();
+            self.check_failed()?;
+            if fits(&self.in_flight) {
+                self.reaped.extend(completions);
+                return Ok(());
+            }
+            if !completions.is_empty() {
+                deadline = Instant::now() + COMPLETION_TIMEOUT;
+            } else if Instant::now() >= deadline {
+                return Err(Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!(
+                        "{} bytes of writes were not reported durable",
+                        self.in_flight.bytes
+                    ),
+                ));
+            }
+            self.reaped.extend(completions);
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn write_entry(
+        &mut self,
+        stream: StreamId,
+        data: &[u8],
+        policy: SyncPolicy,
+        key: u64,
+    ) -> std::io::Result<WalPosition> {
+        let positions = self.write_entries(&[(stream, data, key)], policy)?;
+        Ok(positions[0])
+    }
+
+    // Writes the entries, each a stream, payload and key, back to back with a single write, so
+    // they complete together at the position of the first.
+    fn write_entries(
+        &mut self,
+        batch: &[(StreamId, &[u8], u64)],
+        policy: SyncPolicy,
+    ) -> std::io::Result<Vec<WalPosition>> {
+        self.check_failed()?;
+        failpoint::check("wal::submit")?;
+        let mut write_size = 0;
+        let mut added: HashMap<StreamId, u64> = HashMap::new();
+        for &(stream, data, _) in batch {
+            if stream != StreamId::DEFAULT && self.stream_name(stream).is_none() {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown stream {stream:?}"),
+                ));
+            }
+            if data.len() as u64 > self.layout.max_len(self.capacity) {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("entry of {} bytes is larger than the wal", data.len()),
+                ));
+            }
+            let blocks = self.layout.blocks_for(data.len());
+            write_size += blocks;
+            *added.entry(stream).or_default() += blocks * BLOCK_SIZE as u64;
+        }
+        if write_size > self.capacity - RING_START {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("batch of {write_size} blocks is larger than the wal"),
+            ));
+        }
+        let size = write_size * BLOCK_SIZE as u64;
+        self.wait_for_room(size)?;
+        // Create an aligned buffer that outlives this function. It is destroyed, or goes back to
+        // the pool, when completion happens.
+        let mut aligned = self.write_buffer(write_size)?;
+        for (&stream, &added) in &added {
+            let Some(&quota) = self.quotas.get(&stream) else {
+                continue;
+            };
+            let usage = self.stream_usage(stream);
+            if usage + added > quota {
+                return Err(Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    QuotaExceeded {
+                        stream,
+                        quota,
+                        usage,
+                    },
+                ));
+            }
+        }
+
+        // Runs again after the disk full policy makes room at the start of the ring.
+        let (res, timestamp, digests) = loop {
+            // Move the head for the next write and clear out all the existing data between the
+            // head and that position.
+            if self.head.offset + write_size > self.capacity {
+                let aligned = self.write_buffer(self.capacity - self.head.offset)?;
+                let padding = aligned.size();
+                match failpoint::check("wal::rollover")
+                    .and_then(|_| self.write_internal(self.head, aligned))
+                {
+                    Ok(()) => {
+                        self.stats.ring_padding_bytes += padding;
+                        self.head = self.head.next_pass();
+                    }
+                    Err(e) if is_disk_full(&e) => {
+                        self.make_room(e, write_size)?;
+                        continue;
+                    }
+                    Err(e) => {
+                        return Err(Error::new(
+                            e.kind(),
+                            format!("padding at the end of the ring: {e}"),
+                        ))
+                    }
+                }
+            }
+            if self.head.offset == RING_START && self.head.rollover > 0 {
+                self.archive_lap()?;
+            }
+
+            let timestamp = now_micros();
+            let mut chain = self.chain;
+            let mut digests = Vec::with_capacity(batch.len());
+            let mut offset = 0;
+            for (i, &(stream, data, key)) in batch.iter().enumerate() {
+                let header = EntryHeader {
+                    crc: U32::ZERO,
+                    rollover: U32::new(self.head.rollover),
+                    len: U64::new(data.len() as u64),
+                    stream: U32::new(stream.0),
+                    lsn: U64::new(self.next_lsn + i as u64),
+                    timestamp: U64::new(timestamp),
+                    key: U64::new(key),
+                    chain: U64::new(chain),
+                };
+                debug!("Writing header {:?}", header);
+
+                let end =
+                    offset + self.layout.blocks_for(data.len()) as usize * BLOCK_SIZE as usize;
+                let header = self
+                    .layout
+                    .encode(header, data, &mut aligned.as_slice()[offset..end]);
+                offset = end;
+                if self.control.hash_chain {
+                    chain = header.digest(data);
+                }
+                digests.push(chain);
+            }
+
+            let res = if self.header_last && write_size > 1 {
+                self.write_header_last(aligned)
+            } else {
+                self.dev.write(self.head, aligned, true)
+            };
+            match res {
+                Err(e) if is_disk_full(&e) => {
+                    self.make_room(e, write_size)?;
+                    aligned = self.write_buffer(write_size)?;
+                }
+                res => break (res, timestamp, digests),
+            }
+        };
+        let mut positions = Vec::with_capacity(batch.len());
+        if res.is_ok() {
+            self.in_flight.insert(self.head, size);
+            if self.ordered {
+                self.completion_order.pending.push_back(self.head);
+            }
+            let mut position = self.head;
+            for (&(stream, data, key), digest) in batch.iter().zip(digests) {
+                let blocks = self.layout.blocks_for(data.len());
+                let size = blocks * BLOCK_SIZE as u64;
+                let trailer_bytes = if blocks > 1 { TRAILER_SIZE } else { 0 };
+                let overhead = size - (blocks as usize * self.layout.block_data()) as u64
+                    + (HEADER_SIZE + trailer_bytes) as u64;
+                self.entries.push_back(LiveEntry {
+                    position,
+                    timestamp,
+                    stream,
+                    size,
+                });
+                *self.stream_usage.entry(stream).or_default() += size;
+                self.stream_heads.insert(stream, position);
+                if let Some(index) = self.lsn_index.as_mut() {
+                    index.record(self.next_lsn, position);
+                }
+                if key != 0 {
+                    self.keys.insert(key, position);
+                }
+                self.chain = digest;
+                self.next_lsn += 1;
+                self.stats.entries += 1;
+                self.stats.payload_bytes += data.len() as u64;
+                self.stats.header_bytes += overhead;
+                self.stats.block_padding_bytes += size - overhead - data.len() as u64;
+                positions.push(position);
+                position = position.advance(blocks, self.capacity);
+            }
+        }
+
+        // move the head to the next position for the next write. If the entry ends exactly at
+        // the end of the ring the next write starts a new pass without any padding.
+        self.head = self.head.advance(write_size, self.capacity);
+        self.normalize_tail();
+        if res.is_ok() && policy == SyncPolicy::Immediate {
+            self.dev.sync()?;
+        }
+        if res.is_ok() {
+            if let Err(e) = self.expire() {
+                warn!("Failed to expire entries: {}", e);
+            }
+            self.maybe_snapshot();
+        }
+        res.map(|_| positions)
+    }
+
+    /// Register the provider that is asked for a snapshot once threshold (0.0 to 1.0) or more of
+    /// the ring is in use. Replaces any previously registered provider.
+    pub fn set_snapshot_provider(&mut self, provider: Box<dyn SnapshotProvider>, threshold: f64) {
+        self.snapshot = Some(SnapshotHook {
+            provider,
+            threshold,
+            in_progress: false,
+        });
+    }
+
+    /// Called by a SnapshotProvider that returned None once its snapshot is complete. Entries
+    /// before position are truncated.
+    pub fn snapshot_completed(&mut self, position: WalPosition) -> std::io::Result<()> {
+        if let Some(hook) = self.snapshot.as_mut() {
+            hook.in_progress = false;
+        }
+        self.truncate(position)
+    }
+
+    /// Set the policy deciding when entries expire. Replaces any previously set policy and applies
+    /// from the next append or call to expire.
+    pub fn set_retention(&mut self, policy: RetentionPolicy) {
+        self.retention = policy;
+    }
+
+    /// Set what an append does when the device is out of space for its entry. Writes the device
+    /// accepted that fail for lack of space later, which deferred allocation can cause, fail the
+    /// wal like any other hard error, see failure.
+    pub fn set_disk_full_policy(&mut self, policy: DiskFullPolicy) {
+        self.disk_full = policy;
+    }
+
+    /// Limit the stream to quota bytes of the ring, counting whole blocks, or lift its limit with
+    /// None. Once the entries of the stream that haven't been truncated take up the quota, appending
+    /// to it fails with StorageFull carrying a QuotaExceeded, while the other streams can still
+    /// append. Quotas aren't persisted. After open_append_only, only entries appended since count.
+    pub fn set_stream_quota(
+        &mut self,
+        stream: StreamId,
+        quota: Option<u64>,
+    ) -> std::io::Result<()> {
+        if stream != StreamId::DEFAULT && self.stream_name(stream).is_none() {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown stream {stream:?}"),
+            ));
+        }
+        match quota {
+            Some(quota) => self.quotas.insert(stream, quota),
+            None => self.quotas.remove(&stream),
+        };
+        Ok(())
+    }
+
+    /// The bytes of the ring taken up by the entries of the stream that haven't been truncated.
+    pub fn stream_usage(&self, stream: StreamId) -> u64 {
+        self.stream_usage.get(&stream).copied().unwrap_or(0)
+    }
+
+    /// The fraction of the ring taken up by the entries of the stream that haven't been truncated.
+    pub fn stream_utilization(&self, stream: StreamId) -> f64 {
+        let ring = (self.capacity - RING_START) * BLOCK_SIZE as u64;
+        self.stream_usage(stream) as f64 / ring as f64
+    }
+
+    /// Truncate every stream past the entries that expired under the retention policy and return
+    /// how many entries were removed. Appending already does this, so it only needs to be called
+    /// periodically when entries should expire while nothing is appended.
+    pub fn expire(&mut self) -> std::io::Result<usize> {
+        let policy = self.retention;
+        // The first entry that is kept by each limit, or the head if none are.
+        let first_kept = |keep: &dyn Fn(usize, WalPosition, u64) -> bool| {
+            self.entries
+                .iter()
+                .enumerate()
+                .find(|(i, entry)| keep(*i, entry.position, entry.timestamp))
+                .map_or(self.head, |(_, entry)| entry.position)
+        };
+        let mut position = self.tail;
+        if let Some(max_age) = policy.max_age {
+            let cutoff = now_micros().saturating_sub(max_age.as_micros() as u64);
+            position = position.max(first_kept(&|_, _, timestamp| timestamp >= cutoff));
+        }
+        if let Some(max_entries) = policy.max_entries {
+            let excess = self.entries.len().saturating_sub(max_entries);
+            position = position.max(first_kept(&|i, _, _| i >= excess));
+        }
+        if let Some(max_bytes) = policy.max_bytes {
+            let head = self.head;
+            let capacity = self.capacity;
+            position = position.max(first_kept(&|_, pos, _| {
+                pos.distance(head, capacity) * BLOCK_SIZE as u64 <= max_bytes
+            }));
+        }
+        self.truncate_all(position)
+    }
+
+    // Move the truncation point of every stream forward to position and return the number of
+    // entries that are no longer live.
+    fn truncate_all(&mut self, position: WalPosition) -> std::io::Result<usize> {
+        if position <= self.tail {
+            return Ok(0);
+        }
+        let live = self.entries.len();
+        let prev = self.control.clone();
+        let mut changed = false;
+        if self.control.low_water < position {
+            self.control.low_water = position;
+            changed = true;
+        }
+        for state in self.control.streams.values_mut() {
+            if state.low_water < position {
+                state.low_water = position;
+                changed = true;
+            }
+        }
+        if changed {
+            if let Err(e) = self.write_control() {
+                self.control = prev;
+                return Err(e);
+            }
+        }
+        self.advance_tail();
+        Ok(live - self.entries.len())
+    }
+
+    /// Rewrite the entries keep returns true for at the head and truncate every stream past the
+    /// region they were copied from, reclaiming the space of the entries keep returns false for.
+    /// The region runs from the tail to the slowest consumer cursor, or to the head if there are
+    /// none. Entries before the truncation point of their stream are dropped without asking keep.
+    /// Rewritten entries get new positions and LSNs, so consumers see them again. The copies are
+    /// synced before the truncation is persisted, so a crash never loses a kept entry. Returns the
+    /// number of entries dropped, or StorageFull without changing anything if the kept entries
+    /// don't fit in the free part of the ring. If a copy fails part way, nothing is truncated and
+    /// the entries copied so far stay in the wal alongside the originals.
+    pub fn compact<F: FnMut(&Entry) -> bool>(&mut self, mut keep: F) -> std::io::Result<usize> {
+        let end = match self.min_cursor() {
+            Some(cursor) if cursor < self.head => cursor,
+            _ => self.head,
+        };
+        let mut kept = Vec::new();
+        let mut dropped = 0;
+        let mut iter = self.iterate_from(self.tail).with_meta();
+        while iter.position() < end {
+            let entry = match iter.next() {
+                Some(entry) => check_entry(entry?)?,
+                None => break,
+            };
+            let live = self
+                .low_water(entry.stream)
+                .is_some_and(|low_water| entry.position >= low_water);
+            if live && keep(&entry) {
+                kept.push(entry);
+            } else {
+                dropped += 1;
+            }
+        }
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        // Work out where the head ends up, including any padding at the end of a pass, and make
+        // sure the copies don't overwrite the region before it is truncated.
+        let mut head = self.head;
+        for entry in &kept {
+            let blocks = self.layout.blocks_for(entry.payload.len());
+            if head.offset + blocks > self.capacity {
+                head = head.next_pass();
+            }
+            head = head.advance(blocks, self.capacity);
+        }
+        if self.tail.is_overwritten(head, self.capacity) {
+            return Err(Error::new(
+                std::io::ErrorKind::StorageFull,
+                "not enough free space in the ring to compact",
+            ));
+        }
+
+        for entry in kept {
+            self.write_entry(
+                entry.stream,
+                &entry.payload,
+                SyncPolicy::Batched,
+                entry.key.unwrap_or(0),
+            )?;
+        }
+        // The truncation is written to the control region, which nothing orders after the copies.
+        self.sync_device()?;
+        self.truncate_all(end)?;
+        Ok(dropped)
+    }
+
+    /// The position the next entry is appended at.
+    pub fn head(&self) -> WalPosition {
+        self.head
+    }
+
+    /// The highest position before which every entry is durable, which is the head once every
+    /// write has completed. Devices can complete writes out of order, so this is the position of
+    /// the oldest entry that hasn't completed rather than of the newest one that has. An entry
+    /// whose write failed holds it back for good.
+    pub fn durable_head(&self) -> WalPosition {
+        self.in_flight.first().unwrap_or(self.head)
+    }
+
+    // Whether any write hasn't been reported complete yet.
+    pub(crate) fn has_outstanding(&self) -> bool {
+        !self.in_flight.is_empty()
+    }
+
+    /// The position of the oldest entry that hasn't been truncated, or the head if there are none.
+    pub fn tail(&self) -> WalPosition {
+        self.tail
+    }
+
+    /// The number of entries between the tail and the head.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// What the device the wal is on supports.
+    pub fn capabilities(&self) -> Capabilities {
+        self.dev.capabilities()
+    }
+
+    /// The bytes written to the device since the wal was opened.
+    pub fn stats(&self) -> WalStats {
+        WalStats {
+            durable_head: self.durable_head(),
+            ..self.stats
+        }
+    }
+
+    /// The fraction of the ring between the tail and the head.
+    pub fn utilization(&self) -> f64 {
+        let used = self.tail.distance(self.head, self.capacity);
+        used as f64 / (self.capacity - RING_START) as f64
+    }
+
+    fn maybe_snapshot(&mut self) {
+        let utilization = self.utilization();
+        if self
+            .snapshot
+            .as_ref()
+            .is_some_and(|hook| utilization >= hook.threshold)
+        {
+            self.request_snapshot();
+        }
+    }
+
+    // Ask the provider for a snapshot unless one is in progress.
+    fn request_snapshot(&mut self) {
+        let utilization = self.utilization();
+        let head = self.head;
+        let hook = match self.snapshot.as_mut() {
+            Some(hook) if !hook.in_progress => hook,
+            _ => return,
+        };
+        debug!(
+            "Requesting snapshot at {:?}, utilization {}",
+            head, utilization
+        );
+        hook.in_progress = true;
+        match hook.provider.snapshot(head) {
+            Ok(Some(position)) => {
+                if let Err(e) = self.snapshot_completed(position) {
+                    warn!("Failed to truncate after the snapshot: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Snapshot failed: {}", e);
+                hook.in_progress = false;
+            }
+        }
+    }
+
+    /// Move the tail of the default stream forward to position. If position is behind the current
+    /// tail, truncate is a no-op. The tail of the wal never moves past the slowest consumer cursor
+    /// or the truncation point of any other stream. The truncation point is persisted in the
+    /// control region like those of the other streams, so truncated entries stay truncated when
+    /// the wal is reopened.
+    pub fn truncate(&mut self, position: WalPosition) -> std::io::Result<()> {
+        if position > self.control.low_water {
+            let prev = std::mem::replace(&mut self.control.low_water, position);
+            if let Err(e) = self.write_control() {
+                self.control.low_water = prev;
+                return Err(e);
+            }
+        }
+        self.advance_tail();
+        Ok(())
+    }
+
+    /// Return the id of the named stream, creating and persisting it if it doesn't exist yet.
+    pub fn stream(&mut self, name: &str) -> std::io::Result<StreamId> {
+        if let Some(state) = self.control.streams.get(name) {
+            return Ok(StreamId(state.id));
+        }
+        let id = self
+            .control
+            .streams
+            .values()
+            .map(|state| state.id)
+            .max()
+            .unwrap_or(StreamId::DEFAULT.0)
+            + 1;
+        self.control.streams.insert(
+            name.to_string(),
+            StreamState {
+                id,
+                low_water: self.tail,
+            },
+        );
+        if let Err(e) = self.write_control() {
+            self.control.streams.remove(name);
+            return Err(e);
+        }
+        Ok(StreamId(id))
+    }
+
+    /// Return the name of the stream.
+    pub fn stream_name(&self, stream: StreamId) -> Option<&str> {
+        self.control
+            .streams
+            .iter()
+            .find(|(_, state)| state.id == stream.0)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The name and id of every named stream. The default stream is not included.
+    pub fn streams(&self) -> impl Iterator<Item = (&str, StreamId)> {
+        self.control
+            .streams
+            .iter()
+            .map(|(name, state)| (name.as_str(), StreamId(state.id)))
+    }
+
+    /// Move the truncation point of the stream forward to position. The truncation point is
+    /// persisted, entries of the stream before it are no longer returned from iterate_stream. The
+    /// tail of the wal moves once every stream has been truncated past it.
+    pub fn truncate_stream(
+        &mut self,
+        stream: StreamId,
+        position: WalPosition,
+    ) -> std::io::Result<()> {
+        if stream == StreamId::DEFAULT {
+            return self.truncate(position);
+        }
+        let name = match self.stream_name(stream) {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown stream {stream:?}"),
+                ))
+            }
+        };
+        let state = self.control.streams.get_mut(&name).unwrap();
+        if position <= state.low_water {
+            return Ok(());
+        }
+        let prev = std::mem::replace(&mut state.low_water, position);
+        if let Err(e) = self.write_control() {
+            self.control.streams.get_mut(&name).unwrap().low_water = prev;
+            return Err(e);
+        }
+        self.advance_tail();
+        Ok(())
+    }
+
+    /// Iterate over the live entries of a single stream.
+    pub fn iterate_stream(&self, stream: StreamId) -> StreamIterator<'_> {
+        let low_water = self.low_water(stream).unwrap_or(self.head);
+        StreamIterator {
+            inner: WalIterator::new(&*self.reader, self.tail, self.head, self.capacity)
+                .with_layout(self.layout),
+            stream,
+            low_water,
+        }
+    }
+
+    fn low_water(&self, stream: StreamId) -> Option<WalPosition> {
+        if stream == StreamId::DEFAULT {
+            return Some(self.control.low_water);
+        }
+        self.control
+            .streams
+            .values()
+            .find(|state| state.id == stream.0)
+            .map(|state| state.low_water)
+    }
+
+    // Move the tail to the lowest truncation point of any stream that still has live entries,
+    // without passing the slowest consumer cursor.
+    fn advance_tail(&mut self) {
+        let mut position = self.head;
+        for (stream, last) in &self.stream_heads {
+            match self.low_water(*stream) {
+                Some(low_water) if *last >= low_water && low_water < position => {
+                    position = low_water
+                }
+                _ => {}
+            }
+        }
+
+        let position = match self.min_cursor() {
+            Some(cursor) if cursor < position => cursor,
+            _ => position,
+        };
+
+        if position > self.tail {
+            self.tail = position
+        }
+        self.normalize_tail();
+        self.count_usage();
+    }
+
+    // Count the bytes taken up by each stream from scratch, for when truncation points move.
+    fn count_usage(&mut self) {
+        let mut usage: HashMap<StreamId, u64> = HashMap::new();
+        for entry in &self.entries {
+            if self.is_live(entry) {
+                *usage.entry(entry.stream).or_default() += entry.size;
+            }
+        }
+        self.stream_usage = usage;
+    }
+
+    // Whether the entry is at or after the truncation point of its stream.
+    fn is_live(&self, entry: &LiveEntry) -> bool {
+        self.low_water(entry.stream)
+            .is_some_and(|low_water| entry.position >= low_water)
+    }
+
+    // A tail at the padding at the end of a pass is the same as the start of the next pass. Once
+    // the next pass overwrites the padding, the tail is moved to the start of the ring where the
+    // next entry is. Entries before the tail are no longer counted.
+    fn normalize_tail(&mut self) {
+        if self.tail.is_overwritten(self.head, self.capacity) {
+            self.tail = self.tail.next_pass();
+        }
+        while let Some(entry) = self.entries.front().copied() {
+            if entry.position >= self.tail {
+                break;
+            }
+            self.entries.pop_front();
+            if self.is_live(&entry) {
+                if let Some(usage) = self.stream_usage.get_mut(&entry.stream) {
+                    *usage = usage.saturating_sub(entry.size);
+                }
+            }
+        }
+        self.publish_bounds();
+    }
+
+    fn publish_bounds(&self) {
+        *self.bounds.lock().unwrap() = Bounds {
+            tail: self.tail,
+            head: self.head,
+        };
+    }
+
+    /// Move the named consumer cursor forward to position, creating it if it doesn't exist. The
+    /// cursor is the position of the next entry the consumer needs, everything before it has been
+    /// processed. The cursor is persisted in the control region and survives a reopen. Moving a
+    /// cursor backwards is a no-op.
+    ///
+    /// A cursor holds back truncation and retention, but not the ring itself: entries still
+    /// waiting when the ring starts its next pass over them are lost, so a consumer has to keep up
+    /// with the appends. The consumers in this crate, such as Archiver, Capture and Leader, each
+    /// keep their progress in a cursor.
+    pub fn ack(&mut self, name: &str, position: WalPosition) -> std::io::Result<()> {
+        match self.control.cursors.get(name) {
+            Some(cur) if *cur >= position => return Ok(()),
+            _ => {}
+        }
+        let prev = self.control.cursors.insert(name.to_string(), position);
+        if let Err(e) = self.write_control() {
+            // Keep the in-memory state consistent with what is persisted.
+            match prev {
+                Some(prev) => self.control.cursors.insert(name.to_string(), prev),
+                None => self.control.cursors.remove(name),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Return the position of the named consumer cursor.
+    pub fn cursor(&self, name: &str) -> Option<WalPosition> {
+        self.control.cursors.get(name).copied()
+    }
+
+    /// Remove the named consumer cursor so it no longer holds back truncation.
+    pub fn remove_cursor(&mut self, name: &str) -> std::io::Result<()> {
+        if self.control.cursors.remove(name).is_some() {
+            self.write_control()?;
+        }
+        Ok(())
+    }
+
+    /// Write data at byte_offset of the device's sidecar file, see SidecarDevice. The write is
+    /// ordered after every entry appended before it.
+    pub fn write_sidecar(&mut self, byte_offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.dev.write_sidecar(byte_offset, data)
+    }
+
+    /// Read up to len bytes at byte_offset of the device's sidecar file.
+    pub fn read_sidecar(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        self.dev.read_sidecar(byte_offset, len)
+    }
+
+    // The position of the slowest consumer.
+    fn min_cursor(&self) -> Option<WalPosition> {
+        self.control.cursors.values().copied().min()
+    }
+
+    // Record where the wal stands so open_append_only can skip the recovery scan.
+    fn write_checkpoint(&mut self) -> std::io::Result<()> {
+        self.control.checkpoint = Some(Checkpoint {
+            head: self.head,
+            tail: self.tail,
+            next_lsn: self.next_lsn,
+        });
+        self.write_control()?;
+        self.dev.sync()
+    }
+
+    // Write the control block to the control region, overwriting the older of the two copies. Like
+    // the entries, it is durable once the device processes its completions.
+    fn write_control(&mut self) -> std::io::Result<()> {
+        self.check_failed()?;
+        let sequence = self.control_sequence + 1;
+        let aligned = self.control.encode(sequence)?;
+        let size = aligned.size();
+        let pos = WalPosition {
+            offset: sequence % CONTROL_BLOCKS,
+            rollover: 0,
+        };
+        self.write_internal(pos, aligned)
+            .map_err(|e| Error::new(e.kind(), format!("control block: {e}")))?;
+        self.control_sequence = sequence;
+        self.stats.control_bytes += size;
+        Ok(())
+    }
+
+    // Write something the wal needs itself rather than an entry. Its completion is tracked in
+    // internal and never returned from process_completions.
+    fn write_internal(&mut self, pos: WalPosition, data: AlignedSlice) -> std::io::Result<()> {
+        self.dev.write(pos, data, true)?;
+        self.internal.insert(pos);
+        Ok(())
+    }
+
+    // Writes the entry in aligned at the head, with its first block written only once the rest of
+    // it is durable.
+    fn write_header_last(&mut self, aligned: AlignedSlice) -> std::io::Result<()> {
+        let block = BLOCK_SIZE as usize;
+        let mut rest = self.write_buffer(aligned.blocks() - 1)?;
+        rest.as_slice()
+            .copy_from_slice(&aligned.as_bytes()[block..]);
+        self.write_internal(self.head.advance(1, self.capacity), rest)?;
+        self.dev.sync()?;
+        let mut first = self.write_buffer(1)?;
+        first
+            .as_slice()
+            .copy_from_slice(&aligned.as_bytes()[..block]);
+        self.dev.write(self.head, first, true)
+    }
+
+    /// The id of this wal. It is chosen when the wal is formatted, so a reformatted wal has a new
+    /// one.
+    pub fn id(&self) -> u64 {
+        self.control.id
+    }
+
+    /// A token for position that can only be resolved by this wal.
+    pub fn token(&self, position: WalPosition) -> WalToken {
+        WalToken {
+            position,
+            wal_id: self.control.id,
+        }
+    }
+
+    /// Return the position of a token so it can be passed to read_at, iterate_from or truncate.
+    /// Fails with InvalidInput if the token came from another wal, or from this one before it was
+    /// reformatted, or if its position is past the head, and with NotFound if it was truncated.
+    pub fn resolve(&self, token: &WalToken) -> std::io::Result<WalPosition> {
+        if token.wal_id != self.control.id {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "token belongs to wal {:x}, not {:x}",
+                    token.wal_id, self.control.id
+                ),
+            ));
+        }
+        if token.position > self.head {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "token {:?} is past the head {:?}",
+                    token.position, self.head
+                ),
+            ));
+        }
+        if token.position < self.tail {
+            return Err(Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "token {:?} is before the tail {:?}",
+                    token.position, self.tail
+                ),
+            ));
+        }
+        Ok(token.position)
+    }
+
+    /// The LSN that will be assigned to the next appended entry.
+    pub fn next_lsn(&self) -> u64 {
+        self.next_lsn
+    }
+
+    /// Maintain the given index from now on so position_of can find entries by LSN without a
+    /// scan from the tail. Records missing from the index are filled in from the wal.
+    pub fn enable_lsn_index(&mut self, mut index: LsnIndex) -> std::io::Result<()> {
+        index.truncate_from(self.next_lsn)?;
+        let start = match index.last() {
+            Some((_, pos)) if pos >= self.tail && pos < self.head => pos,
+            _ => self.tail,
+        };
+        let mut iter = WalIterator::new(&*self.reader, start, self.head, self.capacity)
+            .with_layout(self.layout);
+        while let Some(entry) = iter.next_header() {
+            let (header, pos) = entry?;
+            index.record(header.lsn.get(), pos);
+        }
+        self.lsn_index = Some(index);
+        Ok(())
+    }
+
+    /// Publish the tail and durable head to the positions file at path, creating it if it doesn't
+    /// exist, so processes that open the wal file with a SharedReader can read it while this wal
+    /// keeps appending. The positions are published again whenever completions are processed.
+    #[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+    pub fn publish_positions(&mut self, path: &Path) -> std::io::Result<()> {
+        let publisher = Publisher::open(
+            path,
+            self.tail,
+            self.durable_head(),
+            self.capacity,
+            self.layout,
+        )?;
+        self.publisher = Some(publisher);
+        Ok(())
+    }
+
+    /// Write any outstanding records of the LSN index.
+    pub fn flush_index(&mut self) -> std::io::Result<()> {
+        match self.lsn_index.as_mut() {
+            Some(index) => index.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Find the position of the live entry with the given LSN.
+    pub fn position_of(&self, lsn: u64) -> std::io::Result<Option<WalPosition>> {
+        if lsn >= self.next_lsn {
+            return Ok(None);
+        }
+        let start = match self.lsn_index.as_ref().and_then(|index| index.lookup(lsn)) {
+            Some((_, pos)) if pos >= self.tail && pos < self.head => pos,
+            _ => self.tail,
+        };
+        for start in [start, self.tail] {
+            let mut iter = WalIterator::new(&*self.reader, start, self.head, self.capacity)
+                .with_layout(self.layout);
+            while let Some(entry) = iter.next_header() {
+                let (header, pos) = entry?;
+                if header.lsn.get() == lsn {
+                    return Ok(Some(pos));
+                }
+                if header.lsn.get() > lsn {
+                    break;
+                }
+            }
+            if start == self.tail {
+                break;
+            }
+            // The index pointed at an unexpected entry, fall back to a scan from the tail.
+            warn!("LSN index is inconsistent for {lsn}");
+        }
+        Ok(None)
+    }
+
+    /// Read the data of the live entry at position.
+    pub fn read_at(&self, position: WalPosition) -> std::io::Result<Vec<u8>> {
+        let bounds = Bounds {
+            tail: self.tail,
+            head: self.head,
+        };
+        read_entry(&*self.reader, position, bounds, self.capacity, self.layout)
+    }
+
+    /// Read the data of the live entries at positions, like read_at does for each. The reads are
+    /// handed to the device together, so a device that can have many reads outstanding works on
+    /// them at once rather than one after another. done is called with each position and its
+    /// result as its reads complete, which is not necessarily in the order of positions.
+    pub fn read_many<F: FnMut(WalPosition, std::io::Result<Vec<u8>>)>(
+        &self,
+        positions: &[WalPosition],
+        mut done: F,
+    ) {
+        let bounds = Bounds {
+            tail: self.tail,
+            head: self.head,
+        };
+        read_entries(
+            &*self.reader,
+            positions,
+            bounds,
+            self.capacity,
+            self.layout,
+            &mut done,
+        )
+    }
+
+    /// Iterate over the entries from start up to end, for example to send a replica the entries it
+    /// is missing. start must be the position of a live entry, or end, and end the position just
+    /// past an entry or the head. Fails with NotFound if start has been truncated and InvalidInput
+    /// if the range isn't before the head.
+    pub fn read_range(
+        &self,
+        start: WalPosition,
+        end: WalPosition,
+    ) -> std::io::Result<WalIterator<'_>> {
+        let bounds = Bounds {
+            tail: self.tail,
+            head: self.head,
+        };
+        check_range(start, end, bounds)?;
+        Ok(WalIterator::new(&*self.reader, start, end, self.capacity).with_layout(self.layout))
+    }
+
+    /// Ask the device to start loading the blocks of the entries in range into memory, so reading
+    /// them afterwards, with read_range or read_many for example, doesn't wait on the disk for each
+    /// entry. Returns without waiting for the blocks to be loaded. Devices that don't read through a
+    /// cache ignore it. Fails like read_range if the range isn't live.
+    pub fn prefetch(&self, range: Range<WalPosition>) -> std::io::Result<()> {
+        let bounds = Bounds {
+            tail: self.tail,
+            head: self.head,
+        };
+        check_range(range.start, range.end, bounds)?;
+        if range.start == range.end {
+            return Ok(());
+        }
+        if !(RING_START..self.capacity).contains(&range.start.offset) {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} is outside the ring of {} blocks",
+                    range.start, self.capacity
+                ),
+            ));
+        }
+        let blocks = range.start.distance(range.end, self.capacity);
+        let first = blocks.min(self.capacity - range.start.offset);
+        self.reader.prefetch(
+            range.start.byte_offset(),
+            (first * BLOCK_SIZE as u64) as usize,
+        )?;
+        // The part of the range that wrapped around to the start of the ring.
+        if blocks > first {
+            let wrapped = WalPosition {
+                offset: RING_START,
+                rollover: range.end.rollover,
+            };
+            self.reader.prefetch(
+                wrapped.byte_offset(),
+                ((blocks - first) * BLOCK_SIZE as u64) as usize,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Open the live entry at position for reading its payload a piece at a time, so a large entry
+    /// doesn't need to fit in memory like it does with read_at.
+    pub fn entry_reader(&self, position: WalPosition) -> std::io::Result<EntryReader<'_>> {
+        let bounds = Bounds {
+            tail: self.tail,
+            head: self.head,
+        };
+        open_entry(&*self.reader, position, bounds, self.capacity, self.layout)
+    }
+
+    /// Check the live entry at position against its CRC without returning its payload, for
+    /// callers holding on to positions that want to know they still point at an intact entry.
+    /// Fails with NotFound if position is outside the live region and InvalidData if there is no
+    /// entry at position or it is damaged.
+    pub fn verify_at(&self, position: WalPosition) -> std::io::Result<EntryMeta> {
+        let bounds = Bounds {
+            tail: self.tail,
+            head: self.head,
+        };
+        verify_entry(&*self.reader, position, bounds, self.capacity, self.layout)
+    }
+
+    /// Check the hash chain of a wal formatted with WalOptions::hash_chain, from the tail to the
+    /// head, and return the digest of the last entry, or 0 if there are none. The first live
+    /// entry holds the digest of an entry that has been truncated, so it is only covered by the
+    /// entry after it. Fails with InvalidData carrying a BrokenChain at the first entry that
+    /// doesn't hold the digest of the one before it, and with Unsupported if the wal isn't hash
+    /// chained.
+    pub fn verify_chain(&self) -> std::io::Result<u64> {
+        if !self.control.hash_chain {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "wal was formatted without a hash chain",
+            ));
+        }
+        let mut iter = WalIterator::new(&*self.reader, self.tail, self.head, self.capacity)
+            .with_layout(self.layout);
+        let mut previous = None;
+        while let Some(entry) = iter.next_entry() {
+            let (header, position, payload) = entry?;
+            match previous {
+                Some((previous, digest)) if header.chain.get() != digest => {
+                    return Err(Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        BrokenChain { previous, position },
+                    ))
+                }
+                _ => {}
+            }
+            previous = Some((position, header.digest(&payload)));
+        }
+        Ok(previous.map_or(0, |(_, digest)| digest))
+    }
+
+    /// Return a reader that shares the device with this wal. It can be moved to another thread to
+    /// iterate over the entries while this wal keeps appending.
+    pub fn reader(&self) -> WalReader {
+        WalReader {
+            dev: self.reader.clone(),
+            bounds: self.bounds.clone(),
+            capacity: self.capacity,
+            layout: self.layout,
+        }
+    }
+
+    /// A cursor at the oldest live entry.
+    pub fn tail_cursor(&self) -> WalCursor {
+        WalCursor {
+            position: self.tail,
+            lsn: self.next_lsn - self.entries.len() as u64,
+        }
+    }
+
+    /// Check that the entry the cursor points to is still in the wal. Returns NotFound if the
+    /// cursor is before the tail, and InvalidData if the entry at its position isn't the one it
+    /// expects, for example because the entry was overwritten or lost in a crash.
+    pub fn check_cursor(&self, cursor: &WalCursor) -> std::io::Result<()> {
+        self.read_cursor(cursor).map(|_| ())
+    }
+
+    /// Read the entry the cursor points to and move the cursor past it. Returns None once the
+    /// cursor reaches the head. Fails like check_cursor if the cursor is no longer valid.
+    pub fn next_from(
+        &self,
+        cursor: &mut WalCursor,
+    ) -> std::io::Result<Option<(WalPosition, Vec<u8>)>> {
+        match self.read_cursor(cursor)? {
+            Some((pos, data, next)) => {
+                *cursor = next;
+                Ok(Some((pos, data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Reads the entry at the cursor and returns it along with the cursor for the entry after it.
+    fn read_cursor(
+        &self,
+        cursor: &WalCursor,
+    ) -> std::io::Result<Option<(WalPosition, Vec<u8>, WalCursor)>> {
+        if cursor.position < self.tail {
+            return Err(Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("cursor at {:?} has been truncated", cursor.position),
+            ));
+        }
+        if cursor.position == self.head && cursor.lsn == self.next_lsn {
+            return Ok(None);
+        }
+        let mismatch = || {
+            Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("entry {} is no longer at {:?}", cursor.lsn, cursor.position),
+            )
+        };
+        if cursor.position >= self.head {
+            return Err(mismatch());
+        }
+        let mut iter = WalIterator::new(&*self.reader, cursor.position, self.head, self.capacity)
+            .with_layout(self.layout);
+        match iter.next_entry() {
+            Some(Ok((header, pos, data))) if header.lsn.get() == cursor.lsn => {
+                let next = WalCursor {
+                    position: iter.position(),
+                    lsn: header.lsn.get() + 1,
+                };
+                Ok(Some((pos, data, next)))
+            }
+            _ => Err(mismatch()),
+        }
+    }
+
+    /// Read the data of the live entry with the given LSN.
+    pub fn read_lsn(&self, lsn: u64) -> std::io::Result<Option<Vec<u8>>> {
+        match self.position_of(lsn)? {
+            Some(pos) => self.read_at(pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterate from position, or from the tail if position has been truncated, to the head.
+    pub fn iterate_from(&self, position: WalPosition) -> WalIterator<'_> {
+        let start = position.max(self.tail);
+        WalIterator::new(&*self.reader, start, self.head, self.capacity).with_layout(self.layout)
+    }
+
+    /// Iterate over the entries from position, or from the tail if position has been truncated, up
+    /// to the durable head as it is now, so no entry a crash could still lose is returned. A
+    /// damaged entry is returned as an InvalidData error. Consumers that copy entries out of the
+    /// wal and keep their progress in a cursor read it this way, see ack.
+    pub fn iterate_durable(&self, position: WalPosition) -> DurableIterator<'_> {
+        DurableIterator {
+            inner: self.iterate_from(position).with_meta(),
+            end: self.durable_head(),
+        }
+    }
+
+    // Iterate over the durable entries of the pass the head is on, including those truncated but
+    // not overwritten yet, or from the tail if it is on an earlier pass.
+    pub(crate) fn iterate_current_pass(&self) -> MetaIterator<'_> {
+        let pass_start = WalPosition {
+            offset: RING_START,
+            rollover: self.head.rollover,
+        };
+        WalIterator::new(
+            &*self.reader,
+            self.tail.min(pass_start),
+            self.durable_head(),
+            self.capacity,
+        )
+        .with_layout(self.layout)
+        .with_meta()
+    }
+
+    /// Iterate over the live entries like iterate, returning the header information of each.
+    pub fn iterate_with_meta(&self) -> MetaIterator<'_> {
+        self.iterate().with_meta()
+    }
+
+    pub fn iterate(&self) -> WalIterator<'_> {
+        let iterator = WalIterator::new(&*self.reader, self.tail, self.head, self.capacity)
+            .with_layout(self.layout);
+        info!("Recovering from {:?} to {:?}", self.tail, self.head);
+        iterator
+    }
+
+    /// Erase the device at the given URI and open it as an empty wal. See format_device.
+    pub fn format(url: url::Url) -> std::io::Result<Self> {
+        Self::format_with_options(url, &WalOptions::default())
+    }
+
+    /// Erase the device at the given URI like format, with the device set up and the wal laid out
+    /// according to options.
+    pub fn format_with_options(url: url::Url, options: &WalOptions) -> std::io::Result<Self> {
+        let (dev, capacity, fallback) = Self::create_device(url, options)?;
+        let mut wal = Self::format_device_with_options(dev, capacity, options)?;
+        wal.stats.io_uring_fallback = fallback;
+        Ok(wal)
+    }
+
+    /// Zero the device and open it as an empty wal. Open only accepts devices that were formatted
+    /// or are entirely zeroed, so this is needed to reuse a device that held other data. Zeroing
+    /// the whole ring keeps stale data from being recovered as entries.
+    pub fn format_device(dev: Box<dyn PersistentDevice>, capacity: u64) -> std::io::Result<Self> {
+        Self::format_device_with_options(dev, capacity, &WalOptions::default())
+    }
+
+    /// Zero the device like format_device, and lay the wal out according to options.
+    pub fn format_device_with_options(
+        mut dev: Box<dyn PersistentDevice>,
+        capacity: u64,
+        options: &WalOptions,
+    ) -> std::io::Result<Self> {
+        if capacity <= RING_START {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("capacity of {capacity} blocks is too small"),
+            ));
+        }
+        // Large devices take more writes than the device can queue at once.
+        let depth = dev.capabilities().max_queue_depth.unwrap_or(usize::MAX);
+        let mut outstanding = HashSet::new();
+        let mut offset = 0;
+        while offset < capacity {
+            let blocks = (capacity - offset).min(FORMAT_CHUNK_BLOCKS);
+            let pos = WalPosition {
+                offset,
+                rollover: 0,
+            };
+            dev.write(
+                pos,
+                AlignedSlice::new((blocks * BLOCK_SIZE as u64) as usize),
+                true,
+            )?;
+            outstanding.insert(pos);
+            offset += blocks;
+            if outstanding.len() >= depth {
+                wait_for(&mut outstanding, || Ok(dev.process_completions()))?;
+            }
+        }
+        wait_for(&mut outstanding, || Ok(dev.process_completions()))?;
+        Self::open_with(dev, capacity, false, options)
+    }
+
+    /// Create a wal file of the given capacity in blocks at path and open it. The wal is built in
+    /// a temporary file in the same directory and only renamed to path once it is formatted and
+    /// synced, so a crash part way through never leaves a half initialized file at path. Fails
+    /// with AlreadyExists if path exists.
+    pub fn create(path: &Path, capacity: u64) -> std::io::Result<Self> {
+        Self::create_with_options(path, capacity, &WalOptions::default())
+    }
+
+    /// Create a wal file like create, with the device set up and the wal laid out according to
+    /// options.
+    pub fn create_with_options(
+        path: &Path,
+        capacity: u64,
+        options: &WalOptions,
+    ) -> std::io::Result<Self> {
+        Self::create_with(path, capacity, capacity, options)
+    }
+
+    /// Create a wal file at path like create, but only as long as the control region and the
+    /// first block of the ring, and open it. The file is extended as the ring fills, up to
+    /// capacity blocks, in chunks of WalOptions::grow_chunk_blocks, see GrowableDevice. The
+    /// capacity is recorded in the control block, so the file opens with it from then on.
+    /// Formatting the wal allocates the whole ring.
+    pub fn create_growable(path: &Path, capacity: u64) -> std::io::Result<Self> {
+        Self::create_growable_with_options(path, capacity, &WalOptions::default())
+    }
+
+    /// Create a growable wal file like create_growable, with the device set up and the wal laid
+    /// out according to options.
+    pub fn create_growable_with_options(
+        path: &Path,
+        capacity: u64,
+        options: &WalOptions,
+    ) -> std::io::Result<Self> {
+        Self::create_with(path, capacity, RING_START + 1, options)
+    }
+
+    // Create a wal file at path with a ring of capacity blocks in a file of blocks blocks.
+    fn create_with(
+        path: &Path,
+        capacity: u64,
+        blocks: u64,
+        options: &WalOptions,
+    ) -> std::io::Result<Self> {
+        if capacity <= RING_START {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("capacity of {capacity} blocks is too small"),
+            ));
+        }
+        let temp = temp_file_beside(path, blocks)?;
+        // Opening the zeroed file formats it, and closing it cleanly syncs the control block.
+        let (mut dev, _, _) = Self::file_device(temp.path(), None, options)?;
+        if blocks < capacity {
+            dev = Box::new(GrowableDevice::new(
+                dev,
+                temp.path(),
+                capacity,
+                options.grow_chunk_blocks,
+            )?);
+        }
+        let mut wal = Self::open_with(dev, capacity, false, options)?;
+        if blocks < capacity {
+            wal.control.capacity = capacity;
+        }
+        drop(wal);
+        persist(temp, path)?;
+
+        let (dev, capacity, fallback) = Self::file_device(path, None, options)?;
+        let mut wal = Self::open_with(dev, capacity, false, options)?;
+        wal.stats.io_uring_fallback = fallback;
+        Ok(wal)
+    }
+
+    /// Copy the wal to a new file at path, which opens as this wal would if it were reopened now.
+    /// The device is synced first so every entry appended so far is in the copy, and appends wait
+    /// until the copy is done, so unlike copying the file underneath a running wal the backup is
+    /// never torn. Like create, the copy only appears at path once it is complete and fails with
+    /// AlreadyExists if path exists. The in-memory device can't be backed up since it only reads
+    /// whole writes back.
+    pub fn backup_to(&mut self, path: &Path) -> std::io::Result<()> {
+        self.dev.sync()?;
+        let temp = temp_file_beside(path, self.capacity)?;
+        let mut offset = 0;
+        while offset < self.capacity {
+            let blocks = (self.capacity - offset).min(FORMAT_CHUNK_BLOCKS);
+            let byte_offset = offset * BLOCK_SIZE as u64;
+            let data = self
+                .reader
+                .read(byte_offset, (blocks * BLOCK_SIZE as u64) as usize)?;
+            temp.as_file().write_all_at(&data, byte_offset)?;
+            offset += blocks;
+        }
+        persist(temp, path)
+    }
+
+    /// Open the given URI and begin recovery. The WalIterator is returned.
+    /// Supported URIs:
+    ///   - mem:// - Use an in-memory device
+    ///   - file:///path/to/file - Use a file-based device
+    ///   - file:///path/to/file?read=mmap - Read through a memory mapping of the file, see
+    ///     MappedDevice
+    ///   - file:///path/to/file?sidecar=/path/to/sidecar - Pair the file with a sidecar file, see
+    ///     SidecarDevice
+    ///   - file:///path/to/file?device=dax - Write through a mapping of a file on persistent
+    ///     memory on Linux, see DaxDevice
+    ///   - file:///path/to/file?device=pwrite|kqueue|calibrate - Pick the device on macOS, see
+    ///     MacOsDevice
+    ///   - /path/to/file - Use a file-based device (backwards compatibility)
+    ///
+    /// A file is locked while it is open, so opening a file another wal has open fails with
+    /// ResourceBusy. A file that doesn't hold a wal fails with a NotAWal.
+    pub fn open(url: url::Url) -> std::io::Result<Self> {
+        Self::open_with_options(url, &WalOptions::default())
+    }
+
+    /// Open the given URI like open, with the device set up according to options.
+    pub fn open_with_options(url: url::Url, options: &WalOptions) -> std::io::Result<Self> {
+        info!("Starting recovery from {}", url);
+
+        let (dev, capacity, fallback) = Self::create_device(url, options)?;
+        let mut wal = Self::open_with(dev, capacity, false, options)?;
+        wal.stats.io_uring_fallback = fallback;
+        Ok(wal)
+    }
+
+    /// Open a wal on an already created device with the given capacity in blocks and begin
+    /// recovery. This allows tests to run the wal on a simulated device. A device that is entirely
+    /// zeroed is formatted as an empty wal. A device that holds data but was never formatted is
+    /// rejected with a NotAWal since its contents can't be told apart from entries, use
+    /// format_device to reuse it.
+    pub fn open_device(dev: Box<dyn PersistentDevice>, capacity: u64) -> std::io::Result<Self> {
+        Self::open_with(dev, capacity, false, &WalOptions::default())
+    }
+
+    /// Open the given URI for appending without scanning the ring. See open_append_only_device.
+    pub fn open_append_only(url: url::Url) -> std::io::Result<Self> {
+        let (dev, capacity, fallback) = Self::create_device(url, &WalOptions::default())?;
+        let mut wal = Self::open_append_only_device(dev, capacity)?;
+        wal.stats.io_uring_fallback = fallback;
+        Ok(wal)
+    }
+
+    /// Open a wal for appending without scanning the ring, for producers that never read their own
+    /// log. The head and tail come from the checkpoint written when the wal was last closed
+    /// cleanly. If it wasn't, for example after a crash, this falls back to the recovery scan of
+    /// open_device. Entries from before the open can still be read, but len, tail_cursor,
+    /// retention and the truncation of streams only account for entries appended since.
+    pub fn open_append_only_device(
+        dev: Box<dyn PersistentDevice>,
+        capacity: u64,
+    ) -> std::io::Result<Self> {
+        Self::open_with(dev, capacity, true, &WalOptions::default())
+    }
+
+    // Opens the wal on dev. options only decide how a zeroed device is formatted.
+    fn open_with(
+        mut dev: Box<dyn PersistentDevice>,
+        capacity: u64,
+        append_only: bool,
+        options: &WalOptions,
+    ) -> std::io::Result<Self> {
+        if capacity <= RING_START {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("capacity of {capacity} blocks is too small"),
+            ));
+        }
+
+        let mut buffer = read_control_region(&mut dev)?;
+        if control::is_blank(&buffer) {
+            format_blank(&mut dev, options)?;
+            buffer = read_control_region(&mut dev)?;
+        }
+        let (mut control, control_sequence) = match ControlBlock::decode(&buffer) {
+            Ok(decoded) => decoded,
+            Err(_) if is_legacy_device(&mut dev, capacity).unwrap_or(false) => {
+                return Err(NotAWal::Legacy.error())
+            }
+            Err(e) => return Err(e),
+        };
+        if control.capacity != 0 && control.capacity != capacity {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "wal has a capacity of {} blocks but was opened with {capacity}",
+                    control.capacity
+                ),
+            ));
+        }
+        // The id is lost along with the rest of the control block if no copy survived.
+        let lost_id = control.id == 0;
+        if lost_id {
+            control.id = control::new_id();
+        }
+        // Entries appended from now on can be torn.
+        let torn_writes = control.atomic_writes && !dev.capabilities().atomic_blocks();
+        if torn_writes {
+            info!("Device can tear writes, single block entries are no longer atomic");
+            control.atomic_writes = false;
+        }
+
+        let init_position = WalPosition {
+            offset: RING_START,
+            rollover: 0,
+        };
+        let bounds = Bounds {
+            tail: init_position,
+            head: init_position,
+        };
+        let mut wal = Wal {
+            reader: dev.reader()?,
+            bounds: Arc::new(Mutex::new(bounds)),
+            dev,
+            capacity,
+            layout: BlockLayout::new(control.block_crcs),
+            head: init_position,
+            tail: init_position,
+            entries: VecDeque::new(),
+            control,
+            control_sequence,
+            stream_heads: HashMap::new(),
+            stream_usage: HashMap::new(),
+            quotas: HashMap::new(),
+            notifier: Notifier::default(),
+            listeners: Vec::new(),
+            failed: None,
+            scrub: None,
+            damage_listeners: Vec::new(),
+            in_flight: InFlight::default(),
+            in_flight_limit: None,
+            reaped: Vec::new(),
+            internal: HashSet::new(),
+            fences: Fences::default(),
+            ordered: false,
+            header_last: false,
+            chain: 0,
+            erase: None,
+            lap_dir: None,
+            erased: init_position,
+            completion_order: CompletionOrder::default(),
+            keys: RecentKeys::default(),
+            stats: WalStats::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            max_sync_interval: Some(DEFAULT_MAX_SYNC_INTERVAL),
+            snapshot: None,
+            retention: RetentionPolicy::default(),
+            disk_full: DiskFullPolicy::default(),
+            next_lsn: 0,
+            lsn_index: None,
+            #[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+            publisher: None,
+            pool: None,
+        };
+        if let Some(bounded) = options.bounded {
+            let blocks = wal.layout.blocks_for(bounded.max_entry_len);
+            wal.pool = Some(BufferPool::new(bounded.buffers, blocks));
+        }
+
+        let checkpoint = wal.control.checkpoint.take();
+        if checkpoint.is_some() || lost_id || torn_writes {
+            // Appending makes the checkpoint stale, so it must be gone from the device first.
+            wal.write_control()?;
+            wal.dev.sync()?;
+        }
+        match checkpoint {
+            // The checkpoint doesn't hold the digest of the last entry, which is only found by
+            // recovering.
+            Some(checkpoint) if append_only && !wal.control.hash_chain => {
+                wal.head = checkpoint.head;
+                wal.tail = checkpoint.tail;
+                wal.next_lsn = checkpoint.next_lsn;
+                wal.clamp_low_water();
+                wal.publish_bounds();
+            }
+            _ => {
+                if append_only {
+                    info!("Wal was not closed cleanly, recovering");
+                }
+                wal.recover()?;
+            }
+        }
+        Ok(wal)
+    }
+
+    fn recover(&mut self) -> std::io::Result<()> {
+        recover(self)?;
+        self.clamp_low_water();
+
+        // Find the last entry of each stream so truncation knows which streams are still live.
+        let mut iter = WalIterator::new(&*self.reader, self.tail, self.head, self.capacity)
+            .with_layout(self.layout);
+        while let Some(entry) = iter.next_entry() {
+            let (header, pos, payload) = entry?;
+            if self.control.hash_chain {
+                self.chain = header.digest(&payload);
+            }
+            self.entries.push_back(LiveEntry {
+                position: pos,
+                timestamp: header.timestamp.get(),
+                stream: StreamId(header.stream.get()),
+                size: header.num_blocks(self.layout) * BLOCK_SIZE as u64,
+            });
+            self.stream_heads.insert(StreamId(header.stream.get()), pos);
+            if header.key.get() != 0 {
+                self.keys.insert(header.key.get(), pos);
+            }
+            self.next_lsn = header.lsn.get() + 1;
+        }
+        self.advance_tail();
+        self.publish_bounds();
+        Ok(())
+    }
+
+    // The persisted truncation point of the default stream can be from an earlier pass, or past
+    // the head if the entries before it were lost in a crash. Entries appended from the head must
+    // not be treated as truncated.
+    fn clamp_low_water(&mut self) {
+        self.control.low_water = self.control.low_water.clamp(self.tail, self.head);
+    }
+
+    pub fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+        let mut completions = std::mem::take(&mut self.reaped);
+        completions.extend(self.reap_completions());
+        completions.into_iter()
+    }
+
+    // Processes the completions the device has, moving durable_head forward, and keeps them for
+    // the next process_completions, so code within the crate can wait for entries to be durable
+    // without taking completions from the caller.
+    pub(crate) fn poll_completions(&mut self) {
+        let completions = self.reap_completions();
+        self.reaped.extend(completions);
+    }
+
+    // Processes the completions the device has, without the ones reaped earlier.
+    fn reap_completions(&mut self) -> Vec<WalPosition> {
+        let completions: Vec<WalPosition> = self
+            .dev
+            .process_completions()
+            .filter(|pos| {
+                self.internal.remove(pos);
+                self.in_flight.remove(pos)
+            })
+            .collect();
+        self.check_device_failure();
+        let completions = self.fences.release(completions, &self.in_flight);
+        let completions = self.completion_order.release(completions);
+        self.notifier.publish(completions.as_slice());
+        if !completions.is_empty() {
+            self.listeners
+                .retain_mut(|listener| completions.iter().all(|pos| listener(*pos)));
+        }
+        self.flush_index_if_needed();
+        self.publish_if_needed();
+        completions
+    }
+
+    /// Process completions like process_completions, but return them coalesced into the highest
+    /// position that completed and the number that did, rather than collecting every position.
+    /// With ordered completions, every entry before the position is durable too. Every Watch is
+    /// notified as usual.
+    pub fn process_watermark(&mut self) -> Option<Watermark> {
+        // Fences and ordering hold back completions by position, and subscribers receive every
+        // one, so they need each of them.
+        if !self.fences.at.is_empty()
+            || !self.completion_order.pending.is_empty()
+            || !self.listeners.is_empty()
+            || !self.reaped.is_empty()
+        {
+            return Watermark::of(self.process_completions());
+        }
+        let watermark = Watermark::of(self.dev.process_completions().filter(|pos| {
+            self.internal.remove(pos);
+            self.in_flight.remove(pos)
+        }));
+        self.check_device_failure();
+        if let Some(watermark) = watermark {
+            self.notifier.publish(&[watermark.position]);
+        }
+        self.flush_index_if_needed();
+        self.publish_if_needed();
+        watermark
+    }
+
+    // The index is written as entries complete rather than on append to keep its sync off the
+    // append path.
+    fn flush_index_if_needed(&mut self) {
+        if let Some(index) = self.lsn_index.as_mut().filter(|index| index.needs_flush()) {
+            if let Err(e) = index.flush() {
+                warn!("Failed to flush lsn index: {}", e);
+            }
+        }
+    }
+
+    // The positions are published as completions are processed, which is when the durable head
+    // moves, and pick up any truncation since the last time.
+    fn publish_if_needed(&mut self) {
+        #[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+        {
+            let (tail, durable) = (self.tail, self.durable_head());
+            if let Some(publisher) = self.publisher.as_mut() {
+                if let Err(e) = publisher.publish(tail, durable) {
+                    warn!("Failed to publish positions: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Make sure every entry appended before the fence is reported durable before any entry appended
+    /// after it. Completions of later entries are held back until the earlier ones have all
+    /// completed, without waiting for them here. Note that if a write before the fence fails, the
+    /// entries after it are held back for good.
+    pub fn fence(&mut self) {
+        if !self.in_flight.is_empty() && self.fences.at.back() != Some(&self.head) {
+            self.fences.at.push_back(self.head);
+        }
+    }
+
+    /// Report completions strictly in append order. A completion that arrives before the
+    /// completion of an earlier entry is held back until that one arrives, so every position
+    /// returned from process_completions means all entries before it are durable too. Only applies
+    /// to entries appended after it is set. Note that if a write fails, the entries after it are
+    /// held back for good.
+    pub fn set_ordered_completions(&mut self, ordered: bool) {
+        self.ordered = ordered;
+    }
+
+    /// Write the first block of each entry longer than a block, which holds its header, only once
+    /// the rest of the entry is durable. A crash then leaves either the whole entry or no header at
+    /// all, rather than a header whose data never made it, on devices that don't write several
+    /// blocks atomically. Each such append waits for a sync of the device and makes a second write.
+    /// Only applies to entries appended after it is set.
+    pub fn set_header_last(&mut self, header_last: bool) {
+        self.header_last = header_last;
+    }
+
+    /// Copy each pass of the ring into an archive file in dir before the next pass starts to
+    /// overwrite it, or stop with None, so history is kept beyond the ring. The file of a pass is
+    /// named by its rollover, see lap_file_name, and read back with LapArchive. The first append of
+    /// each pass syncs the device and writes the archive before it goes ahead, failing if the
+    /// archive can't be written, which makes that append as slow as copying the ring. Passes that
+    /// finished before this is set aren't archived. The ring is read in large pieces, which the
+    /// MemDevice behind mem: URLs doesn't support.
+    pub fn set_rollover_archive(&mut self, dir: Option<&Path>) -> std::io::Result<()> {
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir)?;
+        }
+        self.lap_dir = dir.map(Path::to_path_buf);
+        Ok(())
+    }
+
+    // Archives the pass before the one the head is on, unless it already is. Called before the
+    // first write of a pass.
+    fn archive_lap(&mut self) -> std::io::Result<()> {
+        let Some(dir) = &self.lap_dir else {
+            return Ok(());
+        };
+        let rollover = self.head.rollover - 1;
+        if dir.join(lap_file_name(rollover)).exists() {
+            return Ok(());
+        }
+        // The writes of the pass, including the padding at its end, must have landed first.
+        self.dev.sync()?;
+        write_lap(dir, rollover, self.capacity, self.layout, &*self.reader)?;
+        Ok(())
+    }
+
+    /// Overwrite the blocks of truncated entries with pattern before the ring comes around to
+    /// reuse them, or stop with None, for when data must not stay on the device once it is
+    /// truncated. The blocks are overwritten by erase_reclaimed, which a WalDriver calls in the
+    /// background. Blocks truncated while erasing was off are erased once it is turned on, as long
+    /// as they haven't been overwritten since.
+    pub fn set_erase_reclaimed(&mut self, pattern: Option<u8>) {
+        self.erase = pattern;
+    }
+
+    /// Overwrite up to max_blocks blocks of truncated entries that haven't been erased yet with the
+    /// pattern given to set_erase_reclaimed, and return how many were. The blocks are overwritten
+    /// with entries that are skipped when reading, so recovery still finds the entries after them.
+    /// The device is synced before and after, so neither an earlier write to the blocks nor a
+    /// later one can land in the wrong order.
+    pub fn erase_reclaimed(&mut self, max_blocks: u64) -> std::io::Result<u64> {
+        let Some(pattern) = self.erase else {
+            return Ok(0);
+        };
+        if !self.has_unerased() || max_blocks == 0 {
+            return Ok(0);
+        }
+        self.dev.sync()?;
+        let mut erased = 0;
+        while self.erased < self.tail && erased < max_blocks {
+            let start = self.erased;
+            // An entry can't run past the end of the ring.
+            let end = if self.tail.rollover > start.rollover {
+                self.capacity
+            } else {
+                self.tail.offset
+            };
+            let blocks = (end - start.offset).min(max_blocks - erased);
+            let header = EntryHeader {
+                crc: U32::ZERO,
+                rollover: U32::new(start.rollover),
+                len: U64::new(self.layout.fill_len(blocks)),
+                stream: U32::new(ERASED_STREAM),
+                lsn: U64::ZERO,
+                timestamp: U64::ZERO,
+                key: U64::ZERO,
+                chain: U64::ZERO,
+            };
+            let data = vec![pattern; self.layout.fill_len(blocks) as usize];
+            let mut aligned = AlignedSlice::new((blocks * BLOCK_SIZE as u64) as usize);
+            self.layout.encode(header, &data, aligned.as_slice());
+            self.write_internal(start, aligned)?;
+            self.stats.erase_bytes += blocks * BLOCK_SIZE as u64;
+            erased += blocks;
+            self.erased = start.advance(blocks, self.capacity);
+        }
+        self.dev.sync()?;
+        Ok(erased)
+    }
+
+    // Whether there are truncated blocks left for erase_reclaimed.
+    pub(crate) fn has_unerased(&mut self) -> bool {
+        if self.erase.is_none() {
+            return false;
+        }
+        // The blocks a pass behind the head have been overwritten.
+        if self.head.rollover > 0 {
+            let lap = WalPosition {
+                offset: self.head.offset,
+                rollover: self.head.rollover - 1,
+            };
+            self.erased = self.erased.max(lap);
+        }
+        self.erased < self.tail
+    }
+
+    /// Re-check the CRCs of the durable entries from the tail on, a pass over them at a time, at
+    /// up to blocks_per_second blocks a second, or stop with None. Damage the device picked up
+    /// since the entries were written, which would otherwise only show up when they are read or
+    /// at recovery, is reported to the callbacks added with on_damaged and counted in the stats.
+    /// The entries are checked by scrub, which a WalDriver calls in the background.
+    pub fn set_scrub_rate(&mut self, blocks_per_second: Option<u64>) {
+        self.scrub = blocks_per_second.map(|rate| Scrub {
+            blocks_per_second: rate,
+            next: self.tail,
+            budget: 0.0,
+            refilled: Instant::now(),
+        });
+    }
+
+    /// Call f with the position of every damaged entry scrub finds. An entry stays damaged, so it
+    /// is reported again on each pass until it is truncated.
+    pub fn on_damaged<F: FnMut(WalPosition) + Send + 'static>(&mut self, f: F) {
+        self.damage_listeners.push(Box::new(f));
+    }
+
+    /// Check the next entries as set_scrub_rate allows since the last call and return the number
+    /// of blocks checked. A damaged entry, including one that can't be read, is reported rather
+    /// than failing the scrub, and the entries after it are still checked. Once the durable head is
+    /// reached, the next call starts over from the tail.
+    pub fn scrub(&mut self) -> u64 {
+        let Some(scrub) = self.scrub.as_mut() else {
+            return 0;
+        };
+        // The budget builds up to at most a second's worth, and goes into debt for an entry larger
+        // than what is left of it.
+        let rate = scrub.blocks_per_second as f64;
+        let now = Instant::now();
+        scrub.budget =
+            (scrub.budget + now.duration_since(scrub.refilled).as_secs_f64() * rate).min(rate);
+        scrub.refilled = now;
+        let mut next = scrub.next.max(self.tail);
+        let end = self.durable_head();
+        let mut checked = 0;
+        let mut index = self.entries.partition_point(|entry| entry.position < next);
+        while self.scrub.as_ref().is_some_and(|scrub| scrub.budget > 0.0) {
+            let Some(entry) = self.entries.get(index).copied() else {
+                next = self.tail;
+                break;
+            };
+            if entry.position >= end {
+                next = self.tail;
+                break;
+            }
+            if let Err(e) = self.verify_at(entry.position) {
+                warn!("Scrub found a damaged entry at {:?}: {}", entry.position, e);
+                self.stats.scrub_damaged += 1;
+                for listener in &mut self.damage_listeners {
+                    listener(entry.position);
+                }
+            }
+            let blocks = entry.size / BLOCK_SIZE as u64;
+            self.stats.scrubbed_bytes += entry.size;
+            checked += blocks;
+            if let Some(scrub) = self.scrub.as_mut() {
+                scrub.budget -= blocks as f64;
+            }
+            index += 1;
+            next = entry.position.advance(blocks, self.capacity);
+        }
+        if let Some(scrub) = self.scrub.as_mut() {
+            scrub.next = next;
+        }
+        checked
+    }
+
+    pub(crate) fn is_scrubbing(&self) -> bool {
+        self.scrub.is_some()
+    }
+
+    /// Set how long dropping the wal waits for outstanding writes to complete. Completions that
+    /// arrive while dropping are still published to every Watch. Zero doesn't wait.
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Limit the bytes of entries whose writes the device hasn't reported complete, so a producer
+    /// that appends faster than the disk writes doesn't queue up writes without bound. An append
+    /// that would go past max_bytes waits for writes to complete or fails, as backpressure says.
+    /// An entry larger than max_bytes on its own is appended once nothing else is in flight. None
+    /// removes the limit.
+    pub fn set_in_flight_limit(&mut self, limit: Option<(u64, Backpressure)>) {
+        self.in_flight_limit = limit;
+    }
+
+    /// The bytes of entries whose writes the device hasn't reported complete.
+    pub fn in_flight_bytes(&self) -> u64 {
+        self.in_flight.bytes
+    }
+
+    /// Set how long a WalDriver lets a write stay outstanding before it syncs the device, so an
+    /// entry is durable within about this long of being appended even if nothing is appended after
+    /// it. None leaves it to the device to make writes durable on its own schedule. Without a
+    /// driver, whoever calls process_completions decides when the device is synced.
+    pub fn set_max_sync_interval(&mut self, interval: Option<Duration>) {
+        self.max_sync_interval = interval;
+    }
+
+    pub(crate) fn max_sync_interval(&self) -> Option<Duration> {
+        self.max_sync_interval
+    }
+
+    // Make every write submitted so far durable. Their completions are still returned from
+    // process_completions.
+    pub(crate) fn sync_device(&mut self) -> std::io::Result<()> {
+        self.check_failed()?;
+        let res = self.dev.sync();
+        if let Err(e) = &res {
+            self.fail(Error::new(e.kind(), e.to_string()));
+        }
+        res
+    }
+
+    /// The error the wal failed with, if it has. A wal fails once its device reports a hard error,
+    /// such as EIO, for a write it accepted, or a sync fails: the completions of the writes
+    /// affected will never arrive, and which writes reached the disk is unknown. From then on
+    /// appends and anything else that writes fail with the error, carrying a WalFailed, without
+    /// touching the device. Entries can still be read, and completions that arrive are still
+    /// returned from process_completions.
+    ///
+    /// The failure is only held in memory. Reopening the wal clears it, recovering from what is on
+    /// the device as after a crash: entries whose writes failed are not recovered.
+    pub fn failure(&self) -> Option<&WalFailed> {
+        self.failed.as_ref()
+    }
+
+    fn check_failed(&self) -> std::io::Result<()> {
+        match &self.failed {
+            Some(failed) => Err(failed.error()),
+            None => Ok(()),
+        }
+    }
+
+    // Only the first error is kept, the ones after it are usually a consequence of it.
+    fn fail(&mut self, e: Error) {
+        if self.failed.is_none() {
+            if is_disk_full(&e) {
+                error!("Wal failed after running out of space: {}", e);
+            } else {
+                error!("Wal failed after a device error: {}", e);
+            }
+            self.failed = Some(WalFailed {
+                kind: e.kind(),
+                message: e.to_string(),
+            });
+        }
+    }
+
+    // Process completions for a wait, which gives up once the wal fails since the writes it waits
+    // for may never complete.
+    pub(crate) fn completions_or_failure(
+        &mut self,
+    ) -> std::io::Result<std::vec::IntoIter<WalPosition>> {
+        let completions = self.process_completions();
+        self.check_failed()?;
+        Ok(completions)
+    }
+
+    fn check_device_failure(&mut self) {
+        if let Some(e) = self.dev.take_failure() {
+            self.fail(e);
+        }
+    }
+
+    /// Return a handle that is woken whenever process_completions reports new durable entries.
+    pub fn watch(&self) -> Watch {
+        self.notifier.subscribe()
+    }
+
+    /// Return a channel that receives every completion process_completions returns from now on,
+    /// in the same order. Each channel queues the completions for its own receiver, so several
+    /// parts of an application can each observe all of them. Dropping the receiver unsubscribes
+    /// it.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<WalPosition> {
+        let (sender, receiver) = mpsc::channel();
+        self.listeners
+            .push(Box::new(move |pos| sender.send(pos).is_ok()));
+        receiver
+    }
+
+    /// Call f with every completion process_completions returns from now on, before they are
+    /// returned. f is called from whichever thread processes completions.
+    pub fn on_complete<F: FnMut(WalPosition) + Send + 'static>(&mut self, mut f: F) {
+        self.listeners.push(Box::new(move |pos| {
+            f(pos);
+            true
+        }));
+    }
+
+    /// Hand the wal to a thread that drives its completions, so nothing needs to call
+    /// process_completions. See WalDriver.
+    pub fn spawn_driver(self) -> std::io::Result<WalDriver> {
+        WalDriver::spawn(self)
+    }
+
+    /// Append every record and wait until they are all durable. Returns the number of records
+    /// appended. This is intended for bulk loading and consumes all completions while it runs. A
+    /// write that fails is never reported complete, so an error is returned if none of the
+    /// outstanding writes complete for COMPLETION_TIMEOUT.
+    pub fn import<I>(&mut self, records: I) -> std::io::Result<usize>
+    where
+        I: IntoIterator<Item = std::io::Result<Vec<u8>>>,
+    {
+        let mut outstanding = HashSet::new();
+        let mut imported = 0;
+        for record in records {
+            outstanding.insert(self.append(&record?)?);
+            imported += 1;
+            for pos in self.process_completions() {
+                outstanding.remove(&pos);
+            }
+        }
+        wait_for(&mut outstanding, || self.completions_or_failure())?;
+        Ok(imported)
+    }
+
+    // Returns the device, its capacity in blocks and whether io_uring wasn't available.
+    fn create_device(
+        url: url::Url,
+        options: &WalOptions,
+    ) -> std::io::Result<(Box<dyn PersistentDevice>, u64, bool)> {
+        if url.scheme() == "mem" {
+            // Parse size from path (e.g. mem://64 means 64 blocks)
+            let blocks = url.path().parse::<u64>().map_err(|e| {
+                Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("bad size {:?} in {url}: {e}", url.path()),
+                )
+            })?;
+            let dev: Box<dyn PersistentDevice> = Box::new(crate::mem::MemDevice::new(blocks));
+            Ok((dev, blocks, false))
+        } else if url.scheme() == "file" {
+            // Handle file paths
+            let path = Path::new(url.path());
+            debug!("Opening {}", path.display());
+            // The device opens the file, so it is picked before the options that wrap it.
+            let device = url
+                .query_pairs()
+                .find(|(key, _)| key == "device")
+                .map(|(_, value)| value.into_owned());
+            let (mut dev, capacity, fallback) =
+                Self::file_device(path, device.as_deref(), options)?;
+            for (key, value) in url.query_pairs() {
+                dev = match (key.as_ref(), value.as_ref()) {
+                    ("device", _) => dev,
+                    #[cfg(all(any(target_os = "linux", target_os = "macos"), not(miri)))]
+                    ("read", "mmap") => {
+                        // The mapping covers the file as it was when it was mapped.
+                        if capacity * BLOCK_SIZE as u64 > path.metadata()?.len() {
+                            return Err(Error::new(
+                                std::io::ErrorKind::Unsupported,
+                                "read=mmap can't be used with a wal file that is still growing",
+                            ));
+                        }
+                        Box::new(MappedDevice::new(dev, path)?)
+                    }
+                    ("sidecar", sidecar) => Box::new(SidecarDevice::new(dev, Path::new(sidecar))?),
+                    _ => {
+                        return Err(Error::new(
+                            std::io::ErrorKind::Unsupported,
+                            format!("unsupported option {key}={value}"),
+                        ))
+                    }
+                };
+            }
+            Ok((dev, capacity, fallback))
+        } else {
+            Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unknown URI scheme",
+            ))
+        }
+    }
+
+    // Returns the device, its capacity in blocks and whether io_uring wasn't available. The device
+    // named by the device option of the URL is used if there is one, otherwise the platform's
+    // default.
+    fn file_device(
+        path: &Path,
+        device: Option<&str>,
+        options: &WalOptions,
+    ) -> std::io::Result<(Box<dyn PersistentDevice>, u64, bool)> {
+        let (dev, fallback) = match device {
+            Some(name) => (Self::named_device(path, name, options)?, false),
+            None => Self::chosen_device(path, options.device, options)?,
+        };
+
+        let capacity_bytes = path.metadata()?.len();
+        if capacity_bytes % BLOCK_SIZE as u64 != 0 {
+            return Err(NotAWal::BlockSize(capacity_bytes).error());
+        }
+        if capacity_bytes <= RING_START * BLOCK_SIZE as u64 {
+            return Err(NotAWal::TooSmall(capacity_bytes).error());
+        }
+        let capacity = capacity_bytes / BLOCK_SIZE as u64;
+        match growable_capacity(path)? {
+            Some(grow_to) if grow_to > capacity => {
+                let dev = GrowableDevice::new(dev, path, grow_to, options.grow_chunk_blocks)?;
+                Ok((Box::new(dev), grow_to, fallback))
+            }
+            _ => Ok((dev, capacity, fallback)),
+        }
+    }
+
+    // Opens device on the file at path, and returns whether io_uring wasn't available so the
+    // platform's device fell back to synchronous writes.
+    fn chosen_device(
+        path: &Path,
+        device: FileDevice,
+        options: &WalOptions,
+    ) -> std::io::Result<(Box<dyn PersistentDevice>, bool)> {
+        match device {
+            FileDevice::Sync => Ok((Box::new(sync_device(path, options)?), false)),
+            #[cfg(all(target_os = "linux", not(miri)))]
+            FileDevice::Uring => Ok((Box::new(uring_device(path, options)?), false)),
+            #[cfg(any(miri, not(target_os = "linux")))]
+            FileDevice::Uring => Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "io_uring is only available on Linux",
+            )),
+            #[cfg(all(target_os = "linux", not(miri)))]
+            FileDevice::Platform => match uring_device(path, options) {
+                Ok(uring) => Ok((Box::new(uring), false)),
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                    warn!("{e}, using synchronous writes for {}", path.display());
+                    Ok((Box::new(sync_device(path, options)?), true))
+                }
+                Err(e) => Err(e),
+            },
+            #[cfg(all(target_os = "macos", not(miri)))]
+            FileDevice::Platform => Ok((
+                Self::macos_device(path, options.macos_device, options)?,
+                false,
+            )),
+            #[cfg(any(miri, not(any(target_os = "linux", target_os = "macos"))))]
+            FileDevice::Platform => Ok((Box::new(sync_device(path, options)?), false)),
+        }
+    }
+
+    // Opens the device selected with device=name in the URL.
+    fn named_device(
+        path: &Path,
+        name: &str,
+        options: &WalOptions,
+    ) -> std::io::Result<Box<dyn PersistentDevice>> {
+        match name {
+            "sync" => Ok(Self::chosen_device(path, FileDevice::Sync, options)?.0),
+            "uring" => Ok(Self::chosen_device(path, FileDevice::Uring, options)?.0),
+            #[cfg(all(target_os = "linux", not(miri)))]
+            "dax" => Ok(Box::new(DaxDevice::new(path)?)),
+            #[cfg(all(target_os = "macos", not(miri)))]
+            "pwrite" => Self::macos_device(path, MacOsDevice::Pwrite, options),
+            #[cfg(all(target_os = "macos", not(miri)))]
+            "kqueue" => Self::macos_device(path, MacOsDevice::Kqueue, options),
+            #[cfg(all(target_os = "macos", not(miri)))]
+            "calibrate" => Self::macos_device(path, MacOsDevice::Calibrate, options),
+            _ => Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("unsupported option device={name}"),
+            )),
+        }
+    }
+
+    #[cfg(all(target_os = "macos", not(miri)))]
+    fn macos_device(
+        path: &Path,
+        device: MacOsDevice,
+        options: &WalOptions,
+    ) -> std::io::Result<Box<dyn PersistentDevice>> {
+        Ok(match device {
+            MacOsDevice::Pwrite => {
+                Box::new(MacOsAsyncIO::with_workers(path, options.pwrite_workers)?)
+            }
+            MacOsDevice::Kqueue => Box::new(KQueue::new(path)?),
+            MacOsDevice::Calibrate => {
+                // The scratch file is on the same filesystem as the wal, so the timings reflect
+                // the drive the wal is written to.
+                let temp = temp_file_beside(path, CALIBRATION_BLOCKS)?;
+                let pwrite = time_writes(Box::new(MacOsAsyncIO::with_workers(
+                    temp.path(),
+                    options.pwrite_workers,
+                )?))?;
+                let kqueue = time_writes(Box::new(KQueue::new(temp.path())?))?;
+                info!(
+                    "Calibrated {}: pwrite {pwrite:?}, kqueue {kqueue:?}",
+                    path.display()
+                );
+                let device = if kqueue < pwrite {
+                    MacOsDevice::Kqueue
+                } else {
+                    MacOsDevice::Pwrite
+                };
+                return Self::macos_device(path, device, options);
+            }
+        })
+    }
+}
+
+// Create a zeroed temporary file of capacity blocks in the directory of path, so it can be renamed
+// to path once it is complete.
+fn temp_file_beside(path: &Path, capacity: u64) -> std::io::Result<tempfile::NamedTempFile> {
+    let temp = tempfile::Builder::new()
+        .prefix(".wal")
+        .tempfile_in(parent_dir(path))?;
+    temp.as_file().set_len(capacity * BLOCK_SIZE as u64)?;
+    Ok(temp)
+}
+
+// Sync the temporary file and rename it to path, failing if path exists. The directory is synced
+// too so the rename survives a crash.
+fn persist(temp: tempfile::NamedTempFile, path: &Path) -> std::io::Result<()> {
+    temp.as_file().sync_all()?;
+    temp.persist_noclobber(path).map_err(|e| e.error)?;
+    std::fs::File::open(parent_dir(path))?.sync_all()
+}
+
+// The synchronous device for the file at path, with RWF_DSYNC writes if the options ask for them.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn sync_device(path: &Path, options: &WalOptions) -> std::io::Result<SyncDevice> {
+    #[cfg(target_os = "linux")]
+    if options.dsync_writes {
+        return SyncDevice::with_dsync(path);
+    }
+    SyncDevice::new(path)
+}
+
+// The io_uring device for the file at path, with the queue depth, RWF_DSYNC writes and I/O
+// priority the options ask for.
+#[cfg(all(target_os = "linux", not(miri)))]
+fn uring_device(path: &Path, options: &WalOptions) -> std::io::Result<LinuxUring> {
+    let mut uring = LinuxUring::with_queue_depth(path, options.dsync_writes, options.queue_depth)?;
+    uring.set_io_priority(options.io_priority);
+    Ok(uring)
+}
+
+// How long the device takes to write CALIBRATION_BLOCKS blocks one at a time and sync them.
+#[cfg(all(target_os = "macos", not(miri)))]
+fn time_writes(mut dev: Box<dyn PersistentDevice>) -> std::io::Result<Duration> {
+    let start = Instant::now();
+    for offset in 0..CALIBRATION_BLOCKS {
+        let pos = WalPosition {
+            offset,
+            rollover: 0,
+        };
+        dev.write(pos, AlignedSlice::new(BLOCK_SIZE as usize), false)?;
+    }
+    dev.sync()?;
+    Ok(start.elapsed())
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+// Reads each copy of the control block separately since each was written on its own.
+fn read_control_region(dev: &mut Box<dyn PersistentDevice>) -> std::io::Result<Vec<u8>> {
+    let mut region = Vec::with_capacity((CONTROL_BLOCKS * BLOCK_SIZE as u64) as usize);
+    for block in 0..CONTROL_BLOCKS {
+        region.extend(dev.read(block * BLOCK_SIZE as u64, BLOCK_SIZE as usize)?);
+    }
+    Ok(region)
+}
+
+// The capacity recorded in the control block of the wal file at path if it grows as the ring
+// fills. Whether the file is a valid wal at all is left to open.
+fn growable_capacity(path: &Path) -> std::io::Result<Option<u64>> {
+    let mut region = vec![0; (CONTROL_BLOCKS * BLOCK_SIZE as u64) as usize];
+    let file = std::fs::File::open(path)?;
+    if file.read_exact_at(&mut region, 0).is_err() || control::is_blank(&region) {
+        return Ok(None);
+    }
+    Ok(ControlBlock::decode(&region)
+        .ok()
+        .map(|(control, _)| control.capacity)
+        .filter(|capacity| *capacity != 0))
+}
+
+// Stamps a device whose control region was never written with an empty control block. Only a device
+// whose ring starts with a zeroed block is taken to be new. The control block must be durable
+// before any entry is, otherwise a crash could leave entries on a device that looks unformatted.
+fn format_blank(dev: &mut Box<dyn PersistentDevice>, options: &WalOptions) -> std::io::Result<()> {
+    let ring = dev.read(RING_START * BLOCK_SIZE as u64, BLOCK_SIZE as usize)?;
+    if ring.iter().any(|b| *b != 0) {
+        return Err(NotAWal::Unformatted.error());
+    }
+    let pos = WalPosition {
+        offset: 1,
+        rollover: 0,
+    };
+    let control = ControlBlock {
+        id: control::new_id(),
+        atomic_writes: dev.capabilities().atomic_blocks(),
+        block_crcs: options.block_crcs,
+        hash_chain: options.hash_chain,
+        ..Default::default()
+    };
+    dev.write(pos, control.encode(1)?, true)?;
+    wait_for(&mut HashSet::from([pos]), || Ok(dev.process_completions()))
+}
+
+// Calls process_completions until every outstanding position has completed, failing if it does.
+// A write that fails is never reported complete, so an error is returned if nothing completes for
+// COMPLETION_TIMEOUT.
+pub(crate) fn wait_for<F>(
+    outstanding: &mut HashSet<WalPosition>,
+    mut process_completions: F,
+) -> std::io::Result<()>
+where
+    F: FnMut() -> std::io::Result<std::vec::IntoIter<WalPosition>>,
+{
+    let mut deadline = Instant::now() + COMPLETION_TIMEOUT;
+    loop {
+        let before = outstanding.len();
+        for pos in process_completions()? {
+            outstanding.remove(&pos);
+        }
+        if outstanding.is_empty() {
+            return Ok(());
+        }
+        if outstanding.len() < before {
+            deadline = Instant::now() + COMPLETION_TIMEOUT;
+        } else if Instant::now() >= deadline {
+            return Err(Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("{} writes were not reported durable", outstanding.len()),
+            ));
+        }
+        sleep(Duration::from_millis(1));
+    }
+}
+
+// Before the control region existed the ring started at the first block and entries had a 12 byte
+// header of crc, rollover and len. Returns whether the device starts with such an entry.
+fn is_legacy_device(dev: &mut Box<dyn PersistentDevice>, capacity: u64) -> std::io::Result<bool> {
+    const LEGACY_HEADER_SIZE: usize = 12;
+    let header = dev.read(0, LEGACY_HEADER_SIZE)?;
+    let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as u64;
+    if LEGACY_HEADER_SIZE as u64 + len > capacity * BLOCK_SIZE as u64 {
+        return Ok(false);
+    }
+    let buffer = dev.read(0, LEGACY_HEADER_SIZE + len as usize)?;
+    let mut hasher = Hasher::new();
+    hasher.update(&buffer[4..]);
+    Ok(hasher.finalize() == crc)
+}
+
+// Reads the header at offset and returns it if it is the start of a valid entry of the ring.
+fn read_valid_header(wal: &mut Wal, offset: u64) -> Result<Option<EntryHeader>, Error> {
+    let position = WalPosition {
+        offset,
+        rollover: 0,
+    };
+    let buffer = wal.dev.read(position.byte_offset(), HEADER_SIZE)?;
+    let header = match EntryHeader::read_from_bytes(&buffer) {
+        Ok(h) => h,
+        Err(_) => return Ok(None),
+    };
+    if let Err(e) = header.validate(position, false, wal.capacity, wal.layout) {
+        debug!("{e}");
+        return Ok(None);
+    }
+    let blocks = header.num_blocks(wal.layout);
+    // An entry whose last block was never written is caught without reading all of it.
+    if blocks > 1 {
+        let end = blocks as usize * wal.layout.block_data();
+        let trailer = wal.dev.read(
+            wal.layout.device_offset(position, end - TRAILER_SIZE),
+            TRAILER_SIZE,
+        )?;
+        if trailer != header.trailer() {
+            debug!("Trailer mismatch at {offset}, {:?}", header);
+            return Ok(None);
+        }
+    }
+
+    // Back up and read the entire data in one buffer.
+    let (buffer, damaged) = wal.layout.read_raw(
+        &*wal.reader,
+        position,
+        0,
+        HEADER_SIZE + header.len.get() as usize,
+    )?;
+    if let Some(damaged) = damaged {
+        debug!("{damaged} at {offset}, {:?}", header);
+        return Ok(None);
+    }
+    let crc = header.compute_crc(&buffer);
+    if crc != header.crc.get() {
+        debug!("CRC mismatch {crc} at {offset}, {:?}", header);
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+// Whether the block at pos holds the header of a single block entry of the same pass whose CRC
+// doesn't match. Entries are written in order, so in a wal whose single block entries are written
+// atomically this is damage rather than an entry torn by a crash.
+fn is_damaged_block(wal: &mut Wal, pos: WalPosition) -> Result<bool, Error> {
+    let buffer = wal.dev.read(pos.byte_offset(), BLOCK_SIZE as usize)?;
+    let header = match EntryHeader::read_from_bytes(&buffer[..HEADER_SIZE]) {
+        Ok(h) => h,
+        Err(_) => return Ok(false),
+    };
+    if header
+        .validate(pos, true, wal.capacity, wal.layout)
+        .is_err()
+        || header.num_blocks(wal.layout) != 1
+        || header.is_padding(&buffer)
+    {
+        return Ok(false);
+    }
+    Ok(header.compute_crc(&buffer) != header.crc.get())
+}
+
+// Reads from the device to initialize the wal head and tail. The entries of the current pass start
+// at the beginning of the ring, so the head is found by following them until reaching something
+// that isn't a valid entry of the same pass. Everything after the head belongs to the previous pass,
+// and the tail is the first of its entries that is still intact.
+fn recover(wal: &mut Wal) -> Result<(), Error> {
+    let mut first = true;
+    loop {
+        // Read the header including the CRC.
+        if failpoint::check("wal::header_parse").is_err() {
+            break;
+        }
+        let header = match read_valid_header(wal, wal.head.offset)? {
+            Some(h) => h,
+            None => break,
+        };
+
+        debug!("Head {:?}, found {:?}", wal.head, header);
+        if first {
+            wal.head.rollover = header.rollover.get();
+            first = false;
+        } else if header.rollover.get() != wal.head.rollover {
+            // Stop once we find an entry from another pass.
+            debug!("Found older entry");
+            break;
+        }
+
+        wal.head = wal
+            .head
+            .advance(header.num_blocks(wal.layout), wal.capacity);
+        debug!("Moving head to {:?}", wal.head);
+    }
+    if wal.control.atomic_writes && !first && is_damaged_block(wal, wal.head)? {
+        warn!(
+            "Entry at {:?} is damaged, which a crash can't explain since it was written atomically",
+            wal.head
+        );
+        wal.stats.damaged_entries += 1;
+    }
+
+    wal.tail = WalPosition {
+        offset: RING_START,
+        rollover: wal.head.rollover,
+    };
+    if wal.head.rollover > 0 {
+        debug!("Finding tail starting from {:?}", wal.head);
+
+        // Entries of the previous pass can start at any block, scan forward from the head until
+        // we find a valid entry that is one rollover behind us. If there are none, the ring was
+        // fully overwritten and the tail is the start of the current pass.
+        // TODO: Add a security mechanism against someone writing a bad block that looks like a
+        // header and checks out from a CRC perspective.
+        for offset in wal.head.offset..wal.capacity {
+            if let Some(header) = read_valid_header(wal, offset)? {
+                if header.rollover.get() == wal.head.rollover - 1 {
+                    wal.tail = WalPosition {
+                        offset,
+                        rollover: header.rollover.get(),
+                    };
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
 impl Drop for Wal {
     fn drop(&mut self) {
-        // Discard all the data that is completed when the wal is being dropped.
-        for _ in self.dev.process_completions() {}
+        // Give outstanding writes until the drain timeout to complete so watchers learn they are
+        // durable.
+        let deadline = Instant::now() + self.drain_timeout;
+        loop {
+            self.process_completions().for_each(drop);
+            if self.in_flight.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        if !self.in_flight.is_empty() {
+            warn!(
+                "Dropping the wal with {} writes outstanding",
+                self.in_flight.len()
+            );
+        } else if let Err(e) = self.write_checkpoint() {
+            warn!("Failed to write the head checkpoint: {}", e);
+        }
+        self.notifier.close();
+        if let Err(e) = self.flush_index() {
+            warn!("Failed to flush lsn index: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payloads<I: Iterator<Item = std::io::Result<(WalPosition, Vec<u8>)>>>(
+        iter: I,
+    ) -> Vec<Vec<u8>> {
+        iter.map(|e| e.unwrap().1).collect()
+    }
+
+    #[test]
+    fn test_streams() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let metrics = wal.stream("metrics")?;
+        let audit = wal.stream("audit")?;
+        assert_ne!(metrics, audit);
+        assert_eq!(wal.stream("metrics")?, metrics);
+        assert_eq!(wal.stream_name(audit), Some("audit"));
+
+        let m1 = wal.append_to(metrics, b"m1")?;
+        wal.append_to(audit, b"a1")?;
+        let m2 = wal.append_to(metrics, b"m2")?;
+        wal.append_to(audit, b"a2")?;
+
+        assert_eq!(payloads(wal.iterate_stream(metrics)), vec![b"m1", b"m2"]);
+        assert_eq!(payloads(wal.iterate_stream(audit)), vec![b"a1", b"a2"]);
+
+        // Truncating one stream hides its entries but the audit stream holds back the tail.
+        wal.truncate_stream(metrics, m2)?;
+        assert_eq!(payloads(wal.iterate_stream(metrics)), vec![b"m2"]);
+        assert_eq!(wal.iterate().count(), 4);
+
+        // Once audit is fully truncated the tail moves to the oldest live metrics entry.
+        let head = wal.head;
+        wal.truncate_stream(audit, head)?;
+        assert!(payloads(wal.iterate_stream(audit)).is_empty());
+        assert_eq!(wal.tail, m2);
+        assert!(wal.tail > m1);
+
+        let unknown = StreamId(100);
+        assert!(wal.append_to(unknown, b"x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_quotas() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let noisy = wal.stream("noisy")?;
+        let quiet = wal.stream("quiet")?;
+        let block = BLOCK_SIZE as u64;
+        wal.set_stream_quota(noisy, Some(3 * block))?;
+        assert!(wal.set_stream_quota(StreamId(100), Some(block)).is_err());
+
+        let first = wal.append_to(noisy, b"n1")?;
+        wal.append_to(noisy, &[1; BLOCK_SIZE as usize])?;
+        assert_eq!(wal.stream_usage(noisy), 3 * block);
+        let ring = (wal.capacity - RING_START) as f64;
+        assert_eq!(wal.stream_utilization(noisy), 3.0 / ring);
+
+        let err = wal.append_to(noisy, b"n3").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        let exceeded = wal_error::<QuotaExceeded>(&err);
+        assert_eq!(
+            exceeded,
+            Some(&QuotaExceeded {
+                stream: noisy,
+                quota: 3 * block,
+                usage: 3 * block,
+            })
+        );
+
+        // The other streams aren't held back.
+        wal.append_to(quiet, b"q1")?;
+        wal.append(b"d1")?;
+        assert_eq!(wal.stream_usage(quiet), block);
+        assert_eq!(wal.stream_usage(StreamId::DEFAULT), block);
+
+        // Truncating the stream frees up its quota even though the tail can't move yet.
+        let second = first.advance(1, wal.capacity);
+        wal.truncate_stream(noisy, second)?;
+        assert_eq!(wal.stream_usage(noisy), 2 * block);
+        assert_eq!(wal.tail, first);
+        wal.append_to(noisy, b"n3")?;
+
+        wal.set_stream_quota(noisy, None)?;
+        wal.append_to(noisy, b"n4")?;
+        assert_eq!(wal.stream_usage(noisy), 4 * block);
+
+        // Usage is counted again from the entries when the wal is reopened.
+        drop(wal);
+        let wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        assert_eq!(wal.stream_usage(noisy), 4 * block);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_prefetch() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let mut wal = open_file(file.path())?;
+        // Wrap the ring, so the live entries are split between its end and its start.
+        let mut positions = Vec::new();
+        while wal.head().rollover == 0 || wal.head().offset < RING_START + 8 {
+            let pos = wal.append(&[7; 3 * BLOCK_SIZE as usize])?;
+            wal.process_completions().for_each(drop);
+            positions.push(pos);
+            wal.truncate(positions[positions.len().saturating_sub(4)])?;
+        }
+        let start = wal.tail();
+        assert_eq!(start.rollover, 0);
+        wal.prefetch(start..wal.head())?;
+        assert_eq!(wal.read_range(start, wal.head())?.count(), 4);
+        wal.prefetch(wal.head()..wal.head())?;
+
+        let err = wal.prefetch(positions[0]..wal.head()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        let ahead = wal.head().advance(1, wal.capacity);
+        let err = wal.prefetch(start..ahead).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // A position past the end of the ring on the pass of the tail is still between the tail
+        // and the head.
+        let outside = WalPosition {
+            offset: wal.capacity + 1,
+            rollover: start.rollover,
+        };
+        let err = wal.prefetch(outside..wal.head()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_read_many() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let mut wal = open_file(file.path())?;
+        let truncated = wal.append(b"truncated")?;
+        let mut positions = Vec::new();
+        for i in 0..20usize {
+            // Every third entry spans more than one block, so it takes a second read.
+            let len = if i % 3 == 0 {
+                BLOCK_SIZE as usize + i
+            } else {
+                i
+            };
+            positions.push(wal.append(&vec![i as u8; len])?);
+        }
+        wal.truncate(positions[0])?;
+
+        let mut results = HashMap::new();
+        wal.read_many(&positions, |pos, res| {
+            assert!(results.insert(pos, res.unwrap()).is_none());
+        });
+        assert_eq!(results.len(), positions.len());
+        for pos in &positions {
+            assert_eq!(results[pos], wal.read_at(*pos)?);
+        }
+
+        // Positions that aren't live entries fail like they do with read_at.
+        let inside = positions[0].advance(1, wal.capacity);
+        let mut errors = Vec::new();
+        wal.reader()
+            .read_many(&[truncated, inside, positions[1]], |pos, res| {
+                errors.push((pos, res.map_err(|e| e.kind())));
+            });
+        errors.sort_by_key(|(pos, _)| *pos);
+        assert_eq!(
+            errors,
+            vec![
+                (truncated, Err(std::io::ErrorKind::NotFound)),
+                (inside, Err(std::io::ErrorKind::InvalidData)),
+                (positions[1], Ok(vec![1])),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_empty_entry() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let empty = wal.append(b"")?;
+        let next = wal.append(b"next")?;
+        assert_eq!(wal.read_at(empty)?, b"");
+        assert_eq!(wal.read_lsn(0)?, Some(Vec::new()));
+        assert_eq!(wal.read_at(next)?, b"next");
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordered_completions() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::new(7, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        // An entry appended before ordering is turned on is reported as soon as it completes.
+        let unordered = wal.append(b"before")?;
+        wal.set_ordered_completions(true);
+
+        let mut appended = Vec::new();
+        let mut completed = Vec::new();
+        for i in 0..20u8 {
+            appended.push(wal.append(&[i])?);
+            completed.extend(wal.process_completions());
+        }
+        sim.set_complete_probability(1.0);
+        completed.extend(wal.process_completions());
+
+        completed.retain(|pos| *pos != unordered);
+        assert_eq!(completed, appended);
+        Ok(())
+    }
+
+    #[test]
+    fn test_durable_head() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::new(17, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        assert_eq!(wal.durable_head(), wal.head());
+        sim.set_complete_probability(0.3);
+
+        let mut outstanding = BTreeSet::new();
+        for i in 0..30u8 {
+            outstanding.insert(wal.append(&[i])?);
+            for pos in wal.process_completions() {
+                outstanding.remove(&pos);
+            }
+            // Completions arrive out of order, and only the oldest outstanding entry counts.
+            let expected = outstanding.first().copied().unwrap_or(wal.head());
+            assert_eq!(wal.durable_head(), expected);
+            assert_eq!(wal.stats().durable_head, expected);
+        }
+        sim.set_complete_probability(1.0);
+        wal.process_completions().for_each(drop);
+        assert_eq!(wal.durable_head(), wal.head());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_watermark() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::new(13, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let mut watch = wal.watch();
+        assert_eq!(wal.process_watermark(), None);
+
+        let mut appended = Vec::new();
+        let mut count = 0;
+        for i in 0..20u8 {
+            appended.push(wal.append(&[i])?);
+            if let Some(watermark) = wal.process_watermark() {
+                assert!(appended.contains(&watermark.position));
+                count += watermark.count;
+            }
+        }
+        sim.set_complete_probability(1.0);
+        let last = wal.process_watermark().unwrap();
+        assert_eq!(last.position, *appended.last().unwrap());
+        assert_eq!(count + last.count, appended.len());
+        assert!(!wal.has_outstanding());
+        assert_eq!(watch.wait_timeout(Duration::ZERO), Some(last.position));
+
+        // With ordering, the watermark only moves past entries once the ones before them are in.
+        wal.set_ordered_completions(true);
+        sim.set_complete_probability(0.3);
+        let mut frontier = None;
+        for i in 0..20u8 {
+            let pos = wal.append(&[i])?;
+            if let Some(watermark) = wal.process_watermark() {
+                assert!(!wal.in_flight.any_before(watermark.position));
+                frontier = Some(watermark.position);
+            }
+            assert!(frontier <= Some(pos));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_immediate_sync() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::new(3, 16);
+        let recording = crate::recording::RecordingDevice::new(sim.clone());
+        let trace = recording.trace();
+        let mut wal = Wal::open_device(Box::new(recording), 16)?;
+        wal.set_drain_timeout(Duration::ZERO);
+        sim.set_complete_probability(0.0);
+
+        wal.append(b"one")?;
+        assert!(!trace.events().contains(&crate::recording::Event::Sync));
+        let two = wal.append_with(b"two", SyncPolicy::Immediate)?;
+        assert_eq!(trace.events().last(), Some(&crate::recording::Event::Sync));
+        wal.append(b"three")?;
+
+        // Nothing completes on its own, but both entries before the sync survive a crash.
+        let completed: Vec<WalPosition> = wal.process_completions().collect();
+        assert_eq!(completed.len(), 2);
+        assert!(completed.contains(&two));
+        drop(wal);
+        let wal = Wal::open_device(Box::new(sim.crash()), 16)?;
+        let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(data[..2], [b"one".to_vec(), b"two".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fence() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::new(11, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        sim.set_complete_probability(0.3);
+
+        let mut completed = Vec::new();
+        let mut batches = Vec::new();
+        for batch in 0..4u8 {
+            let mut appended = Vec::new();
+            for i in 0..5u8 {
+                appended.push(wal.append(&[batch, i])?);
+                completed.extend(wal.process_completions());
+            }
+            wal.fence();
+            batches.push(appended);
+        }
+        sim.set_complete_probability(1.0);
+        completed.extend(wal.process_completions());
+
+        // Within a batch completions come in any order, but never before an earlier batch's.
+        assert_eq!(completed.len(), 20);
+        for (i, batch) in batches.iter().enumerate() {
+            let reported = &completed[i * 5..(i + 1) * 5];
+            assert!(batch.iter().all(|pos| reported.contains(pos)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_append_only() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(5, 16);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 16)?;
+        wal.append(b"one")?;
+        wal.append(b"two")?;
+        let head = wal.head();
+        drop(wal);
+
+        // The clean close left a checkpoint, so nothing is scanned.
+        let mut wal = Wal::open_append_only_device(Box::new(sim.clone()), 16)?;
+        assert_eq!(wal.head(), head);
+        assert_eq!(wal.next_lsn(), 2);
+        assert!(wal.is_empty());
+        wal.append(b"three")?;
+        wal.set_drain_timeout(Duration::ZERO);
+        sim.set_complete_probability(0.0);
+        drop(wal);
+
+        // Without a clean close the checkpoint is gone and the ring is recovered instead.
+        let wal = Wal::open_append_only_device(Box::new(sim.crash()), 16)?;
+        let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+        assert_eq!(data[..2], [b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(wal.len(), data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_keyed() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let first = wal.append_keyed(7, b"one")?;
+        wal.append(b"two")?;
+        assert_eq!(wal.append_keyed(7, b"one")?, first);
+        assert_eq!(wal.len(), 2);
+        let err = wal.append_keyed(0, b"three").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        drop(wal);
+
+        // The key is found again in the ring after a reopen.
+        let mut wal = Wal::open_device(Box::new(sim), 64)?;
+        assert_eq!(wal.append_keyed(7, b"one")?, first);
+        let keys: Vec<Option<u64>> = wal.iterate_with_meta().map(|e| e.unwrap().key).collect();
+        assert_eq!(keys, vec![Some(7), None]);
+
+        // Only the most recent keys are remembered.
+        for key in 1..=KEY_WINDOW as u64 {
+            wal.keys.insert(key + 100, first);
+        }
+        assert_ne!(wal.append_keyed(7, b"one")?, first);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:32".parse().unwrap())?;
+        let metrics = wal.stream("metrics")?;
+        for i in 0..10u8 {
+            wal.append(&[i])?;
+        }
+        let old = wal.append_to(metrics, b"old")?;
+        wal.append_to(metrics, b"new")?;
+        wal.truncate_stream(metrics, old.advance(1, 32))?;
+
+        // Keep the even entries. The truncated metrics entry is dropped without being asked about.
+        let mut asked = 0;
+        let dropped = wal.compact(|entry| {
+            asked += 1;
+            entry.stream == metrics || entry.payload[0] % 2 == 0
+        })?;
+        assert_eq!(dropped, 6);
+        assert_eq!(asked, 11);
+        assert_eq!(
+            payloads(wal.iterate()),
+            vec![vec![0], vec![2], vec![4], vec![6], vec![8], b"new".to_vec()]
+        );
+        assert_eq!(payloads(wal.iterate_stream(metrics)), vec![b"new".to_vec()]);
+
+        // The kept entries don't fit in what is left of the ring.
+        for i in 0..20u8 {
+            wal.append(&[i])?;
+        }
+        let err = wal.compact(|entry| entry.payload[0] != 0).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(wal.len(), 26);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_bad_url() {
+        let err = Wal::open("mem:lots".parse().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_compact_crash() -> std::io::Result<()> {
+        for seed in 0..20 {
+            let sim = crate::sim::SimDevice::eager(seed, 32);
+            let mut wal = Wal::open_device(Box::new(sim.clone()), 32)?;
+            wal.append(b"drop")?;
+            wal.append(b"keep")?;
+            wal.compact(|entry| entry.payload == b"keep")?;
+
+            // The truncation can be lost, but never the copy it relies on.
+            let wal = Wal::open_device(Box::new(sim.crash()), 32)?;
+            let recovered = payloads(wal.iterate());
+            assert!(
+                recovered == vec![b"keep".to_vec()]
+                    || recovered == vec![b"drop".to_vec(), b"keep".to_vec(), b"keep".to_vec()],
+                "{recovered:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_count() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 8);
+        sim.set_reorder(false);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 8)?;
+        assert!(wal.is_empty());
+        assert_eq!(wal.tail(), wal.head());
+
+        let first = wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        wal.append(b"three")?;
+        assert_eq!(wal.len(), 3);
+        assert_eq!(wal.tail(), first);
+        wal.truncate(second)?;
+        assert_eq!(wal.len(), 2);
+        assert_eq!(wal.tail(), second);
+
+        // Wrapping around overwrites the truncated entry and the ones after it.
+        wal.append(&[0; 3 * BLOCK_SIZE as usize])?;
+        assert_eq!(wal.len(), 1);
+        wal.append(b"four")?;
+        assert_eq!(wal.len(), 2);
+
+        // Recovery counts the entries it finds.
+        wal.process_completions().for_each(drop);
+        let head = wal.head();
+        drop(wal);
+        let wal = Wal::open_device(Box::new(sim.crash()), 8)?;
+        assert_eq!(wal.len(), 2);
+        assert_eq!(wal.head(), head);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:8".parse().unwrap())?;
+        assert_eq!(wal.stats().write_amplification(), 0.0);
+        wal.append(&[1; 100])?;
+        let stats = wal.stats();
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.payload_bytes, 100);
+        assert_eq!(stats.header_bytes, HEADER_SIZE as u64);
+        assert_eq!(
+            stats.block_padding_bytes,
+            BLOCK_SIZE as u64 - 100 - HEADER_SIZE as u64
+        );
+        assert_eq!(stats.device_bytes(), BLOCK_SIZE as u64);
+
+        // Five blocks are used of the six in the ring, so the next two block entry pads the end.
+        wal.append(&[2; 4 * BLOCK_SIZE as usize - HEADER_SIZE - TRAILER_SIZE])?;
+        wal.append(&[3; BLOCK_SIZE as usize])?;
+        let stats = wal.stats();
+        assert_eq!(stats.entries, 3);
+        // Both entries longer than a block have a trailer.
+        assert_eq!(
+            stats.header_bytes,
+            (3 * HEADER_SIZE + 2 * TRAILER_SIZE) as u64
+        );
+        assert_eq!(stats.ring_padding_bytes, BLOCK_SIZE as u64);
+        assert_eq!(stats.device_bytes(), 8 * BLOCK_SIZE as u64);
+
+        // Control blocks count too.
+        wal.ack("reader", wal.head())?;
+        assert_eq!(wal.stats().control_bytes, BLOCK_SIZE as u64);
+        let amplification = wal.stats().write_amplification();
+        assert_eq!(
+            amplification,
+            9.0 * BLOCK_SIZE as f64 / stats.payload_bytes as f64
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_persists() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 16);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 16)?;
+        wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        wal.append(b"three")?;
+        wal.truncate(second)?;
+        wal.process_completions().for_each(drop);
+        drop(wal);
+
+        // The truncated entry stays gone after a crash, and appends continue its lsns.
+        let mut wal = Wal::open_device(Box::new(sim.crash()), 16)?;
+        assert_eq!(wal.tail(), second);
+        assert_eq!(wal.low_water(StreamId::DEFAULT), Some(second));
+        assert_eq!(
+            payloads(wal.iterate()),
+            vec![b"two".to_vec(), b"three".to_vec()]
+        );
+        let next = wal.append(b"four")?;
+        assert_eq!(wal.iterate_from(next).with_meta().next().unwrap()?.lsn, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain_on_drop() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::new(0, 16);
+        sim.set_complete_probability(0.2);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 16)?;
+        wal.append(b"one")?;
+        let last = wal.append(b"two")?;
+        let watch = wal.watch();
+        drop(wal);
+        assert_eq!(sim.pending(), 0);
+        assert_eq!(watch.latest(), Some(last));
+
+        // Without a timeout writes that haven't completed are left behind.
+        sim.set_complete_probability(0.0);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 16)?;
+        wal.set_drain_timeout(Duration::ZERO);
+        wal.append(b"three")?;
+        drop(wal);
+        assert_eq!(sim.pending(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_iterate_with_meta() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let metrics = wal.stream("metrics")?;
+        let before = now_micros();
+        let first = wal.append(b"one")?;
+        let after = now_micros();
+        let second = wal.append_to(metrics, b"two")?;
+
+        // Damage the payload of the second entry.
+        let mut block = AlignedSlice::new(BLOCK_SIZE as usize);
+        let data = wal.dev.read(second.byte_offset(), BLOCK_SIZE as usize)?;
+        block.as_slice().copy_from_slice(&data);
+        block.as_slice()[HEADER_SIZE] ^= 0xff;
+        wal.dev.write(second, block, false)?;
+
+        let entries: Vec<Entry> = wal.iterate_with_meta().collect::<std::io::Result<_>>()?;
+        let timestamp = entries[0].timestamp;
+        assert!(before <= timestamp && timestamp <= after);
+        assert_eq!(
+            entries[0],
+            Entry {
+                position: first,
+                len: 3,
+                crc_valid: true,
+                stream: StreamId::DEFAULT,
+                lsn: 0,
+                timestamp,
+                key: None,
+                payload: b"one".to_vec(),
+            }
+        );
+        assert_eq!(entries[1].position, second);
+        assert_eq!(entries[1].stream, metrics);
+        assert!(!entries[1].crc_valid);
+        assert!(wal.iterate().nth(1).unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_at() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 128);
+        let mut wal = Wal::open_device(Box::new(sim), 128)?;
+        let first = wal.append(b"one")?;
+        // Large enough to be read in more than one chunk.
+        let large = vec![7; (VERIFY_CHUNK_BLOCKS * BLOCK_SIZE as u64) as usize + 100];
+        let second = wal.append_keyed(9, &large)?;
+        wal.append(b"three")?;
+
+        let meta = wal.verify_at(second)?;
+        assert_eq!(meta.position, second);
+        assert_eq!(meta.len, large.len() as u64);
+        assert_eq!(meta.lsn, 1);
+        assert_eq!(meta.key, Some(9));
+        assert_eq!(wal.reader().verify_at(second)?, meta);
+
+        // A position inside an entry doesn't hold one.
+        let inside = second.advance(1, wal.capacity);
+        let err = wal.verify_at(inside).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Damage the last chunk of the payload.
+        let last = second.advance(VERIFY_CHUNK_BLOCKS, wal.capacity);
+        let mut block = AlignedSlice::new(BLOCK_SIZE as usize);
+        block
+            .as_slice()
+            .copy_from_slice(&wal.dev.read(last.byte_offset(), BLOCK_SIZE as usize)?);
+        block.as_slice()[10] ^= 0xff;
+        wal.dev.write(last, block, false)?;
+        let err = wal.verify_at(second).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        wal.truncate(second)?;
+        let err = wal.verify_at(first).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_reader() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 16);
+        let mut wal = Wal::open_device(Box::new(sim), 16)?;
+        let large: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+        let first = wal.append(&large)?;
+        let second = wal.append(b"two")?;
+
+        let mut reader = wal.entry_reader(first)?;
+        assert_eq!(reader.meta().len, large.len() as u64);
+        let mut chunk = [0; 1000];
+        let mut payload = Vec::new();
+        loop {
+            let n = reader.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            payload.extend_from_slice(&chunk[..n]);
+        }
+        assert_eq!(payload, large);
+        assert_eq!(reader.remaining(), 0);
+
+        let mut iter = wal.iterate();
+        let mut payloads = Vec::new();
+        while let Some(reader) = iter.next_reader() {
+            let mut payload = Vec::new();
+            reader?.read_to_end(&mut payload)?;
+            payloads.push(payload);
+        }
+        assert_eq!(payloads, vec![large.clone(), b"two".to_vec()]);
+
+        // A damaged entry fails on the read that reaches its end.
+        let mut block = AlignedSlice::new(BLOCK_SIZE as usize);
+        block
+            .as_slice()
+            .copy_from_slice(&wal.dev.read(first.byte_offset(), BLOCK_SIZE as usize)?);
+        block.as_slice()[HEADER_SIZE] ^= 0xff;
+        wal.dev.write(first, block, false)?;
+        let shared = wal.reader();
+        let mut reader = shared.entry_reader(first)?;
+        let mut start = vec![0; BLOCK_SIZE as usize];
+        reader.read_exact(&mut start)?;
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(wal.read_at(second)?, b"two");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_filter_meta() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let mut wal = open_file(file.path())?;
+        let audit = wal.stream("audit")?;
+        let mut positions = Vec::new();
+        for i in 0..6u8 {
+            let stream = if i % 2 == 0 { audit } else { StreamId::DEFAULT };
+            positions.push(wal.append_to(stream, &[i; 10])?);
+        }
+        wal.process_completions().for_each(drop);
+        let lsns = |iter: &mut dyn Iterator<Item = std::io::Result<Entry>>| {
+            iter.map(|e| e.unwrap().lsn).collect::<Vec<_>>()
+        };
+
+        assert_eq!(lsns(&mut wal.iterate().in_stream(audit)), vec![0, 2, 4]);
+        assert_eq!(
+            lsns(&mut wal.iterate().in_range(positions[1]..positions[4])),
+            vec![1, 2, 3]
+        );
+        let times: Vec<u64> = wal
+            .iterate_with_meta()
+            .map(|e| e.unwrap().timestamp)
+            .collect();
+        let appended = lsns(&mut wal.iterate().appended_between(times[2]..times[3] + 1));
+        assert!(appended.contains(&2) && appended.contains(&3));
+        let mut odd = wal.iterate().filter_meta(|meta| meta.lsn % 2 == 1);
+        assert_eq!(odd.next().unwrap()?.payload, vec![1; 10]);
+        assert_eq!(odd.position(), positions[2]);
+
+        // Damaged entries are only noticed if they pass the filter, the payload of the others
+        // isn't read.
+        overwrite(
+            file.path(),
+            positions[1].byte_offset() + HEADER_SIZE as u64,
+            &[0xff],
+        )?;
+        assert_eq!(lsns(&mut wal.iterate().in_stream(audit)), vec![0, 2, 4]);
+        assert_eq!(wal.iterate_stream(audit).count(), 3);
+        let err = wal.iterate().in_stream(StreamId::DEFAULT).next().unwrap();
+        assert_eq!(err.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let commits = wal.subscribe();
+        let replication = wal.subscribe();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        wal.on_complete(move |pos| recorded.lock().unwrap().push(pos));
+
+        let one = wal.append(b"one")?;
+        let two = wal.append(b"two")?;
+        assert_eq!(
+            wal.process_completions().collect::<Vec<_>>(),
+            vec![one, two]
+        );
+        assert_eq!(commits.try_iter().collect::<Vec<_>>(), vec![one, two]);
+        assert_eq!(replication.try_iter().collect::<Vec<_>>(), vec![one, two]);
+        assert_eq!(*seen.lock().unwrap(), vec![one, two]);
+
+        // The watermark still delivers each completion to subscribers.
+        drop(commits);
+        let three = wal.append(b"three")?;
+        assert_eq!(wal.process_watermark().unwrap().position, three);
+        assert_eq!(replication.try_iter().collect::<Vec<_>>(), vec![three]);
+        assert_eq!(wal.listeners.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_device() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let one = wal.append(b"one")?;
+        assert_eq!(wal.process_completions().collect::<Vec<_>>(), vec![one]);
+        wal.append(b"two")?;
+        sim.fail_pending();
+        assert_eq!(wal.process_completions().count(), 0);
+
+        // Appends fail fast with the device error rather than waiting for completions that
+        // will never arrive.
+        let failed = wal.failure().cloned().unwrap();
+        assert!(failed.message.contains("writes failed"), "{failed}");
+        let err = wal.append(b"three").unwrap_err();
+        assert_eq!(wal_error::<WalFailed>(&err), Some(&failed));
+        assert_eq!(sim.pending(), 0);
+        assert!(wal.truncate(wal.head()).is_err());
+        assert!(wal.import(vec![Ok(b"four".to_vec())]).is_err());
+        assert_eq!(wal.read_at(one)?, b"one");
+
+        // Reopening recovers what reached the disk and leaves the failure behind.
+        drop(wal);
+        let mut wal = Wal::open_device(Box::new(sim.crash()), 64)?;
+        assert!(wal.failure().is_none());
+        assert_eq!(payloads(wal.iterate()), vec![b"one".to_vec()]);
+        wal.append(b"five")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_batch() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        assert!(wal.append_batch(&[])?.is_empty());
+        let large = vec![7u8; BLOCK_SIZE as usize];
+        let positions = wal.append_batch(&[b"one", &large, b"three"])?;
+        assert_eq!(sim.pending(), 1);
+        assert_eq!(positions[1], positions[0].advance(1, 64));
+        assert_eq!(positions[2], positions[1].advance(2, 64));
+        assert_eq!(wal.head(), positions[2].advance(1, 64));
+        assert_eq!(wal.len(), 3);
+
+        // The batch completes once, at its first entry.
+        assert_eq!(wal.durable_head(), positions[0]);
+        assert_eq!(
+            wal.process_completions().collect::<Vec<_>>(),
+            vec![positions[0]]
+        );
+        assert_eq!(wal.durable_head(), wal.head());
+        assert_eq!(wal.read_at(positions[1])?, large);
+
+        // An entry that can't be appended keeps the rest of the batch out too.
+        let head = wal.head();
+        let huge = vec![0u8; 64 * BLOCK_SIZE as usize];
+        assert!(wal.append_batch(&[b"four", &huge]).is_err());
+        assert_eq!(wal.head(), head);
+
+        drop(wal);
+        let mut wal = Wal::open_device(Box::new(sim.crash()), 64)?;
+        let lsns: Vec<u64> = wal
+            .iterate()
+            .with_meta()
+            .map(|entry| entry.unwrap().lsn)
+            .collect();
+        assert_eq!(lsns, vec![0, 1, 2]);
+        wal.append(b"four")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_full() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        sim.set_space_limit(Some(RING_START + 4));
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        for i in 0..4u8 {
+            wal.append(&[i])?;
+        }
+        let head = wal.head();
+        let err = wal.append(b"full").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        let full = wal_error::<DiskFull>(&err).unwrap();
+        assert_eq!((full.position, full.blocks), (head, 1));
+        assert!(wal.failure().is_none());
+        assert_eq!(wal.head(), head);
+
+        // Expiring entries frees the start of the ring, where the next pass starts early, but
+        // live entries aren't overwritten for it.
+        wal.set_disk_full_policy(DiskFullPolicy::Expire);
+        let err = wal.append(b"full").unwrap_err();
+        assert!(err.get_ref().unwrap().is::<DiskFull>());
+        wal.set_retention(RetentionPolicy {
+            max_entries: Some(2),
+            ..Default::default()
+        });
+        let pos = wal.append(b"wrapped")?;
+        assert_eq!(pos, head.next_pass());
+        let expected = vec![vec![3], b"wrapped".to_vec()];
+        assert_eq!(payloads(wal.iterate()), expected);
+
+        wal.process_completions().for_each(drop);
+        drop(wal);
+        let wal = Wal::open_device(Box::new(sim.crash()), 64)?;
+        assert_eq!(wal.head(), pos.advance(1, 64));
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_flight_limit() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let block = BLOCK_SIZE as u64;
+        wal.set_in_flight_limit(Some((2 * block, Backpressure::Fail)));
+        wal.append(b"one")?;
+        wal.append(b"two")?;
+        assert_eq!(wal.in_flight_bytes(), 2 * block);
+        let err = wal.append(b"three").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(
+            wal_error::<InFlightLimit>(&err),
+            Some(&InFlightLimit {
+                in_flight: 2 * block,
+                max_bytes: 2 * block
+            })
+        );
+        assert_eq!(wal.process_completions().count(), 2);
+        assert_eq!(wal.in_flight_bytes(), 0);
+
+        // An entry larger than the limit still goes through once nothing else is in flight.
+        wal.append(&[0; 3 * BLOCK_SIZE as usize])?;
+        assert!(wal.append(b"small").is_err());
+        wal.process_completions().for_each(drop);
+
+        // Blocking appends process completions until there is room, and the completions are
+        // still returned afterwards.
+        wal.set_in_flight_limit(Some((2 * block, Backpressure::Block)));
+        let three = wal.append(b"three")?;
+        let four = wal.append(b"four")?;
+        let five = wal.append(b"five")?;
+        assert_eq!(wal.in_flight_bytes(), block);
+        let mut completions: Vec<_> = wal.process_completions().collect();
+        completions.sort();
+        assert_eq!(completions, vec![three, four, five]);
+
+        // A failed device ends the wait rather than blocking for good.
+        wal.append(b"six")?;
+        wal.append(b"seven")?;
+        sim.fail_pending();
+        let err = wal.append(b"eight").unwrap_err();
+        assert!(err.get_ref().unwrap().is::<WalFailed>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_range() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let first = wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        let third = wal.append(b"three")?;
+        wal.append(b"four")?;
+
+        let range = wal.read_range(second, wal.head())?;
+        assert_eq!(payloads(range).len(), 3);
+        let range = wal.read_range(second, third)?;
+        assert_eq!(payloads(range), vec![b"two".to_vec()]);
+        assert_eq!(wal.read_range(third, third)?.count(), 0);
+        assert_eq!(payloads(wal.reader().read_range(first, second)?).len(), 1);
+
+        let ahead = wal.head().advance(1, wal.capacity);
+        let err = wal.read_range(second, ahead).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = wal.read_range(third, second).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        wal.truncate(second)?;
+        let err = wal.read_range(first, third).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 16);
+
+        // Data left behind by something else is rejected.
+        let mut stale = AlignedSlice::new(BLOCK_SIZE as usize);
+        stale.as_slice()[..5].copy_from_slice(b"stale");
+        let pos = WalPosition {
+            offset: RING_START,
+            rollover: 0,
+        };
+        sim.clone().write(pos, stale, true)?;
+        let err = Wal::open_device(Box::new(sim.clone()), 16).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut wal = Wal::format_device(Box::new(sim.clone()), 16)?;
+        assert!(wal.is_empty());
+        wal.append(b"one")?;
+        drop(wal);
+        let wal = Wal::open_device(Box::new(sim.crash()), 16)?;
+        assert_eq!(wal.len(), 1);
+        Ok(())
+    }
+
+    // A device that only takes two writes at a time.
+    struct ShallowDevice {
+        inner: crate::sim::SimDevice,
+        outstanding: usize,
+    }
+
+    impl PersistentDevice for ShallowDevice {
+        fn write(
+            &mut self,
+            pos: WalPosition,
+            data: AlignedSlice,
+            notify: bool,
+        ) -> std::io::Result<()> {
+            if self.outstanding == 2 {
+                return Err(Error::new(std::io::ErrorKind::WouldBlock, "queue full"));
+            }
+            self.outstanding += 1;
+            self.inner.write(pos, data, notify)
+        }
+
+        fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+            self.outstanding = 0;
+            self.inner.process_completions()
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.inner.sync()
+        }
+
+        fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+            self.inner.read(byte_offset, len)
+        }
+
+        fn reader(&self) -> std::io::Result<std::sync::Arc<dyn DeviceReader>> {
+            self.inner.reader()
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                max_queue_depth: Some(2),
+                ..Capabilities::default()
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_within_queue_depth() -> std::io::Result<()> {
+        let capacity = 5 * FORMAT_CHUNK_BLOCKS;
+        let sim = crate::sim::SimDevice::eager(0, capacity);
+        let dev = ShallowDevice {
+            inner: sim,
+            outstanding: 0,
+        };
+        let wal = Wal::format_device(Box::new(dev), capacity)?;
+        assert_eq!(wal.capabilities().max_queue_depth, Some(2));
+        assert!(wal.is_empty());
+        Ok(())
+    }
+
+    // A device that writes a block atomically.
+    struct AtomicDevice(crate::sim::SimDevice);
+
+    impl PersistentDevice for AtomicDevice {
+        fn write(
+            &mut self,
+            pos: WalPosition,
+            data: AlignedSlice,
+            notify: bool,
+        ) -> std::io::Result<()> {
+            self.0.write(pos, data, notify)
+        }
+
+        fn process_completions(&mut self) -> std::vec::IntoIter<WalPosition> {
+            self.0.process_completions()
+        }
+
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.0.sync()
+        }
+
+        fn read(&mut self, byte_offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+            self.0.read(byte_offset, len)
+        }
+
+        fn reader(&self) -> std::io::Result<std::sync::Arc<dyn DeviceReader>> {
+            self.0.reader()
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                atomic_write_unit: Some(BLOCK_SIZE),
+                ..Capabilities::default()
+            }
+        }
+    }
+
+    #[test]
+    fn test_trailer() -> std::io::Result<()> {
+        // An entry that only fits in two blocks without the trailer takes three with it.
+        assert_eq!(blocks_for(BLOCK_SIZE as usize - HEADER_SIZE), 1);
+        assert_eq!(blocks_for(2 * BLOCK_SIZE as usize - HEADER_SIZE), 3);
+
+        let mut sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        wal.append(b"one")?;
+        let large = wal.append(&[2; 3 * BLOCK_SIZE as usize])?;
+        drop(wal);
+        let last = large.advance(3, 64);
+        let block = sim.read(last.byte_offset(), BLOCK_SIZE as usize)?;
+        assert_eq!(
+            block[BLOCK_SIZE as usize - TRAILER_SIZE..][..4],
+            TRAILER_MAGIC
+        );
+
+        // Recovery stops at an entry whose last block was never written.
+        sim.write(last, AlignedSlice::new(BLOCK_SIZE as usize), false)?;
+        sim.sync()?;
+        let wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        assert_eq!(wal.len(), 1);
+        assert_eq!(wal.head(), large);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounded_allocation() -> std::io::Result<()> {
+        let options = WalOptions {
+            bounded: Some(BoundedAllocation {
+                buffers: 2,
+                max_entry_len: 100,
+            }),
+            ..Default::default()
+        };
+        let mut wal = Wal::open_with_options("mem:16".parse().unwrap(), &options)?;
+        // Buffers go back to the pool once the device is done with them, so appends keep fitting
+        // as the ring wraps.
+        for i in 0..40u8 {
+            wal.append(&[i; 100])?;
+            wal.process_completions().for_each(drop);
+        }
+        assert_eq!(wal.pool.as_ref().unwrap().available(), 2);
+
+        let err = wal.append(&[1; BLOCK_SIZE as usize]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+        let limit = wal_error::<AllocationLimit>(&err);
+        assert_eq!(
+            limit,
+            Some(&AllocationLimit::TooLarge { blocks: 2, max: 1 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_little_endian() {
+        let header = EntryHeader {
+            crc: U32::new(0x0403_0201),
+            rollover: U32::new(5),
+            len: U64::new(0x0c0b_0a09_0807_0605),
+            stream: U32::new(1),
+            lsn: U64::new(2),
+            timestamp: U64::new(3),
+            key: U64::new(4),
+            chain: U64::new(0),
+        };
+        let bytes = header.as_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(bytes[..4], [1, 2, 3, 4]);
+        assert_eq!(bytes[4..8], [5, 0, 0, 0]);
+        assert_eq!(bytes[8..16], [5, 6, 7, 8, 9, 10, 11, 12]);
+        let decoded = EntryHeader::read_from_bytes(bytes).unwrap();
+        assert_eq!(decoded.len.get(), 0x0c0b_0a09_0807_0605);
+        assert_eq!(decoded.key.get(), 4);
+    }
+
+    #[test]
+    fn test_header_last() -> std::io::Result<()> {
+        for seed in 0..8 {
+            let sim = crate::sim::SimDevice::eager(seed, 64);
+            let mut wal = Wal::format_device(Box::new(sim.clone()), 64)?;
+            wal.set_header_last(true);
+            wal.set_drain_timeout(Duration::ZERO);
+            let first = wal.append(b"one")?;
+            wal.process_completions();
+
+            sim.set_complete_probability(0.0);
+            let large: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+            let second = wal.append(&large)?;
+            // Only the block holding the header is still outstanding.
+            assert_eq!(sim.pending(), 1);
+            assert_eq!(wal.read_at(second)?, large);
+
+            let mut wal = Wal::open_device(Box::new(sim.crash()), 64)?;
+            assert_eq!(wal.read_at(first)?, b"one");
+            if wal.len() == 2 {
+                assert_eq!(wal.read_at(second)?, large);
+            } else {
+                assert_eq!(wal.head(), second);
+            }
+            wal.append(b"three")?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_header() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 16);
+        let mut wal = Wal::format_device(Box::new(sim.clone()), 16)?;
+        let first = wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        wal.append(b"three")?;
+
+        // Overwrite the header of the second entry with each fault in turn.
+        let block = wal.dev.read(second.byte_offset(), BLOCK_SIZE as usize)?;
+        let original = EntryHeader::read_from_bytes(&block[..HEADER_SIZE]).unwrap();
+        for fault in [
+            HeaderFault::TooLong(u64::MAX),
+            HeaderFault::PastEnd(14),
+            HeaderFault::Rollover(7),
+        ] {
+            let mut header = original;
+            match fault {
+                HeaderFault::TooLong(len) => header.len = U64::new(len),
+                // The ring is 14 blocks, so an entry of 14 blocks only fits at its start.
+                HeaderFault::PastEnd(_) => header.len = U64::new(13 * BLOCK_SIZE as u64),
+                HeaderFault::Rollover(rollover) => header.rollover = U32::new(rollover),
+            }
+            let mut aligned = AlignedSlice::new(BLOCK_SIZE as usize);
+            aligned.as_slice().copy_from_slice(&block);
+            aligned.as_slice()[..HEADER_SIZE].copy_from_slice(header.as_bytes());
+            wal.dev.write(second, aligned, false)?;
+
+            let expected = CorruptHeader {
+                position: second,
+                fault,
+            };
+            let mut iter = wal.iterate();
+            assert_eq!(iter.next().unwrap()?, (first, b"one".to_vec()));
+            let err = iter.next().unwrap().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(err.get_ref().unwrap().downcast_ref(), Some(&expected));
+            let err = wal.read_at(second).unwrap_err();
+            assert_eq!(err.get_ref().unwrap().downcast_ref(), Some(&expected));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_erase_reclaimed() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 16);
+        let mut wal = Wal::format_device(Box::new(sim.clone()), 16)?;
+        let first = wal.append(b"one")?;
+        let large = vec![7; 2 * BLOCK_SIZE as usize];
+        wal.append(&large)?;
+        let third = wal.append(b"three")?;
+        wal.truncate(third)?;
+        assert_eq!(wal.erase_reclaimed(16)?, 0);
+
+        // Blocks truncated before erasing was turned on are erased too, a few at a time.
+        wal.set_erase_reclaimed(Some(0xa5));
+        assert_eq!(wal.erase_reclaimed(2)?, 2);
+        assert_eq!(wal.erase_reclaimed(16)?, 2);
+        assert_eq!(wal.erase_reclaimed(16)?, 0);
+        assert_eq!(wal.stats().erase_bytes, 4 * BLOCK_SIZE as u64);
+        let erased = wal.dev.read(first.byte_offset(), 4 * BLOCK_SIZE as usize)?;
+        assert!(!erased.windows(3).any(|w| w == b"one"));
+        assert!(!erased.windows(8).any(|w| w == [7; 8]));
+        assert_eq!(erased[HEADER_SIZE..HEADER_SIZE + 8], [0xa5; 8]);
+        assert_eq!(payloads(wal.iterate()), vec![b"three".to_vec()]);
+        let head = wal.append(b"four")?;
+        wal.process_completions().for_each(drop);
+        drop(wal);
+
+        // Recovery follows the erased blocks to the entries after them.
+        let wal = Wal::open_device(Box::new(sim.crash()), 16)?;
+        assert_eq!(wal.head(), head.advance(1, 16));
+        assert_eq!(
+            payloads(wal.iterate()),
+            vec![b"three".to_vec(), b"four".to_vec()]
+        );
+        assert!(wal.read_at(first).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_chain() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let wal = Wal::format_device(Box::new(sim.clone()), 64)?;
+        assert_eq!(
+            wal.verify_chain().unwrap_err().kind(),
+            std::io::ErrorKind::Unsupported
+        );
+        drop(wal);
+        let options = WalOptions {
+            hash_chain: true,
+            ..WalOptions::default()
+        };
+        let mut wal = Wal::format_device_with_options(Box::new(sim.clone()), 64, &options)?;
+        assert_eq!(wal.verify_chain()?, 0);
+        wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        let digest = wal.verify_chain()?;
+        assert_ne!(digest, 0);
+        drop(wal);
+
+        // The chain continues from the last entry found when the wal is reopened.
+        let mut wal = Wal::open_device(Box::new(sim), 64)?;
+        assert!(wal.control.hash_chain);
+        assert_eq!(wal.verify_chain()?, digest);
+        let third = wal.append(b"three")?;
+        assert_ne!(wal.verify_chain()?, digest);
+
+        // Rewrite the second entry along with its CRC, which only the chain catches.
+        let mut block = AlignedSlice::new(BLOCK_SIZE as usize);
+        block
+            .as_slice()
+            .copy_from_slice(&wal.dev.read(second.byte_offset(), BLOCK_SIZE as usize)?);
+        let buffer = block.as_slice();
+        buffer[HEADER_SIZE..HEADER_SIZE + 3].copy_from_slice(b"TWO");
+        let mut header = EntryHeader::read_from_bytes(&buffer[..HEADER_SIZE]).unwrap();
+        header.crc = U32::new(header.compute_crc(buffer));
+        buffer[..HEADER_SIZE].copy_from_slice(header.as_bytes());
+        wal.dev.write(second, block, false)?;
+        assert_eq!(wal.read_at(second)?, b"TWO");
+        let err = wal.verify_chain().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref(),
+            Some(&BrokenChain {
+                previous: second,
+                position: third,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_crcs() -> std::io::Result<()> {
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let options = WalOptions {
+            block_crcs: true,
+            ..WalOptions::default()
+        };
+        let mut wal = Wal::format_device_with_options(Box::new(sim.clone()), 64, &options)?;
+        // A payload that fills a block without block CRCs needs a second block with them.
+        let full = vec![1; BLOCK_SIZE as usize - HEADER_SIZE];
+        let first = wal.append(&full)?;
+        let large: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect();
+        let second = wal.append(&large)?;
+        assert_eq!(second, first.advance(2, 64));
+        let third = wal.append(b"three")?;
+        assert_eq!(third, second.advance(4, 64));
+        assert_eq!(wal.read_at(second)?, large);
+        drop(wal);
+
+        // The layout is kept when the wal is reopened.
+        let mut wal = Wal::open_device(Box::new(sim), 64)?;
+        assert!(wal.control.block_crcs);
+        assert_eq!(
+            payloads(wal.iterate()),
+            vec![full, large, b"three".to_vec()]
+        );
+
+        // Damage the third block of the large entry, which is found by its own CRC.
+        let damaged = second.advance(2, 64);
+        let mut block = AlignedSlice::new(BLOCK_SIZE as usize);
+        block
+            .as_slice()
+            .copy_from_slice(&wal.dev.read(damaged.byte_offset(), BLOCK_SIZE as usize)?);
+        block.as_slice()[100] ^= 1;
+        wal.dev.write(damaged, block, false)?;
+        wal.truncate(second)?;
+        let err = wal.verify_at(second).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            wal_error::<DamagedBlock>(&err),
+            Some(&DamagedBlock {
+                entry: second,
+                block: damaged,
+            })
+        );
+        let crc_valid: Vec<bool> = wal
+            .iterate_with_meta()
+            .map(|e| e.unwrap().crc_valid)
+            .collect();
+        assert_eq!(crc_valid, vec![false, true]);
+        assert!(wal.read_at(second).is_err());
+        assert_eq!(wal.read_at(third)?, b"three");
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrub() -> std::io::Result<()> {
+        let mut sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        let (sender, damaged) = mpsc::channel();
+        wal.on_damaged(move |pos| sender.send(pos).unwrap());
+        assert_eq!(wal.scrub(), 0);
+        wal.append(b"one")?;
+        let two = wal.append(b"two")?;
+        wal.append(b"three")?;
+        wal.process_completions().for_each(drop);
+
+        let mut block = sim.read(two.byte_offset(), BLOCK_SIZE as usize)?;
+        block[HEADER_SIZE] ^= 1;
+        let mut slice = AlignedSlice::new(BLOCK_SIZE as usize);
+        slice.as_slice().copy_from_slice(&block);
+        sim.write(two, slice, false)?;
+        sim.sync()?;
+
+        // A slow rate checks an entry at a time and then waits for the budget to build up again.
+        wal.set_scrub_rate(Some(2));
+        sleep(Duration::from_millis(10));
+        assert_eq!(wal.scrub(), 1);
+        assert_eq!(wal.scrub(), 0);
+
+        wal.set_scrub_rate(Some(1_000_000));
+        sleep(Duration::from_millis(10));
+        assert_eq!(wal.scrub(), 3);
+        assert_eq!(damaged.try_iter().collect::<Vec<_>>(), vec![two]);
+        let stats = wal.stats();
+        assert_eq!(stats.scrub_damaged, 1);
+        assert_eq!(stats.scrubbed_bytes, 4 * BLOCK_SIZE as u64);
+
+        // The next pass starts over from the tail, and skips truncated entries.
+        wal.truncate(two.advance(1, 64))?;
+        assert_eq!(wal.scrub(), 1);
+        assert!(damaged.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_atomic_writes() -> std::io::Result<()> {
+        let mut sim = crate::sim::SimDevice::eager(0, 64);
+        let open =
+            |sim: &crate::sim::SimDevice| Wal::open_device(Box::new(AtomicDevice(sim.clone())), 64);
+
+        let mut wal = open(&sim)?;
+        assert!(wal.capabilities().atomic_blocks());
+        assert!(wal.control.atomic_writes);
+        wal.append(b"one")?;
+        let damaged = wal.append_with(b"two", SyncPolicy::Immediate)?;
+        drop(wal);
+
+        // Flip a byte of the second entry, which a crash can't do to a single block write.
+        let mut block = sim.read(damaged.byte_offset(), BLOCK_SIZE as usize)?;
+        block[HEADER_SIZE] ^= 1;
+        let mut slice = AlignedSlice::new(BLOCK_SIZE as usize);
+        slice.as_slice().copy_from_slice(&block);
+        sim.write(damaged, slice, false)?;
+        sim.sync()?;
+
+        let wal = open(&sim)?;
+        assert_eq!(wal.stats().damaged_entries, 1);
+        assert_eq!(wal.head(), damaged);
+        drop(wal);
+
+        // Once opened on a device that can tear writes, the entries are no longer trusted to be
+        // atomic, even on a device that is again.
+        let wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        assert!(!wal.control.atomic_writes);
+        assert_eq!(wal.stats().damaged_entries, 0);
+        drop(wal);
+        let wal = open(&sim)?;
+        assert!(!wal.control.atomic_writes);
+        assert_eq!(wal.stats().damaged_entries, 0);
+        Ok(())
+    }
+
+    fn open_file(path: &Path) -> std::io::Result<Wal> {
+        Wal::open_device(Box::new(SyncDevice::new(path)?), 64)
+    }
+
+    fn overwrite(path: &Path, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        use std::io::{Seek, Write};
+        let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_backup() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("backup");
+        // Wrap the ring so the backup has to keep the entries from both passes.
+        let sim = crate::sim::SimDevice::eager(0, 64);
+        let mut wal = Wal::open_device(Box::new(sim.clone()), 64)?;
+        wal.set_retention(RetentionPolicy {
+            max_entries: Some(20),
+            ..Default::default()
+        });
+        for i in 0..80u32 {
+            wal.append(&i.to_le_bytes())?;
+        }
+        wal.ack("reader", wal.head())?;
+        wal.backup_to(&path)?;
+        let err = wal.backup_to(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        drop(wal);
+
+        // The backup recovers like the wal itself.
+        let wal = Wal::open_device(Box::new(sim), 64)?;
+        let backup = open_file(&path)?;
+        assert_eq!(payloads(backup.iterate()), payloads(wal.iterate()));
+        assert_eq!(backup.head(), wal.head());
+        assert_eq!(backup.cursor("reader"), wal.cursor("reader"));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_damaged_control_block() -> std::io::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let mut wal = open_file(file.path())?;
+        let first = wal.append(b"one")?;
+        let second = wal.append(b"two")?;
+        wal.ack("reader", first)?;
+        wal.ack("reader", second)?;
+        wal.process_completions().for_each(drop);
+        drop(wal);
+
+        // Formatting wrote the second block, so the first ack went to the first block, the second
+        // ack to the second and the checkpoint written on close to the first. Damage the first
+        // and the copy written by the second ack is used instead.
+        overwrite(file.path(), 30, &[0xff])?;
+        let wal = open_file(file.path())?;
+        assert_eq!(wal.cursor("reader"), Some(second));
+        drop(wal);
+
+        // With both copies gone the cursors are lost, but the entries are still there.
+        overwrite(file.path(), 30, &[0xff])?;
+        overwrite(file.path(), BLOCK_SIZE as u64 + 30, &[0xff])?;
+        let wal = open_file(file.path())?;
+        assert_eq!(wal.cursor("reader"), None);
+        assert_eq!(wal.iterate().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_reject_legacy_device() -> std::io::Result<()> {
+        // An entry as written before the control region existed, at the first block.
+        let file = tempfile::NamedTempFile::new()?;
+        file.as_file().set_len(64 * BLOCK_SIZE as u64)?;
+        let mut entry = vec![0; 12];
+        entry[8..12].copy_from_slice(&5u32.to_le_bytes());
+        entry.extend_from_slice(b"hello");
+        let mut hasher = Hasher::new();
+        hasher.update(&entry[4..]);
+        entry[..4].copy_from_slice(&hasher.finalize().to_le_bytes());
+        overwrite(file.path(), 0, &entry)?;
+
+        let err = open_file(file.path()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        let not_a_wal = |err: Error| wal_error::<NotAWal>(&err).copied();
+        assert_eq!(not_a_wal(err), Some(NotAWal::Legacy));
+
+        // Anything else that isn't a wal is rejected too.
+        overwrite(file.path(), 0, b"garbage")?;
+        let err = open_file(file.path()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(not_a_wal(err), Some(NotAWal::Foreign));
+
+        // Data in the ring of a device whose control region was never written.
+        overwrite(file.path(), 0, &[0; 32])?;
+        overwrite(file.path(), RING_START * BLOCK_SIZE as u64, b"garbage")?;
+        let err = open_file(file.path()).err().unwrap();
+        assert_eq!(not_a_wal(err), Some(NotAWal::Unformatted));
+        Ok(())
     }
 }
@@ -0,0 +1,265 @@
+use crate::common::*;
+use crate::primitives::{Arc, Condvar, Mutex, MutexGuard};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct State {
+    // The highest position reported durable so far.
+    durable: Option<WalPosition>,
+    // Incremented every time durable changes so watchers can tell if they have seen it.
+    version: u64,
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// Notifier is owned by the wal and publishes durable positions to every Watch.
+pub(crate) struct Notifier {
+    shared: Arc<Shared>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+}
+
+impl Notifier {
+    pub fn subscribe(&self) -> Watch {
+        let seen = self.shared.state.lock().unwrap().version;
+        Watch {
+            shared: self.shared.clone(),
+            seen,
+        }
+    }
+
+    // Called with the completions from each process_completions call.
+    pub fn publish(&self, completions: &[WalPosition]) {
+        let max = match completions.iter().copied().max() {
+            Some(max) => max,
+            None => return,
+        };
+        let mut state = self.shared.state.lock().unwrap();
+        match state.durable {
+            Some(durable) if durable >= max => return,
+            _ => {}
+        }
+        state.durable = Some(max);
+        state.version += 1;
+        self.wake(state);
+    }
+
+    // Called when the wal is dropped so no watcher blocks forever.
+    pub fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        self.wake(state);
+    }
+
+    fn wake(&self, mut state: MutexGuard<State>) {
+        let wakers = std::mem::take(&mut state.wakers);
+        drop(state);
+        self.shared.cond.notify_all();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Watch is a handle that is woken whenever new entries become durable. Each handle tracks which
+/// update it has already returned, so it can be moved to another thread or task and used
+/// independently of the wal and of other handles. Positions are only published when the owner of
+/// the wal calls process_completions.
+///
+/// The published position is the highest one completed so far, not a watermark below which
+/// everything is durable. Devices such as io_uring complete writes out of order, so entries before
+/// the published position may still be pending. To know that a particular entry is durable, wait
+/// for its own completion from process_completions, or turn on Wal::set_ordered_completions so the
+/// published position is only reached once everything before it is durable.
+#[derive(Clone)]
+pub struct Watch {
+    shared: Arc<Shared>,
+    seen: u64,
+}
+
+impl Watch {
+    /// The highest position that has been reported durable, if any. Entries before it are not
+    /// necessarily durable yet.
+    pub fn latest(&self) -> Option<WalPosition> {
+        self.shared.state.lock().unwrap().durable
+    }
+
+    /// Block until a newer durable position than the last one returned is available, and return
+    /// it. Like latest, entries before the returned position may still be pending. Returns None
+    /// once the wal is closed.
+    pub fn wait(&mut self) -> Option<WalPosition> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.version != self.seen {
+                self.seen = state.version;
+                return state.durable;
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.shared.cond.wait(state).unwrap();
+        }
+    }
+
+    /// Like wait, but gives up and returns None after timeout.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Option<WalPosition> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.version != self.seen {
+                self.seen = state.version;
+                return state.durable;
+            }
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                return None;
+            }
+            state = self
+                .shared
+                .cond
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Returns a future that resolves like wait, for use from async code.
+    pub fn changed(&mut self) -> Changed<'_> {
+        Changed { watch: self }
+    }
+}
+
+/// Future returned by Watch::changed.
+pub struct Changed<'a> {
+    watch: &'a mut Watch,
+}
+
+impl Future for Changed<'_> {
+    type Output = Option<WalPosition>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let watch = &mut self.get_mut().watch;
+        let mut state = watch.shared.state.lock().unwrap();
+        if state.version != watch.seen {
+            watch.seen = state.version;
+            return Poll::Ready(state.durable);
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wal::Wal;
+
+    #[test]
+    fn test_watch() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let mut watch = wal.watch();
+        let mut async_watch = wal.watch();
+        assert_eq!(watch.latest(), None);
+
+        let waiter = std::thread::spawn(move || watch.wait());
+        wal.append(b"one")?;
+        let pos = wal.append(b"two")?;
+        assert_eq!(wal.process_completions().count(), 2);
+
+        assert_eq!(waiter.join().unwrap(), Some(pos));
+        assert_eq!(
+            futures::executor::block_on(async_watch.changed()),
+            Some(pos)
+        );
+
+        // Nothing new, and dropping the wal releases the waiters.
+        let mut watch = wal.watch();
+        assert_eq!(
+            watch.wait_timeout(std::time::Duration::from_millis(1)),
+            None
+        );
+        drop(wal);
+        assert_eq!(watch.wait(), None);
+        Ok(())
+    }
+}
+
+// These cover the Notifier only, not the device completion paths.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    fn pos(offset: u64) -> WalPosition {
+        WalPosition {
+            offset,
+            rollover: 0,
+        }
+    }
+
+    // The completion thread publishes while the appender waits. The waiter must never miss the
+    // last update and positions must never go backwards.
+    #[test]
+    fn loom_publish_wait() {
+        loom::model(|| {
+            let notifier = Notifier::default();
+            let mut watch = notifier.subscribe();
+            let publisher = loom::thread::spawn(move || {
+                notifier.publish(&[pos(1)]);
+                notifier.publish(&[pos(3), pos(2)]);
+                notifier
+            });
+
+            let mut last = pos(0);
+            while last != pos(3) {
+                let next = watch.wait().unwrap();
+                assert!(next > last);
+                last = next;
+            }
+            drop(publisher.join().unwrap());
+        });
+    }
+
+    // Dropping the wal closes the notifier, which must release a waiter whether it started
+    // waiting before or after the close.
+    #[test]
+    fn loom_close_releases_waiters() {
+        loom::model(|| {
+            let notifier = Notifier::default();
+            let mut watch = notifier.subscribe();
+            let closer = loom::thread::spawn(move || {
+                notifier.publish(&[pos(1)]);
+                notifier.close();
+            });
+
+            match watch.wait() {
+                Some(p) => {
+                    assert_eq!(p, pos(1));
+                    assert_eq!(watch.wait(), None);
+                }
+                None => panic!("update published before close was lost"),
+            }
+            closer.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,209 @@
+use crate::common::WalPosition;
+use crate::wal::{check_entry, Entry, Wal};
+use log::warn;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// An entry handed out by WorkQueue::lease. It has to be acked before deadline, or it is handed
+/// out again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub entry: Entry,
+    /// How many times the entry has been handed out, starting at 1. Counted from when the queue
+    /// was opened.
+    pub deliveries: u32,
+    pub deadline: Instant,
+}
+
+// An entry that has been handed out and not acked yet.
+struct Leased {
+    deliveries: u32,
+    deadline: Instant,
+}
+
+/// WorkQueue hands out the durable entries of a wal as work items to be acked once they are done.
+/// Each entry leased is invisible to further leases until its visibility timeout passes, and if it
+/// isn't acked by then it is handed out again. Entries are acked one at a time and in any order,
+/// and the named consumer cursor, see Wal::ack, is moved past the entries before the first one
+/// that isn't acked yet, truncating the wal up to there.
+///
+/// Delivery is at least once. Leases are only held in memory, so after the queue is reopened every
+/// entry from the cursor on is handed out again, including ones acked out of order past it.
+pub struct WorkQueue {
+    name: String,
+    visibility: Duration,
+    leased: BTreeMap<WalPosition, Leased>,
+    // Where the entries not handed out yet start.
+    next: WalPosition,
+}
+
+impl WorkQueue {
+    /// Open the queue that keeps its progress in the consumer cursor called name, starting from
+    /// the tail if the cursor doesn't exist yet. Leased entries are handed out again once they
+    /// have been leased for visibility.
+    pub fn open(wal: &mut Wal, name: &str, visibility: Duration) -> std::io::Result<Self> {
+        let next = wal.cursor(name).unwrap_or(wal.tail()).max(wal.tail());
+        // Create the cursor, so the entries are kept until they are acked.
+        wal.ack(name, next)?;
+        Ok(WorkQueue {
+            name: name.to_string(),
+            visibility,
+            leased: BTreeMap::new(),
+            next,
+        })
+    }
+
+    /// How long a leased entry stays invisible to further leases.
+    pub fn set_visibility(&mut self, visibility: Duration) {
+        self.visibility = visibility;
+    }
+
+    /// The number of entries handed out and not acked yet.
+    pub fn outstanding(&self) -> usize {
+        self.leased.len()
+    }
+
+    /// Hand out up to n entries, oldest first. Entries whose lease expired without being acked
+    /// come first, followed by durable entries that haven't been handed out yet. Returns fewer
+    /// than n, possibly none, if there aren't that many. Fails with InvalidData on a damaged
+    /// entry.
+    pub fn lease(&mut self, wal: &mut Wal, n: usize) -> std::io::Result<Vec<Lease>> {
+        wal.poll_completions();
+        let now = Instant::now();
+        let deadline = now + self.visibility;
+        let mut leases = Vec::new();
+
+        let expired: Vec<WalPosition> = self
+            .leased
+            .iter()
+            .filter(|(_, leased)| leased.deadline <= now)
+            .map(|(position, _)| *position)
+            .take(n)
+            .collect();
+        for position in expired {
+            let entry = match wal.iterate_from(position).with_meta().next() {
+                Some(entry) if position >= wal.tail() => entry?,
+                _ => {
+                    warn!("Leased entry at {position:?} of {} is gone", self.name);
+                    self.leased.remove(&position);
+                    continue;
+                }
+            };
+            let entry = check_entry(entry)?;
+            let leased = self.leased.get_mut(&position).unwrap();
+            leased.deliveries += 1;
+            leased.deadline = deadline;
+            leases.push(Lease {
+                entry,
+                deliveries: leased.deliveries,
+                deadline,
+            });
+        }
+
+        let mut iter = wal.iterate_durable(self.next);
+        while leases.len() < n {
+            let entry = match iter.next() {
+                Some(entry) => entry?,
+                None => break,
+            };
+            self.next = iter.position();
+            self.leased.insert(
+                entry.position,
+                Leased {
+                    deliveries: 1,
+                    deadline,
+                },
+            );
+            leases.push(Lease {
+                entry,
+                deliveries: 1,
+                deadline,
+            });
+        }
+        Ok(leases)
+    }
+
+    /// Mark the leased entry at position as done, so it is never handed out again. Once every
+    /// entry before the first one that isn't acked is, the cursor moves past them and the wal is
+    /// truncated. An ack after the lease expired still counts, even if the entry was handed out
+    /// again. Fails with InvalidInput if the entry at position isn't leased.
+    pub fn ack(&mut self, wal: &mut Wal, position: WalPosition) -> std::io::Result<()> {
+        if self.leased.remove(&position).is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("no entry at {position:?} is leased from {}", self.name),
+            ));
+        }
+        // Everything before the oldest outstanding entry is acked.
+        let done = match self.leased.keys().next() {
+            Some(oldest) => *oldest,
+            None => self.next,
+        };
+        if wal.cursor(&self.name).is_some_and(|cursor| cursor >= done) {
+            return Ok(());
+        }
+        wal.ack(&self.name, done)?;
+        wal.truncate(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_and_ack() -> std::io::Result<()> {
+        let mut wal = Wal::open("mem:64".parse().unwrap())?;
+        let visibility = Duration::from_millis(200);
+        let mut queue = WorkQueue::open(&mut wal, "work", visibility)?;
+        for i in 0..4u8 {
+            wal.append(&[i])?;
+        }
+        wal.process_completions().for_each(drop);
+
+        let first = queue.lease(&mut wal, 3)?;
+        assert_eq!(first.len(), 3);
+        assert!(first.iter().all(|lease| lease.deliveries == 1));
+        let rest = queue.lease(&mut wal, 3)?;
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].entry.payload, vec![3]);
+        assert!(queue.lease(&mut wal, 3)?.is_empty());
+
+        // Acking out of order only moves the cursor past the acked prefix.
+        queue.ack(&mut wal, first[1].entry.position)?;
+        assert_eq!(wal.cursor("work"), Some(first[0].entry.position));
+        queue.ack(&mut wal, first[0].entry.position)?;
+        assert_eq!(wal.cursor("work"), Some(first[2].entry.position));
+        assert_eq!(wal.tail(), first[2].entry.position);
+        let err = queue.ack(&mut wal, first[0].entry.position).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Unacked entries are handed out again once their lease expires.
+        assert!(queue.lease(&mut wal, 1)?.is_empty());
+        std::thread::sleep(visibility);
+        let expired = queue.lease(&mut wal, 5)?;
+        assert_eq!(expired.len(), 2);
+        assert_eq!(expired[0].entry.payload, vec![2]);
+        assert!(expired.iter().all(|lease| lease.deliveries == 2));
+        queue.ack(&mut wal, expired[0].entry.position)?;
+        queue.ack(&mut wal, expired[1].entry.position)?;
+        assert_eq!(queue.outstanding(), 0);
+        assert_eq!(wal.tail(), wal.head());
+
+        // Leasing leaves the completions of the appends to the caller.
+        let pos = wal.append(b"kept")?;
+        let leases = queue.lease(&mut wal, 5)?;
+        assert_eq!(leases.last().unwrap().entry.payload, b"kept");
+        assert_eq!(wal.process_completions().collect::<Vec<_>>(), vec![pos]);
+        queue.ack(&mut wal, pos)?;
+
+        // A reopened queue continues from the cursor.
+        wal.append(b"more")?;
+        wal.process_completions().for_each(drop);
+        let mut queue = WorkQueue::open(&mut wal, "work", Duration::from_secs(60))?;
+        let leases = queue.lease(&mut wal, 5)?;
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].entry.payload, b"more");
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+// The model of the wal shared by the property tests and the fuzz target, which includes this file
+// by path.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use wal::common::*;
+use wal::sim::SimDevice;
+use wal::wal::{blocks_for, Wal, RING_START};
+
+// Small enough that most runs wrap the ring several times.
+pub const CAPACITY: u64 = 16;
+
+fn payload(seq: u32, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (seq as usize + i) as u8).collect()
+}
+
+struct Entry {
+    pos: WalPosition,
+    blocks: u64,
+    data: Vec<u8>,
+    // A completion was returned for the entry.
+    durable: bool,
+}
+
+// The expected state of the wal.
+pub struct Model {
+    head: WalPosition,
+    live: VecDeque<Entry>,
+    // Every entry, including truncated ones, that was appended but hasn't completed.
+    pub incomplete: Vec<WalPosition>,
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Model {
+            head: WalPosition {
+                offset: RING_START,
+                rollover: 0,
+            },
+            live: VecDeque::new(),
+            incomplete: Vec::new(),
+        }
+    }
+}
+
+impl Model {
+    // Where the next entry of the given size goes, and the first block of the padding written
+    // before it if it doesn't fit in the rest of the ring.
+    fn place(&self, blocks: u64) -> (WalPosition, Option<u64>) {
+        if self.head.offset + blocks > CAPACITY {
+            (self.head.next_pass(), Some(self.head.offset))
+        } else {
+            (self.head, None)
+        }
+    }
+
+    // Whether writing blocks [start, end) would overwrite an entry that is still live.
+    fn overwrites_live(&self, start: u64, end: u64) -> bool {
+        self.live
+            .iter()
+            .any(|e| e.pos.offset < end && start < e.pos.offset + e.blocks)
+    }
+
+    // Appends an entry of the given size to the wal and the model. The wal doesn't stop the head
+    // from running into the tail, that is up to the caller, so entries that would overwrite live
+    // ones are skipped.
+    pub fn append(&mut self, wal: &mut Wal, seq: u32, len: usize) -> std::io::Result<()> {
+        let blocks = blocks_for(len);
+        let (start, padding) = self.place(blocks);
+        if self.overwrites_live(start.offset, start.offset + blocks)
+            || padding.is_some_and(|p| self.overwrites_live(p, CAPACITY))
+        {
+            return Ok(());
+        }
+        let data = payload(seq, len);
+        let pos = wal.append(&data)?;
+        assert_eq!(pos, start);
+        self.incomplete.push(pos);
+        self.live.push_back(Entry {
+            pos,
+            blocks,
+            data,
+            durable: false,
+        });
+        self.head = pos.advance(blocks, CAPACITY);
+        Ok(())
+    }
+
+    // Truncates the wal and the model to the nth live entry, or to the head.
+    pub fn truncate(&mut self, wal: &mut Wal, n: usize) -> std::io::Result<()> {
+        let i = n % (self.live.len() + 1);
+        let pos = self.live.get(i).map(|e| e.pos).unwrap_or(self.head);
+        wal.truncate(pos)?;
+        self.live.drain(..i);
+        Ok(())
+    }
+
+    pub fn complete(&mut self, positions: impl Iterator<Item = WalPosition>) {
+        for pos in positions {
+            let i = self.incomplete.iter().position(|p| *p == pos);
+            self.incomplete
+                .swap_remove(i.expect("unexpected completion"));
+            // Completions for truncated entries are fine, anything else must be a live entry.
+            if let Some(entry) = self.live.iter_mut().find(|e| e.pos == pos) {
+                entry.durable = true;
+            } else {
+                assert!(
+                    self.live.front().is_none_or(|e| pos < e.pos),
+                    "unknown completion {pos:?}"
+                );
+            }
+        }
+    }
+
+    fn tail(&self) -> WalPosition {
+        self.live.front().map(|e| e.pos).unwrap_or(self.head)
+    }
+
+    // Only entries that completed along with every entry before them are guaranteed to survive a
+    // crash.
+    fn guaranteed(&self, entry: &Entry) -> bool {
+        entry.durable && self.incomplete.iter().all(|pos| entry.pos < *pos)
+    }
+
+    // Checks that the wal holds exactly the live entries, in order.
+    pub fn check(&self, wal: &mut Wal) -> std::io::Result<()> {
+        let utilization = wal.utilization();
+        assert!(
+            (0.0..=1.0).contains(&utilization),
+            "utilization {utilization}"
+        );
+        let entries: Vec<(WalPosition, Vec<u8>)> = wal.iterate().collect::<std::io::Result<_>>()?;
+        let positions: Vec<WalPosition> = entries.iter().map(|e| e.0).collect();
+        let expected: Vec<WalPosition> = self.live.iter().map(|e| e.pos).collect();
+        assert_eq!(positions, expected);
+        assert_eq!(wal.len(), self.live.len());
+        for ((_, data), entry) in entries.iter().zip(&self.live) {
+            assert!(*data == entry.data, "wrong data at {:?}", entry.pos);
+        }
+        Ok(())
+    }
+}
+
+// Completes every write in order, which is also the order a crash persists them in. Writes lost in a
+// crash never complete, so dropping the wal afterwards doesn't wait for them.
+pub fn open(sim: &SimDevice) -> std::io::Result<Wal> {
+    sim.set_complete_probability(1.0);
+    sim.set_reorder(false);
+    let mut wal = Wal::open_device(Box::new(sim.clone()), CAPACITY)?;
+    wal.set_drain_timeout(Duration::ZERO);
+    Ok(wal)
+}
+
+// Recovers after a crash. The control block holding the truncation point may not have been written
+// before the crash, so entries before the model tail may come back and are truncated again. From the model tail on the wal must hold live entries in order,
+// including every one that was guaranteed to survive.
+pub fn recover(sim: &SimDevice, model: &mut Model) -> std::io::Result<Wal> {
+    let mut wal = open(sim)?;
+    let tail = model.tail();
+    let mut iter = wal.iterate();
+    let recovered: Vec<(WalPosition, Vec<u8>)> = iter.by_ref().collect::<std::io::Result<_>>()?;
+    let head = iter.position();
+
+    let mut live = VecDeque::new();
+    let mut expected = std::mem::take(&mut model.live).into_iter();
+    for (pos, data) in recovered.into_iter().filter(|(pos, _)| *pos >= tail) {
+        let entry = loop {
+            let entry = expected.next().expect("recovered an unexpected entry");
+            if entry.pos == pos {
+                break entry;
+            }
+            assert!(!model.guaranteed(&entry), "lost entry {:?}", entry.pos);
+        };
+        assert!(data == entry.data, "recovered wrong data at {pos:?}");
+        live.push_back(Entry {
+            durable: true,
+            ..entry
+        });
+    }
+    for entry in expected {
+        assert!(!model.guaranteed(&entry), "lost entry {:?}", entry.pos);
+    }
+    model.live = live;
+    model.incomplete.clear();
+    model.head = head;
+    wal.truncate(model.tail())?;
+    Ok(wal)
+}
@@ -0,0 +1,118 @@
+#![cfg(unix)]
+
+// Crash recovery test using real processes. The test binary re-runs itself as a child that appends
+// to a file-backed wal and prints every completion it receives. The parent SIGKILLs the child at a
+// random point, reopens the wal and checks that everything the child was told is durable was
+// recovered.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use tempfile::NamedTempFile;
+use wal::common::*;
+use wal::wal::Wal;
+
+// Set in the environment of the child to the path of the wal file.
+const CHILD_ENV: &str = "WAL_CRASH_CHILD";
+
+const BLOCKS: u64 = 4096;
+// Stay well short of filling the ring so the child never wraps.
+const MAX_ENTRIES: u32 = 2000;
+
+fn payload(seq: u32) -> Vec<u8> {
+    seq.to_le_bytes().repeat(64)
+}
+
+// Parses a completion printed by the child, skipping output from the test harness.
+fn parse(line: &str) -> Option<(WalPosition, u32)> {
+    let fields: Vec<u64> = line
+        .split(' ')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    match fields[..] {
+        [offset, rollover, seq] => Some((
+            WalPosition {
+                offset,
+                rollover: u32::try_from(rollover).ok()?,
+            },
+            u32::try_from(seq).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+// The child half. It does nothing unless it was started by test_crash_recovery.
+#[test]
+fn crash_child() {
+    let path = match std::env::var(CHILD_ENV) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let mut wal = Wal::open(format!("file://{path}?device=sync").parse().unwrap()).unwrap();
+    let mut stdout = std::io::stdout().lock();
+    let mut appended = HashMap::new();
+    for seq in 0..MAX_ENTRIES {
+        let pos = wal.append(&payload(seq)).unwrap();
+        appended.insert(pos, seq);
+        for pos in wal.process_completions() {
+            writeln!(stdout, "{} {} {}", pos.offset, pos.rollover, appended[&pos]).unwrap();
+        }
+        stdout.flush().unwrap();
+    }
+    // Wait to be killed.
+    loop {
+        std::thread::park();
+    }
+}
+
+#[test]
+fn test_crash_recovery() -> std::io::Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut rng = fastrand::Rng::new();
+
+    for _ in 0..5 {
+        let file = NamedTempFile::new()?;
+        file.as_file().set_len(BLOCKS * BLOCK_SIZE as u64)?;
+        let kill_after = rng.u32(0..MAX_ENTRIES);
+
+        let mut child = Command::new(&exe)
+            .args(["--exact", "crash_child", "--nocapture", "--test-threads=1"])
+            .env(CHILD_ENV, file.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        // Read completions until it is time to kill the child, then collect whatever it managed to
+        // report before it died.
+        let mut reported = Vec::new();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        for line in lines.by_ref() {
+            reported.extend(parse(&line?));
+            if reported.len() as u32 > kill_after {
+                break;
+            }
+        }
+        child.kill()?;
+        for line in lines {
+            reported.extend(parse(&line?));
+        }
+        child.wait()?;
+
+        let wal = Wal::open(
+            format!("file://{}?device=sync", file.path().display())
+                .parse()
+                .unwrap(),
+        )?;
+        let recovered: HashMap<WalPosition, Vec<u8>> =
+            wal.iterate().collect::<std::io::Result<_>>()?;
+        for (pos, seq) in &reported {
+            assert_eq!(
+                recovered.get(pos),
+                Some(&payload(*seq)),
+                "completion {pos:?} for entry {seq} was not recovered after killing at {kill_after}"
+            );
+        }
+    }
+    Ok(())
+}
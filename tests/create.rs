@@ -0,0 +1,200 @@
+#![cfg(unix)]
+
+// Creating a wal file through Wal::create, and opening it with the file devices Wal::open uses.
+
+use wal::common::BLOCK_SIZE;
+use wal::wal::{wal_error, FileDevice, NotAWal, Wal, WalOptions, RING_START};
+#[cfg(target_os = "macos")]
+use wal::wal::{MacOsDevice, SyncPolicy};
+
+// Use the synchronous device, the temporary directory may not support O_DIRECT.
+fn sync_options() -> WalOptions {
+    WalOptions {
+        device: FileDevice::Sync,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_create() -> std::io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("wal");
+    let mut wal = Wal::create_with_options(&path, 64, &sync_options())?;
+    wal.append(b"one")?;
+    drop(wal);
+
+    let err = Wal::create_with_options(&path, 64, &sync_options())
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    let wal = Wal::open(
+        format!("file://{}?device=sync", path.display())
+            .parse()
+            .unwrap(),
+    )?;
+    let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+    assert_eq!(data, vec![b"one".to_vec()]);
+
+    // Nothing but the wal is left in the directory.
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_create_growable() -> std::io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("wal");
+    let blocks = || std::fs::metadata(&path).unwrap().len() / BLOCK_SIZE as u64;
+    drop(Wal::create_growable_with_options(
+        &path,
+        1024,
+        &sync_options(),
+    )?);
+    assert_eq!(blocks(), RING_START + 1);
+
+    let options = WalOptions {
+        grow_chunk_blocks: 16,
+        ..sync_options()
+    };
+    let url: url::Url = format!("file://{}", path.display()).parse().unwrap();
+    let mut wal = Wal::open_with_options(url.clone(), &options)?;
+    wal.append(b"one")?;
+    wal.append(&[2; 4 * BLOCK_SIZE as usize])?;
+    drop(wal);
+    assert_eq!(blocks(), 16);
+
+    // A mapping wouldn't cover the blocks the file grows into.
+    let mapped: url::Url = format!("file://{}?device=sync&read=mmap", path.display())
+        .parse()
+        .unwrap();
+    let err = Wal::open(mapped.clone()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+    // The capacity comes from the control block rather than the length of the file, and the
+    // default chunk takes the file the rest of the way.
+    let mut wal = Wal::open_with_options(url, &sync_options())?;
+    wal.append(&[3; 512 * BLOCK_SIZE as usize])?;
+    drop(wal);
+    assert_eq!(blocks(), 1024);
+    let wal = Wal::open(mapped)?;
+    assert_eq!(wal.iterate().count(), 3);
+    Ok(())
+}
+
+#[test]
+fn test_open_not_a_wal() -> std::io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("notes.txt");
+    let url: url::Url = format!("file://{}?device=sync", path.display())
+        .parse()
+        .unwrap();
+    let not_a_wal = |err: std::io::Error| wal_error::<NotAWal>(&err).copied();
+
+    std::fs::write(&path, b"not a wal")?;
+    let err = Wal::open(url.clone()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(not_a_wal(err), Some(NotAWal::BlockSize(9)));
+
+    std::fs::write(&path, b"")?;
+    let err = Wal::open(url.clone()).err().unwrap();
+    assert_eq!(not_a_wal(err), Some(NotAWal::TooSmall(0)));
+
+    let mut data = vec![0; 16 * BLOCK_SIZE as usize];
+    data[..9].copy_from_slice(b"not a wal");
+    std::fs::write(&path, &data)?;
+    let err = Wal::open(url).err().unwrap();
+    assert_eq!(not_a_wal(err), Some(NotAWal::Foreign));
+    Ok(())
+}
+
+#[test]
+fn test_open_mapped() -> std::io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("wal");
+    let mut wal = Wal::create_with_options(&path, 64, &sync_options())?;
+    wal.append(b"one")?;
+    drop(wal);
+
+    let mut wal = Wal::open(
+        format!("file://{}?device=sync&read=mmap", path.display())
+            .parse()
+            .unwrap(),
+    )?;
+    wal.append(b"two")?;
+    let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+    assert_eq!(data, vec![b"one".to_vec(), b"two".to_vec()]);
+    drop(wal);
+
+    let url = format!("file://{}?device=sync&read=other", path.display());
+    let err = Wal::open(url.parse().unwrap()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    Ok(())
+}
+
+#[test]
+fn test_open_device() -> std::io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("wal");
+    let mut wal = Wal::create_with_options(&path, 64, &sync_options())?;
+    wal.append(b"one")?;
+    drop(wal);
+
+    #[cfg(target_os = "macos")]
+    {
+        let mut expected = vec![b"one".to_vec()];
+        for device in ["kqueue", "pwrite", "calibrate"] {
+            let url = format!("file://{}?device={device}", path.display());
+            let mut wal = Wal::open(url.parse().unwrap())?;
+            // The write is asynchronous, so it is only read back once it is synced.
+            wal.append_with(device.as_bytes(), SyncPolicy::Immediate)?;
+            expected.push(device.as_bytes().to_vec());
+            let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+            assert_eq!(data, expected);
+        }
+
+        // Calibrating leaves nothing behind but the wal.
+        let options = WalOptions {
+            macos_device: MacOsDevice::Calibrate,
+            ..Default::default()
+        };
+        let url = format!("file://{}", path.display());
+        drop(Wal::open_with_options(url.parse().unwrap(), &options)?);
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+    }
+
+    // The device can be picked in the URL as well as with WalOptions::device.
+    let url = format!("file://{}?device=sync", path.display());
+    let wal = Wal::open(url.parse().unwrap())?;
+    assert_eq!(wal.iterate().count(), 1);
+    drop(wal);
+
+    let url = format!("file://{}?device=other", path.display());
+    let err = Wal::open(url.parse().unwrap()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_open_dsync() -> std::io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("wal");
+    drop(Wal::create_with_options(&path, 64, &sync_options())?);
+
+    let options = WalOptions {
+        dsync_writes: true,
+        ..sync_options()
+    };
+    let url: url::Url = format!("file://{}", path.display()).parse().unwrap();
+    let mut wal = Wal::open_with_options(url.clone(), &options)?;
+    assert!(wal.capabilities().dsync_writes);
+    let pos = wal.append(b"one")?;
+    assert_eq!(wal.process_completions().collect::<Vec<_>>(), vec![pos]);
+    drop(wal);
+
+    let wal = Wal::open_with_options(url, &sync_options())?;
+    assert!(!wal.capabilities().dsync_writes);
+    let data: Vec<Vec<u8>> = wal.iterate().map(|e| e.unwrap().1).collect();
+    assert_eq!(data, vec![b"one".to_vec()]);
+    Ok(())
+}
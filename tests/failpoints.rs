@@ -0,0 +1,73 @@
+#![cfg(feature = "failpoints")]
+
+use wal::common::*;
+use wal::mem::MemDevice;
+use wal::wal::{Wal, WalFailed};
+
+// Failpoints are process wide, so everything runs in one test to keep them from leaking into
+// other tests running in parallel.
+#[test]
+fn test_failpoints() -> std::io::Result<()> {
+    let scenario = fail::FailScenario::setup();
+    let mut wal = Wal::open("mem:64".parse().unwrap())?;
+    wal.append(b"one")?;
+
+    fail::cfg("wal::submit", "return(disk on fire)").unwrap();
+    let err = wal.append(b"two").unwrap_err();
+    assert!(err.to_string().contains("disk on fire"));
+    fail::remove("wal::submit");
+
+    fail::cfg("wal::header_parse", "return").unwrap();
+    assert!(wal.iterate().next().unwrap().is_err());
+    fail::remove("wal::header_parse");
+    assert_eq!(wal.iterate().count(), 1);
+
+    // A failed completion is never reported durable, and the wal fails rather than accepting
+    // appends that may never complete.
+    wal.append(b"three")?;
+    fail::cfg("mem::completion", "return").unwrap();
+    assert_eq!(wal.process_completions().count(), 0);
+    fail::remove("mem::completion");
+    assert!(wal.failure().is_some());
+    let err = wal.append(b"four").unwrap_err();
+    assert!(err.get_ref().unwrap().is::<WalFailed>());
+
+    // An import on a wal that fails while it waits gives up rather than waiting forever.
+    let mut wal = Wal::open("mem:64".parse().unwrap())?;
+    fail::cfg("mem::completion", "return").unwrap();
+    let err = wal.import(vec![Ok(b"lost".to_vec())]).unwrap_err();
+    assert!(err.get_ref().unwrap().is::<WalFailed>());
+    fail::remove("mem::completion");
+
+    // Only the first completion fails.
+    let mut device = MemDevice::new(4);
+    fail::cfg("mem::completion", "1*return->off").unwrap();
+    for offset in 0..2 {
+        device.write(
+            WalPosition {
+                offset,
+                rollover: 0,
+            },
+            AlignedSlice::new(10),
+            true,
+        )?;
+    }
+    assert_eq!(device.process_completions().count(), 1);
+    fail::remove("mem::completion");
+
+    // The padding write at the end of the ring fails, leaving the head where it was. The error
+    // says it was the padding rather than the entry that failed.
+    let mut wal = Wal::open("mem:5".parse().unwrap())?;
+    for _ in 0..2 {
+        wal.append(b"fill")?;
+    }
+    let large = vec![0; 5000];
+    fail::cfg("wal::rollover", "return").unwrap();
+    let err = wal.append(&large).unwrap_err();
+    assert!(err.to_string().contains("padding"), "{err}");
+    fail::remove("wal::rollover");
+    assert_eq!(wal.append(&large)?.rollover, 1);
+
+    scenario.teardown();
+    Ok(())
+}
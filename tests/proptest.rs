@@ -0,0 +1,96 @@
+// Property tests that run random sequences of appends, truncates, completions and reopens against
+// an in-memory device and check that exactly the expected entries survive, in order.
+// Run with `cargo test --features testing`.
+#![cfg(feature = "testing")]
+
+mod common;
+
+use common::{recover, Model, CAPACITY};
+use proptest::prelude::*;
+use wal::common::*;
+use wal::sim::SimDevice;
+use wal::wal::{HEADER_SIZE, TRAILER_SIZE};
+
+/// The size of the data of an entry, covering empty entries and entries that span several blocks.
+#[derive(Debug, Clone, Copy)]
+struct EntrySize(usize);
+
+impl Arbitrary for EntrySize {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<EntrySize>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        let block = BLOCK_SIZE as usize;
+        prop_oneof![
+            Just(0),
+            1..block - HEADER_SIZE,
+            // Sizes around the block boundaries.
+            (1..4usize, -2..3isize)
+                .prop_map(move |(b, d)| (b * block - HEADER_SIZE).saturating_add_signed(d)),
+            // Sizes around the block boundaries of entries with a trailer.
+            (2..4usize, -2..3isize).prop_map(move |(b, d)| {
+                (b * block - HEADER_SIZE - TRAILER_SIZE).saturating_add_signed(d)
+            }),
+            0..3 * block,
+        ]
+        .prop_map(EntrySize)
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Append(EntrySize),
+    /// Truncate to the nth live entry, or to the head.
+    Truncate(usize),
+    Complete,
+    /// Sync everything, drop the wal and open it again from what is on the device.
+    Reopen,
+}
+
+impl Arbitrary for Op {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Op>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            6 => any::<EntrySize>().prop_map(Op::Append),
+            3 => any::<usize>().prop_map(Op::Truncate),
+            1 => Just(Op::Complete),
+            1 => Just(Op::Reopen),
+        ]
+        .boxed()
+    }
+}
+
+fn run(ops: Vec<Op>) -> std::io::Result<()> {
+    let mut sim = SimDevice::new(0, CAPACITY);
+    let mut wal = common::open(&sim)?;
+    let mut model = Model::default();
+
+    for (seq, op) in ops.into_iter().enumerate() {
+        match op {
+            Op::Append(EntrySize(len)) => model.append(&mut wal, seq as u32, len)?,
+            Op::Truncate(n) => model.truncate(&mut wal, n)?,
+            Op::Complete => model.complete(wal.process_completions()),
+            Op::Reopen => {
+                model.complete(wal.process_completions());
+                assert_eq!(sim.pending(), 0);
+                sim = sim.crash();
+                drop(wal);
+                wal = recover(&sim, &mut model)?;
+            }
+        }
+        model.check(&mut wal)?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(500))]
+
+    #[test]
+    fn test_surviving_entries(ops in prop::collection::vec(any::<Op>(), 1..200)) {
+        run(ops).unwrap();
+    }
+}